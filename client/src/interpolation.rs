@@ -0,0 +1,297 @@
+use core::player::Player;
+use core::tick::{Tick, TICK_SECONDS};
+use std::collections::{HashMap, VecDeque};
+
+/// The maximum number of snapshots buffered for each player.
+///
+/// Snapshots are normally discarded as soon as they're too old to be rendered, so this only
+/// matters if the client stops rendering for a while (e.g. if the window is being dragged).
+const MAX_SNAPSHOTS: usize = 64;
+
+/// How much of the difference between the estimated server tick and the tick of each incoming
+/// snapshot is corrected at once.
+///
+/// Snapshots arrive with some jitter, so correcting slowly averages it out instead of passing it
+/// on to the rendered players.
+const CLOCK_CORRECTION: f32 = 0.05;
+
+/// If the estimated server tick is further than this many ticks from an incoming snapshot, the
+/// estimate is reset to the snapshot's tick rather than slowly corrected.
+const MAX_CLOCK_ERROR: f32 = 30.0;
+
+/// Settings for how remote players are interpolated, loaded from `resources/interpolation.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InterpolationConfig {
+    /// How far in the past remote players are rendered, in milliseconds.
+    ///
+    /// Rendering remote players in the past means there's usually a snapshot on either side of
+    /// the render time to interpolate between. This should be at least a couple of server
+    /// frames so that a single late or dropped snapshot doesn't cause a hitch.
+    pub delay_millis: u64,
+
+    /// How far past the newest snapshot a remote player can be extrapolated, in milliseconds.
+    ///
+    /// If snapshots stop arriving, remote players continue moving in the direction they were
+    /// going for this long, and then stop until a new snapshot arrives.
+    pub max_extrapolation_millis: u64,
+}
+
+impl InterpolationConfig {
+    /// Returns `delay_millis` in ticks.
+    pub fn delay_ticks(&self) -> f32 {
+        millis_to_ticks(self.delay_millis)
+    }
+
+    /// Returns `max_extrapolation_millis` in ticks.
+    pub fn max_extrapolation_ticks(&self) -> f32 {
+        millis_to_ticks(self.max_extrapolation_millis)
+    }
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> InterpolationConfig {
+        InterpolationConfig {
+            delay_millis: 100,
+            max_extrapolation_millis: 250,
+        }
+    }
+}
+
+/// The state of a player received from the server, along with the server tick it was sent on.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub tick: Tick,
+    pub player: Player,
+}
+
+/// Buffered server snapshots for each remote player, keyed by player ID.
+///
+/// Snapshots are placed on a timeline by the server tick they were sent on rather than by when
+/// they arrived, so jitter in how long they take to arrive doesn't affect how players move. The
+/// buffers also estimate which tick the server is currently on, which is used to pick the
+/// render time.
+#[derive(Debug, Default)]
+pub struct SnapshotBuffers {
+    players: HashMap<u64, VecDeque<Snapshot>>,
+
+    /// The estimated current server tick, including the fraction of the current tick that has
+    /// elapsed. `None` until the first snapshot arrives.
+    server_time: Option<f32>,
+}
+
+impl SnapshotBuffers {
+    /// Adds a snapshot for a player, sent on the server tick `tick`.
+    ///
+    /// Snapshots that arrive out of order are put back in order, and duplicates are discarded.
+    pub fn push(&mut self, tick: Tick, player: Player) {
+        let snapshots = self.players.entry(player.id).or_insert_with(VecDeque::new);
+        let index = match snapshots.iter().rposition(|snapshot| snapshot.tick <= tick) {
+            Some(index) if snapshots[index].tick == tick => { return; }
+            Some(index) => index + 1,
+            None => 0,
+        };
+        snapshots.insert(index, Snapshot { tick, player });
+
+        if snapshots.len() > MAX_SNAPSHOTS {
+            snapshots.pop_front();
+        }
+    }
+
+    /// Discards all snapshots for a player, e.g. because the player left the game.
+    pub fn remove(&mut self, id: u64) {
+        self.players.remove(&id);
+    }
+
+    /// Corrects the estimate of the server's current tick using a snapshot sent on `tick`.
+    ///
+    /// Should be called once for each world snapshot received.
+    pub fn observe_tick(&mut self, tick: Tick) {
+        let tick = tick.0 as f32;
+        self.server_time = match self.server_time {
+            Some(time) if (tick - time).abs() <= MAX_CLOCK_ERROR => {
+                Some(time + (tick - time) * CLOCK_CORRECTION)
+            }
+
+            _ => Some(tick),
+        };
+    }
+
+    /// Advances the estimate of the server's current tick by `delta` seconds.
+    pub fn advance(&mut self, delta: f32) {
+        if let Some(ref mut time) = self.server_time {
+            *time += delta / TICK_SECONDS;
+        }
+    }
+
+    /// Returns the server tick to render remote players at, `delay` ticks behind the estimated
+    /// current server tick. Returns `None` if no snapshots have been received yet.
+    pub fn render_time(&self, delay: f32) -> Option<f32> {
+        self.server_time.map(|time| time - delay)
+    }
+
+    /// Returns the state to render for a player at `render_time`, in ticks.
+    ///
+    /// Blends between the snapshots on either side of `render_time`. If `render_time` is past
+    /// the newest snapshot, the player is extrapolated from the last two snapshots for at most
+    /// `max_extrapolation` ticks. Returns `None` if there are no snapshots for the player.
+    ///
+    /// Snapshots that are too old to be needed again are discarded.
+    pub fn sample(
+        &mut self,
+        id: u64,
+        render_time: f32,
+        max_extrapolation: f32,
+    ) -> Option<Player> {
+        let snapshots = self.players.get_mut(&id)?;
+
+        // Discard snapshots until the oldest one is the last snapshot before the render time. We
+        // always keep the newest two snapshots so that we can extrapolate from them.
+        while snapshots.len() > 2 && snapshots[1].tick.0 as f32 <= render_time {
+            snapshots.pop_front();
+        }
+
+        let oldest = snapshots.front()?;
+        let newest = snapshots.back()?;
+
+        // If we don't have any snapshots from before the render time, then all we can do is
+        // show the oldest state we have.
+        if render_time <= oldest.tick.0 as f32 {
+            return Some(oldest.player.clone());
+        }
+
+        // If we've run out of snapshots, extrapolate from the newest two. We only extrapolate
+        // up to `max_extrapolation` past the newest snapshot, after which the player is held in
+        // place until a new snapshot arrives.
+        if render_time >= newest.tick.0 as f32 {
+            if snapshots.len() < 2 {
+                return Some(newest.player.clone());
+            }
+
+            let previous = &snapshots[snapshots.len() - 2];
+            let interval = (newest.tick.0 - previous.tick.0) as f32;
+            let overdue = (render_time - newest.tick.0 as f32).min(max_extrapolation);
+            let t = ratio(interval + overdue, interval);
+            return Some(previous.player.interpolate(&newest.player, t));
+        }
+
+        // Otherwise the render time is between the oldest two snapshots.
+        let from = &snapshots[0];
+        let to = &snapshots[1];
+        let t = ratio(render_time - from.tick.0 as f32, (to.tick.0 - from.tick.0) as f32);
+        Some(from.player.interpolate(&to.player, t))
+    }
+}
+
+/// Returns `numerator / denominator`, or 1 if the denominator is 0.
+fn ratio(numerator: f32, denominator: f32) -> f32 {
+    if denominator <= 0.0 {
+        return 1.0;
+    }
+
+    numerator / denominator
+}
+
+fn millis_to_ticks(millis: u64) -> f32 {
+    millis as f32 / 1000.0 / TICK_SECONDS
+}
+
+#[cfg(test)]
+mod test {
+    use core::math::*;
+    use super::*;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!((expected - actual).abs() < 1e-4, "Expected {}, got {}", expected, actual);
+    }
+
+    fn player_at(x: f32) -> Player {
+        Player::new(1, Point3::new(x, 0.0, 0.0))
+    }
+
+    // Two snapshots 6 ticks apart, with the player moving from x = 0 to x = 6.
+    fn buffers() -> SnapshotBuffers {
+        let mut buffers = SnapshotBuffers::default();
+        buffers.push(Tick(10), player_at(0.0));
+        buffers.push(Tick(16), player_at(6.0));
+        buffers
+    }
+
+    fn sample_x(buffers: &mut SnapshotBuffers, render_time: f32) -> f32 {
+        buffers
+            .sample(1, render_time, 15.0)
+            .expect("No snapshot for player")
+            .position
+            .x
+    }
+
+    #[test]
+    fn ratio_of_ticks() {
+        assert_close(0.25, ratio(1.5, 6.0));
+        assert_close(1.5, ratio(9.0, 6.0));
+
+        // A zero-length interval jumps straight to the end.
+        assert_eq!(1.0, ratio(1.0, 0.0));
+    }
+
+    #[test]
+    fn interpolates_between_snapshots() {
+        let mut buffers = buffers();
+
+        assert_close(1.5, sample_x(&mut buffers, 11.5));
+        assert_close(4.0, sample_x(&mut buffers, 14.0));
+    }
+
+    #[test]
+    fn holds_oldest_snapshot_before_render_time() {
+        let mut buffers = buffers();
+
+        assert_eq!(0.0, sample_x(&mut buffers, 7.0));
+    }
+
+    #[test]
+    fn extrapolation_is_capped() {
+        let mut buffers = buffers();
+
+        // 3 ticks past the newest snapshot, the player keeps moving at the same speed.
+        assert_close(9.0, sample_x(&mut buffers, 19.0));
+
+        // Long after the newest snapshot, the player stops 15 ticks past it.
+        assert_close(21.0, sample_x(&mut buffers, 300.0));
+    }
+
+    #[test]
+    fn out_of_order_snapshots_are_sorted() {
+        let mut buffers = SnapshotBuffers::default();
+        buffers.push(Tick(16), player_at(6.0));
+        buffers.push(Tick(10), player_at(0.0));
+        buffers.push(Tick(16), player_at(100.0));
+
+        assert_close(3.0, sample_x(&mut buffers, 13.0));
+    }
+
+    #[test]
+    fn jitter_is_smoothed() {
+        let mut buffers = SnapshotBuffers::default();
+        assert_eq!(None, buffers.render_time(6.0));
+
+        buffers.observe_tick(Tick(100));
+        assert_eq!(Some(94.0), buffers.render_time(6.0));
+
+        // A snapshot that arrives a couple of ticks late only nudges the estimate.
+        buffers.advance(TICK_SECONDS * 3.0);
+        buffers.observe_tick(Tick(101));
+        let render_time = buffers.render_time(0.0).unwrap();
+        assert!(render_time > 102.8 && render_time < 103.0, "Render time is {}", render_time);
+
+        // A snapshot that's far off resets the estimate.
+        buffers.observe_tick(Tick(500));
+        assert_eq!(Some(500.0), buffers.render_time(0.0));
+    }
+
+    #[test]
+    fn unknown_player() {
+        let mut buffers = SnapshotBuffers::default();
+        assert!(buffers.sample(1, 0.0, 15.0).is_none());
+    }
+}
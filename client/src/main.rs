@@ -37,9 +37,12 @@ use amethyst_gltf::{GltfSceneAsset, GltfSceneLoaderSystem};
 use components::*;
 use core::math::*;
 use core::player::*;
+use core::weapon::Weapons;
 use core::*;
 use futures::{prelude::*, sync::oneshot};
+use std::net::SocketAddr;
 use std::thread;
+use interpolation::InterpolationConfig;
 use std::time::Duration;
 use tap::*;
 use tokio_core::reactor::Core;
@@ -47,6 +50,8 @@ use waiting_late_init::*;
 use {state::InitState, systems::*};
 
 mod components;
+mod interpolation;
+mod prediction;
 mod state;
 mod systems;
 mod waiting_late_init;
@@ -69,6 +74,14 @@ fn main() -> amethyst::Result<()> {
 
     let input_config_path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/input.ron");
 
+    let interpolation_config = InterpolationConfig::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/resources/interpolation.ron"
+    ));
+
+    let weapons = Weapons::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/weapons"))
+        .expect("Failed to load weapon definitions");
+
     let pipe = Pipeline::build().with_stage(
         Stage::with_backbuffer()
             .clear_target([1.0, 0.0, 0.0, 1.0], 1.0)
@@ -81,8 +94,7 @@ fn main() -> amethyst::Result<()> {
     let sync_editor = SyncEditorBundle::new()
         .tap(SyncEditorBundle::sync_default_types)
         .tap(|bundle| sync_components!(bundle, Player, InputFrame, LocalPlayer, PlayerPitch))
-        .tap(|bundle| read_components!(bundle, RevolverEntities, PlayerEntities))
-        .tap(|bundle| sync_resources!(bundle, FrameId));
+        .tap(|bundle| read_components!(bundle, RevolverEntities, PlayerEntities));
 
     let game_data = GameDataBuilder::default()
         // Early setup systems. These perform loading and late initialization that should
@@ -106,18 +118,44 @@ fn main() -> amethyst::Result<()> {
         .with_barrier()
         // Gameplay systems. These perform the bulk of the actual gameplay logic during the
         // a frame.
-        .with(PlayerInputSystem::default(), "player_input", &[])
-        .with(PlayerPositionSystem::default(), "player_position", &[])
-        .with(PlayerYawSystem::default(), "player_yaw", &[])
-        .with(PlayerPitchSystem::default(), "player_pitch", &[])
+        .with(TickSystem::default(), "tick", &[])
+        .with(PlayerInputSystem::default(), "player_input", &["tick"])
+        .with(
+            PlayerPredictionSystem::default(),
+            "player_prediction",
+            &["player_input"],
+        )
+        .with(
+            RemotePlayerInterpolationSystem::default(),
+            "remote_player_interpolation",
+            &[],
+        )
+        .with(
+            PlayerPositionSystem::default(),
+            "player_position",
+            &["player_prediction", "remote_player_interpolation"],
+        )
+        .with(
+            PlayerYawSystem::default(),
+            "player_yaw",
+            &["player_prediction", "remote_player_interpolation"],
+        )
+        .with(
+            PlayerPitchSystem::default(),
+            "player_pitch",
+            &["player_prediction", "remote_player_interpolation"],
+        )
+        .with(
+            HideGunSystem::default(),
+            "hide_gun",
+            &["player_prediction", "remote_player_interpolation"],
+        )
+        .with(ProjectileSystem::default(), "projectile", &["tick"])
         .with(CylinderPivotSystem::default(), "cylinder_pivot", &[])
         .with(RevolverChamberSystem::default(), "revolver_chamber", &[])
         .with(RevolverHammerSystem::default(), "revolver_hammer", &[])
         .with(RevolverCylinderSystem::default(), "revolver_cylinder", &[])
         .with(EjectAnimationSystem::default(), "eject_animation", &[])
-        // End of frame logic. Thread-local systems that need to run after all other work for
-        // the frame has been done.
-        .with_thread_local(FrameIdSystem)
         .with_bundle(sync_editor)?;
 
     trace!("Adding input bundle");
@@ -139,24 +177,56 @@ fn main() -> amethyst::Result<()> {
         let mut core = Core::new().expect("Failed to create reactor");
         let handle = core.handle();
 
-        // Spawn the connection listener onto the reactor and create a new `Stream` that yields each
-        // connection as it is received.
-        let address = "127.0.0.1:1234".parse().unwrap();
-        let wait_for_connection = ::sumi::Connection::connect(address, &core.handle())
-            .expect("Failed to bind socket")
+        // Search the local network for a server to connect to. If no servers respond, fall back
+        // to connecting to a server running on the local machine.
+        let fallback_address = SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT));
+        let connect_handle = core.handle();
+        let wait_for_connection = ::sumi::discover(
+            DEFAULT_PORT,
+            Duration::from_millis(500),
+            &core.handle(),
+        )
+            .expect("Failed to bind discovery socket")
+            .filter(|server| {
+                // Skip servers we can't connect to because they use a different version of
+                // the protocol.
+                if !server.is_compatible() {
+                    warn!(
+                        "Skipping server at {} using protocol version {}",
+                        server.address,
+                        server.protocol_version,
+                    );
+                }
+                server.is_compatible()
+            })
+            .into_future()
+            .map_err(|(error, _)| error)
+            .and_then(move |(server, _)| {
+                let address = match server {
+                    Some(server) => {
+                        info!("Found server {:?} at {}", server.info, server.address);
+                        server.address
+                    }
+
+                    None => {
+                        info!("No servers found, connecting to {}", fallback_address);
+                        fallback_address
+                    }
+                };
+
+                ::sumi::Connection::connect_with_compression(
+                    address,
+                    ::sumi::Compression::Lz4,
+                    &connect_handle,
+                )
+            })
+            .flatten()
             .map(move |connection| {
                 ::core::Connection::<ClientMessage, ServerMessage>::new(connection, &handle)
             })
-            .and_then(move |connection| {
-                sender.send(connection).expect("Failed to send connection");
-                Ok(())
-            })
-            .map_err(|error| {
-                panic!(
-                    "Error establishing connection: {:?} {:?}",
-                    error.kind(),
-                    error
-                );
+            .then(move |result| {
+                // Forward the result to the main thread, which decides how to handle any errors.
+                sender.send(result).map_err(|_| ())
             });
         core.handle().spawn(wait_for_connection);
 
@@ -169,12 +239,47 @@ fn main() -> amethyst::Result<()> {
     trace!("Waiting on connection to arrive from I/O tread...");
     let connection = connection_receiver
         .wait()
-        .expect("Error occurred while establishing connection with server");
+        .expect("I/O thread dropped the connection sender");
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(error) => {
+            match error {
+                ::sumi::Error::HandshakeTimeout => {
+                    error!("Server did not respond, is it running?");
+                }
+
+                ::sumi::Error::VersionMismatch { local, remote } => {
+                    error!(
+                        "Server uses protocol version {} but this client uses version {}, \
+                         please update to a matching version",
+                        remote,
+                        local,
+                    );
+                }
+
+                ::sumi::Error::CookieRejected => {
+                    error!("Server rejected the connection handshake, please try again");
+                }
+
+                error => {
+                    error!("Error establishing connection with server: {}", error);
+                }
+            }
+
+            // NOTE: We don't join the IO thread here since the reactor has no more work to do
+            // and would block forever waiting for an event. It'll be torn down when the process
+            // exits.
+            SHUTDOWN_IO_THREAD.store(true, ::std::sync::atomic::Ordering::SeqCst);
+            return Ok(());
+        }
+    };
     trace!("Established connection");
 
     trace!("Building the application");
     let mut application = Application::build("../assets", InitState)?
         .with_resource(connection)
+        .with_resource(interpolation_config)
+        .with_resource(weapons)
         .with_frame_limit(
             FrameRateLimitStrategy::SleepAndYield(Duration::from_millis(2)),
             144,
@@ -201,10 +306,8 @@ type GltfCache = AssetCache<GltfSceneAsset>;
 /// Map used to lookup the root entity for a player given the player's ID.
 type PlayerLookup = ::std::collections::HashMap<u64, Entity>;
 
-#[derive(
-    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
-)]
-pub struct FrameId(pub usize);
+/// Map used to lookup the entity for a projectile given the projectile's ID.
+type ProjectileLookup = ::std::collections::HashMap<u64, Entity>;
 
 /// Builds the entity hierarchy for a player.
 ///
@@ -0,0 +1,231 @@
+use core::collision::CollisionWorld;
+use core::player::Player;
+use core::revolver::RevolverAction;
+use core::tick::{Tick, TICK_SECONDS};
+use core::weapon::WeaponDefinition;
+use core::{InputFrame, TickInput, ViewTime};
+use std::collections::VecDeque;
+
+/// The maximum number of ticks of history to keep for re-simulation.
+///
+/// At 60 ticks per second this is a little over four seconds of history. If the server takes
+/// longer than that to acknowledge a tick, the oldest ticks are discarded and can no longer be
+/// replayed.
+pub const MAX_PREDICTION_TICKS: usize = 256;
+
+/// A single tick of local input, along with the predicted state of the local player after the
+/// input was applied.
+#[derive(Debug, Clone)]
+pub struct PredictedTick {
+    pub tick: Tick,
+    pub input: InputFrame,
+
+    /// The revolver actions the player performed during the tick, in the order they happened.
+    pub actions: Vec<RevolverAction>,
+
+    /// What the player was seeing when they performed the actions.
+    pub view: ViewTime,
+
+    /// The predicted state of the local player at the end of the tick.
+    pub player: Option<Player>,
+}
+
+/// Ring buffer of the local player's input history and predicted states.
+///
+/// The client predicts the local player's state by applying its input immediately, rather than
+/// waiting for the server to send back the result. When a world update arrives from the server,
+/// the server's state for the local player is only current as of the last frame of input the
+/// server had received (the update's `client_tick`). To reconcile the prediction with the
+/// server's state, we rewind to the server's state and replay all of the input the server
+/// hasn't seen yet.
+#[derive(Debug, Default)]
+pub struct PredictionHistory {
+    ticks: VecDeque<PredictedTick>,
+}
+
+impl PredictionHistory {
+    /// Records the input for `tick`.
+    ///
+    /// The predicted state for the tick is filled in with `predict` once the tick has been
+    /// simulated.
+    pub fn push_input(
+        &mut self,
+        tick: Tick,
+        input: InputFrame,
+        actions: Vec<RevolverAction>,
+        view: ViewTime,
+    ) {
+        if self.ticks.len() >= MAX_PREDICTION_TICKS {
+            self.ticks.pop_front();
+        }
+
+        self.ticks.push_back(PredictedTick {
+            tick,
+            input,
+            actions,
+            view,
+            player: None,
+        });
+    }
+
+    /// Returns the input for up to `count` of the most recent ticks, oldest first.
+    ///
+    /// Every tick in the history is one the server hasn't acknowledged yet, since acknowledged
+    /// ticks are discarded by `reconcile`.
+    pub fn unacknowledged_inputs(&self, count: usize) -> Vec<TickInput> {
+        let skip = self.ticks.len().saturating_sub(count);
+        self.ticks
+            .iter()
+            .skip(skip)
+            .map(|predicted| TickInput {
+                tick: predicted.tick,
+                input: predicted.input.clone(),
+                actions: predicted.actions.clone(),
+                view: predicted.view,
+            })
+            .collect()
+    }
+
+    /// Simulates any recorded ticks of input that haven't been simulated yet on `player`,
+    /// recording the results.
+    pub fn predict(
+        &mut self,
+        player: &mut Player,
+        level: &CollisionWorld,
+        weapon: &WeaponDefinition,
+    ) {
+        // Only simulate each tick once.
+        for predicted in self.ticks.iter_mut().filter(|predicted| predicted.player.is_none()) {
+            simulate(player, predicted, level, weapon);
+            predicted.player = Some(player.clone());
+        }
+    }
+
+    /// Reconciles the predicted state with the state sent by the server.
+    ///
+    /// `server_player` is the server's state for the local player as of `acknowledged`, the
+    /// most recent tick of input the server has processed. Any history up to and including that
+    /// tick is discarded, and the remaining input is replayed on top of the server state. The
+    /// result is the new predicted state for the local player.
+    pub fn reconcile(
+        &mut self,
+        acknowledged: Tick,
+        server_player: Player,
+        level: &CollisionWorld,
+        weapon: &WeaponDefinition,
+    ) -> Player {
+        while self.ticks.front().map(|predicted| predicted.tick <= acknowledged).unwrap_or(false) {
+            self.ticks.pop_front();
+        }
+
+        let mut player = server_player;
+        for predicted in &mut self.ticks {
+            // Ticks that haven't been simulated yet will be simulated normally.
+            if predicted.player.is_none() {
+                break;
+            }
+
+            simulate(&mut player, predicted, level, weapon);
+            predicted.player = Some(player.clone());
+        }
+
+        player
+    }
+}
+
+/// Applies a single tick of input to `player`, following the same steps as the server.
+fn simulate(
+    player: &mut Player,
+    predicted: &PredictedTick,
+    level: &CollisionWorld,
+    weapon: &WeaponDefinition,
+) {
+    for &action in &predicted.actions {
+        player.handle_revolver_action(action, weapon);
+    }
+
+    player.step(&predicted.input, level, TICK_SECONDS);
+
+    // Recoil is deterministic, so we can kick the aim the same way the server does.
+    if player.gun.step(TICK_SECONDS, weapon) && player.is_alive() {
+        player.apply_recoil(weapon);
+    }
+
+    // Round to the precision the server sends, the same as the server does each frame.
+    player.quantize();
+}
+
+#[cfg(test)]
+mod test {
+    use core::math::*;
+    use core::weapon::test_weapons;
+    use super::*;
+
+    fn forward() -> InputFrame {
+        InputFrame {
+            movement_dir: Vector2::new(0.0, 1.0),
+            yaw_delta: 0.0,
+            pitch_delta: 0.0,
+            sprint: false,
+            jump: false,
+            crouch: false,
+        }
+    }
+
+    #[test]
+    fn reconcile_replays_unacknowledged_input() {
+        let weapons = test_weapons();
+        let weapon = weapons.default_weapon();
+        let level = CollisionWorld::flat_ground();
+
+        let mut history = PredictionHistory::default();
+        let mut predicted = Player::new(0, Point3::origin());
+        for tick in 1 .. 6 {
+            history.push_input(Tick(tick), forward(), Vec::new(), ViewTime::default());
+            history.predict(&mut predicted, &level, weapon);
+        }
+
+        // The server's state for tick 2 has the player somewhere other than where we predicted,
+        // so the three ticks it hasn't seen yet need to be replayed on top of it.
+        let mut server_player = history.ticks[1].player.clone().unwrap();
+        server_player.position.x += 1.0;
+        let reconciled = history.reconcile(Tick(2), server_player.clone(), &level, weapon);
+
+        let mut expected = server_player;
+        let mut replay = PredictionHistory::default();
+        for tick in 3 .. 6 {
+            replay.push_input(Tick(tick), forward(), Vec::new(), ViewTime::default());
+        }
+        replay.predict(&mut expected, &level, weapon);
+
+        assert_eq!(expected, reconciled);
+        assert_ne!(predicted, reconciled);
+
+        // Only the unacknowledged ticks are kept, and their predictions are updated.
+        let ticks = history.unacknowledged_inputs(MAX_PREDICTION_TICKS)
+            .into_iter()
+            .map(|input| input.tick)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![Tick(3), Tick(4), Tick(5)], ticks);
+        assert_eq!(Some(&reconciled), history.ticks.back().unwrap().player.as_ref());
+    }
+
+    #[test]
+    fn reconcile_matching_server_state() {
+        let weapons = test_weapons();
+        let weapon = weapons.default_weapon();
+        let level = CollisionWorld::flat_ground();
+
+        let mut history = PredictionHistory::default();
+        let mut predicted = Player::new(0, Point3::origin());
+        for tick in 1 .. 6 {
+            history.push_input(Tick(tick), forward(), Vec::new(), ViewTime::default());
+            history.predict(&mut predicted, &level, weapon);
+        }
+
+        // If the server agrees with our prediction, replaying lands us back where we were.
+        let server_player = history.ticks[1].player.clone().unwrap();
+        let reconciled = history.reconcile(Tick(2), server_player, &level, weapon);
+        assert_eq!(predicted, reconciled);
+    }
+}
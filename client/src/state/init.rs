@@ -1,9 +1,17 @@
-use amethyst::{assets::PrefabLoader, core::Transform, ecs::prelude::*, prelude::*, renderer::*};
+use amethyst::{
+    assets::PrefabLoader,
+    core::{GlobalTransform, Transform},
+    ecs::prelude::*,
+    prelude::*,
+    renderer::*,
+};
 use amethyst_gltf::{GltfPrefab, GltfSceneFormat, GltfSceneOptions};
 use components::*;
+use core::level::{Level, LevelError};
 use core::math::*;
+use core::weapon::{Weapons, DEFAULT_WEAPON};
 use core::ServerMessageBody;
-use {state::MainState, GltfCache, PlayerLookup, ReadConnection};
+use {state::MainState, GltfCache, PlayerLookup, ProjectileLookup, ReadConnection};
 
 /// Game state that waits for the init message from the server.
 ///
@@ -20,6 +28,7 @@ impl SimpleState for InitState {
 
         let world = data.world;
         world.add_resource(PlayerLookup::default());
+        world.add_resource(ProjectileLookup::default());
         world.register::<PlayerEntities>();
         world.register::<PlayerPitch>();
 
@@ -106,15 +115,30 @@ impl SimpleState for InitState {
             updater: Read<'a, LazyUpdate>,
             gltf_cache: Read<'a, GltfCache>,
             player_lookup: Write<'a, PlayerLookup>,
+            weapons: WriteExpect<'a, Weapons>,
         }
 
         // Listen for the `Init` message. Once we receive it, we can initialize the local state
         // and then switch to the main game state.
+        let mut map = None;
         let trans = data.world.exec(|mut data: Data| {
             for message in data.connection.try_iter() {
                 match message.body {
-                    ServerMessageBody::Init { id, world } => {
-                        trace!("Received init message, id {:#x}", id);
+                    ServerMessageBody::Init { id, world, map: map_name, weapon } => {
+                        trace!("Received init message, id {:#x}, map {}", id, map_name);
+
+                        // The server simulates the weapon using its own definition, so predict
+                        // using the same one. Running a server with a weapon we can't simulate
+                        // isn't possible, so disconnect if its definition is invalid.
+                        if weapon != *data.weapons.default_weapon() {
+                            warn!("Server's definition of {} differs, using it", DEFAULT_WEAPON);
+                            if let Err(error) = data.weapons.insert(DEFAULT_WEAPON, weapon) {
+                                error!("Server's weapon is invalid, disconnecting: {}", error);
+                                return Trans::Quit;
+                            }
+                        }
+
+                        map = Some(map_name);
 
                         let biped = data.gltf_cache.get("biped").expect("No biped model");
                         let revolver = data.gltf_cache.get("revolver").expect("No revolver model");
@@ -145,6 +169,47 @@ impl SimpleState for InitState {
             Trans::None
         });
 
+        // The map name comes from the server, so the map might not be one we have, or might not
+        // be a valid map name at all. In either case there's no way to play, so disconnect.
+        if let Some(map) = map {
+            if let Err(error) = load_level(data.world, &map) {
+                error!("Failed to load map {}, disconnecting: {}", map, error);
+                return Trans::Quit;
+            }
+        }
+
         trans
     }
 }
+
+/// Loads the level `name`, both its scene for rendering and its collision for predicting the
+/// local player.
+fn load_level(world: &mut World, name: &str) -> Result<(), LevelError> {
+    let level = Level::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../assets"), name)?;
+
+    let path = Level::asset_path(name)?;
+    trace!("Loading {}", path);
+    let handle = world.exec(|loader: PrefabLoader<GltfPrefab>| {
+        loader.load(
+            path,
+            GltfSceneFormat,
+            GltfSceneOptions {
+                generate_tex_coords: (0.1, 0.1),
+                load_animations: false,
+                flip_v_coord: true,
+                scene_index: None,
+            },
+            (),
+        )
+    });
+
+    world
+        .create_entity()
+        .with(handle)
+        .with(GlobalTransform::default())
+        .with(Transform::default())
+        .build();
+
+    world.add_resource(level);
+    Ok(())
+}
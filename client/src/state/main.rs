@@ -1,8 +1,17 @@
-use amethyst::{ecs::prelude::*, prelude::*};
+use amethyst::{
+    core::{GlobalTransform, Transform},
+    ecs::prelude::*,
+    prelude::*,
+};
 use components::*;
-use core::{player::Player, ServerMessageBody};
+use core::{
+    level::Level, player::Player, snapshot::SnapshotHistory, tick::TickClock,
+    weapon::Weapons, ClientMessage, ClientMessageBody, ServerMessageBody,
+};
+use interpolation::SnapshotBuffers;
+use prediction::PredictionHistory;
 use std::collections::HashSet;
-use {GltfCache, PlayerLookup, ReadConnection};
+use {GltfCache, PlayerLookup, ProjectileLookup, WriteConnection};
 
 #[derive(Debug)]
 pub struct MainState {
@@ -16,7 +25,8 @@ impl SimpleState for MainState {
 
         #[derive(SystemData)]
         struct Data<'a> {
-            connection: ReadConnection<'a>,
+            connection: WriteConnection<'a>,
+            clock: Write<'a, TickClock>,
             players: WriteStorage<'a, Player>,
             player_entities: ReadStorage<'a, PlayerEntities>,
             pitches: WriteStorage<'a, PlayerPitch>,
@@ -24,6 +34,12 @@ impl SimpleState for MainState {
             entities: Entities<'a>,
             gltf_cache: Read<'a, GltfCache>,
             player_lookup: Write<'a, PlayerLookup>,
+            projectile_lookup: Write<'a, ProjectileLookup>,
+            history: Write<'a, PredictionHistory>,
+            snapshots: Write<'a, SnapshotBuffers>,
+            world_history: Write<'a, SnapshotHistory>,
+            level: Read<'a, Level>,
+            weapons: ReadExpect<'a, Weapons>,
         }
 
         // Process incoming server messages for the frame, updating the local world state with
@@ -37,8 +53,48 @@ impl SimpleState for MainState {
             // components from incoming server messages.
             let mut new_players = HashSet::new();
 
-            for message in data.connection.try_iter() {
-                match message.body {
+            let messages = data.connection.try_iter().collect::<Vec<_>>();
+            for message in messages {
+                let acknowledged = message.client_tick;
+
+                // Speed up or slow down slightly so that our input arrives at the server just
+                // before it's needed.
+                data.clock.set_time_scale(message.input_buffer.time_scale());
+
+                // Reconstruct the full world state from delta snapshots, and let the server know
+                // which snapshots we've received so that it can use them as future baselines.
+                let body = match message.body {
+                    ServerMessageBody::WorldUpdate(world) => {
+                        data.world_history.push(message.server_tick, world.clone());
+                        ServerMessageBody::WorldUpdate(world)
+                    }
+
+                    ServerMessageBody::WorldDelta(delta) => {
+                        match data.world_history.decode(message.server_tick, &delta) {
+                            Some(world) => ServerMessageBody::WorldUpdate(world),
+                            None => {
+                                debug!(
+                                    "Discarding snapshot for tick {:?} with unknown baseline {:?}",
+                                    message.server_tick,
+                                    delta.baseline,
+                                );
+                                continue;
+                            }
+                        }
+                    }
+
+                    body => body,
+                };
+
+                if let ServerMessageBody::WorldUpdate(_) = body {
+                    data.snapshots.observe_tick(message.server_tick);
+                    data.connection.send(ClientMessage {
+                        tick: data.clock.current(),
+                        body: ClientMessageBody::AcknowledgeSnapshot(message.server_tick),
+                    });
+                }
+
+                match body {
                     ServerMessageBody::WorldUpdate(server_world) => {
                         // Replace the local state for each player with the latest state sent by
                         // the server.
@@ -59,12 +115,27 @@ impl SimpleState for MainState {
                                 }
                             };
 
-                            // Replace the local state for the player with the server state.
+                            if id != self.id {
+                                // Remote players are rendered slightly in the past, so buffer
+                                // the server state for `RemotePlayerInterpolationSystem` rather
+                                // than applying it immediately.
+                                data.snapshots.push(message.server_tick, server_player);
+                                continue;
+                            }
+
+                            // The server's state for the local player doesn't include any input
+                            // it hasn't received yet, so rewind to the server's state and replay
+                            // the unacknowledged input on top of it.
                             let player = data
                                 .players
                                 .get_mut(root)
                                 .expect("No `Player` found on root player entity");
-                            *player = server_player;
+                            *player = data.history.reconcile(
+                                acknowledged,
+                                server_player,
+                                &data.level.collision,
+                                data.weapons.default_weapon(),
+                            );
 
                             // Find the `PlayerEntities` component for the player so that we can
                             // update the pitch of the player's head.
@@ -118,6 +189,58 @@ impl SimpleState for MainState {
                         }
 
                         data.player_lookup.remove(&id);
+                        data.snapshots.remove(id);
+                    }
+
+                    ServerMessageBody::ProjectileSpawned(projectile) => {
+                        debug!("Player {:#x} fired", projectile.shooter);
+
+                        // Draw a tracer for the projectile using the same model as the
+                        // cartridges. `ProjectileSystem` moves it along the projectile's path.
+                        let bullet = data.gltf_cache.get("bullet").expect("No bullet model");
+                        let entity = data
+                            .updater
+                            .create_entity(&data.entities)
+                            .with(bullet.clone())
+                            .with(GlobalTransform::default())
+                            .with(Transform::from(projectile.position.coords))
+                            .with(projectile)
+                            .build();
+                        data.projectile_lookup.insert(projectile.id, entity);
+                    }
+
+                    ServerMessageBody::ProjectileImpact { id, location } => {
+                        trace!("Projectile {} hit at {:?}", id, location);
+
+                        // The projectile may have already expired locally, in which case there's
+                        // nothing left to remove.
+                        if let Some(entity) = data.projectile_lookup.remove(&id) {
+                            data.entities
+                                .delete(entity)
+                                .expect("Failed to delete projectile entity");
+                        }
+                    }
+
+                    ServerMessageBody::PlayerHit { shooter, victim, hitbox, damage, .. } => {
+                        info!(
+                            "Player {:#x} hit player {:#x} in the {:?} for {} damage",
+                            shooter,
+                            victim,
+                            hitbox,
+                            damage,
+                        );
+                    }
+
+                    ServerMessageBody::PlayerDied { id, killer } => {
+                        info!("Player {:#x} was killed by player {:#x}", id, killer);
+                    }
+
+                    ServerMessageBody::PlayerRespawned { id, position } => {
+                        info!("Player {:#x} respawned at {:?}", id, position);
+                    }
+
+                    ServerMessageBody::WorldDelta(..) => {
+                        unreachable!("Delta snapshots are decoded into full world updates");
                     }
 
                     ServerMessageBody::Init { .. } => {
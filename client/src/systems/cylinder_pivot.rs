@@ -5,6 +5,7 @@ use components::*;
 use core::math::*;
 use core::player::*;
 use core::revolver::*;
+use core::weapon::Weapons;
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct CylinderPivotSystem;
@@ -15,12 +16,14 @@ pub struct Data<'a> {
     player_entities: ReadStorage<'a, PlayerEntities>,
     revolver_entities: ReadStorage<'a, RevolverEntities>,
     transforms: WriteStorage<'a, Transform>,
+    weapons: ReadExpect<'a, Weapons>,
 }
 
 impl<'a> System<'a> for CylinderPivotSystem {
     type SystemData = Data<'a>;
 
     fn run(&mut self, mut data: Self::SystemData) {
+        let open_seconds = data.weapons.default_weapon().timings.cylinder_open_seconds();
         for (player, player_entities) in (&data.players, &data.player_entities).join() {
             let revolver_entities = data
                 .revolver_entities
@@ -43,8 +46,7 @@ impl<'a> System<'a> for CylinderPivotSystem {
 
                 CylinderState::Opening { remaining, .. } => {
                     // Lerp the cylinder opening.
-                    let remaining_millis = remaining * 1000.0;
-                    let t = 1.0 - (remaining_millis as f32 / CYLINDER_OPEN_MILLIS as f32);
+                    let t = 1.0 - remaining / open_seconds;
                     transform.set_rotation(closed_orientation.nlerp(&open_orientation, t));
                 }
 
@@ -54,8 +56,7 @@ impl<'a> System<'a> for CylinderPivotSystem {
 
                 CylinderState::Closing { remaining, .. } => {
                     // Lerp the cylinder closing.
-                    let remaining_millis = remaining * 1000.0;
-                    let t = 1.0 - (remaining_millis as f32 / CYLINDER_OPEN_MILLIS as f32);
+                    let t = 1.0 - remaining / open_seconds;
                     transform.set_rotation(open_orientation.nlerp(&closed_orientation, t));
                 }
 
@@ -4,6 +4,7 @@ use components::*;
 use core::math::*;
 use core::player::*;
 use core::revolver::*;
+use core::weapon::Weapons;
 
 #[derive(Debug, Copy, Clone, Default)]
 pub struct EjectAnimationSystem;
@@ -14,12 +15,14 @@ pub struct Data<'a> {
     player_entities: ReadStorage<'a, PlayerEntities>,
     revolver_entities: ReadStorage<'a, RevolverEntities>,
     transforms: WriteStorage<'a, Transform>,
+    weapons: ReadExpect<'a, Weapons>,
 }
 
 impl<'a> System<'a> for EjectAnimationSystem {
     type SystemData = Data<'a>;
 
     fn run(&mut self, mut data: Self::SystemData) {
+        let timings = &data.weapons.default_weapon().timings;
         for (player, player_entities) in (&data.players, &data.player_entities).join() {
             let revolver = data
                 .revolver_entities
@@ -43,13 +46,14 @@ impl<'a> System<'a> for EjectAnimationSystem {
                     keyframe,
                     ..
                 } => {
-                    let remaining_millis = remaining * 1000.0;
-                    let duration = EJECT_KEYFRAME_MILLIS[keyframe];
-                    let t = 1.0 - (remaining_millis as f32 / duration as f32);
+                    let t = 1.0 - remaining / timings.eject_keyframe_seconds(keyframe);
 
-                    let from = eject_keyframes[keyframe];
-                    let to = eject_keyframes[keyframe + 1];
-                    let orientation = from.nlerp(&to, t);
+                    // Weapons can have a different number of keyframes than the animation, so
+                    // hold the gun still for any extra keyframes.
+                    let identity = UnitQuaternion::identity();
+                    let from = eject_keyframes.get(keyframe).unwrap_or(&identity);
+                    let to = eject_keyframes.get(keyframe + 1).unwrap_or(&identity);
+                    let orientation = from.nlerp(to, t);
 
                     body.set_rotation(orientation);
                 }
@@ -0,0 +1,30 @@
+use ::components::*;
+use amethyst::{core::Transform, ecs::prelude::*};
+use core::player::Player;
+
+/// Hides the gun for players that are dead, and shows it again once they respawn.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HideGunSystem;
+
+#[derive(SystemData)]
+pub struct Data<'a> {
+    players: ReadStorage<'a, Player>,
+    player_entities: ReadStorage<'a, PlayerEntities>,
+
+    transforms: WriteStorage<'a, Transform>,
+}
+
+impl<'a> System<'a> for HideGunSystem {
+    type SystemData = Data<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        for (player, entities) in (&data.players, &data.player_entities).join() {
+            // Scale the gun down to nothing rather than removing its mesh, since the gun is made
+            // up of several entities that would all need their meshes restored.
+            let scale = if player.is_alive() { 1.0 } else { 0.0 };
+            if let Some(transform) = data.transforms.get_mut(entities.gun.into()) {
+                transform.set_scale(scale, scale, scale);
+            }
+        }
+    }
+}
@@ -1,25 +1,33 @@
 mod cylinder_pivot;
 mod eject_animation;
-mod frame_id;
 mod hide_body;
+mod hide_gun;
 mod late_init;
 mod player_input;
 mod player_pitch;
 mod player_position;
+mod player_prediction;
 mod player_yaw;
+mod projectile;
+mod remote_player_interpolation;
 mod revolver_chamber;
 mod revolver_cylinder;
 mod revolver_hammer;
+mod tick;
 
 pub use self::cylinder_pivot::CylinderPivotSystem;
 pub use self::eject_animation::EjectAnimationSystem;
-pub use self::frame_id::FrameIdSystem;
 pub use self::hide_body::HideBodySystem;
+pub use self::hide_gun::HideGunSystem;
 pub use self::late_init::LateInitSystem;
 pub use self::player_input::PlayerInputSystem;
 pub use self::player_pitch::PlayerPitchSystem;
 pub use self::player_position::PlayerPositionSystem;
+pub use self::player_prediction::PlayerPredictionSystem;
 pub use self::player_yaw::PlayerYawSystem;
+pub use self::projectile::ProjectileSystem;
+pub use self::remote_player_interpolation::RemotePlayerInterpolationSystem;
 pub use self::revolver_chamber::RevolverChamberSystem;
 pub use self::revolver_cylinder::RevolverCylinderSystem;
 pub use self::revolver_hammer::RevolverHammerSystem;
+pub use self::tick::TickSystem;
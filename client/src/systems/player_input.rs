@@ -1,4 +1,6 @@
-use ::{FrameId, WriteConnection};
+use ::WriteConnection;
+use ::interpolation::InterpolationConfig;
+use ::prediction::PredictionHistory;
 use ::components::*;
 use amethyst::{
     input::InputEvent,
@@ -8,22 +10,39 @@ use amethyst::{
 };
 use core::*;
 use core::math::*;
+use core::player::Player;
 use core::revolver::*;
+use core::snapshot::SnapshotHistory;
+use core::tick::TickClock;
 
+/// Gathers the local player's input and sends it to the server once per simulation tick.
+///
+/// Input from frames that don't start a new tick is accumulated and applied on the next tick.
 #[derive(Debug, Default)]
 pub struct PlayerInputSystem {
     event_reader: Option<ReaderId<InputEvent<String>>>,
+
+    /// Mouse movement that hasn't been sent yet.
+    pending_yaw_delta: f32,
+    pending_pitch_delta: f32,
+
+    /// Revolver actions that haven't been sent yet, in the order they happened.
+    pending_actions: Vec<RevolverAction>,
 }
 
 #[derive(SystemData)]
 pub struct Data<'s> {
     input_frame: WriteStorage<'s, InputFrame>,
     local_player: ReadStorage<'s, LocalPlayer>,
+    players: ReadStorage<'s, Player>,
 
     input: Read<'s, InputHandler<String, String>>,
     events: Read<'s, EventChannel<InputEvent<String>>>,
     connection: WriteConnection<'s>,
-    frame_id: Read<'s, FrameId>,
+    clock: Read<'s, TickClock>,
+    history: Write<'s, PredictionHistory>,
+    world_history: Read<'s, SnapshotHistory>,
+    interpolation: Read<'s, InterpolationConfig>,
 }
 
 impl<'s> System<'s> for PlayerInputSystem {
@@ -39,91 +58,121 @@ impl<'s> System<'s> for PlayerInputSystem {
             .axis_value("left_right")
             .expect("left_right axis not found");
 
-        let mut input = InputFrame {
-            // TODO: Is it a good idea to downcast from `f64` here? Would it make more sense to
-            // keep the input as `f32`?
-            movement_dir: Vector2::new(left_right as f32, forward_backward as f32),
-            yaw_delta: 0.0,
-            pitch_delta: 0.0,
-        };
+        // TODO: Is it a good idea to downcast from `f64` here? Would it make more sense to keep
+        // the input as `f32`?
+        let movement_dir = Vector2::new(left_right as f32, forward_backward as f32);
+
+        // Sprinting, jumping and crouching last for as long as the buttons are held, so sample
+        // them rather than waiting for press events.
+        let sprint = data.input.action_is_down("sprint").unwrap_or(false);
+        let jump = data.input.action_is_down("jump").unwrap_or(false);
+        let crouch = data.input.action_is_down("crouch").unwrap_or(false);
 
+        let actions = &mut self.pending_actions;
         let event_reader = self.event_reader.as_mut().expect("System was not setup");
         for event in data.events.read(event_reader) {
             match event {
                 InputEvent::MouseMoved { delta_x, delta_y } => {
                     trace!("Mouse moved: {:?}, {:?}", delta_x, delta_y);
-                    input.yaw_delta -= *delta_x as f32 * TAU * 0.001;
-                    input.pitch_delta += *delta_y as f32 * TAU * 0.001;
+                    self.pending_yaw_delta -= *delta_x as f32 * TAU * 0.001;
+                    self.pending_pitch_delta += *delta_y as f32 * TAU * 0.001;
                 }
 
                 InputEvent::ActionPressed(action) => match action.as_ref() {
                     "toggle-cylinder" => {
                         trace!("Toggling cylinder");
-                        data.connection.send(ClientMessage {
-                            frame: data.frame_id.0,
-                            body: ClientMessageBody::RevolverAction(
-                                RevolverAction::ToggleCylinder,
-                            ),
-                        });
+                        actions.push(RevolverAction::ToggleCylinder);
                     }
 
                     "eject-cartridges" => {
                         trace!("Ejecting cartridges");
-                        data.connection.send(ClientMessage {
-                            frame: data.frame_id.0,
-                            body: ClientMessageBody::RevolverAction(
-                                RevolverAction::EjectCartridges,
-                            ),
-                        });
+                        actions.push(RevolverAction::EjectCartridges);
                     }
 
                     "load-cartridge" => {
                         trace!("Loading cartridge");
-                        data.connection.send(ClientMessage {
-                            frame: data.frame_id.0,
-                            body: ClientMessageBody::RevolverAction(
-                                RevolverAction::LoadCartridge,
-                            ),
-                        });
+                        actions.push(RevolverAction::LoadCartridge);
                     }
 
                     "pull-trigger" => {
                         trace!("Pulling trigger");
-                        data.connection.send(ClientMessage {
-                            frame: data.frame_id.0,
-                            body: ClientMessageBody::RevolverAction(
-                                RevolverAction::PullTrigger,
-                            ),
-                        });
+                        actions.push(RevolverAction::PullTrigger);
                     }
 
                     "pull-hammer" => {
                         trace!("Pulling hammer");
-                        data.connection.send(ClientMessage {
-                            frame: data.frame_id.0,
-                            body: ClientMessageBody::RevolverAction(
-                                RevolverAction::PullHammer,
-                            ),
-                        });
+                        actions.push(RevolverAction::PullHammer);
                     }
 
+                    // Handled above.
+                    "sprint" | "jump" | "crouch" => {}
+
                     _ => warn!("Unexpected action: {}", action),
                 }
 
+                // Fully automatic weapons keep firing until the trigger is released.
+                InputEvent::ActionReleased(action) => {
+                    if action == "pull-trigger" {
+                        trace!("Releasing trigger");
+                        actions.push(RevolverAction::ReleaseTrigger);
+                    }
+                }
+
                 _ => trace!("Unused input event: {:?}", event),
             }
         }
 
-        // Update the `InputFrame` component for the local player.
-        for (input_frame, _) in (&mut data.input_frame, &data.local_player).join() {
-            *input_frame = input.clone();
+        // Dead players can't move, look around, or use their gun, so throw away their input.
+        let is_alive = (&data.players, &data.local_player)
+            .join()
+            .all(|(player, _)| player.is_alive());
+        let movement_dir = if is_alive { movement_dir } else { Vector2::zeros() };
+        let sprint = sprint && is_alive;
+        let jump = jump && is_alive;
+        let crouch = crouch && is_alive;
+        if !is_alive {
+            self.pending_yaw_delta = 0.0;
+            self.pending_pitch_delta = 0.0;
+            self.pending_actions.clear();
         }
 
-        // Send the input for this frame to the server.
-        data.connection.send(ClientMessage {
-            frame: data.frame_id.0,
-            body: ClientMessageBody::Input(input),
-        });
+        for tick in data.clock.pending() {
+            // All of the pending mouse movement and actions are applied on the first tick of the
+            // frame, and any remaining ticks only get the movement input.
+            let input = InputFrame {
+                movement_dir,
+                yaw_delta: ::std::mem::replace(&mut self.pending_yaw_delta, 0.0),
+                pitch_delta: ::std::mem::replace(&mut self.pending_pitch_delta, 0.0),
+                sprint,
+                jump,
+                crouch,
+            };
+            let actions = ::std::mem::replace(&mut self.pending_actions, Vec::new());
+
+            // Update the `InputFrame` component for the local player.
+            for (input_frame, _) in (&mut data.input_frame, &data.local_player).join() {
+                *input_frame = input.clone();
+            }
+
+            // Remember what the player was seeing when they performed the revolver actions, so
+            // that the server can check shots against it.
+            let view = ViewTime {
+                server_tick: data.world_history.latest_tick().unwrap_or_default(),
+                interpolation_delay_millis: data.interpolation.delay_millis as u32,
+            };
+
+            // Record the input so that it can be replayed when reconciling with the server.
+            data.history.push_input(tick, input, actions, view);
+
+            // Send the input and actions for this tick, along with the input for recent ticks the
+            // server hasn't acknowledged in case the messages carrying them were lost.
+            data.connection.send(ClientMessage {
+                tick,
+                body: ClientMessageBody::Input(
+                    data.history.unacknowledged_inputs(MAX_REDUNDANT_INPUTS),
+                ),
+            });
+        }
     }
 
     fn setup(&mut self, resources: &mut Resources) {
@@ -1,4 +1,6 @@
+use ::components::*;
 use amethyst::{core::Transform, ecs::prelude::*};
+use core::math::*;
 use core::player::Player;
 
 #[derive(Debug, Default)]
@@ -7,6 +9,7 @@ pub struct PlayerPositionSystem;
 #[derive(SystemData)]
 pub struct Data<'a> {
     player: ReadStorage<'a, Player>,
+    player_entities: ReadStorage<'a, PlayerEntities>,
     transform: WriteStorage<'a, Transform>,
 }
 
@@ -17,5 +20,12 @@ impl<'a> System<'a> for PlayerPositionSystem {
         for (player, transform) in (&data.player, &mut data.transform).join() {
             transform.set_position(player.position.coords);
         }
+
+        // Lower the player's head while they're crouching.
+        for (player, entities) in (&data.player, &data.player_entities).join() {
+            if let Some(transform) = data.transform.get_mut(entities.head.into()) {
+                transform.set_position(Vector3::new(0.0, player.eye_height(), 0.0));
+            }
+        }
     }
 }
@@ -0,0 +1,49 @@
+use ::components::*;
+use ::prediction::PredictionHistory;
+use amethyst::ecs::prelude::*;
+use core::level::Level;
+use core::player::Player;
+use core::weapon::Weapons;
+
+/// Predicts the local player's state by applying the input for each tick immediately.
+///
+/// Must run after `PlayerInputSystem` has recorded the input for the frame's ticks.
+#[derive(Debug, Default)]
+pub struct PlayerPredictionSystem;
+
+#[derive(SystemData)]
+pub struct Data<'a> {
+    players: WriteStorage<'a, Player>,
+    local_player: ReadStorage<'a, LocalPlayer>,
+    player_entities: ReadStorage<'a, PlayerEntities>,
+    pitches: WriteStorage<'a, PlayerPitch>,
+    history: Write<'a, PredictionHistory>,
+
+    /// The level is empty until the server tells us which one to load.
+    level: Read<'a, Level>,
+
+    weapons: ReadExpect<'a, Weapons>,
+}
+
+impl<'a> System<'a> for PlayerPredictionSystem {
+    type SystemData = Data<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        for (player, entities, _) in (
+            &mut data.players,
+            &data.player_entities,
+            &data.local_player,
+        ).join() {
+            data.history.predict(
+                player,
+                &data.level.collision,
+                data.weapons.default_weapon(),
+            );
+
+            // Update the pitch of the player's head to match the predicted state.
+            if let Some(pitch) = data.pitches.get_mut(entities.head.into()) {
+                pitch.pitch = player.pitch;
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use ::ProjectileLookup;
+use amethyst::{core::Transform, ecs::prelude::*};
+use core::{projectile::Projectile, tick::*};
+
+/// Moves the tracers for projectiles in flight, and removes them once they expire.
+///
+/// The server decides what projectiles hit, so projectiles that hit something are removed when
+/// the `ProjectileImpact` message arrives rather than here.
+#[derive(Debug, Default)]
+pub struct ProjectileSystem;
+
+#[derive(SystemData)]
+pub struct Data<'a> {
+    entities: Entities<'a>,
+    projectiles: WriteStorage<'a, Projectile>,
+    transforms: WriteStorage<'a, Transform>,
+
+    clock: Read<'a, TickClock>,
+    projectile_lookup: Write<'a, ProjectileLookup>,
+}
+
+impl<'a> System<'a> for ProjectileSystem {
+    type SystemData = Data<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        let ticks = data.clock.pending().count();
+        for (entity, projectile, transform) in
+            (&*data.entities, &mut data.projectiles, &mut data.transforms).join()
+        {
+            for _ in 0..ticks {
+                projectile.step(TICK_SECONDS);
+            }
+
+            if projectile.is_expired() {
+                data.projectile_lookup.remove(&projectile.id);
+                data.entities
+                    .delete(entity)
+                    .expect("Failed to delete projectile entity");
+                continue;
+            }
+
+            // Point the tracer in the direction the projectile is travelling.
+            let velocity = projectile.velocity;
+            let yaw = (-velocity.x).atan2(-velocity.z);
+            let pitch = velocity.y.atan2(velocity.x.hypot(velocity.z));
+            transform.set_position(projectile.position.coords);
+            transform.set_rotation_euler(pitch, yaw, 0.0);
+        }
+    }
+}
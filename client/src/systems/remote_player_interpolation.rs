@@ -0,0 +1,53 @@
+use ::components::*;
+use ::interpolation::{InterpolationConfig, SnapshotBuffers};
+use amethyst::{core::timing::Time, ecs::prelude::*};
+use core::player::Player;
+
+/// Updates remote players to their interpolated state for the current frame.
+///
+/// Remote players are rendered `InterpolationConfig::delay_millis` in the past, blending between
+/// the buffered server snapshots on either side of the render time. The render time is measured
+/// in server ticks, following the server's clock as estimated from the incoming snapshots.
+#[derive(Debug, Default)]
+pub struct RemotePlayerInterpolationSystem;
+
+#[derive(SystemData)]
+pub struct Data<'a> {
+    players: WriteStorage<'a, Player>,
+    local_player: ReadStorage<'a, LocalPlayer>,
+    player_entities: ReadStorage<'a, PlayerEntities>,
+    pitches: WriteStorage<'a, PlayerPitch>,
+    snapshots: Write<'a, SnapshotBuffers>,
+    config: Read<'a, InterpolationConfig>,
+    time: Read<'a, Time>,
+}
+
+impl<'a> System<'a> for RemotePlayerInterpolationSystem {
+    type SystemData = Data<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        data.snapshots.advance(data.time.delta_seconds());
+        let render_time = match data.snapshots.render_time(data.config.delay_ticks()) {
+            Some(render_time) => render_time,
+            None => return,
+        };
+        let max_extrapolation = data.config.max_extrapolation_ticks();
+
+        for (player, entities, _) in (
+            &mut data.players,
+            &data.player_entities,
+            !&data.local_player,
+        ).join() {
+            let sampled = match data.snapshots.sample(player.id, render_time, max_extrapolation) {
+                Some(sampled) => sampled,
+                None => continue,
+            };
+            *player = sampled;
+
+            // Update the pitch of the player's head to match the interpolated state.
+            if let Some(pitch) = data.pitches.get_mut(entities.head.into()) {
+                pitch.pitch = player.pitch;
+            }
+        }
+    }
+}
@@ -27,9 +27,10 @@ impl<'a> System<'a> for RevolverChamberSystem {
             let revolver = data.revolver_entities.get_mut(player_entities.gun.into())
                 .expect("No `RevolverEntities` component found on gun entity");
 
-            // Update the render state of the cartridges in the cylinder.
-            for chamber_index in 0 .. 6 {
-                let expected = player.gun.cartridges[chamber_index];
+            // Update the render state of the cartridges in the cylinder. The model only has six
+            // chambers, so for weapons with a larger capacity only the first six are shown.
+            for chamber_index in 0 .. revolver.chambers.len() {
+                let expected = player.gun.chamber(chamber_index);
                 let actual = revolver.cartridges[chamber_index];
                 match (expected, actual) {
                     // If there's not already a cartridge instance in the scene
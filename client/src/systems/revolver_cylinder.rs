@@ -4,6 +4,7 @@ use components::*;
 use core::math::*;
 use core::player::*;
 use core::revolver::*;
+use core::weapon::Weapons;
 
 #[derive(Debug, Copy, Clone, Default)]
 pub struct RevolverCylinderSystem;
@@ -14,12 +15,14 @@ pub struct Data<'a> {
     player_entities: ReadStorage<'a, PlayerEntities>,
     revolver_entities: ReadStorage<'a, RevolverEntities>,
     transforms: WriteStorage<'a, Transform>,
+    weapons: ReadExpect<'a, Weapons>,
 }
 
 impl<'a> System<'a> for RevolverCylinderSystem {
     type SystemData = Data<'a>;
 
     fn run(&mut self, mut data: Self::SystemData) {
+        let timings = &data.weapons.default_weapon().timings;
         for (player, player_entities) in (&data.players, &data.player_entities).join() {
             let revolver = data
                 .revolver_entities
@@ -37,18 +40,29 @@ impl<'a> System<'a> for RevolverCylinderSystem {
                 CylinderState::Closed { position } => {
                     let cylinder_orientation =
                         UnitQuaternion::from_euler_angles(0.0, 0.0, TAU / 6.0 * position as f32);
-                    match player.gun.hammer_state {
+                    // If the hammer is cocking, either by hand or from a double-action trigger
+                    // pull, find how far the cylinder has rotated to the current position.
+                    let cocking = match player.gun.hammer_state {
+                        HammerState::Cocking { remaining } => Some(remaining),
+                        HammerState::Firing { remaining }
+                            if remaining > timings.hammer_fall_seconds() =>
+                        {
+                            Some(remaining - timings.hammer_fall_seconds())
+                        }
+                        _ => None,
+                    };
+
+                    match cocking {
                         // If the hammer is cocking, we animate the rotation of the cylinder as it
                         // rotates to the current position.
-                        HammerState::Cocking { remaining } => {
+                        Some(remaining) => {
                             let prev_orientation = UnitQuaternion::from_euler_angles(
                                 0.0,
                                 0.0,
                                 TAU / 6.0 * (position as f32 - 1.0),
                             );
 
-                            let remaining_millis = remaining * 1000.0;
-                            let t = 1.0 - (remaining_millis as f32 / HAMMER_COCK_MILLIS as f32);
+                            let t = 1.0 - remaining / timings.hammer_cock_seconds();
 
                             let orientation = prev_orientation.nlerp(&cylinder_orientation, t);
                             cylinder.set_rotation(orientation);
@@ -56,7 +70,7 @@ impl<'a> System<'a> for RevolverCylinderSystem {
 
                         // For all other hammer state, the cylinder is static at its current
                         // position.
-                        _ => {
+                        None => {
                             cylinder.set_rotation(cylinder_orientation);
                         }
                     }
@@ -4,6 +4,7 @@ use components::*;
 use core::math::*;
 use core::player::*;
 use core::revolver::*;
+use core::weapon::Weapons;
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct RevolverHammerSystem;
@@ -14,12 +15,14 @@ pub struct Data<'a> {
     player_entities: ReadStorage<'a, PlayerEntities>,
     revolver_entities: ReadStorage<'a, RevolverEntities>,
     transforms: WriteStorage<'a, Transform>,
+    weapons: ReadExpect<'a, Weapons>,
 }
 
 impl<'a> System<'a> for RevolverHammerSystem {
     type SystemData = Data<'a>;
 
     fn run(&mut self, mut data: Self::SystemData) {
+        let timings = &data.weapons.default_weapon().timings;
         for (player, player_entities) in (&data.players, &data.player_entities).join() {
             let revolver = data
                 .revolver_entities
@@ -41,8 +44,7 @@ impl<'a> System<'a> for RevolverHammerSystem {
                 }
 
                 HammerState::Cocking { remaining } => {
-                    let remaining_millis = remaining * 1000.0;
-                    let t = 1.0 - (remaining_millis as f32 / HAMMER_COCK_MILLIS as f32);
+                    let t = 1.0 - remaining / timings.hammer_cock_seconds();
                     hammer.set_rotation(uncocked_orientation.nlerp(&cocked_orientation, t));
                 }
 
@@ -51,9 +53,15 @@ impl<'a> System<'a> for RevolverHammerSystem {
                 }
 
                 HammerState::Firing { remaining } => {
-                    let remaining_millis = remaining * 1000.0;
-                    let t = 1.0 - (remaining_millis as f32 / HAMMER_COCK_MILLIS as f32);
-                    hammer.set_rotation(cocked_orientation.nlerp(&uncocked_orientation, t));
+                    let fall = timings.hammer_fall_seconds();
+                    if remaining > fall {
+                        // A double-action trigger pull cocks the hammer before it falls.
+                        let t = 1.0 - (remaining - fall) / timings.hammer_cock_seconds();
+                        hammer.set_rotation(uncocked_orientation.nlerp(&cocked_orientation, t));
+                    } else {
+                        let t = 1.0 - remaining / fall;
+                        hammer.set_rotation(cocked_orientation.nlerp(&uncocked_orientation, t));
+                    }
                 }
             }
         }
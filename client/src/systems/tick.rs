@@ -0,0 +1,22 @@
+use amethyst::{core::timing::Time, ecs::prelude::*};
+use core::tick::TickClock;
+
+/// Advances the simulation clock, determining which ticks will be simulated this frame.
+///
+/// Must run before any systems that simulate ticks.
+#[derive(Debug, Default)]
+pub struct TickSystem;
+
+#[derive(SystemData)]
+pub struct Data<'a> {
+    clock: Write<'a, TickClock>,
+    time: Read<'a, Time>,
+}
+
+impl<'a> System<'a> for TickSystem {
+    type SystemData = Data<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        data.clock.advance(data.time.delta_seconds());
+    }
+}
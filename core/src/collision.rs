@@ -0,0 +1,632 @@
+//! Static level geometry and collision against it.
+//!
+//! The level is made up of `Collider`s that never move: infinite planes, axis-aligned boxes and
+//! triangles (from which arbitrary meshes can be built). Players are represented as vertical
+//! capsules, which slide smoothly along walls and over small bumps.
+//!
+//! Movement is resolved by moving the capsule in small substeps and pushing it back out of any
+//! colliders it ends up overlapping, removing the part of its velocity that points into the
+//! collider so that it slides along the surface. Everything here is plain `f32` math with a
+//! fixed number of iterations, so given the same inputs the client and server arrive at exactly
+//! the same result.
+
+use math::*;
+use std::cmp::Ordering;
+
+/// The minimum Y component of a surface's normal for the surface to count as ground.
+///
+/// Anything steeper is treated as a wall, which players slide down rather than stand on.
+pub const MIN_GROUND_NORMAL_Y: f32 = 0.7;
+
+/// The maximum number of times an overlap is resolved per substep.
+const MAX_RESOLVE_ITERATIONS: usize = 4;
+
+/// The maximum number of substeps a single move is split into.
+const MAX_SUBSTEPS: usize = 16;
+
+/// The number of refinement iterations used when finding the closest points between a capsule
+/// and a box or triangle.
+const CLOSEST_POINT_ITERATIONS: usize = 4;
+
+/// A piece of static level geometry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collider {
+    /// An infinite plane. Everything on the opposite side from `normal` is solid.
+    Plane {
+        /// The direction the plane faces, as a unit vector.
+        normal: Vector3<f32>,
+
+        /// The distance from the origin to the plane along `normal`.
+        distance: f32,
+    },
+
+    /// An axis-aligned box.
+    Box { min: Point3<f32>, max: Point3<f32> },
+
+    /// A single triangle. Triangles are solid from both sides.
+    Triangle([Point3<f32>; 3]),
+}
+
+impl Collider {
+    /// Returns the contact between the collider and a capsule, or `None` if they don't overlap.
+    fn capsule_contact(&self, capsule: &Capsule, position: Point3<f32>) -> Option<Contact> {
+        let (bottom, top) = capsule.segment(position);
+
+        match *self {
+            Collider::Plane { normal, distance } => {
+                // The deepest point of the capsule is whichever end is lower relative to the
+                // plane.
+                let depth = |point: Point3<f32>| normal.dot(&point.coords) - distance;
+                let separation = depth(bottom).min(depth(top));
+                if separation >= capsule.radius {
+                    return None;
+                }
+
+                Some(Contact {
+                    normal,
+                    depth: capsule.radius - separation,
+                })
+            }
+
+            Collider::Box { min, max } => {
+                let clamp = |point: Point3<f32>| {
+                    Point3::new(
+                        point.x.clamp(min.x, max.x),
+                        point.y.clamp(min.y, max.y),
+                        point.z.clamp(min.z, max.z),
+                    )
+                };
+                let center = Point3::from_coordinates((min.coords + max.coords) * 0.5);
+                let (on_segment, on_box) = closest_points(bottom, top, center, clamp);
+
+                let offset = on_segment - on_box;
+                let distance = offset.norm();
+                if distance >= capsule.radius {
+                    return None;
+                }
+
+                if distance > ::std::f32::EPSILON {
+                    return Some(Contact {
+                        normal: offset / distance,
+                        depth: capsule.radius - distance,
+                    });
+                }
+
+                // The capsule's core is inside the box, so push it out through the nearest face.
+                let faces = [
+                    (Vector3::new(-1.0, 0.0, 0.0), on_segment.x - min.x),
+                    (Vector3::new(1.0, 0.0, 0.0), max.x - on_segment.x),
+                    (Vector3::new(0.0, -1.0, 0.0), on_segment.y - min.y),
+                    (Vector3::new(0.0, 1.0, 0.0), max.y - on_segment.y),
+                    (Vector3::new(0.0, 0.0, -1.0), on_segment.z - min.z),
+                    (Vector3::new(0.0, 0.0, 1.0), max.z - on_segment.z),
+                ];
+                let &(normal, inside) = faces
+                    .iter()
+                    .filter(|&&(_, inside)| !inside.is_nan())
+                    .min_by(|&&(_, a), &&(_, b)| compare(a, b))?;
+
+                Some(Contact {
+                    normal,
+                    depth: inside + capsule.radius,
+                })
+            }
+
+            Collider::Triangle(vertices) => {
+                let center = Point3::from_coordinates(
+                    (vertices[0].coords + vertices[1].coords + vertices[2].coords) / 3.0,
+                );
+                let closest = |point| closest_on_triangle(point, &vertices);
+                let (on_segment, on_triangle) = closest_points(bottom, top, center, closest);
+
+                let offset = on_segment - on_triangle;
+                let distance = offset.norm();
+                if distance >= capsule.radius {
+                    return None;
+                }
+
+                if distance > ::std::f32::EPSILON {
+                    return Some(Contact {
+                        normal: offset / distance,
+                        depth: capsule.radius - distance,
+                    });
+                }
+
+                // The capsule's core passes through the triangle, so push it out along the
+                // triangle's face towards whichever side the capsule is mostly on. Degenerate
+                // triangles have no face to push out along.
+                let mut normal = (vertices[1] - vertices[0])
+                    .cross(&(vertices[2] - vertices[0]))
+                    .try_normalize(::std::f32::EPSILON)?;
+                let middle = Point3::from_coordinates((bottom.coords + top.coords) * 0.5);
+                if normal.dot(&(middle - vertices[0])) < 0.0 {
+                    normal = -normal;
+                }
+
+                Some(Contact {
+                    normal,
+                    depth: capsule.radius,
+                })
+            }
+        }
+    }
+
+    /// Returns the distance along the ray to the collider, if the ray hits it.
+    ///
+    /// `direction` must be a unit vector.
+    fn raycast(&self, origin: Point3<f32>, direction: Vector3<f32>) -> Option<f32> {
+        match *self {
+            Collider::Plane { normal, distance } => {
+                let facing = normal.dot(&direction);
+                if facing >= 0.0 {
+                    return None;
+                }
+
+                let t = (distance - normal.dot(&origin.coords)) / facing;
+                if t >= 0.0 { Some(t) } else { None }
+            }
+
+            Collider::Box { min, max } => ray_box(origin, direction, min, max),
+
+            Collider::Triangle(vertices) => ray_triangle(origin, direction, &vertices),
+        }
+    }
+}
+
+/// A vertical capsule standing on its bottom point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capsule {
+    pub radius: f32,
+
+    /// The total height of the capsule, including the rounded ends.
+    pub height: f32,
+}
+
+impl Capsule {
+    /// Returns the bottom and top of the capsule's core segment when its bottom point is at
+    /// `position`.
+    fn segment(&self, position: Point3<f32>) -> (Point3<f32>, Point3<f32>) {
+        let bottom = position + Vector3::new(0.0, self.radius, 0.0);
+        let top = position + Vector3::new(0.0, (self.height - self.radius).max(self.radius), 0.0);
+        (bottom, top)
+    }
+}
+
+/// An overlap between a capsule and a collider.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    /// The direction to push the capsule to separate it from the collider, as a unit vector.
+    pub normal: Vector3<f32>,
+
+    /// How far the capsule has to be pushed along `normal` to separate it from the collider.
+    pub depth: f32,
+}
+
+/// The result of moving a capsule through the level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Movement {
+    /// Where the capsule ended up.
+    pub position: Point3<f32>,
+
+    /// The capsule's velocity after sliding along anything it hit.
+    pub velocity: Vector3<f32>,
+
+    /// Whether the capsule ended up standing on ground.
+    pub grounded: bool,
+}
+
+/// The static geometry of a level.
+#[derive(Debug, Clone, Default)]
+pub struct CollisionWorld {
+    colliders: Vec<Collider>,
+}
+
+impl CollisionWorld {
+    /// Creates an empty level.
+    pub fn new() -> CollisionWorld {
+        CollisionWorld {
+            colliders: Vec::new(),
+        }
+    }
+
+    /// Creates a level with nothing but flat ground at `y = 0`.
+    pub fn flat_ground() -> CollisionWorld {
+        let mut world = CollisionWorld::new();
+        world.add(Collider::Plane {
+            normal: Vector3::new(0.0, 1.0, 0.0),
+            distance: 0.0,
+        });
+        world
+    }
+
+    pub fn add(&mut self, collider: Collider) {
+        self.colliders.push(collider);
+    }
+
+    /// Adds a triangle mesh to the level.
+    ///
+    /// Each consecutive group of three `indices` makes up one triangle. Any leftover indices are
+    /// ignored, as are triangles with no area. Fails without adding anything if any of the
+    /// indices are out of range.
+    pub fn add_mesh(
+        &mut self,
+        vertices: &[Point3<f32>],
+        indices: &[u32],
+    ) -> Result<(), InvalidIndex> {
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= vertices.len()) {
+            return Err(InvalidIndex { index, vertex_count: vertices.len() });
+        }
+
+        for triangle in indices.chunks(3).filter(|triangle| triangle.len() == 3) {
+            let triangle = [
+                vertices[triangle[0] as usize],
+                vertices[triangle[1] as usize],
+                vertices[triangle[2] as usize],
+            ];
+
+            let area = (triangle[1] - triangle[0]).cross(&(triangle[2] - triangle[0])).norm();
+            if area <= ::std::f32::EPSILON {
+                continue;
+            }
+
+            self.add(Collider::Triangle(triangle));
+        }
+
+        Ok(())
+    }
+
+    pub fn colliders(&self) -> &[Collider] {
+        &self.colliders
+    }
+
+    /// Returns the deepest contact between the capsule at `position` and the level, if it
+    /// overlaps anything.
+    pub fn contact(&self, capsule: &Capsule, position: Point3<f32>) -> Option<Contact> {
+        self.colliders
+            .iter()
+            .filter_map(|collider| collider.capsule_contact(capsule, position))
+            .filter(|contact| !contact.depth.is_nan())
+            .max_by(|a, b| compare(a.depth, b.depth))
+    }
+
+    /// Returns `true` if the capsule at `position` doesn't overlap anything.
+    pub fn fits(&self, capsule: &Capsule, position: Point3<f32>) -> bool {
+        self.contact(capsule, position).is_none()
+    }
+
+    /// Moves the capsule from `position` at `velocity` for `delta` seconds, sliding along
+    /// anything it hits.
+    pub fn move_capsule(
+        &self,
+        capsule: &Capsule,
+        position: Point3<f32>,
+        velocity: Vector3<f32>,
+        delta: f32,
+    ) -> Movement {
+        // Split the move into substeps no longer than half the capsule's radius, so that fast
+        // moving capsules don't pass through thin geometry.
+        let distance = velocity.norm() * delta;
+        let substeps = ((distance / (capsule.radius * 0.5)).ceil() as usize)
+            .max(1)
+            .min(MAX_SUBSTEPS);
+        let substep_delta = delta / substeps as f32;
+
+        let mut position = position;
+        let mut velocity = velocity;
+        let mut grounded = false;
+        for _ in 0..substeps {
+            position += velocity * substep_delta;
+
+            for _ in 0..MAX_RESOLVE_ITERATIONS {
+                let contact = match self.contact(capsule, position) {
+                    Some(contact) => contact,
+                    None => break,
+                };
+
+                position += contact.normal * contact.depth;
+
+                // Remove the part of the velocity pointing into the surface so that the capsule
+                // slides along it.
+                let into = velocity.dot(&contact.normal);
+                if into < 0.0 {
+                    velocity -= contact.normal * into;
+                }
+
+                if contact.normal.y >= MIN_GROUND_NORMAL_Y {
+                    grounded = true;
+                }
+            }
+        }
+
+        Movement {
+            position,
+            velocity,
+            grounded,
+        }
+    }
+
+    /// Casts a ray against the level, returning the distance to the closest hit within
+    /// `max_distance`.
+    ///
+    /// `direction` must be a unit vector.
+    pub fn raycast(
+        &self,
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<f32> {
+        self.colliders
+            .iter()
+            .filter_map(|collider| collider.raycast(origin, direction))
+            .filter(|&distance| !distance.is_nan() && distance <= max_distance)
+            .min_by(|&a, &b| compare(a, b))
+    }
+}
+
+/// A mesh index that refers to a vertex that doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Fail)]
+#[fail(display = "Index {} is out of range for a mesh with {} vertices", index, vertex_count)]
+pub struct InvalidIndex {
+    pub index: u32,
+    pub vertex_count: usize,
+}
+
+/// Compares two values that have already had NaNs filtered out.
+///
+/// A stray NaN would otherwise panic in the middle of a tick, so they're treated as equal to
+/// everything rather than failing.
+fn compare(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+/// Returns the closest point to `point` on the segment from `start` to `end`.
+fn closest_on_segment(start: Point3<f32>, end: Point3<f32>, point: Point3<f32>) -> Point3<f32> {
+    let segment = end - start;
+    let length_squared = segment.norm_squared();
+    if length_squared <= ::std::f32::EPSILON {
+        return start;
+    }
+
+    let t = ((point - start).dot(&segment) / length_squared).clamp(0.0, 1.0);
+    start + segment * t
+}
+
+/// Finds the closest points between the segment from `start` to `end` and a convex shape.
+///
+/// `closest_on_shape` returns the closest point on the shape to a given point. Starting from
+/// `center`, this alternates between the closest point on the segment and the closest point on
+/// the shape, which converges quickly for the short segments and small shapes we deal with.
+fn closest_points<F>(
+    start: Point3<f32>,
+    end: Point3<f32>,
+    center: Point3<f32>,
+    closest_on_shape: F,
+) -> (Point3<f32>, Point3<f32>)
+where
+    F: Fn(Point3<f32>) -> Point3<f32>,
+{
+    let mut on_segment = closest_on_segment(start, end, center);
+    let mut on_shape = closest_on_shape(on_segment);
+    for _ in 0..CLOSEST_POINT_ITERATIONS {
+        on_segment = closest_on_segment(start, end, on_shape);
+        on_shape = closest_on_shape(on_segment);
+    }
+
+    (on_segment, on_shape)
+}
+
+/// Returns the closest point to `point` on the triangle.
+///
+/// See "Real-Time Collision Detection" by Christer Ericson, section 5.1.5.
+fn closest_on_triangle(point: Point3<f32>, vertices: &[Point3<f32>; 3]) -> Point3<f32> {
+    let (a, b, c) = (vertices[0], vertices[1], vertices[2]);
+    let ab = b - a;
+    let ac = c - a;
+
+    // Check if the point is in the vertex region outside `a`.
+    let ap = point - a;
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    // Check if the point is in the vertex region outside `b`.
+    let bp = point - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    // Check if the point is in the edge region of `ab`.
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    // Check if the point is in the vertex region outside `c`.
+    let cp = point - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    // Check if the point is in the edge region of `ac`.
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    // Check if the point is in the edge region of `bc`.
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    // Otherwise the point projects onto the face of the triangle.
+    let denominator = 1.0 / (va + vb + vc);
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+/// Returns the distance along the ray to the first intersection with the box, if any.
+///
+/// Uses the slab method: The ray hits the box if the ranges where the ray is between each pair
+/// of parallel planes all overlap.
+pub fn ray_box(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    min: Point3<f32>,
+    max: Point3<f32>,
+) -> Option<f32> {
+    let mut near = 0.0f32;
+    let mut far = ::std::f32::INFINITY;
+    for axis in 0..3 {
+        if direction[axis].abs() < ::std::f32::EPSILON {
+            // The ray is parallel to the slab, so it has to start inside of it.
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+
+            continue;
+        }
+
+        let inverse = 1.0 / direction[axis];
+        let mut enter = (min[axis] - origin[axis]) * inverse;
+        let mut exit = (max[axis] - origin[axis]) * inverse;
+        if enter > exit {
+            ::std::mem::swap(&mut enter, &mut exit);
+        }
+
+        near = near.max(enter);
+        far = far.min(exit);
+        if near > far {
+            return None;
+        }
+    }
+
+    Some(near)
+}
+
+/// Returns the distance along the ray to the triangle, if the ray hits it.
+///
+/// Uses the Möller–Trumbore algorithm.
+fn ray_triangle(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    vertices: &[Point3<f32>; 3],
+) -> Option<f32> {
+    let edge1 = vertices[1] - vertices[0];
+    let edge2 = vertices[2] - vertices[0];
+    let p = direction.cross(&edge2);
+    let determinant = edge1.dot(&p);
+    if determinant.abs() < ::std::f32::EPSILON {
+        // The ray is parallel to the triangle.
+        return None;
+    }
+
+    let inverse = 1.0 / determinant;
+    let s = origin - vertices[0];
+    let u = s.dot(&p) * inverse;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(&edge1);
+    let v = direction.dot(&q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(&q) * inverse;
+    if t >= 0.0 { Some(t) } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn capsule() -> Capsule {
+        Capsule { radius: 0.5, height: 1.8 }
+    }
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!((expected - actual).abs() < 1e-4, "Expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn slides_along_wall() {
+        let mut world = CollisionWorld::flat_ground();
+        world.add(Collider::Box {
+            min: Point3::new(1.0, -10.0, -10.0),
+            max: Point3::new(2.0, 10.0, 10.0),
+        });
+
+        let movement = world.move_capsule(
+            &capsule(),
+            Point3::new(0.4, 0.0, 0.0),
+            Vector3::new(2.0, 0.0, -2.0),
+            0.1,
+        );
+
+        // The capsule stops at the wall, but keeps moving along it.
+        assert_close(0.5, movement.position.x);
+        assert_close(-0.2, movement.position.z);
+        assert_close(0.0, movement.velocity.x);
+        assert_close(-2.0, movement.velocity.z);
+        assert!(!movement.grounded);
+    }
+
+    #[test]
+    fn landing_sets_grounded() {
+        let world = CollisionWorld::flat_ground();
+
+        let falling = world.move_capsule(
+            &capsule(),
+            Point3::new(0.0, 1.0, 0.0),
+            Vector3::new(0.0, -5.0, 0.0),
+            0.1,
+        );
+        assert_close(0.5, falling.position.y);
+        assert!(!falling.grounded);
+
+        let landed = world.move_capsule(&capsule(), falling.position, falling.velocity, 0.2);
+        assert_close(0.0, landed.position.y);
+        assert_close(0.0, landed.velocity.y);
+        assert!(landed.grounded);
+    }
+
+    #[test]
+    fn add_mesh_validates_indices() {
+        let vertices = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 0.0, 1.0),
+            Point3::new(2.0, 0.0, 0.0),
+        ];
+
+        let mut world = CollisionWorld::new();
+        assert_eq!(
+            Err(InvalidIndex { index: 4, vertex_count: 4 }),
+            world.add_mesh(&vertices, &[0, 1, 2, 0, 1, 4]),
+        );
+        assert!(world.colliders().is_empty());
+
+        // The second triangle has no area, since all of its vertices are in a line.
+        world.add_mesh(&vertices, &[0, 1, 2, 0, 1, 3]).expect("Invalid mesh");
+        assert_eq!(1, world.colliders().len());
+    }
+
+    #[test]
+    fn degenerate_triangle_has_no_contact() {
+        let mut world = CollisionWorld::new();
+        world.add(Collider::Triangle([
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ]));
+
+        assert!(world.fits(&capsule(), Point3::origin()));
+    }
+}
@@ -0,0 +1,231 @@
+//! Player hitboxes, and raycasts against them for checking what projectiles hit.
+//!
+//! Each player has a hitbox for their head and one for their body. The hitboxes are axis-aligned
+//! boxes positioned relative to the player's root position, so they don't rotate as the player
+//! turns. This is a rough approximation of the player's model, but the model is roughly
+//! symmetric so it's close enough for now.
+
+use collision::ray_box;
+use math::*;
+use player::{Player, STANDING_HEIGHT};
+
+/// The height of a player's eyes above their root position.
+///
+/// Shots are fired from this height while standing. This matches the position of the head entity
+/// on the client.
+pub const EYE_HEIGHT: f32 = 1.5;
+
+/// The maximum distance a shot can travel.
+pub const MAX_SHOT_DISTANCE: f32 = 200.0;
+
+/// Which part of a player was hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HitboxKind {
+    Head,
+    Body,
+}
+
+/// An axis-aligned box positioned relative to a player's root position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hitbox {
+    pub kind: HitboxKind,
+
+    /// The minimum corner of the box, as an `[x, y, z]` offset from the player's root position.
+    pub min: [f32; 3],
+
+    /// The maximum corner of the box, as an `[x, y, z]` offset from the player's root position.
+    pub max: [f32; 3],
+}
+
+impl Hitbox {
+    /// Returns the minimum and maximum corners of the box in world space for `player`.
+    ///
+    /// The boxes are squashed vertically while the player is crouching.
+    pub fn bounds(&self, player: &Player) -> (Point3<f32>, Point3<f32>) {
+        let scale = player.height() / STANDING_HEIGHT;
+        let offset = |corner: [f32; 3]| {
+            player.position + Vector3::new(corner[0], corner[1] * scale, corner[2])
+        };
+
+        (offset(self.min), offset(self.max))
+    }
+}
+
+/// The hitboxes for a player, relative to the player's root position.
+pub static HITBOXES: &[Hitbox] = &[
+    Hitbox {
+        kind: HitboxKind::Head,
+        min: [-0.15, 1.35, -0.15],
+        max: [0.15, 1.75, 0.15],
+    },
+    Hitbox {
+        kind: HitboxKind::Body,
+        min: [-0.3, 0.0, -0.3],
+        max: [0.3, 1.35, 0.3],
+    },
+];
+
+/// The result of a shot hitting a player.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    /// The ID of the player that was hit.
+    pub victim: u64,
+
+    /// Which part of the player was hit.
+    pub hitbox: HitboxKind,
+
+    /// The point where the shot hit the player, in world space.
+    pub location: Point3<f32>,
+
+    /// The distance from the origin of the shot to `location`.
+    pub distance: f32,
+}
+
+impl Player {
+    /// Returns the height of the player's eyes above their root position.
+    ///
+    /// This is lower while the player is crouching.
+    pub fn eye_height(&self) -> f32 {
+        EYE_HEIGHT * self.height() / STANDING_HEIGHT
+    }
+
+    /// Returns the position that the player's shots are fired from.
+    pub fn eye_position(&self) -> Point3<f32> {
+        self.position + Vector3::new(0.0, self.eye_height(), 0.0)
+    }
+
+    /// Returns the direction the player is aiming, as a unit vector.
+    pub fn aim_direction(&self) -> Vector3<f32> {
+        let yaw = Rotation3::from_euler_angles(0.0, self.yaw, 0.0);
+        let pitch = Rotation3::from_euler_angles(self.pitch, 0.0, 0.0);
+        yaw * pitch * Vector3::new(0.0, 0.0, -1.0)
+    }
+}
+
+/// Casts a ray from `origin` in `direction` against the hitboxes of `players`, returning the
+/// closest hit.
+///
+/// The player with the ID `shooter` is ignored, so that players can't shoot themselves. Dead
+/// players are also ignored.
+/// `direction` must be a unit vector.
+pub fn raycast<'a, I>(
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    shooter: u64,
+    players: I,
+) -> Option<Hit>
+where
+    I: IntoIterator<Item = &'a Player>,
+{
+    let mut closest: Option<Hit> = None;
+    for player in players {
+        if player.id == shooter || !player.is_alive() {
+            continue;
+        }
+
+        for hitbox in HITBOXES {
+            let (min, max) = hitbox.bounds(player);
+            let distance = match ray_box(origin, direction, min, max) {
+                Some(distance) => distance,
+                None => continue,
+            };
+
+            if distance > MAX_SHOT_DISTANCE {
+                continue;
+            }
+
+            if closest.map(|closest| distance < closest.distance).unwrap_or(true) {
+                closest = Some(Hit {
+                    victim: player.id,
+                    hitbox: hitbox.kind,
+                    location: origin + direction * distance,
+                    distance,
+                });
+            }
+        }
+    }
+
+    closest
+}
+
+#[cfg(test)]
+mod test {
+    use player::LifeState;
+    use super::*;
+
+    fn forward() -> Vector3<f32> {
+        Vector3::new(0.0, 0.0, -1.0)
+    }
+
+    fn shoot(height: f32, players: &[Player]) -> Option<Hit> {
+        raycast(Point3::new(0.0, height, 0.0), forward(), 0, players)
+    }
+
+    #[test]
+    fn head_and_body() {
+        let players = [Player::new(1, Point3::new(0.0, 0.0, -5.0))];
+
+        let hit = shoot(EYE_HEIGHT, &players).unwrap();
+        assert_eq!(hit.victim, 1);
+        assert_eq!(hit.hitbox, HitboxKind::Head);
+        assert!((hit.distance - 4.85).abs() < 1e-4);
+
+        let hit = shoot(0.5, &players).unwrap();
+        assert_eq!(hit.hitbox, HitboxKind::Body);
+
+        assert_eq!(shoot(2.0, &players), None);
+    }
+
+    #[test]
+    fn shooter_is_ignored() {
+        // The shot starts inside the shooter's own head.
+        let players = [
+            Player::new(0, Point3::origin()),
+            Player::new(1, Point3::new(0.0, 0.0, -5.0)),
+        ];
+
+        let hit = shoot(EYE_HEIGHT, &players).unwrap();
+        assert_eq!(hit.victim, 1);
+    }
+
+    #[test]
+    fn dead_players_are_ignored() {
+        let mut target = Player::new(1, Point3::new(0.0, 0.0, -5.0));
+        target.life = LifeState::Dead { respawn_remaining: 1.0 };
+
+        assert_eq!(shoot(EYE_HEIGHT, &[target]), None);
+    }
+
+    #[test]
+    fn closest_player_is_hit() {
+        let players = [
+            Player::new(1, Point3::new(0.0, 0.0, -10.0)),
+            Player::new(2, Point3::new(0.0, 0.0, -5.0)),
+        ];
+
+        let hit = shoot(EYE_HEIGHT, &players).unwrap();
+        assert_eq!(hit.victim, 2);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let players = [Player::new(1, Point3::new(0.0, 0.0, -(MAX_SHOT_DISTANCE + 1.0)))];
+        assert_eq!(shoot(EYE_HEIGHT, &players), None);
+    }
+
+    #[test]
+    fn crouching_shrinks_hitboxes() {
+        let mut target = Player::new(1, Point3::new(0.0, 0.0, -5.0));
+        let (_, standing_max) = HITBOXES[0].bounds(&target);
+
+        target.crouching = true;
+        let (_, crouching_max) = HITBOXES[0].bounds(&target);
+        assert!(crouching_max.y < standing_max.y);
+
+        // A shot at standing eye height goes over a crouching player's head.
+        let eye_height = target.eye_height();
+        let players = [target];
+        assert_eq!(shoot(EYE_HEIGHT, &players), None);
+        assert!(shoot(eye_height, &players).is_some());
+    }
+}
@@ -0,0 +1,248 @@
+//! Levels, loaded from glTF files in `assets/maps`.
+//!
+//! The client renders a level's scene like any other glTF asset. Both the client and the server
+//! also read the same file to build the level's collision and find its spawn points:
+//!
+//! * Every triangle mesh in the default scene is solid, and is added to the level's
+//!   `CollisionWorld`.
+//! * Nodes whose names start with `SPAWN_POINT_PREFIX` mark spawn points, at the node's origin.
+//!   Any mesh attached to a spawn point node is ignored.
+//!
+//! Loading only reads the glTF document and its buffers, so the server can load levels without
+//! a renderer.
+
+use amethyst::core::nalgebra::Matrix4;
+use collision::{CollisionWorld, InvalidIndex};
+use gltf::{self, buffer, mesh::Mode, Node};
+use math::*;
+use player::Player;
+use std::cmp::Ordering;
+use std::path::Path;
+
+/// Nodes with names starting with this are spawn points.
+pub const SPAWN_POINT_PREFIX: &str = "spawn";
+
+/// The static parts of the game world.
+#[derive(Debug, Clone, Default)]
+pub struct Level {
+    /// The name of the level, which is also the name of its file in `assets/maps`.
+    pub name: String,
+
+    pub collision: CollisionWorld,
+
+    /// The points where players can spawn.
+    pub spawn_points: Vec<Point3<f32>>,
+}
+
+impl Level {
+    /// Loads the level `name` from the `maps` directory in `assets_dir`.
+    ///
+    /// Fails if `name` isn't a valid level name (see `asset_path`).
+    pub fn load<P: AsRef<Path>>(assets_dir: P, name: &str) -> Result<Level, LevelError> {
+        let path = assets_dir.as_ref().join(Level::asset_path(name)?);
+        let (document, buffers, _) = gltf::import(&path)?;
+
+        let mut level = Level {
+            name: name.into(),
+            collision: CollisionWorld::new(),
+            spawn_points: Vec::new(),
+        };
+
+        let scene = document.default_scene().or_else(|| document.scenes().next());
+        for node in scene.iter().flat_map(|scene| scene.nodes()) {
+            level.add_node(&node, &buffers, Matrix4::identity())?;
+        }
+
+        // Make sure there's always somewhere to spawn.
+        if level.spawn_points.is_empty() {
+            warn!("Level {} has no spawn points, spawning players at the origin", name);
+            level.spawn_points.push(Point3::origin());
+        }
+
+        Ok(level)
+    }
+
+    /// Returns the path of the level's file, relative to the assets directory.
+    ///
+    /// Level names come from the server, so only names made up of ASCII letters, digits, `_` and
+    /// `-` are accepted. Anything else could refer to a file outside of the `maps` directory.
+    pub fn asset_path(name: &str) -> Result<String, LevelError> {
+        let is_valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_valid {
+            return Err(LevelError::InvalidName(name.into()));
+        }
+
+        Ok(format!("maps/{}.gltf", name))
+    }
+
+    /// Chooses the spawn point that is farthest from any living player.
+    pub fn choose_spawn_point<'a, I>(&self, players: I) -> Point3<f32>
+    where
+        I: IntoIterator<Item = &'a Player>,
+    {
+        let living = players
+            .into_iter()
+            .filter(|player| player.is_alive())
+            .map(|player| player.position)
+            .collect::<Vec<_>>();
+
+        self.spawn_points
+            .iter()
+            .map(|&point| {
+                // Find the distance to the closest living player.
+                let closest = living
+                    .iter()
+                    .map(|position| (position - point).norm())
+                    .fold(::std::f32::INFINITY, f32::min);
+                (point, closest)
+            })
+            // A player with a non-finite position shouldn't crash the server, so treat NaN
+            // distances as equal to anything else.
+            .max_by(|&(_, a), &(_, b)| a.partial_cmp(&b).unwrap_or(Ordering::Equal))
+            .map(|(point, _)| point)
+            .unwrap_or_else(Point3::origin)
+    }
+
+    /// Adds the collision and spawn points for `node` and its children.
+    fn add_node(
+        &mut self,
+        node: &Node,
+        buffers: &[buffer::Data],
+        parent: Matrix4<f32>,
+    ) -> Result<(), LevelError> {
+        let transform = parent * Matrix4::from(node.transform().matrix());
+        let to_world = |point: Point3<f32>| {
+            Point3::from_homogeneous(transform * point.to_homogeneous())
+                .ok_or(LevelError::NotAffine { node: node.index() })
+        };
+
+        let is_spawn_point = node
+            .name()
+            .map(|name| name.starts_with(SPAWN_POINT_PREFIX))
+            .unwrap_or(false);
+        if is_spawn_point {
+            self.spawn_points.push(to_world(Point3::origin())?);
+        } else if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                // Only triangles have any volume to collide with.
+                if primitive.mode() != Mode::Triangles {
+                    continue;
+                }
+
+                let reader = primitive.reader(|buffer| Some(&*buffers[buffer.index()]));
+                let vertices = match reader.read_positions() {
+                    Some(positions) => positions
+                        .map(|[x, y, z]| to_world(Point3::new(x, y, z)))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    None => continue,
+                };
+
+                // Primitives without indices use each vertex once, in order.
+                let indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                    None => (0..vertices.len() as u32).collect(),
+                };
+
+                self.collision.add_mesh(&vertices, &indices)?;
+            }
+        }
+
+        for child in node.children() {
+            self.add_node(&child, buffers, transform)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An error loading a level.
+#[derive(Debug, Fail)]
+pub enum LevelError {
+    /// The level's name contains characters that aren't allowed in level names.
+    #[fail(display = "Invalid level name {:?}", _0)]
+    InvalidName(String),
+
+    /// The level's file couldn't be read, or isn't valid glTF.
+    #[fail(display = "Failed to import level: {}", _0)]
+    Gltf(#[cause] gltf::Error),
+
+    /// A mesh in the level refers to vertices that don't exist.
+    #[fail(display = "Invalid mesh in level: {}", _0)]
+    InvalidMesh(#[cause] InvalidIndex),
+
+    /// A node's transform can't be applied to points, since it isn't affine.
+    #[fail(display = "Transform of node {} is not affine", node)]
+    NotAffine { node: usize },
+}
+
+impl From<gltf::Error> for LevelError {
+    fn from(error: gltf::Error) -> LevelError {
+        LevelError::Gltf(error)
+    }
+}
+
+impl From<InvalidIndex> for LevelError {
+    fn from(error: InvalidIndex) -> LevelError {
+        LevelError::InvalidMesh(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use player::MAX_HEALTH;
+    use super::*;
+
+    #[test]
+    fn asset_path_validates_name() {
+        assert_eq!("maps/arena_2-night.gltf", Level::asset_path("arena_2-night").unwrap());
+
+        for name in &["", "../../x", "maps/arena", "arena.gltf", "C:\\arena", "aréna"] {
+            assert!(Level::asset_path(name).is_err(), "Accepted {:?}", name);
+        }
+    }
+
+    #[test]
+    fn spawn_farthest_from_living_players() {
+        let level = Level {
+            spawn_points: vec![
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(10.0, 0.0, 0.0),
+                Point3::new(20.0, 0.0, 0.0),
+            ],
+            ..Level::default()
+        };
+
+        let mut players = vec![
+            Player::new(0, Point3::new(1.0, 0.0, 0.0)),
+            Player::new(1, Point3::new(19.0, 0.0, 0.0)),
+        ];
+        assert_eq!(Point3::new(10.0, 0.0, 0.0), level.choose_spawn_point(&players));
+
+        // Dead players don't count.
+        players[1].apply_damage(MAX_HEALTH);
+        assert_eq!(Point3::new(20.0, 0.0, 0.0), level.choose_spawn_point(&players));
+
+        // A broken position doesn't stop a spawn point from being chosen.
+        players.push(Player::new(2, Point3::new(::std::f32::NAN, 0.0, 0.0)));
+        level.choose_spawn_point(&players);
+    }
+
+    #[test]
+    fn load_arena() {
+        let assets = concat!(env!("CARGO_MANIFEST_DIR"), "/../assets");
+        let level = Level::load(assets, "arena").expect("Failed to load arena");
+        assert_eq!("arena", level.name);
+        assert_eq!(6, level.spawn_points.len());
+        assert!(!level.collision.colliders().is_empty());
+    }
+
+    #[test]
+    fn missing_level() {
+        let assets = concat!(env!("CARGO_MANIFEST_DIR"), "/../assets");
+        match Level::load(assets, "no-such-map") {
+            Err(LevelError::Gltf(_)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
@@ -3,10 +3,15 @@
 extern crate amethyst;
 extern crate cgmath;
 extern crate crossbeam_channel;
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
 extern crate futures;
+extern crate gltf;
 #[macro_use]
 extern crate log;
 extern crate rand;
+extern crate ron;
 #[macro_use]
 extern crate serde;
 extern crate sumi;
@@ -27,13 +32,32 @@ use std::{
 };
 use tokio_core::reactor;
 
+use hitbox::HitboxKind;
 use math::*;
 use player::Player;
+use projectile::Projectile;
 use revolver::*;
+use snapshot::WorldDelta;
+use tick::{InputBufferHealth, Tick};
+use weapon::WeaponDefinition;
 
+pub mod collision;
+pub mod hitbox;
+pub mod level;
 pub mod math;
+pub mod net;
 pub mod player;
+pub mod projectile;
 pub mod revolver;
+pub mod snapshot;
+pub mod tick;
+pub mod weapon;
+
+/// The port that the server listens on by default, and that clients search for servers on.
+pub const DEFAULT_PORT: u16 = 1234;
+
+/// The maximum number of ticks of input sent in a single `ClientMessageBody::Input`.
+pub const MAX_REDUNDANT_INPUTS: usize = 8;
 
 #[derive(Debug)]
 pub struct Connection<Out, In> {
@@ -141,7 +165,8 @@ impl World {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InputFrame {
     /// Movement input is given as a 2D vector, where up on the input is the positive Y axis, and
-    /// right on the input is the positive X axis.
+    /// right on the input is the positive X axis. Vectors longer than 1 are treated as unit
+    /// vectors.
     pub movement_dir: Vector2<f32>,
 
     /// The change in yaw for the current frame, in radians.
@@ -149,6 +174,15 @@ pub struct InputFrame {
 
     /// The change in pitch for the current frame, in radians.
     pub pitch_delta: f32,
+
+    /// Whether the sprint button is held.
+    pub sprint: bool,
+
+    /// Whether the jump button is held.
+    pub jump: bool,
+
+    /// Whether the crouch button is held.
+    pub crouch: bool,
 }
 
 impl Component for InputFrame {
@@ -161,6 +195,9 @@ impl Default for InputFrame {
             movement_dir: Vector2::new(0.0, 0.0),
             yaw_delta: 0.0,
             pitch_delta: 0.0,
+            sprint: false,
+            jump: false,
+            crouch: false,
         }
     }
 }
@@ -210,15 +247,20 @@ impl Notify for DummyNotify {
 /// A message sent from the server to the clients.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerMessage {
-    /// On which frame the server sent this message.
+    /// On which tick the server sent this message.
     ///
     /// Used by client to sequence messages from the server, and discard old server messages.
-    pub server_frame: usize,
+    pub server_tick: Tick,
 
-    /// The most recent client frame the server knows about.
+    /// The most recent client tick the server knows about.
     ///
     /// Used by the client to determine how much history needs to be re-simulated locally.
-    pub client_frame: usize,
+    pub client_tick: Tick,
+
+    /// The state of the server's buffer of input from this client.
+    ///
+    /// Used by the client to adjust how fast it runs so that its input arrives on time.
+    pub input_buffer: InputBufferHealth,
 
     /// The main body of the message.
     pub body: ServerMessageBody,
@@ -234,11 +276,26 @@ pub enum ServerMessageBody {
 
         /// The current state of the world.
         world: World,
+
+        /// The name of the level being played. See `level::Level`.
+        map: String,
+
+        /// The server's definition of the weapon the players are using.
+        ///
+        /// The client uses this in place of its own definition, so that its predictions match
+        /// the server even if the two have different versions of the weapon's file.
+        weapon: WeaponDefinition,
     },
 
     /// The current state of the entire game world.
+    ///
+    /// Sent when the client hasn't acknowledged a snapshot that can be used as a baseline for
+    /// a `WorldDelta`.
     WorldUpdate(World),
 
+    /// The changes to the game world since a snapshot the client acknowledged.
+    WorldDelta(WorldDelta),
+
     /// A new player has left the game, and should be added to the scene.
     PlayerJoined {
         /// The unique ID for the new player.
@@ -248,6 +305,62 @@ pub enum ServerMessageBody {
         player: Player,
     },
 
+    /// A player fired their gun, spawning a projectile.
+    ///
+    /// Sent for every shot, whether or not it hits anything. Clients simulate the projectile's
+    /// flight locally to draw a tracer.
+    ProjectileSpawned(Projectile),
+
+    /// A projectile hit a player or the ground and was removed.
+    ///
+    /// Projectiles that expire without hitting anything are removed without a message, since
+    /// clients can tell when that happens on their own.
+    ProjectileImpact {
+        /// The ID of the projectile.
+        id: u64,
+
+        /// The point where the projectile hit, in world space.
+        location: Point3<f32>,
+    },
+
+    /// A shot hit a player.
+    ///
+    /// Always sent after the corresponding `ProjectileImpact`.
+    PlayerHit {
+        /// The ID of the player that fired the shot.
+        shooter: u64,
+
+        /// The ID of the player that was hit.
+        victim: u64,
+
+        /// Which part of the victim was hit.
+        hitbox: HitboxKind,
+
+        /// The point where the shot hit the victim, in world space.
+        location: Point3<f32>,
+
+        /// The damage dealt to the victim.
+        damage: u32,
+    },
+
+    /// A player was killed.
+    PlayerDied {
+        /// The ID of the player that died.
+        id: u64,
+
+        /// The ID of the player that killed them.
+        killer: u64,
+    },
+
+    /// A dead player came back to life.
+    PlayerRespawned {
+        /// The ID of the player that respawned.
+        id: u64,
+
+        /// Where the player respawned.
+        position: Point3<f32>,
+    },
+
     /// A player left the game, and should be removed from the scene.
     PlayerLeft {
         id: u64,
@@ -257,12 +370,12 @@ pub enum ServerMessageBody {
 /// A message sent from the client to the server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientMessage {
-    /// The client's current frame.
+    /// The client's current tick.
     ///
     /// This is not used directly by the server, rather it is sent back to the client in the
     /// server's messages, that way the client can know how far behind the server is in
     /// processing input.
-    pub frame: usize,
+    pub tick: Tick,
 
     /// The main body of the message.
     pub body: ClientMessageBody,
@@ -270,6 +383,46 @@ pub struct ClientMessage {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessageBody {
-    Input(InputFrame),
-    RevolverAction(RevolverAction),
+    /// The input for the client's most recent ticks, oldest first.
+    ///
+    /// Messages can be lost, so each one repeats the input for up to `MAX_REDUNDANT_INPUTS`
+    /// ticks that the server hasn't acknowledged yet. The server ignores input for any ticks it
+    /// has already processed, and rejects messages with more ticks than that.
+    Input(Vec<TickInput>),
+
+    /// The client received the world snapshot sent on the given server tick.
+    ///
+    /// The server uses the most recent acknowledged snapshot as the baseline for delta
+    /// compressing future snapshots.
+    AcknowledgeSnapshot(Tick),
+}
+
+/// The input for a single one of the client's ticks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TickInput {
+    pub tick: Tick,
+    pub input: InputFrame,
+
+    /// The revolver actions the player performed during the tick, in the order they happened.
+    ///
+    /// Actions are resent along with the rest of the input until the server acknowledges the
+    /// tick, so the server has to make sure it only applies them once.
+    pub actions: Vec<RevolverAction>,
+
+    /// What the player was seeing when they performed the actions.
+    pub view: ViewTime,
+}
+
+/// What the client was showing the player at the time they performed an action.
+///
+/// The client renders remote players in the past, so when the player shoots they're aiming at
+/// where the other players were a little while ago. The server uses this to rewind the other
+/// players to where the shooter saw them when checking whether a shot hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ViewTime {
+    /// The most recent server tick that the client had received a snapshot for.
+    pub server_tick: Tick,
+
+    /// How far in the past remote players were being rendered, in milliseconds.
+    pub interpolation_delay_millis: u32,
 }
@@ -0,0 +1,470 @@
+//! Compact network representation of game state.
+//!
+//! `Player` and `Revolver` store their state as `f32`s, which is more precision than we need and
+//! more bytes than we want to send every frame. Instead, the state is quantized before being sent:
+//!
+//! * Positions are fixed-point with `POSITION_SCALE` steps per unit, and are limited to
+//!   `MAX_POSITION` units from the origin on each axis.
+//! * Velocities are fixed-point with `VELOCITY_SCALE` steps per unit per second, and are limited
+//!   to `MAX_VELOCITY` on each axis.
+//! * Angles are stored as fractions of a full turn.
+//! * Animation timers are stored as a whole number of `TIMER_TICKS_PER_SECOND` ticks.
+//! * The cylinder's cartridges are packed into 2 bits each, 4 to a byte, and only the chambers
+//!   that have been loaded are sent.
+//!
+//! The server quantizes its own state every frame (see `Player::quantize`), and the client does
+//! the same when predicting the local player. Since quantized state is exactly representable in
+//! the network format, converting between `Player` and `NetPlayer` is lossless and both sides
+//! stay in agreement about the player's state.
+
+use math::*;
+use player::{LifeState, Player};
+use revolver::*;
+use std::{i16, u16};
+
+/// The number of fixed-point steps per unit for positions.
+pub const POSITION_SCALE: f32 = 128.0;
+
+/// The maximum distance from the origin along each axis that can be represented.
+pub const MAX_POSITION: f32 = i16::MAX as f32 / POSITION_SCALE;
+
+/// The number of fixed-point steps per unit per second for velocities.
+pub const VELOCITY_SCALE: f32 = 256.0;
+
+/// The maximum speed along each axis that can be represented, in units per second.
+pub const MAX_VELOCITY: f32 = i16::MAX as f32 / VELOCITY_SCALE;
+
+/// The number of ticks per second for animation timers.
+pub const TIMER_TICKS_PER_SECOND: f32 = 1000.0;
+
+/// The number of fixed-point steps per chamber for the cylinder's rotation.
+pub const ROTATION_SCALE: f32 = 256.0;
+
+/// The network representation of a `Player`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetPlayer {
+    pub id: u64,
+    pub position: [i16; 3],
+    pub velocity: [i16; 3],
+    pub grounded: bool,
+    pub crouching: bool,
+    pub yaw: u16,
+    pub pitch: i16,
+    pub gun: NetRevolver,
+    pub health: u16,
+    pub life: NetLifeState,
+}
+
+impl<'a> From<&'a Player> for NetPlayer {
+    fn from(player: &'a Player) -> NetPlayer {
+        NetPlayer {
+            id: player.id,
+            position: [
+                quantize_position(player.position.x),
+                quantize_position(player.position.y),
+                quantize_position(player.position.z),
+            ],
+            velocity: [
+                quantize_velocity(player.velocity.x),
+                quantize_velocity(player.velocity.y),
+                quantize_velocity(player.velocity.z),
+            ],
+            grounded: player.grounded,
+            crouching: player.crouching,
+            yaw: quantize_yaw(player.yaw),
+            pitch: quantize_pitch(player.pitch),
+            gun: NetRevolver::from(&player.gun),
+            health: ::std::cmp::min(player.health, u16::MAX as u32) as u16,
+            life: match player.life {
+                LifeState::Alive => NetLifeState::Alive,
+                LifeState::Dead { respawn_remaining } => NetLifeState::Dead {
+                    respawn_remaining: quantize_timer(respawn_remaining),
+                },
+            },
+        }
+    }
+}
+
+impl From<NetPlayer> for Player {
+    fn from(player: NetPlayer) -> Player {
+        Player {
+            id: player.id,
+            position: Point3::new(
+                dequantize_position(player.position[0]),
+                dequantize_position(player.position[1]),
+                dequantize_position(player.position[2]),
+            ),
+            velocity: Vector3::new(
+                dequantize_velocity(player.velocity[0]),
+                dequantize_velocity(player.velocity[1]),
+                dequantize_velocity(player.velocity[2]),
+            ),
+            grounded: player.grounded,
+            crouching: player.crouching,
+            yaw: dequantize_yaw(player.yaw),
+            pitch: dequantize_pitch(player.pitch),
+            gun: player.gun.into(),
+            health: player.health as u32,
+            life: match player.life {
+                NetLifeState::Alive => LifeState::Alive,
+                NetLifeState::Dead { respawn_remaining } => LifeState::Dead {
+                    respawn_remaining: dequantize_timer(respawn_remaining),
+                },
+            },
+        }
+    }
+}
+
+/// The network representation of a `LifeState`, with the respawn timer in ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetLifeState {
+    Alive,
+    Dead { respawn_remaining: u16 },
+}
+
+/// The network representation of a `Revolver`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetRevolver {
+    pub hammer_state: NetHammerState,
+    pub cylinder_state: NetCylinderState,
+
+    /// The number of chambers in `cartridges`.
+    pub chambers: u8,
+
+    /// The state of each chamber, packed into 2 bits per chamber. See `pack_cartridges`.
+    pub cartridges: Vec<u8>,
+
+    pub trigger_held: bool,
+}
+
+impl<'a> From<&'a Revolver> for NetRevolver {
+    fn from(revolver: &'a Revolver) -> NetRevolver {
+        let hammer_state = match revolver.hammer_state {
+            HammerState::Uncocked => NetHammerState::Uncocked,
+            HammerState::Cocking { remaining } => NetHammerState::Cocking {
+                remaining: quantize_timer(remaining),
+            },
+            HammerState::Cocked => NetHammerState::Cocked,
+            HammerState::Firing { remaining } => NetHammerState::Firing {
+                remaining: quantize_timer(remaining),
+            },
+        };
+
+        let cylinder_state = match revolver.cylinder_state {
+            CylinderState::Closed { position } => NetCylinderState::Closed {
+                position: position as u8,
+            },
+            CylinderState::Opening { remaining, rotation } => NetCylinderState::Opening {
+                remaining: quantize_timer(remaining),
+                rotation: quantize_rotation(rotation),
+            },
+            CylinderState::Open { rotation } => NetCylinderState::Open {
+                rotation: quantize_rotation(rotation),
+            },
+            CylinderState::Ejecting { rotation, keyframe, remaining } => {
+                NetCylinderState::Ejecting {
+                    rotation: quantize_rotation(rotation),
+                    keyframe: keyframe as u8,
+                    remaining: quantize_timer(remaining),
+                }
+            }
+            CylinderState::Closing { remaining, rotation } => NetCylinderState::Closing {
+                remaining: quantize_timer(remaining),
+                rotation: quantize_rotation(rotation),
+            },
+        };
+
+        // `MAX_CAPACITY` is small enough that the number of chambers always fits in a `u8`.
+        let cartridges = &revolver.cartridges[..revolver.cartridges.len().min(MAX_CAPACITY)];
+        NetRevolver {
+            hammer_state,
+            cylinder_state,
+            chambers: cartridges.len() as u8,
+            cartridges: pack_cartridges(cartridges),
+            trigger_held: revolver.trigger_held,
+        }
+    }
+}
+
+impl From<NetRevolver> for Revolver {
+    fn from(revolver: NetRevolver) -> Revolver {
+        let hammer_state = match revolver.hammer_state {
+            NetHammerState::Uncocked => HammerState::Uncocked,
+            NetHammerState::Cocking { remaining } => HammerState::Cocking {
+                remaining: dequantize_timer(remaining),
+            },
+            NetHammerState::Cocked => HammerState::Cocked,
+            NetHammerState::Firing { remaining } => HammerState::Firing {
+                remaining: dequantize_timer(remaining),
+            },
+        };
+
+        let cylinder_state = match revolver.cylinder_state {
+            NetCylinderState::Closed { position } => CylinderState::Closed {
+                // Clamp the position so that a bad value from the network can't cause an
+                // out-of-bounds access on the cartridges.
+                position: position as usize % MAX_CAPACITY,
+            },
+            NetCylinderState::Opening { remaining, rotation } => CylinderState::Opening {
+                remaining: dequantize_timer(remaining),
+                rotation: dequantize_rotation(rotation),
+            },
+            NetCylinderState::Open { rotation } => CylinderState::Open {
+                rotation: dequantize_rotation(rotation),
+            },
+            NetCylinderState::Ejecting { rotation, keyframe, remaining } => {
+                CylinderState::Ejecting {
+                    rotation: dequantize_rotation(rotation),
+
+                    // Clamp the keyframe for the same reason, since it's used to index the
+                    // eject animation's keyframes.
+                    keyframe: ::std::cmp::min(keyframe as usize, MAX_EJECT_KEYFRAMES - 1),
+                    remaining: dequantize_timer(remaining),
+                }
+            }
+            NetCylinderState::Closing { remaining, rotation } => CylinderState::Closing {
+                remaining: dequantize_timer(remaining),
+                rotation: dequantize_rotation(rotation),
+            },
+        };
+
+        Revolver {
+            hammer_state,
+            cylinder_state,
+            cartridges: unpack_cartridges(revolver.chambers, &revolver.cartridges),
+            trigger_held: revolver.trigger_held,
+        }
+    }
+}
+
+/// The network representation of a `HammerState`, with timers in ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetHammerState {
+    Uncocked,
+    Cocking { remaining: u16 },
+    Cocked,
+    Firing { remaining: u16 },
+}
+
+/// The network representation of a `CylinderState`, with timers in ticks and rotations in
+/// fixed-point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NetCylinderState {
+    Closed { position: u8 },
+    Opening { remaining: u16, rotation: u16 },
+    Open { rotation: u16 },
+    Ejecting { rotation: u16, keyframe: u8, remaining: u16 },
+    Closing { remaining: u16, rotation: u16 },
+}
+
+pub fn quantize_position(position: f32) -> i16 {
+    (position.clamp(-MAX_POSITION, MAX_POSITION) * POSITION_SCALE).round() as i16
+}
+
+pub fn dequantize_position(position: i16) -> f32 {
+    position as f32 / POSITION_SCALE
+}
+
+pub fn quantize_velocity(velocity: f32) -> i16 {
+    (velocity.clamp(-MAX_VELOCITY, MAX_VELOCITY) * VELOCITY_SCALE).round() as i16
+}
+
+pub fn dequantize_velocity(velocity: i16) -> f32 {
+    velocity as f32 / VELOCITY_SCALE
+}
+
+/// Quantizes a yaw to a fraction of a full turn.
+///
+/// Yaws outside of [0, tau) are wrapped into that range.
+pub fn quantize_yaw(yaw: f32) -> u16 {
+    let turns = (yaw / TAU).fract();
+    let turns = if turns < 0.0 { turns + 1.0 } else { turns };
+    ((turns * 65536.0).round() as u32 % 65536) as u16
+}
+
+pub fn dequantize_yaw(yaw: u16) -> f32 {
+    yaw as f32 / 65536.0 * TAU
+}
+
+/// Quantizes a pitch in the range [-pi, pi], clamping pitches outside that range.
+pub fn quantize_pitch(pitch: f32) -> i16 {
+    (pitch.clamp(-PI, PI) / PI * i16::MAX as f32).round() as i16
+}
+
+pub fn dequantize_pitch(pitch: i16) -> f32 {
+    // NOTE: `i16::MIN` can't be produced by `quantize_pitch`, but could still be received from
+    // the network, so clamp to keep pitch within its documented range.
+    (pitch as f32 / i16::MAX as f32 * PI).clamp(-PI, PI)
+}
+
+/// Quantizes an animation timer, in seconds, to a whole number of ticks.
+pub fn quantize_timer(seconds: f32) -> u16 {
+    (seconds * TIMER_TICKS_PER_SECOND).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+pub fn dequantize_timer(ticks: u16) -> f32 {
+    ticks as f32 / TIMER_TICKS_PER_SECOND
+}
+
+/// Quantizes the rotation of the cylinder, in chambers.
+pub fn quantize_rotation(rotation: f32) -> u16 {
+    (rotation * ROTATION_SCALE).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+pub fn dequantize_rotation(rotation: u16) -> f32 {
+    rotation as f32 / ROTATION_SCALE
+}
+
+/// Packs the state of each chamber into 2 bits, 4 chambers to a byte, with the first chamber in
+/// the lowest bits of the first byte.
+///
+/// Empty chambers are `0`, fresh cartridges are `1`, and spent cartridges are `2`.
+pub fn pack_cartridges(cartridges: &[Option<Cartridge>]) -> Vec<u8> {
+    cartridges
+        .chunks(4)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0, |packed, (index, cartridge)| {
+                let bits = match cartridge {
+                    None => 0,
+                    Some(Cartridge::Fresh) => 1,
+                    Some(Cartridge::Spent) => 2,
+                };
+
+                packed | bits << (index * 2)
+            })
+        })
+        .collect()
+}
+
+/// Unpacks `chambers` cartridges packed with `pack_cartridges`.
+///
+/// The unused value `3` is treated as an empty chamber. The number of chambers is clamped to
+/// `MAX_CAPACITY` and to the number of chambers in `packed`, so that a bad value from the network
+/// can't make us allocate an unreasonable number of chambers.
+pub fn unpack_cartridges(chambers: u8, packed: &[u8]) -> Vec<Option<Cartridge>> {
+    let chambers = ::std::cmp::min(chambers as usize, MAX_CAPACITY);
+    let chambers = ::std::cmp::min(chambers, packed.len() * 4);
+    (0..chambers)
+        .map(|index| match (packed[index / 4] >> (index % 4 * 2)) & 0b11 {
+            1 => Some(Cartridge::Fresh),
+            2 => Some(Cartridge::Spent),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::u8;
+    use super::*;
+
+    fn player() -> Player {
+        let mut player = Player::new(7, Point3::new(1.234_567, -20.001, 300.7));
+        player.velocity = Vector3::new(0.333, -9.81, 12.345_6);
+        player.grounded = true;
+        player.yaw = 4.321;
+        player.pitch = -0.777;
+        player.health = 63;
+        player.life = LifeState::Dead { respawn_remaining: 1.234_56 };
+        player.gun = Revolver {
+            hammer_state: HammerState::Cocking { remaining: 0.123_45 },
+            cylinder_state: CylinderState::Ejecting {
+                rotation: 2.345_6,
+                keyframe: 1,
+                remaining: 0.0456,
+            },
+            cartridges: vec![
+                Some(Cartridge::Fresh),
+                None,
+                Some(Cartridge::Spent),
+                Some(Cartridge::Fresh),
+                None,
+                Some(Cartridge::Spent),
+            ],
+            trigger_held: true,
+        };
+        player
+    }
+
+    #[test]
+    fn player_quantization_is_idempotent() {
+        let net_player = NetPlayer::from(&player());
+        let quantized = Player::from(net_player.clone());
+        assert_eq!(net_player, NetPlayer::from(&quantized));
+
+        // Quantizing an already quantized player must not change it at all.
+        let mut requantized = quantized.clone();
+        requantized.quantize();
+        assert_eq!(quantized, requantized);
+    }
+
+    #[test]
+    fn revolver_quantization_is_idempotent() {
+        let cylinder_states = [
+            CylinderState::Closed { position: 4 },
+            CylinderState::Opening { remaining: 0.016_67, rotation: 3.499 },
+            CylinderState::Open { rotation: 0.501 },
+            CylinderState::Ejecting { rotation: 1.0, keyframe: 2, remaining: 0.2 },
+            CylinderState::Closing { remaining: 0.05, rotation: 5.9 },
+        ];
+        let hammer_states = [
+            HammerState::Uncocked,
+            HammerState::Cocking { remaining: 0.099_99 },
+            HammerState::Cocked,
+            HammerState::Firing { remaining: 0.000_4 },
+        ];
+
+        for cylinder_state in &cylinder_states {
+            for hammer_state in &hammer_states {
+                let revolver = Revolver {
+                    hammer_state: *hammer_state,
+                    cylinder_state: *cylinder_state,
+                    cartridges: player().gun.cartridges,
+                    trigger_held: false,
+                };
+
+                let net_revolver = NetRevolver::from(&revolver);
+                let quantized = Revolver::from(net_revolver.clone());
+                assert_eq!(net_revolver, NetRevolver::from(&quantized));
+                assert_eq!(quantized, Revolver::from(NetRevolver::from(&quantized)));
+            }
+        }
+    }
+
+    #[test]
+    fn cartridges_roundtrip() {
+        let cartridges = player().gun.cartridges;
+        let packed = pack_cartridges(&cartridges);
+        assert_eq!(2, packed.len());
+        assert_eq!(cartridges, unpack_cartridges(cartridges.len() as u8, &packed));
+
+        let magazine = vec![Some(Cartridge::Fresh); MAX_CAPACITY];
+        let packed = pack_cartridges(&magazine);
+        assert_eq!(magazine, unpack_cartridges(MAX_CAPACITY as u8, &packed));
+    }
+
+    #[test]
+    fn chamber_count_is_clamped() {
+        let packed = pack_cartridges(&[Some(Cartridge::Fresh); 6]);
+        assert_eq!(8, unpack_cartridges(u8::MAX, &packed).len());
+        assert_eq!(MAX_CAPACITY, unpack_cartridges(u8::MAX, &[0xFF; 32]).len());
+    }
+
+    #[test]
+    fn out_of_range_indices_are_clamped() {
+        let mut net_revolver = NetRevolver::from(&Revolver::default());
+
+        net_revolver.cylinder_state = NetCylinderState::Closed { position: u8::MAX };
+        assert!(Revolver::from(net_revolver.clone()).cylinder_state.position() < MAX_CAPACITY);
+
+        net_revolver.cylinder_state = NetCylinderState::Ejecting {
+            rotation: 0,
+            keyframe: u8::MAX,
+            remaining: 0,
+        };
+        match Revolver::from(net_revolver).cylinder_state {
+            CylinderState::Ejecting { keyframe, .. } => assert!(keyframe < MAX_EJECT_KEYFRAMES),
+            state => panic!("Unexpected cylinder state: {:?}", state),
+        }
+    }
+}
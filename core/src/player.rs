@@ -1,15 +1,83 @@
 use amethyst::ecs::{Component, DenseVecStorage};
+use collision::{Capsule, CollisionWorld};
 use math::*;
+use net::NetPlayer;
 use revolver::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use weapon::{FireMode, WeaponDefinition};
 use InputFrame;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The health a player has when they spawn.
+pub const MAX_HEALTH: u32 = 100;
+
+/// How long a player stays dead before respawning, in seconds.
+pub const RESPAWN_SECONDS: f32 = 3.0;
+
+/// The radius of a player's collision capsule.
+pub const PLAYER_RADIUS: f32 = 0.3;
+
+/// The height of a player's collision capsule while standing.
+pub const STANDING_HEIGHT: f32 = 1.8;
+
+/// The height of a player's collision capsule while crouching.
+pub const CROUCHING_HEIGHT: f32 = 1.2;
+
+/// The top speed of a walking player, in units per second.
+pub const WALK_SPEED: f32 = 4.0;
+
+/// The top speed of a sprinting player, in units per second.
+pub const SPRINT_SPEED: f32 = 6.5;
+
+/// How fast a crouching player moves, relative to a walking player.
+pub const CROUCH_SPEED_SCALE: f32 = 0.5;
+
+/// How quickly a player on the ground speeds up towards the direction they're moving in, in
+/// units per second squared.
+pub const GROUND_ACCELERATION: f32 = 40.0;
+
+/// How quickly a player on the ground slows down once they stop moving, in units per second
+/// squared.
+pub const GROUND_FRICTION: f32 = 30.0;
+
+/// How quickly a player in the air can change their horizontal velocity, in units per second
+/// squared.
+///
+/// This is much lower than on the ground so that players are mostly committed to a jump once
+/// they've left the ground, but can still steer a little.
+pub const AIR_ACCELERATION: f32 = 8.0;
+
+/// The downward acceleration of players and projectiles, in units per second squared.
+pub const GRAVITY: f32 = 9.81;
+
+/// The fastest a player can fall, in units per second.
+pub const MAX_FALL_SPEED: f32 = 50.0;
+
+/// The upward speed of a player when they jump, in units per second.
+pub const JUMP_SPEED: f32 = 4.5;
+
+/// The state of a single player.
+///
+/// `Player` is sent over the network in its quantized form, `NetPlayer`. Call `quantize` after
+/// modifying the player so that the local state matches what will be sent.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Player {
     pub id: u64,
 
     /// The player's current root position in 3D space.
     pub position: Point3<f32>,
 
+    /// The player's current velocity, in units per second.
+    pub velocity: Vector3<f32>,
+
+    /// Whether the player is standing on the ground, and so is able to jump.
+    pub grounded: bool,
+
+    /// Whether the player is crouching.
+    ///
+    /// Players stay crouched after releasing the crouch button until there's room for them to
+    /// stand up.
+    pub crouching: bool,
+
     /// The player's current yaw.
     ///
     /// Yaw has a range of [0, tau), where 0 indicates that the player is facing forward along the
@@ -23,13 +91,43 @@ pub struct Player {
 
     /// The current state of the player's gun.
     pub gun: Revolver,
+
+    /// The player's remaining health. The player dies when this reaches 0.
+    pub health: u32,
+
+    /// Whether the player is alive, or dead and waiting to respawn.
+    pub life: LifeState,
 }
 
 impl Player {
-    /// Performs a single frame step for the player based on it inputs.
+    /// Creates a new player at `position` with full health.
+    pub fn new(id: u64, position: Point3<f32>) -> Player {
+        Player {
+            id,
+            position,
+            velocity: Vector3::zeros(),
+            grounded: false,
+            crouching: false,
+            yaw: 0.0,
+            pitch: 0.0,
+            gun: Revolver::default(),
+            health: MAX_HEALTH,
+            life: LifeState::Alive,
+        }
+    }
+
+    /// Performs a single frame step for the player based on it inputs, colliding with `level`.
     ///
-    /// `delta` is in seconds.
-    pub fn step(&mut self, input: &InputFrame, delta: f32) {
+    /// `delta` is in seconds. Dead players ignore their input, and count down until they can
+    /// respawn instead.
+    pub fn step(&mut self, input: &InputFrame, level: &CollisionWorld, delta: f32) {
+        if let LifeState::Dead { respawn_remaining } = self.life {
+            self.life = LifeState::Dead {
+                respawn_remaining: (respawn_remaining - delta).max(0.0),
+            };
+            return;
+        }
+
         // Apply input to orientation.
         self.yaw = (self.yaw + input.yaw_delta) % TAU;
 
@@ -40,31 +138,228 @@ impl Player {
         let forward = orientation * Vector3::new(0.0, 0.0, -1.0);
         let right = orientation * Vector3::new(1.0, 0.0, 0.0);
 
-        // Convert the 2D input into a 3D movement vector.
-        let velocity = forward * input.movement_dir.y + right * input.movement_dir.x;
+        // Crouch immediately, but only stand back up once there's room to.
+        if input.crouch {
+            self.crouching = true;
+        } else if self.crouching {
+            let standing = Capsule {
+                radius: PLAYER_RADIUS,
+                height: STANDING_HEIGHT,
+            };
+            self.crouching = !level.fits(&standing, self.position);
+        }
+
+        // Convert the 2D input into the horizontal velocity the player wants to move at. The
+        // input is clamped to unit length so that moving diagonally isn't faster. Vertical
+        // movement is left to jumping and gravity.
+        let direction = if input.movement_dir.norm() > 1.0 {
+            input.movement_dir.normalize()
+        } else {
+            input.movement_dir
+        };
+        let speed = if self.crouching {
+            WALK_SPEED * CROUCH_SPEED_SCALE
+        } else if input.sprint && direction.y > 0.0 {
+            // Players can only sprint forwards.
+            SPRINT_SPEED
+        } else {
+            WALK_SPEED
+        };
+        let target = (forward * direction.y + right * direction.x) * speed;
+
+        // Accelerate towards the target velocity. On the ground players have full control, but
+        // in the air they keep their momentum unless they steer.
+        let is_moving = direction.norm_squared() > 0.0;
+        let acceleration = match (self.grounded, is_moving) {
+            (true, true) => Some(GROUND_ACCELERATION),
+            (true, false) => Some(GROUND_FRICTION),
+            (false, true) => Some(AIR_ACCELERATION),
+            (false, false) => None,
+        };
+        if let Some(acceleration) = acceleration {
+            let horizontal = Vector3::new(self.velocity.x, 0.0, self.velocity.z);
+            let change = target - horizontal;
+            let max_change = acceleration * delta;
+            let change = if change.norm() > max_change {
+                change.normalize() * max_change
+            } else {
+                change
+            };
+
+            self.velocity.x += change.x;
+            self.velocity.z += change.z;
+        }
+
+        if input.jump && self.grounded && !self.crouching {
+            self.velocity.y = JUMP_SPEED;
+        }
+
+        self.velocity.y = (self.velocity.y - GRAVITY * delta).max(-MAX_FALL_SPEED);
+
+        let movement = level.move_capsule(&self.capsule(), self.position, self.velocity, delta);
+        self.position = movement.position;
+        self.velocity = movement.velocity;
+        self.grounded = movement.grounded;
+    }
+
+    /// Returns the current height of the player.
+    pub fn height(&self) -> f32 {
+        if self.crouching {
+            CROUCHING_HEIGHT
+        } else {
+            STANDING_HEIGHT
+        }
+    }
+
+    /// Returns the player's collision capsule.
+    pub fn capsule(&self) -> Capsule {
+        Capsule {
+            radius: PLAYER_RADIUS,
+            height: self.height(),
+        }
+    }
+
+    /// Blends between two states of the same player.
+    ///
+    /// `t` is the blend factor, where 0 gives `self` and 1 gives `other`. Values outside of
+    /// [0, 1] extrapolate the player's position and orientation, but the state of the gun is
+    /// always one of the two input states (or somewhere between them).
+    pub fn interpolate(&self, other: &Player, t: f32) -> Player {
+        let nearest = if t < 0.5 { self } else { other };
+
+        // Don't blend across a death or respawn, since the player teleports when they respawn.
+        if self.is_alive() != other.is_alive() {
+            return nearest.clone();
+        }
+
+        // Blend yaw along the shortest path, so that turning across 0 doesn't spin the player
+        // the long way around.
+        let mut yaw_delta = (other.yaw - self.yaw) % TAU;
+        if yaw_delta > PI {
+            yaw_delta -= TAU;
+        } else if yaw_delta < -PI {
+            yaw_delta += TAU;
+        }
+        let yaw = (self.yaw + yaw_delta * t + TAU) % TAU;
+
+        Player {
+            id: self.id,
+            position: self.position + (other.position - self.position) * t,
+            velocity: self.velocity + (other.velocity - self.velocity) * t,
+            grounded: nearest.grounded,
+            crouching: nearest.crouching,
+            yaw,
+            pitch: (self.pitch + (other.pitch - self.pitch) * t).clamp(-PI / 2.0, PI / 2.0),
+            gun: self.gun.interpolate(&other.gun, t.clamp(0.0, 1.0)),
+            health: nearest.health,
+            life: nearest.life,
+        }
+    }
+
+    /// Rounds the player's state to the precision of its network representation.
+    ///
+    /// The server does this every frame, so the client has to do the same when predicting the
+    /// local player in order to arrive at the same state.
+    pub fn quantize(&mut self) {
+        *self = NetPlayer::from(&*self).into();
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.life == LifeState::Alive
+    }
+
+    /// Returns `true` if the player is dead and has waited long enough to respawn.
+    pub fn can_respawn(&self) -> bool {
+        match self.life {
+            LifeState::Dead { respawn_remaining } => respawn_remaining <= 0.0,
+            LifeState::Alive => false,
+        }
+    }
+
+    /// Deals `damage` to the player, returning `true` if it killed them.
+    ///
+    /// Dead players can't take damage.
+    pub fn apply_damage(&mut self, damage: u32) -> bool {
+        if !self.is_alive() {
+            return false;
+        }
+
+        self.health = self.health.saturating_sub(damage);
+        if self.health > 0 {
+            return false;
+        }
 
-        self.position += velocity * delta;
+        self.life = LifeState::Dead {
+            respawn_remaining: RESPAWN_SECONDS,
+        };
+        true
     }
 
-    pub fn handle_revolver_action(&mut self, action: RevolverAction) {
+    /// Brings the player back to life at `position`, with full health and a fresh gun.
+    pub fn respawn(&mut self, position: Point3<f32>) {
+        *self = Player {
+            yaw: self.yaw,
+            ..Player::new(self.id, position)
+        };
+    }
+
+    /// Kicks the player's aim after firing `weapon`.
+    ///
+    /// The aim always kicks upwards by the weapon's pitch recoil, and alternates left and right
+    /// by its yaw recoil with each spent cartridge.
+    pub fn apply_recoil(&mut self, weapon: &WeaponDefinition) {
+        let spent = self.gun.cartridges
+            .iter()
+            .filter(|&&cartridge| cartridge == Some(Cartridge::Spent))
+            .count();
+        let side = if spent % 2 == 0 { 1.0 } else { -1.0 };
+
+        self.pitch = (self.pitch + weapon.recoil.pitch).clamp(-PI / 2.0, PI / 2.0);
+        self.yaw = (self.yaw + side * weapon.recoil.yaw) % TAU;
+    }
+
+    /// Applies a revolver action, operating the gun as described by `weapon`. Actions are
+    /// ignored while the player is dead.
+    pub fn handle_revolver_action(&mut self, action: RevolverAction, weapon: &WeaponDefinition) {
+        if !self.is_alive() {
+            return;
+        }
+
+        let timings = &weapon.timings;
         match action {
             RevolverAction::PullTrigger => {
+                self.gun.trigger_held = true;
+
                 if self.gun.is_hammer_cocked() {
                     self.gun.hammer_state = HammerState::Firing {
-                        remaining: HAMMER_FALL_MILLIS as f32 / 1000.0,
+                        remaining: timings.hammer_fall_seconds(),
+                    };
+                } else if weapon.fire_mode == FireMode::DoubleAction
+                    && self.gun.is_hammer_uncocked()
+                    && self.gun.is_cylinder_closed()
+                {
+                    // Pulling the trigger cocks the hammer on the way, so it takes longer than
+                    // firing with the hammer already cocked.
+                    self.gun.rotate_cylinder(weapon.capacity);
+                    self.gun.hammer_state = HammerState::Firing {
+                        remaining: timings.hammer_cock_seconds() + timings.hammer_fall_seconds(),
                     };
                 }
             }
 
+            RevolverAction::ReleaseTrigger => {
+                self.gun.trigger_held = false;
+            }
+
             RevolverAction::PullHammer => {
                 if self.gun.is_hammer_uncocked() && self.gun.is_cylinder_closed() {
                     // Rotate the cylinder to the next position when we pull the
                     // hammer.
-                    self.gun.rotate_cylinder();
+                    self.gun.rotate_cylinder(weapon.capacity);
 
                     // Start cocking the hammer.
                     self.gun.hammer_state = HammerState::Cocking {
-                        remaining: HAMMER_COCK_MILLIS as f32 / 1000.0,
+                        remaining: timings.hammer_cock_seconds(),
                     };
                 }
             }
@@ -75,14 +370,14 @@ impl Player {
                     match self.gun.cylinder_state {
                         CylinderState::Closed { position } => {
                             self.gun.cylinder_state = CylinderState::Opening {
-                                remaining: CYLINDER_OPEN_MILLIS as f32 / 1000.0,
+                                remaining: timings.cylinder_open_seconds(),
                                 rotation: position as f32,
                             };
                         }
 
                         CylinderState::Open { rotation } => {
                             self.gun.cylinder_state = CylinderState::Closing {
-                                remaining: CYLINDER_OPEN_MILLIS as f32 / 1000.0,
+                                remaining: timings.cylinder_open_seconds(),
                                 rotation,
                             };
                         }
@@ -94,6 +389,10 @@ impl Player {
 
             RevolverAction::LoadCartridge => {
                 if self.gun.is_cylinder_open() {
+                    // Make sure there's a chamber for each round the weapon can hold, and no
+                    // more.
+                    self.gun.cartridges.resize(weapon.capacity, None);
+
                     // Iterate over the chambers and put a fresh cartridge in the first empty one.
                     for chamber in &mut self.gun.cartridges {
                         if chamber.is_none() {
@@ -112,7 +411,7 @@ impl Player {
                     self.gun.cylinder_state = CylinderState::Ejecting {
                         rotation,
                         keyframe: 0,
-                        remaining: EJECT_KEYFRAME_MILLIS[0] as f32 / 1000.0,
+                        remaining: timings.eject_keyframe_seconds(0),
                     };
                 }
             }
@@ -120,6 +419,205 @@ impl Player {
     }
 }
 
+/// Whether a player is alive or dead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LifeState {
+    Alive,
+
+    Dead {
+        /// The time until the player can respawn, in seconds.
+        respawn_remaining: f32,
+    },
+}
+
 impl Component for Player {
     type Storage = DenseVecStorage<Self>;
 }
+
+impl Serialize for Player {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NetPlayer::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Player {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Player, D::Error> {
+        NetPlayer::deserialize(deserializer).map(Player::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tick::TICK_SECONDS;
+    use weapon::test_weapons;
+    use super::*;
+
+    /// Moves a player along flat ground for a second, returning how far they went.
+    fn distance_moved(movement_dir: Vector2<f32>, sprint: bool) -> f32 {
+        let level = CollisionWorld::flat_ground();
+        let input = InputFrame {
+            movement_dir,
+            yaw_delta: 0.0,
+            pitch_delta: 0.0,
+            sprint,
+            jump: false,
+            crouch: false,
+        };
+
+        let mut player = Player::new(0, Point3::origin());
+        for _ in 0 .. 60 {
+            player.step(&input, &level, TICK_SECONDS);
+        }
+
+        Vector2::new(player.position.x, player.position.z).norm()
+    }
+
+    #[test]
+    fn diagonal_is_not_faster() {
+        for &sprint in &[false, true] {
+            let cardinal = distance_moved(Vector2::new(0.0, 1.0), sprint);
+            let diagonal = distance_moved(Vector2::new(1.0, 1.0), sprint);
+            assert!(cardinal > 0.0);
+            assert!(
+                diagonal <= cardinal + 1e-3,
+                "Moved {} diagonally but only {} forwards (sprinting: {})",
+                diagonal,
+                cardinal,
+                sprint,
+            );
+        }
+    }
+
+    #[test]
+    fn damage_kills_at_zero_health() {
+        let mut player = Player::new(0, Point3::origin());
+        assert!(!player.apply_damage(MAX_HEALTH - 1));
+        assert_eq!(1, player.health);
+        assert!(player.is_alive());
+
+        assert!(player.apply_damage(10));
+        assert_eq!(0, player.health);
+        assert_eq!(LifeState::Dead { respawn_remaining: RESPAWN_SECONDS }, player.life);
+
+        // Dead players can't be killed again.
+        assert!(!player.apply_damage(10));
+    }
+
+    #[test]
+    fn respawn_after_delay() {
+        let level = CollisionWorld::flat_ground();
+        let input = InputFrame {
+            movement_dir: Vector2::new(0.0, 1.0),
+            yaw_delta: 1.0,
+            pitch_delta: 0.0,
+            sprint: false,
+            jump: false,
+            crouch: false,
+        };
+
+        let mut player = Player::new(0, Point3::origin());
+        player.yaw = 1.0;
+        player.apply_damage(MAX_HEALTH);
+        assert!(!player.can_respawn());
+
+        // Dead players ignore their input while they wait to respawn.
+        let ticks = (RESPAWN_SECONDS / TICK_SECONDS).round() as usize;
+        for _ in 1 .. ticks {
+            player.step(&input, &level, TICK_SECONDS);
+        }
+        assert!(!player.can_respawn());
+        assert_eq!(Point3::origin(), player.position);
+
+        // Allow an extra tick for rounding errors in the countdown.
+        player.step(&input, &level, TICK_SECONDS);
+        player.step(&input, &level, TICK_SECONDS);
+        assert!(player.can_respawn());
+
+        let spawn_point = Point3::new(5.0, 0.0, 5.0);
+        player.respawn(spawn_point);
+        assert!(player.is_alive());
+        assert!(!player.can_respawn());
+        assert_eq!(MAX_HEALTH, player.health);
+        assert_eq!(spawn_point, player.position);
+        assert_eq!(1.0, player.yaw);
+    }
+
+    /// Returns a player holding a fully loaded `weapon`, with the hammer cocked.
+    fn loaded(weapon: &WeaponDefinition) -> Player {
+        let mut player = Player::new(0, Point3::origin());
+        player.gun.cartridges = vec![Some(Cartridge::Fresh); weapon.capacity];
+        player.handle_revolver_action(RevolverAction::PullHammer, weapon);
+        fire(&mut player, weapon, 60);
+        assert_eq!(HammerState::Cocked, player.gun.hammer_state);
+        player
+    }
+
+    /// Steps the gun for `ticks` ticks, returning how many shots it fired.
+    fn fire(player: &mut Player, weapon: &WeaponDefinition, ticks: usize) -> usize {
+        (0 .. ticks).filter(|_| player.gun.step(TICK_SECONDS, weapon)).count()
+    }
+
+    #[test]
+    fn semi_automatic_fires_once_per_pull() {
+        let weapons = test_weapons();
+        let weapon = weapons.get("pistol").unwrap();
+        assert_eq!(FireMode::SemiAutomatic, weapon.fire_mode);
+
+        let mut player = loaded(weapon);
+        for _ in 0 .. 3 {
+            player.handle_revolver_action(RevolverAction::PullTrigger, weapon);
+            assert_eq!(1, fire(&mut player, weapon, 60));
+
+            // The shot cocks the hammer for the next one, even with the trigger still held.
+            assert_eq!(HammerState::Cocked, player.gun.hammer_state);
+        }
+    }
+
+    #[test]
+    fn recoil_is_deterministic() {
+        let weapons = test_weapons();
+        let weapon = weapons.get("pistol").unwrap();
+
+        let mut player = loaded(weapon);
+        let mut yaws = Vec::new();
+        for _ in 0 .. 2 {
+            player.handle_revolver_action(RevolverAction::PullTrigger, weapon);
+            assert_eq!(1, fire(&mut player, weapon, 60));
+            player.apply_recoil(weapon);
+            yaws.push(player.yaw);
+        }
+
+        // The aim kicks upwards with every shot, and alternates left and right.
+        assert!((2.0 * weapon.recoil.pitch - player.pitch).abs() < 1e-6);
+        assert!((weapon.recoil.yaw - yaws[0].abs()).abs() < 1e-6);
+        assert!(yaws[1].abs() < 1e-6);
+
+        // Recoil can't push the aim past straight up.
+        player.pitch = PI / 2.0 - weapon.recoil.pitch / 2.0;
+        player.apply_recoil(weapon);
+        assert_eq!(PI / 2.0, player.pitch);
+    }
+
+    #[test]
+    fn full_automatic_fires_while_held() {
+        let weapons = test_weapons();
+        let weapon = weapons.get("mp5").unwrap();
+        assert_eq!(FireMode::FullAutomatic, weapon.fire_mode);
+
+        let mut player = loaded(weapon);
+        player.handle_revolver_action(RevolverAction::PullTrigger, weapon);
+        let held = fire(&mut player, weapon, 30);
+        assert!(held > 1, "Only fired {} shots", held);
+
+        // At most the shot already in progress fires after the trigger is released.
+        player.handle_revolver_action(RevolverAction::ReleaseTrigger, weapon);
+        let released = fire(&mut player, weapon, 60);
+        assert!(released <= 1, "Fired {} shots after releasing the trigger", released);
+
+        // Holding the trigger again empties the magazine, and then the weapon stops firing.
+        player.handle_revolver_action(RevolverAction::PullTrigger, weapon);
+        let remaining = fire(&mut player, weapon, 60 * 10);
+        assert_eq!(weapon.capacity, held + released + remaining);
+        assert_eq!(HammerState::Uncocked, player.gun.hammer_state);
+    }
+}
@@ -0,0 +1,222 @@
+//! Simulated bullets.
+//!
+//! Shots don't hit instantly. Each shot fires a projectile that travels at the weapon's muzzle
+//! velocity and drops under `GRAVITY`, so long-range shots need to lead moving targets and aim
+//! above them.
+//!
+//! The server steps every projectile once per tick and checks the segment it travelled during
+//! the tick against the players' hitboxes and the level. Clients simulate the same flight to
+//! draw tracers, but only the server decides what a projectile hits.
+
+use amethyst::ecs::prelude::*;
+use collision::CollisionWorld;
+use hitbox::{self, Hit};
+use math::*;
+use player::{Player, GRAVITY};
+
+/// How long a projectile flies before it's removed, in seconds.
+pub const LIFETIME: f32 = 2.0;
+
+/// A bullet in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Projectile {
+    pub id: u64,
+
+    /// The ID of the player that fired the projectile.
+    pub shooter: u64,
+
+    pub position: Point3<f32>,
+    pub velocity: Vector3<f32>,
+
+    /// The time until the projectile is removed, in seconds.
+    pub remaining: f32,
+}
+
+impl Projectile {
+    /// Creates a projectile fired by `shooter` from `origin` in `direction`, leaving the barrel
+    /// at `muzzle_velocity`.
+    ///
+    /// `direction` must be a unit vector.
+    pub fn new(
+        id: u64,
+        shooter: u64,
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        muzzle_velocity: f32,
+    ) -> Projectile {
+        Projectile {
+            id,
+            shooter,
+            position: origin,
+            velocity: direction * muzzle_velocity,
+            remaining: LIFETIME,
+        }
+    }
+
+    /// Returns `true` if the projectile has run out of time and should be removed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining <= 0.0
+    }
+
+    /// Moves the projectile forward by `delta` seconds, returning the start and end of the
+    /// segment it travelled.
+    pub fn step(&mut self, delta: f32) -> (Point3<f32>, Point3<f32>) {
+        let start = self.position;
+        self.velocity.y -= GRAVITY * delta;
+        self.position += self.velocity * delta;
+        self.remaining -= delta;
+
+        (start, self.position)
+    }
+
+    /// Moves the projectile forward by `delta` seconds and checks whether it hit anything along
+    /// the way.
+    ///
+    /// Returns the closest impact, if any. The projectile should be removed once it has hit
+    /// something.
+    pub fn step_and_collide<'a, I>(
+        &mut self,
+        delta: f32,
+        level: &CollisionWorld,
+        players: I,
+    ) -> Option<Impact>
+    where
+        I: IntoIterator<Item = &'a Player>,
+    {
+        let (start, end) = self.step(delta);
+        let travelled = end - start;
+        let length = travelled.norm();
+        if length <= ::std::f32::EPSILON {
+            return None;
+        }
+
+        // Anything beyond the level geometry in the projectile's path is out of reach.
+        let direction = travelled / length;
+        let wall = level.raycast(start, direction, length);
+        let reach = wall.unwrap_or(length);
+
+        let hit = hitbox::raycast(start, direction, self.shooter, players)
+            .filter(|hit| hit.distance <= reach);
+        if let Some(hit) = hit {
+            return Some(Impact {
+                location: hit.location,
+                hit: Some(hit),
+            });
+        }
+
+        wall.map(|distance| Impact {
+            location: start + direction * distance,
+            hit: None,
+        })
+    }
+}
+
+impl Component for Projectile {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A projectile hitting something.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impact {
+    /// The point where the projectile hit, in world space.
+    pub location: Point3<f32>,
+
+    /// The player that was hit, or `None` if the projectile hit the level.
+    pub hit: Option<Hit>,
+}
+
+#[cfg(test)]
+mod test {
+    use collision::Collider;
+    use hitbox::HitboxKind;
+    use tick::TICK_SECONDS;
+    use super::*;
+
+    const MUZZLE_VELOCITY: f32 = 100.0;
+
+    /// Returns a projectile fired by player 0 from eye height at the origin, along -z.
+    fn fire() -> Projectile {
+        Projectile::new(
+            0,
+            0,
+            Point3::new(0.0, 1.5, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            MUZZLE_VELOCITY,
+        )
+    }
+
+    #[test]
+    fn drops_under_gravity() {
+        let mut projectile = fire();
+        let ticks = 60;
+        for _ in 0 .. ticks {
+            projectile.step(TICK_SECONDS);
+        }
+
+        // The velocity is updated before the position each step, so the projectile falls by
+        // `GRAVITY * TICK_SECONDS^2` times the triangular number of `ticks`.
+        let fall = GRAVITY * TICK_SECONDS * TICK_SECONDS * (ticks * (ticks + 1) / 2) as f32;
+        assert!((1.5 - fall - projectile.position.y).abs() < 1e-3);
+        assert!((-GRAVITY - projectile.velocity.y).abs() < 1e-3);
+        assert!((-MUZZLE_VELOCITY - projectile.position.z).abs() < 1e-3);
+    }
+
+    #[test]
+    fn walls_block_hits() {
+        let players = [Player::new(1, Point3::new(0.0, 0.0, -8.0))];
+        let mut level = CollisionWorld::new();
+
+        // With nothing in the way, a step that covers 10 units reaches the player.
+        let impact = fire().step_and_collide(0.1, &level, &players).unwrap();
+        let hit = impact.hit.unwrap();
+        assert_eq!(1, hit.victim);
+        assert_eq!(HitboxKind::Head, hit.hitbox);
+
+        // A wall between the muzzle and the player stops the projectile first.
+        level.add(Collider::Box {
+            min: Point3::new(-5.0, 0.0, -4.0),
+            max: Point3::new(5.0, 5.0, -3.0),
+        });
+        let impact = fire().step_and_collide(0.1, &level, &players).unwrap();
+        assert_eq!(None, impact.hit);
+        assert!((-3.0 - impact.location.z).abs() < 1e-3);
+    }
+
+    #[test]
+    fn hits_beyond_step_are_ignored() {
+        let players = [Player::new(1, Point3::new(0.0, 0.0, -20.0))];
+        let level = CollisionWorld::new();
+
+        let mut projectile = fire();
+        assert_eq!(None, projectile.step_and_collide(0.1, &level, &players));
+
+        // The next step reaches the player.
+        let impact = projectile.step_and_collide(0.1, &level, &players).unwrap();
+        assert_eq!(1, impact.hit.unwrap().victim);
+    }
+
+    #[test]
+    fn shooter_is_never_hit() {
+        // The projectile starts inside the shooter's head.
+        let players = [Player::new(0, Point3::origin())];
+        let level = CollisionWorld::new();
+
+        let mut projectile = fire();
+        assert_eq!(None, projectile.step_and_collide(TICK_SECONDS, &level, &players));
+    }
+
+    #[test]
+    fn expires_after_lifetime() {
+        let mut projectile = fire();
+        let ticks = (LIFETIME / TICK_SECONDS).round() as usize;
+        for _ in 1 .. ticks {
+            projectile.step(TICK_SECONDS);
+        }
+        assert!(!projectile.is_expired());
+
+        // Allow an extra tick for rounding errors in the countdown.
+        projectile.step(TICK_SECONDS);
+        projectile.step(TICK_SECONDS);
+        assert!(projectile.is_expired());
+    }
+}
@@ -1,12 +1,15 @@
 use std::mem;
+use weapon::{FireMode, WeaponDefinition};
 
-pub const HAMMER_COCK_MILLIS: u64 = 300;
-pub const HAMMER_FALL_MILLIS: u64 = 50;
-pub const CYLINDER_OPEN_MILLIS: u64 = 300;
+/// The largest number of chambers (or rounds in a magazine) a weapon can have.
+///
+/// This limits the size of a weapon's network representation.
+pub const MAX_CAPACITY: usize = 64;
 
-pub static EJECT_KEYFRAME_MILLIS: &[u64] = &[300, 200, 500];
+/// The largest number of keyframes a weapon's eject animation can have.
+pub const MAX_EJECT_KEYFRAMES: usize = 8;
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Revolver {
     /// The current state of the hammer.
     pub hammer_state: HammerState,
@@ -14,24 +17,36 @@ pub struct Revolver {
     /// The current state of the cylinder.
     pub cylinder_state: CylinderState,
 
-    /// The 6 cartridge positions in the cylinder.
+    /// The cartridge positions in the cylinder.
     ///
     /// Each slot can be empty, loaded with a fresh cartridge, or loaded with an empty cartridge.
+    /// Chambers are only added as the weapon is loaded, so any chambers past the end are empty.
     // TODO: Rename me to `chambers`.
-    pub cartridges: [Option<Cartridge>; 6],
+    pub cartridges: Vec<Option<Cartridge>>,
+
+    /// Whether the trigger is being held down. Fully automatic weapons keep firing until it's
+    /// released.
+    pub trigger_held: bool,
 }
 
 impl Revolver {
     /// Step the revolver for a single frame, returning whether or not it was fired.
     ///
     /// Returns `true` if the revolver was fired and a bullet should be spawned, false otherwise.
-    pub fn step(&mut self, delta: f32) -> bool {
+    /// `weapon` determines how long each animation takes, and what happens after each shot.
+    pub fn step(&mut self, delta: f32, weapon: &WeaponDefinition) -> bool {
         // Update the hammer's animation, if necessary.
         let fired = match self.hammer_state {
             HammerState::Cocking { remaining } => {
                 let remaining = remaining - delta;
                 if remaining > 0.0 {
                     self.hammer_state = HammerState::Cocking { remaining };
+                } else if weapon.fire_mode == FireMode::FullAutomatic && self.trigger_held {
+                    // Fully automatic weapons fire again as soon as they're ready, for as long
+                    // as the trigger is held.
+                    self.hammer_state = HammerState::Firing {
+                        remaining: weapon.timings.hammer_fall_seconds(),
+                    };
                 } else {
                     self.hammer_state = HammerState::Cocked;
                 }
@@ -51,6 +66,16 @@ impl Revolver {
                     match self.current_cartridge() {
                         Some(Cartridge::Fresh) => {
                             self.set_current_cartridge(Some(Cartridge::Spent));
+
+                            // Automatic weapons use the energy of the shot to chamber the next
+                            // round and cock the hammer again.
+                            if weapon.fire_mode.is_automatic() {
+                                self.rotate_cylinder(weapon.capacity);
+                                self.hammer_state = HammerState::Cocking {
+                                    remaining: weapon.timings.hammer_cock_seconds(),
+                                };
+                            }
+
                             true
                         }
 
@@ -78,7 +103,7 @@ impl Revolver {
                 if remaining > 0.0 {
                     self.cylinder_state = CylinderState::Closing { remaining, rotation };
                 } else {
-                    let position = rotation.round() as usize % 6;
+                    let position = rotation.round() as usize % weapon.capacity;
                     self.cylinder_state = CylinderState::Closed { position };
                 }
             }
@@ -88,22 +113,24 @@ impl Revolver {
                 if remaining > 0.0 {
                     self.cylinder_state = CylinderState::Ejecting { rotation, keyframe, remaining };
                 } else {
+                    let keyframes = weapon.timings.eject_keyframe_millis.len();
                     let keyframe = keyframe + 1;
-                    if keyframe < EJECT_KEYFRAME_MILLIS.len() {
 
-                        // After the pause in the middle of the animation, officially remove
-                        // all cartridges from the cylinder.
-                        if keyframe == 2 {
-                            self.cartridges = [None; 6];
-                        }
+                    // Once the animation reaches its last keyframe (e.g. after the pause in the
+                    // middle of the revolver's animation), officially remove all cartridges from
+                    // the cylinder.
+                    if keyframe + 1 >= keyframes {
+                        self.cartridges.clear();
+                    }
 
+                    if keyframe < keyframes {
                         // Continue to the next keyframe of the eject animation.
                         self.cylinder_state = CylinderState::Ejecting {
                             rotation,
                             keyframe,
 
                             // TODO: Apply overflow to the progress of the next keyframe.
-                            remaining: EJECT_KEYFRAME_MILLIS[keyframe] as f32 / 1000.0,
+                            remaining: weapon.timings.eject_keyframe_seconds(keyframe),
                         }
                     } else {
                         // The eject animation is done, so return to the open state.
@@ -118,14 +145,14 @@ impl Revolver {
         fired
     }
 
-    /// Rotates the cylinder to the next position.
-    pub fn rotate_cylinder(&mut self) {
+    /// Rotates the cylinder to the next of the weapon's `capacity` positions.
+    pub fn rotate_cylinder(&mut self, capacity: usize) {
         let position = match self.cylinder_state {
             CylinderState::Closed { position } => position,
             _ => panic!("Can only rotate a closed cylinder: {:?}", self.cylinder_state),
         };
 
-        self.cylinder_state = CylinderState::Closed { position: (position + 1) % 6 };
+        self.cylinder_state = CylinderState::Closed { position: (position + 1) % capacity };
     }
 
     /// Returns the state of the currently active cartridge (according to `cylinder_position`).
@@ -135,7 +162,13 @@ impl Revolver {
             _ => panic!("Cannot get current cartridge, cylinder is not closed: {:?}", self.cylinder_state),
         };
 
-        self.cartridges[position]
+        self.chamber(position)
+    }
+
+    /// Returns the state of the chamber at `index`, which is empty if it's past the end of
+    /// `cartridges`.
+    pub fn chamber(&self, index: usize) -> Option<Cartridge> {
+        self.cartridges.get(index).cloned().unwrap_or(None)
     }
 
     /// Sets the state of the currently active cartridge, returning the previous state.
@@ -146,9 +179,68 @@ impl Revolver {
             _ => panic!("Can only rotate a closed cylinder: {:?}", self.cylinder_state),
         };
 
+        if position >= self.cartridges.len() {
+            self.cartridges.resize(position + 1, None);
+        }
+
         mem::replace(&mut self.cartridges[position], cartridge)
     }
 
+    /// Blends between two states of the revolver for smooth animation.
+    ///
+    /// `t` is the blend factor in the range [0, 1], where 0 gives `self` and 1 gives `other`.
+    /// The progress of animations is blended if both states are in the same stage of the same
+    /// animation. Otherwise there's nothing meaningful to blend, so whichever state is closer
+    /// is used.
+    pub fn interpolate(&self, other: &Revolver, t: f32) -> Revolver {
+        let nearest = if t < 0.5 { self } else { other };
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+
+        let hammer_state = match (self.hammer_state, other.hammer_state) {
+            (HammerState::Cocking { remaining: from }, HammerState::Cocking { remaining: to }) => {
+                HammerState::Cocking { remaining: lerp(from, to) }
+            }
+
+            (HammerState::Firing { remaining: from }, HammerState::Firing { remaining: to }) => {
+                HammerState::Firing { remaining: lerp(from, to) }
+            }
+
+            _ => nearest.hammer_state,
+        };
+
+        let cylinder_state = match (self.cylinder_state, other.cylinder_state) {
+            (
+                CylinderState::Opening { remaining: from, rotation },
+                CylinderState::Opening { remaining: to, .. },
+            ) => {
+                CylinderState::Opening { remaining: lerp(from, to), rotation }
+            }
+
+            (
+                CylinderState::Closing { remaining: from, rotation },
+                CylinderState::Closing { remaining: to, .. },
+            ) => {
+                CylinderState::Closing { remaining: lerp(from, to), rotation }
+            }
+
+            (
+                CylinderState::Ejecting { remaining: from, rotation, keyframe },
+                CylinderState::Ejecting { remaining: to, keyframe: other_keyframe, .. },
+            ) if keyframe == other_keyframe => {
+                CylinderState::Ejecting { remaining: lerp(from, to), rotation, keyframe }
+            }
+
+            _ => nearest.cylinder_state,
+        };
+
+        Revolver {
+            hammer_state,
+            cylinder_state,
+            cartridges: nearest.cartridges.clone(),
+            trigger_held: nearest.trigger_held,
+        }
+    }
+
     /// Returns `true` if the hammer is fully cocked.
     ///
     /// If the hammer is animating or uncocked, this returns `false`.
@@ -213,6 +305,7 @@ pub enum Cartridge {
 pub enum RevolverAction {
     PullHammer,
     PullTrigger,
+    ReleaseTrigger,
     ToggleCylinder,
     LoadCartridge,
     EjectCartridges,
@@ -0,0 +1,365 @@
+//! Delta compression for world snapshots.
+//!
+//! Sending the full state of the world to every client every tick is wasteful, since most of
+//! the world doesn't change from one tick to the next. Instead, the server encodes each snapshot
+//! as a delta against the most recent snapshot that the client has acknowledged receiving, only
+//! sending the fields that have changed since then. If the client hasn't acknowledged any
+//! snapshot that the server still remembers, the full snapshot is sent instead.
+//!
+//! Both sides keep a `SnapshotHistory` of recent snapshots: The server uses it to find the
+//! baseline to encode against, and the client uses it to find the baseline to decode against.
+
+use net::{NetLifeState, NetPlayer, NetRevolver};
+use player::Player;
+use std::collections::{HashMap, VecDeque};
+use tick::Tick;
+use {ServerMessageBody, World};
+
+/// The maximum number of snapshots kept in a `SnapshotHistory`.
+///
+/// At 60 ticks per second, this is a little over a second of history. If a client hasn't
+/// acknowledged a snapshot in that time, the server falls back to sending full snapshots.
+pub const MAX_SNAPSHOT_HISTORY: usize = 64;
+
+/// The changes to the world since a baseline snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorldDelta {
+    /// The server tick of the snapshot that this delta is relative to.
+    pub baseline: Tick,
+
+    /// Players that have been added since the baseline.
+    pub added: HashMap<u64, Player>,
+
+    /// Players that have changed since the baseline. Players that haven't changed are omitted.
+    pub changed: HashMap<u64, PlayerDelta>,
+
+    /// Players that have been removed since the baseline.
+    pub removed: Vec<u64>,
+}
+
+impl WorldDelta {
+    /// Creates the delta that transforms `baseline` into `world`.
+    pub fn between(baseline_tick: Tick, baseline: &World, world: &World) -> WorldDelta {
+        let mut added = HashMap::new();
+        let mut changed = HashMap::new();
+        for (&id, player) in &world.players {
+            match baseline.players.get(&id) {
+                Some(base) => {
+                    if let Some(delta) = PlayerDelta::between(base, player) {
+                        changed.insert(id, delta);
+                    }
+                }
+
+                None => {
+                    added.insert(id, player.clone());
+                }
+            }
+        }
+
+        let removed = baseline
+            .players
+            .keys()
+            .filter(|id| !world.players.contains_key(id))
+            .cloned()
+            .collect();
+
+        WorldDelta {
+            baseline: baseline_tick,
+            added,
+            changed,
+            removed,
+        }
+    }
+
+    /// Applies the delta to `baseline`, returning the resulting world.
+    pub fn apply(&self, baseline: &World) -> World {
+        let mut world = baseline.clone();
+
+        for id in &self.removed {
+            world.players.remove(id);
+        }
+
+        for (id, delta) in &self.changed {
+            match world.players.get_mut(id) {
+                Some(player) => delta.apply(player),
+                None => warn!("Received delta for player {:#x} not in baseline", id),
+            }
+        }
+
+        for (&id, player) in &self.added {
+            world.players.insert(id, player.clone());
+        }
+
+        world
+    }
+
+    /// Returns `true` if nothing changed since the baseline.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The fields of a player that have changed since a baseline snapshot.
+///
+/// Fields use the same quantized representation as `NetPlayer`, and are `None` if they haven't
+/// changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    pub position: Option<[i16; 3]>,
+    pub velocity: Option<[i16; 3]>,
+    pub grounded: Option<bool>,
+    pub crouching: Option<bool>,
+    pub yaw: Option<u16>,
+    pub pitch: Option<i16>,
+    pub gun: Option<NetRevolver>,
+    pub health: Option<u16>,
+    pub life: Option<NetLifeState>,
+}
+
+impl PlayerDelta {
+    /// Creates the delta that transforms `baseline` into `player`.
+    ///
+    /// Returns `None` if the player hasn't changed.
+    pub fn between(baseline: &Player, player: &Player) -> Option<PlayerDelta> {
+        // Compare the quantized states, so that changes too small to be sent are ignored.
+        let baseline = NetPlayer::from(baseline);
+        let player = NetPlayer::from(player);
+        if baseline == player {
+            return None;
+        }
+
+        fn changed<T: PartialEq + Clone>(from: &T, to: &T) -> Option<T> {
+            if from != to { Some(to.clone()) } else { None }
+        }
+
+        Some(PlayerDelta {
+            position: changed(&baseline.position, &player.position),
+            velocity: changed(&baseline.velocity, &player.velocity),
+            grounded: changed(&baseline.grounded, &player.grounded),
+            crouching: changed(&baseline.crouching, &player.crouching),
+            yaw: changed(&baseline.yaw, &player.yaw),
+            pitch: changed(&baseline.pitch, &player.pitch),
+            gun: changed(&baseline.gun, &player.gun),
+            health: changed(&baseline.health, &player.health),
+            life: changed(&baseline.life, &player.life),
+        })
+    }
+
+    /// Applies the changed fields to `player`.
+    pub fn apply(&self, player: &mut Player) {
+        let mut net_player = NetPlayer::from(&*player);
+
+        if let Some(position) = self.position {
+            net_player.position = position;
+        }
+
+        if let Some(velocity) = self.velocity {
+            net_player.velocity = velocity;
+        }
+
+        if let Some(grounded) = self.grounded {
+            net_player.grounded = grounded;
+        }
+
+        if let Some(crouching) = self.crouching {
+            net_player.crouching = crouching;
+        }
+
+        if let Some(yaw) = self.yaw {
+            net_player.yaw = yaw;
+        }
+
+        if let Some(pitch) = self.pitch {
+            net_player.pitch = pitch;
+        }
+
+        if let Some(ref gun) = self.gun {
+            net_player.gun = gun.clone();
+        }
+
+        if let Some(health) = self.health {
+            net_player.health = health;
+        }
+
+        if let Some(life) = self.life {
+            net_player.life = life;
+        }
+
+        *player = net_player.into();
+    }
+}
+
+/// A history of recent world snapshots, keyed by the server tick they were sent on.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(Tick, World)>,
+}
+
+impl SnapshotHistory {
+    /// Records the snapshot for `tick`, discarding the oldest snapshot if the history is full.
+    ///
+    /// Snapshots must be recorded in order. Snapshots older than the most recent one are
+    /// ignored.
+    pub fn push(&mut self, tick: Tick, world: World) {
+        if let Some(&(latest, _)) = self.snapshots.back() {
+            if tick <= latest {
+                return;
+            }
+        }
+
+        if self.snapshots.len() >= MAX_SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back((tick, world));
+    }
+
+    /// Returns the tick of the most recent snapshot in the history.
+    pub fn latest_tick(&self) -> Option<Tick> {
+        self.snapshots.back().map(|&(tick, _)| tick)
+    }
+
+    /// Returns the snapshot for `tick`, if it's still in the history.
+    pub fn get(&self, tick: Tick) -> Option<&World> {
+        self.snapshots
+            .iter()
+            .find(|&&(snapshot_tick, _)| snapshot_tick == tick)
+            .map(|&(_, ref world)| world)
+    }
+
+    /// Discards all snapshots older than `tick`.
+    ///
+    /// Used by the server once a client acknowledges `tick`, since the client will never use
+    /// an older snapshot as a baseline again.
+    pub fn discard_before(&mut self, tick: Tick) {
+        while self.snapshots.front().map(|&(oldest, _)| oldest < tick).unwrap_or(false) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Encodes the snapshot for `tick` to be sent to a client, recording it in the history.
+    ///
+    /// `acknowledged` is the most recent tick that the client has acknowledged receiving. If
+    /// the snapshot for that tick is still in the history, the world is encoded as a delta
+    /// against it. Otherwise the full world is sent.
+    pub fn encode(
+        &mut self,
+        tick: Tick,
+        world: World,
+        acknowledged: Option<Tick>,
+    ) -> ServerMessageBody {
+        let body = match acknowledged.and_then(|acknowledged| {
+            self.get(acknowledged).map(|baseline| (acknowledged, baseline))
+        }) {
+            Some((baseline_tick, baseline)) => {
+                let delta = WorldDelta::between(baseline_tick, baseline, &world);
+                ServerMessageBody::WorldDelta(delta)
+            }
+
+            None => ServerMessageBody::WorldUpdate(world.clone()),
+        };
+
+        self.push(tick, world);
+        body
+    }
+
+    /// Decodes a delta snapshot received on `tick`, recording the result in the history.
+    ///
+    /// Returns `None` if the baseline for the delta is no longer in the history, in which case
+    /// the snapshot can't be decoded and has to be discarded.
+    pub fn decode(&mut self, tick: Tick, delta: &WorldDelta) -> Option<World> {
+        let world = delta.apply(self.get(delta.baseline)?);
+        self.push(tick, world.clone());
+        Some(world)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use math::*;
+    use super::*;
+
+    // Players are quantized so that they survive being sent exactly.
+    fn player(id: u64, x: f32) -> Player {
+        let mut player = Player::new(id, Point3::new(x, 0.0, 0.0));
+        player.quantize();
+        player
+    }
+
+    fn world(players: Vec<Player>) -> World {
+        World {
+            players: players.into_iter().map(|player| (player.id, player)).collect(),
+        }
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        let baseline = world(vec![player(1, 0.0), player(2, 0.0), player(3, 0.0)]);
+        let mut moved = player(1, 2.5);
+        moved.health = 40;
+        let current = world(vec![moved, player(2, 0.0), player(4, 1.0)]);
+
+        let delta = WorldDelta::between(Tick(7), &baseline, &current);
+        assert_eq!(Tick(7), delta.baseline);
+        assert_eq!(vec![1], delta.changed.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(vec![4], delta.added.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(vec![3], delta.removed);
+
+        // Only the fields that changed are sent.
+        let changed = &delta.changed[&1];
+        assert!(changed.position.is_some());
+        assert!(changed.health.is_some());
+        assert!(changed.velocity.is_none());
+        assert!(changed.gun.is_none());
+
+        assert_eq!(current.players, delta.apply(&baseline).players);
+    }
+
+    #[test]
+    fn unchanged_world_is_empty() {
+        let baseline = world(vec![player(1, 3.0)]);
+        assert!(WorldDelta::between(Tick(0), &baseline, &baseline.clone()).is_empty());
+    }
+
+    #[test]
+    fn encode_against_acknowledged_baseline() {
+        let mut server = SnapshotHistory::default();
+        let mut client = SnapshotHistory::default();
+
+        // Nothing has been acknowledged yet, so the first snapshot is sent in full.
+        let first = world(vec![player(1, 0.0), player(2, 0.0)]);
+        match server.encode(Tick(1), first.clone(), None) {
+            ServerMessageBody::WorldUpdate(world) => { client.push(Tick(1), world); }
+            body => panic!("Expected a full update, got {:?}", body),
+        }
+
+        // Once the client acknowledges it, later snapshots are sent as deltas against it.
+        let second = world(vec![player(1, 1.0), player(3, 0.0)]);
+        let delta = match server.encode(Tick(2), second.clone(), Some(Tick(1))) {
+            ServerMessageBody::WorldDelta(delta) => delta,
+            body => panic!("Expected a delta, got {:?}", body),
+        };
+        assert_eq!(Tick(1), delta.baseline);
+
+        let decoded = client.decode(Tick(2), &delta).expect("Baseline is missing");
+        assert_eq!(second.players, decoded.players);
+        assert_eq!(Some(Tick(2)), client.latest_tick());
+
+        // If the acknowledged snapshot has been discarded, the full world is sent instead.
+        server.discard_before(Tick(2));
+        match server.encode(Tick(3), second.clone(), Some(Tick(1))) {
+            ServerMessageBody::WorldUpdate(world) => assert_eq!(second.players, world.players),
+            body => panic!("Expected a full update, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn decode_without_baseline() {
+        let mut client = SnapshotHistory::default();
+        client.push(Tick(1), world(vec![player(1, 0.0)]));
+
+        let delta = WorldDelta::between(Tick(5), &World::new(), &world(vec![player(1, 0.0)]));
+        assert!(client.decode(Tick(6), &delta).is_none());
+        assert_eq!(Some(Tick(1)), client.latest_tick());
+    }
+}
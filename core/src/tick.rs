@@ -0,0 +1,186 @@
+//! Fixed-timestep simulation ticks.
+//!
+//! The game simulation always advances in fixed steps of `TICK_SECONDS`, regardless of how fast
+//! the client or server is rendering frames. This keeps the results of the simulation the same on
+//! every machine, which is necessary for the client's predictions to match the server.
+//!
+//! Each frame, `TickClock::advance` is given the length of the frame and determines how many
+//! ticks need to be simulated to keep up with real time. Time left over that isn't enough for a
+//! full tick carries over to the next frame.
+
+use std::ops::Range;
+
+/// The number of simulation ticks per second.
+pub const TICKS_PER_SECOND: u32 = 60;
+
+/// The length of a single simulation tick, in seconds.
+pub const TICK_SECONDS: f32 = 1.0 / TICKS_PER_SECOND as f32;
+
+/// The maximum number of ticks simulated in a single frame.
+///
+/// If a frame takes so long that more ticks than this are needed to catch up, the extra time is
+/// dropped rather than trying to catch up. Otherwise a slow frame could cause the next frame to
+/// be even slower, and the simulation would never recover.
+pub const MAX_TICKS_PER_FRAME: usize = 8;
+
+/// Identifies a single simulation tick.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+pub struct Tick(pub usize);
+
+/// Tracks the current simulation tick, and accumulates frame time to determine when new ticks
+/// need to be simulated.
+#[derive(Debug, Clone)]
+pub struct TickClock {
+    /// The next tick to be simulated.
+    next: Tick,
+
+    /// The ticks to be simulated during the current frame.
+    pending: Range<usize>,
+
+    /// Frame time, in seconds, that hasn't yet been simulated.
+    accumulator: f32,
+
+    /// How fast the clock runs relative to real time.
+    time_scale: f32,
+}
+
+impl Default for TickClock {
+    fn default() -> TickClock {
+        TickClock {
+            next: Tick::default(),
+            pending: 0..0,
+            accumulator: 0.0,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl TickClock {
+    /// Adds `delta` seconds of frame time, and determines which ticks need to be simulated
+    /// during the current frame.
+    ///
+    /// Returns the number of ticks to simulate. The ticks themselves can be retrieved later in
+    /// the frame with `pending`.
+    pub fn advance(&mut self, delta: f32) -> usize {
+        self.accumulator += delta * self.time_scale;
+
+        let mut count = 0;
+        while self.accumulator >= TICK_SECONDS && count < MAX_TICKS_PER_FRAME {
+            self.accumulator -= TICK_SECONDS;
+            count += 1;
+        }
+
+        // Drop any time we couldn't catch up on.
+        if count == MAX_TICKS_PER_FRAME && self.accumulator >= TICK_SECONDS {
+            warn!("Simulation fell behind, dropping {}s", self.accumulator);
+            self.accumulator = 0.0;
+        }
+
+        let start = self.next.0;
+        self.next = Tick(start + count);
+        self.pending = start..self.next.0;
+        count
+    }
+
+    /// Returns the ticks to be simulated during the current frame.
+    ///
+    /// Yields nothing if the frame was too short for a full tick to elapse.
+    pub fn pending(&self) -> impl Iterator<Item = Tick> {
+        self.pending.clone().map(Tick)
+    }
+
+    /// Returns the most recently simulated tick.
+    pub fn current(&self) -> Tick {
+        Tick(self.next.0.saturating_sub(1))
+    }
+
+    /// Sets how fast the clock runs relative to real time.
+    ///
+    /// The client uses this to run slightly faster or slower than the server, so that its input
+    /// arrives at the server just before it's needed. See `InputBufferHealth`.
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale;
+    }
+}
+
+/// How many ticks of a client's input the server has buffered, sent back to the client with
+/// every message.
+///
+/// The server buffers a few ticks of input for each client to absorb jitter in when the input
+/// arrives. If the buffer runs low, the client's input is arriving too late and the client should
+/// run its clock faster. If the buffer is larger than needed, the client is adding unnecessary
+/// latency and should run its clock slower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct InputBufferHealth {
+    /// The number of ticks of input currently buffered.
+    pub buffered: u16,
+
+    /// The number of ticks of input the server wants buffered.
+    pub target: u16,
+}
+
+impl InputBufferHealth {
+    /// Returns the time scale the client should run its clock at to bring the buffer back to
+    /// its target size.
+    pub fn time_scale(&self) -> f32 {
+        if self.buffered < self.target {
+            1.05
+        } else if self.buffered > self.target + 1 {
+            0.95
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leftover_time_carries_over() {
+        let mut clock = TickClock::default();
+        assert_eq!(0, clock.advance(TICK_SECONDS * 0.5));
+        assert_eq!(0, clock.pending().count());
+
+        assert_eq!(1, clock.advance(TICK_SECONDS * 0.6));
+        assert_eq!(vec![Tick(0)], clock.pending().collect::<Vec<_>>());
+
+        assert_eq!(2, clock.advance(TICK_SECONDS * 2.0));
+        assert_eq!(vec![Tick(1), Tick(2)], clock.pending().collect::<Vec<_>>());
+        assert_eq!(Tick(2), clock.current());
+    }
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let mut clock = TickClock::default();
+        assert_eq!(MAX_TICKS_PER_FRAME, clock.advance(1.0));
+        assert_eq!(
+            (0 .. MAX_TICKS_PER_FRAME).map(Tick).collect::<Vec<_>>(),
+            clock.pending().collect::<Vec<_>>(),
+        );
+
+        // The time that couldn't be simulated is dropped rather than caught up on later.
+        assert_eq!(0, clock.advance(0.0));
+        assert_eq!(1, clock.advance(TICK_SECONDS));
+        assert_eq!(Tick(MAX_TICKS_PER_FRAME), clock.current());
+    }
+
+    #[test]
+    fn time_scale() {
+        let mut clock = TickClock::default();
+        clock.set_time_scale(2.0);
+        assert_eq!(2, clock.advance(TICK_SECONDS));
+
+        let health = InputBufferHealth { buffered: 1, target: 3 };
+        assert!(health.time_scale() > 1.0);
+
+        let health = InputBufferHealth { buffered: 3, target: 3 };
+        assert_eq!(1.0, health.time_scale());
+
+        let health = InputBufferHealth { buffered: 6, target: 3 };
+        assert!(health.time_scale() < 1.0);
+    }
+}
@@ -0,0 +1,390 @@
+//! Data-driven weapon definitions.
+//!
+//! Each weapon is described by a RON file in `assets/weapons`, named after the weapon. The
+//! client and server both load the same files: The server uses them to simulate the weapons,
+//! and the client uses them to predict the local player's weapon and to time animations. The
+//! server sends its definition of the weapon in use when a client connects (see
+//! `ServerMessageBody::Init`), and the client uses it in place of its own so that its
+//! predictions match the server.
+//!
+//! Every weapon is operated using the revolver's mechanics: The hammer is cocked, the trigger
+//! fires the round in the current chamber, and the cylinder is opened to reload. Magazine-fed
+//! weapons treat the magazine as the cylinder, with one chamber per round, and use the fire mode
+//! to cycle to the next round after each shot. The definition controls how many rounds the
+//! weapon holds, how long each step takes and what happens when the weapon fires.
+
+use hitbox::HitboxKind;
+use math::*;
+use revolver::{MAX_CAPACITY, MAX_EJECT_KEYFRAMES};
+use ron;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// The weapon every player carries.
+///
+/// Players can't switch weapons yet, so this weapon must always be defined.
+pub const DEFAULT_WEAPON: &str = "revolver";
+
+/// A single weapon, loaded from `assets/weapons/<name>.ron`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeaponDefinition {
+    /// The name shown to players.
+    pub display_name: String,
+
+    /// The number of rounds the weapon can be loaded with, up to `MAX_CAPACITY`.
+    pub capacity: usize,
+
+    pub fire_mode: FireMode,
+    pub timings: WeaponTimings,
+    pub damage: Damage,
+
+    /// The maximum angle, in radians, that a shot can stray from where the player is aiming.
+    pub spread: f32,
+
+    /// The number of projectiles fired by each shot.
+    pub pellets: u32,
+
+    /// The speed of projectiles when they leave the barrel, in units per second.
+    pub muzzle_velocity: f32,
+
+    pub recoil: Recoil,
+}
+
+impl WeaponDefinition {
+    /// Returns the direction of a shot fired towards `direction`, randomly deviated by up to
+    /// `spread`.
+    ///
+    /// `angle` and `offset` are random numbers in the range [0, 1), used to pick where in the
+    /// cone of spread the shot goes. `direction` must be a unit vector.
+    pub fn spread_direction(
+        &self,
+        direction: Vector3<f32>,
+        angle: f32,
+        offset: f32,
+    ) -> Vector3<f32> {
+        if self.spread <= 0.0 {
+            return direction;
+        }
+
+        // Find an axis perpendicular to the direction, then tilt the direction away from it by
+        // up to the spread, rotated by a random angle around the direction.
+        let up = if direction.y.abs() < 0.99 {
+            Vector3::new(0.0, 1.0, 0.0)
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        let perpendicular = direction.cross(&up).normalize();
+        let tilt = Rotation3::new(perpendicular * self.spread * offset);
+        let roll = Rotation3::new(direction * TAU * angle);
+        roll * tilt * direction
+    }
+
+    /// Checks that the definition can be used with the implemented weapon mechanics.
+    fn validate(&self, name: &str) -> Result<(), WeaponError> {
+        let invalid = |reason: String| {
+            Err(WeaponError::Invalid {
+                name: name.into(),
+                reason,
+            })
+        };
+
+        if self.capacity == 0 || self.capacity > MAX_CAPACITY {
+            return invalid(format!(
+                "Capacity must be between 1 and {}, but is {}",
+                MAX_CAPACITY,
+                self.capacity,
+            ));
+        }
+
+        let keyframes = self.timings.eject_keyframe_millis.len();
+        if keyframes == 0 || keyframes > MAX_EJECT_KEYFRAMES {
+            return invalid(format!(
+                "Expected between 1 and {} eject keyframes, found {}",
+                MAX_EJECT_KEYFRAMES,
+                keyframes,
+            ));
+        }
+
+        // Animations are timed as a fraction of their duration, so none of them can be instant.
+        let timings = &self.timings;
+        let is_zero = timings.hammer_cock_millis == 0
+            || timings.hammer_fall_millis == 0
+            || timings.cylinder_open_millis == 0
+            || timings.eject_keyframe_millis.contains(&0);
+        if is_zero {
+            return invalid("Timings must be greater than 0".into());
+        }
+
+        if self.pellets == 0 {
+            return invalid("Weapons must fire at least one pellet".into());
+        }
+
+        if !self.spread.is_finite() || self.spread < 0.0 {
+            return invalid(format!("Spread must be 0 or more, but is {}", self.spread));
+        }
+
+        if !self.muzzle_velocity.is_finite() || self.muzzle_velocity <= 0.0 {
+            return invalid(format!(
+                "Muzzle velocity must be greater than 0, but is {}",
+                self.muzzle_velocity,
+            ));
+        }
+
+        if !self.recoil.pitch.is_finite() || !self.recoil.yaw.is_finite() {
+            return invalid(format!("Recoil must be finite, but is {:?}", self.recoil));
+        }
+
+        Ok(())
+    }
+}
+
+/// How pulling the trigger fires the weapon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FireMode {
+    /// The hammer has to be cocked by hand before pulling the trigger will fire.
+    SingleAction,
+
+    /// Pulling the trigger cocks the hammer and then fires, so the weapon can be fired without
+    /// cocking it first. The hammer can still be cocked by hand for a faster shot.
+    DoubleAction,
+
+    /// Each shot chambers the next round and cocks the hammer, so the weapon fires once each
+    /// time the trigger is pulled. The hammer has to be cocked by hand for the first shot.
+    SemiAutomatic,
+
+    /// Like `SemiAutomatic`, but the weapon keeps firing for as long as the trigger is held.
+    FullAutomatic,
+}
+
+impl FireMode {
+    /// Returns `true` if firing cocks the weapon for the next shot.
+    pub fn is_automatic(self) -> bool {
+        match self {
+            FireMode::SemiAutomatic | FireMode::FullAutomatic => true,
+            FireMode::SingleAction | FireMode::DoubleAction => false,
+        }
+    }
+}
+
+/// How long each of the weapon's actions take, in milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeaponTimings {
+    /// Cocking the hammer. For automatic weapons, this is also how long it takes to chamber the
+    /// next round after each shot.
+    pub hammer_cock_millis: u64,
+
+    /// From the hammer falling to the shot firing.
+    pub hammer_fall_millis: u64,
+
+    /// Opening or closing the cylinder.
+    pub cylinder_open_millis: u64,
+
+    /// Each stage of ejecting the spent cartridges.
+    pub eject_keyframe_millis: Vec<u64>,
+}
+
+impl WeaponTimings {
+    pub fn hammer_cock_seconds(&self) -> f32 {
+        self.hammer_cock_millis as f32 / 1000.0
+    }
+
+    pub fn hammer_fall_seconds(&self) -> f32 {
+        self.hammer_fall_millis as f32 / 1000.0
+    }
+
+    pub fn cylinder_open_seconds(&self) -> f32 {
+        self.cylinder_open_millis as f32 / 1000.0
+    }
+
+    /// Returns the length of `keyframe` of the eject animation.
+    ///
+    /// Keyframes past the end of the animation are treated as the last keyframe.
+    pub fn eject_keyframe_seconds(&self, keyframe: usize) -> f32 {
+        let last = self.eject_keyframe_millis.len().saturating_sub(1);
+        self.eject_keyframe_millis.get(keyframe.min(last)).cloned().unwrap_or(0) as f32 / 1000.0
+    }
+}
+
+/// The damage dealt by each projectile, depending on which part of a player it hits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Damage {
+    pub head: u32,
+    pub body: u32,
+}
+
+impl Damage {
+    pub fn for_hitbox(&self, hitbox: HitboxKind) -> u32 {
+        match hitbox {
+            HitboxKind::Head => self.head,
+            HitboxKind::Body => self.body,
+        }
+    }
+}
+
+/// How far the player's aim is knocked off target each time the weapon fires, in radians.
+///
+/// Recoil isn't random, so that clients can predict it along with the rest of their player's
+/// state. See `Player::apply_recoil`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Recoil {
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+/// All of the available weapon definitions, keyed by name.
+#[derive(Debug, Clone)]
+pub struct Weapons {
+    definitions: HashMap<String, WeaponDefinition>,
+}
+
+impl Weapons {
+    /// Loads every weapon definition in `dir`.
+    ///
+    /// Each `.ron` file in the directory defines one weapon, named after the file.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Weapons, WeaponError> {
+        let mut definitions = HashMap::new();
+        for entry in fs::read_dir(dir.as_ref())? {
+            let path = entry?.path();
+            if path.extension().map(|extension| extension != "ron").unwrap_or(true) {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            let definition: WeaponDefinition = ron::de::from_reader(File::open(&path)?)
+                .map_err(|error| WeaponError::Parse { name: name.clone(), error })?;
+            definition.validate(&name)?;
+
+            trace!("Loaded weapon {}: {:?}", name, definition);
+            definitions.insert(name, definition);
+        }
+
+        if !definitions.contains_key(DEFAULT_WEAPON) {
+            return Err(WeaponError::MissingDefault);
+        }
+
+        Ok(Weapons { definitions })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&WeaponDefinition> {
+        self.definitions.get(name)
+    }
+
+    /// Adds the definition for `name`, replacing any existing definition.
+    ///
+    /// Used by the client to replace its definitions with the ones the server is using. Fails
+    /// if the definition is invalid.
+    pub fn insert(&mut self, name: &str, definition: WeaponDefinition) -> Result<(), WeaponError> {
+        definition.validate(name)?;
+        self.definitions.insert(name.into(), definition);
+        Ok(())
+    }
+
+    /// Returns the definition for `DEFAULT_WEAPON`.
+    pub fn default_weapon(&self) -> &WeaponDefinition {
+        &self.definitions[DEFAULT_WEAPON]
+    }
+}
+
+/// Loads the weapon definitions in the repository's `assets/weapons` directory, panicking if
+/// they can't be loaded.
+///
+/// Used by tests in every crate, which can't rely on the working directory they're run from.
+#[doc(hidden)]
+pub fn test_weapons() -> Weapons {
+    Weapons::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../assets/weapons"))
+        .expect("Failed to load weapons")
+}
+
+/// An error loading weapon definitions.
+#[derive(Debug, Fail)]
+pub enum WeaponError {
+    /// A definition file couldn't be read.
+    #[fail(display = "Failed to read weapon definitions: {}", _0)]
+    Io(#[cause] io::Error),
+
+    /// A definition file isn't valid RON, or doesn't match the definition format.
+    #[fail(display = "Failed to parse definition for weapon {}: {}", name, error)]
+    Parse {
+        name: String,
+
+        #[cause]
+        error: ron::de::Error,
+    },
+
+    /// A definition describes a weapon that can't be simulated.
+    #[fail(display = "Invalid definition for weapon {}: {}", name, reason)]
+    Invalid { name: String, reason: String },
+
+    /// There's no definition for `DEFAULT_WEAPON`.
+    #[fail(display = "No definition found for the default weapon, {}", DEFAULT_WEAPON)]
+    MissingDefault,
+}
+
+impl From<io::Error> for WeaponError {
+    fn from(error: io::Error) -> WeaponError {
+        WeaponError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32;
+    use super::*;
+
+    #[test]
+    fn every_weapon_loads() {
+        let weapons = test_weapons();
+        for name in &["revolver", "shotgun", "mp5", "uzi", "sniper", "pistol"] {
+            assert!(weapons.get(name).is_some(), "Missing {}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let mut weapons = test_weapons();
+        let valid = weapons.default_weapon().clone();
+
+        let invalid: &[fn(&mut WeaponDefinition)] = &[
+            |weapon| weapon.capacity = 0,
+            |weapon| weapon.capacity = MAX_CAPACITY + 1,
+            |weapon| weapon.timings.hammer_cock_millis = 0,
+            |weapon| weapon.timings.hammer_fall_millis = 0,
+            |weapon| weapon.timings.cylinder_open_millis = 0,
+            |weapon| weapon.timings.eject_keyframe_millis = Vec::new(),
+            |weapon| weapon.timings.eject_keyframe_millis = vec![100; MAX_EJECT_KEYFRAMES + 1],
+            |weapon| weapon.timings.eject_keyframe_millis[1] = 0,
+            |weapon| weapon.pellets = 0,
+            |weapon| weapon.spread = -0.1,
+            |weapon| weapon.spread = f32::NAN,
+            |weapon| weapon.muzzle_velocity = 0.0,
+            |weapon| weapon.muzzle_velocity = f32::INFINITY,
+            |weapon| weapon.recoil.pitch = f32::NAN,
+        ];
+        for modify in invalid {
+            let mut weapon = valid.clone();
+            modify(&mut weapon);
+            assert!(weapons.insert(DEFAULT_WEAPON, weapon.clone()).is_err(), "{:?}", weapon);
+        }
+
+        // Rejected definitions must not replace the existing one.
+        assert_eq!(valid, *weapons.default_weapon());
+
+        let mut weapon = valid.clone();
+        weapon.capacity = MAX_CAPACITY;
+        weapons.insert(DEFAULT_WEAPON, weapon.clone()).unwrap();
+        assert_eq!(weapon, *weapons.default_weapon());
+    }
+
+    #[test]
+    fn eject_keyframes_past_the_end_use_the_last() {
+        let timings = &test_weapons().default_weapon().timings;
+        let last = timings.eject_keyframe_millis.len() - 1;
+        assert_eq!(timings.eject_keyframe_seconds(last), timings.eject_keyframe_seconds(last + 5));
+    }
+}
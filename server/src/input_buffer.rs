@@ -0,0 +1,331 @@
+use core::{revolver::RevolverAction, tick::*, InputFrame, TickInput, ViewTime};
+use std::collections::BTreeMap;
+
+/// The smallest number of ticks of input to buffer before simulating.
+const MIN_TARGET: usize = 1;
+
+/// The largest number of ticks of input to buffer before simulating.
+const MAX_TARGET: usize = 8;
+
+/// The number of ticks without an underrun before the target buffer size is reduced.
+const SHRINK_AFTER_TICKS: usize = 2 * TICKS_PER_SECOND as usize;
+
+/// The number of ticks past the next tick to be consumed that input is accepted for.
+///
+/// Input for later ticks is discarded, so that a client can't make the buffer grow without
+/// bound by sending ticks far in the future.
+const MAX_BUFFER: usize = MAX_TARGET * 4;
+
+/// The input a client sent for a single one of its ticks.
+#[derive(Debug, Clone, Default)]
+struct BufferedInput {
+    input: Option<InputFrame>,
+    actions: Vec<(RevolverAction, ViewTime)>,
+}
+
+/// Queues the input from a client so that exactly one tick of input is used per simulation tick.
+///
+/// Input from the client doesn't arrive at a perfectly steady rate, so the buffer holds a few
+/// ticks of input to smooth out the jitter. The number of ticks held adapts to the connection:
+/// Each time the buffer runs dry the target grows, and it shrinks again once the connection has
+/// been steady for a while.
+#[derive(Debug)]
+pub struct InputBuffer {
+    inputs: BTreeMap<Tick, BufferedInput>,
+
+    /// The next client tick to be consumed, or `None` if we haven't started consuming input yet.
+    next: Option<Tick>,
+
+    /// The most recently consumed input, which is repeated if the buffer runs dry.
+    last_input: InputFrame,
+
+    /// The number of ticks of input we try to keep buffered.
+    target: usize,
+
+    /// The number of ticks since the buffer last ran dry.
+    steady_ticks: usize,
+
+    /// The most recent client tick whose actions have been added to the buffer.
+    latest_actions: Option<Tick>,
+}
+
+impl InputBuffer {
+    pub fn new() -> InputBuffer {
+        InputBuffer {
+            inputs: BTreeMap::new(),
+            next: None,
+            last_input: InputFrame::default(),
+            target: 2,
+            steady_ticks: 0,
+            latest_actions: None,
+        }
+    }
+
+    /// Adds the input for one of the client's ticks to the buffer.
+    ///
+    /// Clients resend input until it's acknowledged, so the same tick may be pushed more than
+    /// once. Input for ticks that have already been consumed, or that are too far in the future,
+    /// is discarded.
+    pub fn push(&mut self, input: TickInput) {
+        let TickInput { tick, input, actions, view } = input;
+
+        // Actions are resent along with the input, so only take them the first time they
+        // arrive. Clients send their ticks in order, so anything up to the latest tick we've
+        // taken actions from is a duplicate.
+        let is_new = self.latest_actions.map(|latest| tick > latest).unwrap_or(true);
+        if is_new && !actions.is_empty() {
+            // Unlike movement, actions can't be skipped without the client noticing, so apply
+            // late actions on the next tick instead of dropping them.
+            let action_tick = match self.next {
+                Some(next) if tick < next => next,
+                _ => tick,
+            };
+
+            if self.accepts(action_tick) {
+                self.latest_actions = Some(tick);
+                self.inputs
+                    .entry(action_tick)
+                    .or_insert_with(BufferedInput::default)
+                    .actions
+                    .extend(actions.into_iter().map(|action| (action, view)));
+            } else {
+                debug!("Discarding actions for {:?}, which is too far in the future", tick);
+            }
+        }
+
+        if self.accepts(tick) {
+            self.inputs.entry(tick).or_insert_with(BufferedInput::default).input = Some(input);
+        }
+    }
+
+    /// Takes the input for the next simulation tick.
+    ///
+    /// Returns the client tick that was consumed (if there was input for it), along with the
+    /// input and actions to apply. If the buffer has run dry, the last input is repeated.
+    pub fn consume(&mut self) -> (Option<Tick>, InputFrame, Vec<(RevolverAction, ViewTime)>) {
+        // Wait until the buffer has filled up before we start consuming input.
+        let next = match self.next {
+            Some(next) => next,
+            None => {
+                if self.inputs.len() < self.target {
+                    return (None, self.last_input.clone(), Vec::new());
+                }
+
+                *self.inputs.keys().next().expect("Buffer was empty")
+            }
+        };
+
+        // If the client has gotten too far ahead, skip the oldest input to catch up.
+        let mut next = next;
+        while self.inputs.len() > MAX_TARGET * 2 {
+            let oldest = *self.inputs.keys().next().expect("Buffer was empty");
+            debug!("Input buffer overflowed, skipping input for {:?}", oldest);
+            self.inputs.remove(&oldest);
+
+            // NOTE: There's more input after `oldest`, so it can't be the last possible tick.
+            next = next_tick(oldest).unwrap_or(oldest);
+        }
+
+        // If the client somehow reaches the last possible tick, start over from whichever input
+        // arrives next rather than overflowing.
+        self.next = next_tick(next);
+        match self.inputs.remove(&next) {
+            Some(buffered) => {
+                if let Some(input) = buffered.input {
+                    self.last_input = input;
+                }
+
+                self.steady_ticks += 1;
+                if self.steady_ticks >= SHRINK_AFTER_TICKS && self.target > MIN_TARGET {
+                    self.target -= 1;
+                    self.steady_ticks = 0;
+                }
+
+                (Some(next), self.last_input.clone(), buffered.actions)
+            }
+
+            // The input for this tick hasn't arrived yet, so we have to make do without it. Grow
+            // the buffer so that it's less likely to happen again.
+            None => {
+                trace!("Input buffer ran dry at {:?}", next);
+                self.steady_ticks = 0;
+                if self.target < MAX_TARGET {
+                    self.target += 1;
+                }
+
+                (None, self.last_input.clone(), Vec::new())
+            }
+        }
+    }
+
+    /// Returns the current state of the buffer, to be reported to the client.
+    pub fn health(&self) -> InputBufferHealth {
+        InputBufferHealth {
+            buffered: self.inputs.len() as u16,
+            target: self.target as u16,
+        }
+    }
+
+    /// Returns `true` if input for `tick` should be added to the buffer.
+    fn accepts(&self, tick: Tick) -> bool {
+        match self.next {
+            Some(next) => tick >= next && tick.0 - next.0 < MAX_BUFFER,
+
+            // We don't know which tick the client is on until we start consuming input, so limit
+            // the number of ticks buffered instead.
+            None => self.inputs.len() < MAX_BUFFER || self.inputs.contains_key(&tick),
+        }
+    }
+}
+
+fn next_tick(tick: Tick) -> Option<Tick> {
+    tick.0.checked_add(1).map(Tick)
+}
+
+#[cfg(test)]
+mod test {
+    use std::usize;
+    use super::*;
+
+    fn input(tick: usize, yaw_delta: f32) -> TickInput {
+        TickInput {
+            tick: Tick(tick),
+            input: InputFrame { yaw_delta, ..InputFrame::default() },
+            actions: Vec::new(),
+            view: ViewTime::default(),
+        }
+    }
+
+    fn action(tick: usize, action: RevolverAction) -> TickInput {
+        TickInput { actions: vec![action], ..input(tick, 0.0) }
+    }
+
+    /// Fills the buffer up to its target and consumes the first tick, returning the buffer.
+    fn started(first: usize) -> InputBuffer {
+        let mut buffer = InputBuffer::new();
+        for tick in first .. first + buffer.target {
+            buffer.push(input(tick, tick as f32));
+        }
+
+        assert_eq!(Some(Tick(first)), buffer.consume().0);
+        buffer
+    }
+
+    #[test]
+    fn waits_for_target() {
+        let mut buffer = InputBuffer::new();
+        buffer.push(input(10, 1.0));
+        assert_eq!(None, buffer.consume().0);
+
+        buffer.push(input(11, 2.0));
+        let (tick, consumed, _) = buffer.consume();
+        assert_eq!(Some(Tick(10)), tick);
+        assert_eq!(1.0, consumed.yaw_delta);
+    }
+
+    #[test]
+    fn repeats_last_input_and_grows_when_dry() {
+        let mut buffer = started(0);
+        let target = buffer.health().target;
+
+        assert_eq!(Some(Tick(1)), buffer.consume().0);
+        let (tick, repeated, _) = buffer.consume();
+        assert_eq!(None, tick);
+        assert_eq!(1.0, repeated.yaw_delta);
+        assert_eq!(target + 1, buffer.health().target);
+
+        // The missing tick is skipped, even if its input arrives later.
+        buffer.push(input(2, 2.0));
+        buffer.push(input(3, 3.0));
+        assert_eq!(1, buffer.health().buffered);
+        assert_eq!(Some(Tick(3)), buffer.consume().0);
+    }
+
+    #[test]
+    fn shrinks_when_steady() {
+        let mut buffer = started(0);
+        let target = buffer.health().target;
+
+        for tick in 1 .. SHRINK_AFTER_TICKS + 1 {
+            buffer.push(input(tick, 0.0));
+            assert_eq!(Some(Tick(tick)), buffer.consume().0);
+        }
+
+        assert_eq!(target - 1, buffer.health().target);
+    }
+
+    #[test]
+    fn skips_oldest_input_when_too_far_behind() {
+        let mut buffer = InputBuffer::new();
+        let count = MAX_TARGET * 2 + 4;
+        for tick in 0 .. count {
+            buffer.push(input(tick, tick as f32));
+        }
+
+        assert_eq!(Some(Tick(4)), buffer.consume().0);
+        assert_eq!(count - 5, buffer.health().buffered as usize);
+    }
+
+    #[test]
+    fn discards_input_outside_window() {
+        let mut buffer = started(100);
+
+        // Already consumed.
+        buffer.push(input(100, 0.0));
+        buffer.push(input(5, 0.0));
+        assert_eq!(1, buffer.health().buffered);
+
+        // Too far in the future.
+        buffer.push(input(101 + MAX_BUFFER, 0.0));
+        buffer.push(input(usize::MAX, 0.0));
+        buffer.push(action(usize::MAX, RevolverAction::PullTrigger));
+        assert_eq!(1, buffer.health().buffered);
+
+        buffer.push(input(100 + MAX_BUFFER, 0.0));
+        assert_eq!(2, buffer.health().buffered);
+    }
+
+    #[test]
+    fn late_actions_are_applied_next_tick() {
+        let mut buffer = started(0);
+        buffer.push(action(0, RevolverAction::PullHammer));
+
+        let (tick, _, actions) = buffer.consume();
+        assert_eq!(Some(Tick(1)), tick);
+        assert_eq!(vec![(RevolverAction::PullHammer, ViewTime::default())], actions);
+    }
+
+    #[test]
+    fn resent_actions_are_applied_once() {
+        let mut buffer = started(0);
+        buffer.push(action(2, RevolverAction::PullHammer));
+        buffer.push(action(2, RevolverAction::PullHammer));
+        buffer.push(action(3, RevolverAction::PullTrigger));
+
+        // The client resends the actions for both ticks until they're acknowledged.
+        buffer.push(action(2, RevolverAction::PullHammer));
+        buffer.push(action(3, RevolverAction::PullTrigger));
+
+        assert_eq!(Some(Tick(1)), buffer.consume().0);
+        let (_, _, actions) = buffer.consume();
+        assert_eq!(vec![(RevolverAction::PullHammer, ViewTime::default())], actions);
+        let (_, _, actions) = buffer.consume();
+        assert_eq!(vec![(RevolverAction::PullTrigger, ViewTime::default())], actions);
+
+        // Resending an action after its tick was consumed doesn't apply it again either.
+        buffer.push(action(3, RevolverAction::PullTrigger));
+        buffer.push(input(4, 0.0));
+        assert_eq!(Vec::<(RevolverAction, ViewTime)>::new(), buffer.consume().2);
+    }
+
+    #[test]
+    fn last_tick_does_not_overflow() {
+        let mut buffer = InputBuffer::new();
+        buffer.push(input(usize::MAX - 1, 0.0));
+        buffer.push(input(usize::MAX, 0.0));
+
+        assert_eq!(Some(Tick(usize::MAX - 1)), buffer.consume().0);
+        assert_eq!(Some(Tick(usize::MAX)), buffer.consume().0);
+        assert_eq!(None, buffer.consume().0);
+    }
+}
@@ -7,30 +7,98 @@ extern crate futures_cpupool;
 extern crate log;
 extern crate log4rs;
 extern crate rand;
+#[macro_use]
+extern crate serde;
 extern crate sumi;
 extern crate tokio_core;
 
 use amethyst::{
-    core::frame_limiter::FrameRateLimitStrategy, core::timing::Time, ecs::prelude::*, prelude::*,
+    config::Config, core::frame_limiter::FrameRateLimitStrategy, core::timing::Time,
+    ecs::prelude::*, prelude::*,
+};
+use core::{
+    level::Level, math::*, player::Player, projectile::Projectile, revolver::*,
+    snapshot::SnapshotHistory, tick::*, weapon::Weapons, *,
 };
-use core::{math::*, player::Player, revolver::*, *};
 use crossbeam_channel::Receiver;
 use futures::Stream;
+use input_buffer::InputBuffer;
+use rewind::PlayerHistory;
 use rand::Rng;
-use std::{thread, time::Duration};
-use sumi::ConnectionListener;
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use sumi::{Bincode, Codec, Compression, ConnectionListener, ServerInfo};
 use tokio_core::reactor::Core;
 
+mod input_buffer;
+mod rewind;
+
 type Broadcasts = Vec<ServerMessageBody>;
 
 struct Server {
     new_connections: Receiver<Connection<ServerMessage, ClientMessage>>,
-    frame_count: usize,
+
+    /// Determines how many simulation ticks to run each frame.
+    clock: TickClock,
+
+    /// The number of connected players, shared with the I/O thread so that it can be reported
+    /// to clients looking for servers.
+    player_count: Arc<AtomicUsize>,
+}
+
+/// Server settings, loaded from `resources/server.ron`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct ServerConfig {
+    /// The address to listen for connections on.
+    address: SocketAddr,
+
+    /// The name of the server, displayed to players looking for a server to join.
+    name: String,
+
+    /// The directory to load the map and weapons from.
+    ///
+    /// Relative paths are relative to the working directory the server is run from.
+    assets: PathBuf,
+
+    /// The name of the map to play.
+    map: String,
+
+    /// How far back in time, in milliseconds, the server will rewind other players when checking
+    /// whether a shot hit.
+    ///
+    /// Higher values let players with more latency hit what they're aiming at, at the cost of
+    /// players getting hit after they've already moved behind cover.
+    max_rewind_millis: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: ([0, 0, 0, 0], DEFAULT_PORT).into(),
+            name: "Online FPS Server".into(),
+            assets: "../assets".into(),
+            map: "arena".into(),
+            max_rewind_millis: 250,
+        }
+    }
 }
 
 impl SimpleState for Server {
     fn update(&mut self, data: &mut StateData<GameData>) -> SimpleTrans {
-        self.frame_count += 1;
+        // Determine how many ticks to simulate this frame.
+        let delta = data.world.read_resource::<Time>().delta_seconds();
+        let ticks = self.clock.advance(delta);
+        let server_tick = self.clock.current();
 
         // Handle any new connections, adding a new player for the new client.
         assert!(
@@ -41,21 +109,25 @@ impl SimpleState for Server {
             let id = rand::random();
             info!("New player joined and was assigned ID {:#x}", id);
 
-            let player = Player {
-                id,
-                position: Point3::new(0.0, 0.0, 0.0),
-                yaw: 0.0,
-                pitch: 0.0,
-                gun: Revolver::default(),
+            let position = {
+                let clients = data.world.read_storage::<Client>();
+                let level = data.world.read_resource::<Level>();
+                level.choose_spawn_point((&clients).join().map(|client| &client.player))
             };
+            let player = Player::new(id, position);
 
             let mut client = Client {
                 connection,
 
                 id,
-                input: InputFrame::default(),
+                inputs: InputBuffer::new(),
+
+                latest_tick: Tick::default(),
+
+                snapshots: SnapshotHistory::default(),
+                acknowledged_snapshot: None,
 
-                latest_frame: 0,
+                rewind: 0.0,
 
                 player: player.clone(),
             };
@@ -83,8 +155,9 @@ impl SimpleState for Server {
                 for client in (&mut clients).join() {
                     // TODO: This should be a send-reliable.
                     client.connection.send(ServerMessage {
-                        server_frame: self.frame_count,
-                        client_frame: client.latest_frame,
+                        server_tick,
+                        client_tick: client.latest_tick,
+                        input_buffer: client.inputs.health(),
                         body: ServerMessageBody::PlayerJoined {
                             id,
                             player: player.clone(),
@@ -96,11 +169,14 @@ impl SimpleState for Server {
             // Send the current world state to the new client.
             // TODO: This should be a send-reliable.
             client.connection.send(ServerMessage {
-                server_frame: self.frame_count,
-                client_frame: 0,
+                server_tick,
+                client_tick: Tick::default(),
+                input_buffer: client.inputs.health(),
                 body: ServerMessageBody::Init {
                     id,
                     world: client_world,
+                    map: data.world.read_resource::<Level>().name.clone(),
+                    weapon: data.world.read_resource::<Weapons>().default_weapon().clone(),
                 },
             });
 
@@ -108,10 +184,18 @@ impl SimpleState for Server {
             data.world.create_entity().with(client).build();
         }
 
-        // Allow all systems to run.
-        data.data.update(&data.world);
+        // Run the systems once for each tick. The simulation only ever advances in whole ticks,
+        // so that it doesn't depend on the frame rate.
+        for tick in self.clock.pending() {
+            data.world.add_resource(tick);
+            data.data.update(&data.world);
+            data.world.maintain();
+        }
 
-        data.world.maintain();
+        // Nothing has changed if no ticks ran, so there's nothing to send.
+        if ticks == 0 {
+            return Trans::None;
+        }
 
         let mut broadcasts = data.world.write_resource::<Broadcasts>();
         let mut clients = data.world.write_storage::<Client>();
@@ -128,19 +212,34 @@ impl SimpleState for Server {
             ::core::World { players }
         };
 
-        // Enqueue the broadcast the be sent to all remaining clients.
-        broadcasts.push(ServerMessageBody::WorldUpdate(client_world));
+        // Update the player count reported to clients looking for servers.
+        self.player_count.store(client_world.players.len(), Ordering::Relaxed);
 
-        // Send the current world state to each of the connected clients.
+        // Send the broadcasts and the current world state to each of the connected clients.
         for client in (&mut clients).join() {
             for broadcast in &*broadcasts {
                 trace!("Broadcasting {:?} to client {:#x}", broadcast, client.id);
                 client.connection.send(ServerMessage {
-                    server_frame: self.frame_count,
-                    client_frame: client.latest_frame,
+                    server_tick,
+                    client_tick: client.latest_tick,
+                    input_buffer: client.inputs.health(),
                     body: broadcast.clone(),
                 });
             }
+
+            // Only send the parts of the world that changed since the last snapshot the client
+            // acknowledged.
+            let body = client.snapshots.encode(
+                server_tick,
+                client_world.clone(),
+                client.acknowledged_snapshot,
+            );
+            client.connection.send(ServerMessage {
+                server_tick,
+                client_tick: client.latest_tick,
+                input_buffer: client.inputs.health(),
+                body,
+            });
         }
 
         // Reset the list of broadcasts.
@@ -154,7 +253,40 @@ fn main() -> ::amethyst::Result<()> {
     // Initialize logging first so that we can start capturing logs immediately.
     log4rs::init_file("../log4rs.toml", Default::default()).expect("Failed to init log4rs");
 
+    let config = ServerConfig::load(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/server.ron"));
+    info!("Loaded server config: {:?}", config);
+
+    let level = match Level::load(&config.assets, &config.map) {
+        Ok(level) => level,
+        Err(error) => {
+            error!("Failed to load map {} from {:?}: {}", config.map, config.assets, error);
+            process::exit(1);
+        }
+    };
+    info!(
+        "Loaded map {} with {} spawn points",
+        level.name,
+        level.spawn_points.len(),
+    );
+
+    let weapons = match Weapons::load(config.assets.join("weapons")) {
+        Ok(weapons) => weapons,
+        Err(error) => {
+            error!("Failed to load weapon definitions from {:?}: {}", config.assets, error);
+            process::exit(1);
+        }
+    };
+
+    let player_count = Arc::new(AtomicUsize::new(0));
+
+    let player_system = PlayerSystem {
+        history: PlayerHistory::new(config.max_rewind_millis),
+        projectiles: Vec::new(),
+        next_projectile_id: 0,
+    };
+
     let (connection_sender, new_connections) = crossbeam_channel::bounded(8);
+    let io_player_count = player_count.clone();
     thread::spawn(move || {
         // Create the event loop that will drive network communication.
         let mut core = Core::new().unwrap();
@@ -162,8 +294,30 @@ fn main() -> ::amethyst::Result<()> {
 
         // Spawn the connection listener onto the reactor and create a new `Stream` that yields each
         // connection as it is received.
-        let connection_listener = ConnectionListener::bind("127.0.0.1:1234", &core.handle())
-            .expect("Failed to bind socket")
+        let mut connection_listener = ConnectionListener::bind(config.address, &core.handle())
+            .expect("Failed to bind socket");
+
+        // World snapshots are highly redundant, so compress them for any clients that support it.
+        connection_listener.set_compression(Compression::Lz4);
+
+        let server_info = move || ServerInfo {
+            name: config.name.clone(),
+            map: config.map.clone(),
+            player_count: io_player_count.load(Ordering::Relaxed) as u32,
+        };
+
+        // Respond to clients searching for servers on the local network.
+        connection_listener.enable_discovery(server_info.clone());
+
+        // Let server browsers check on the server without connecting. The payload is the same
+        // info sent in response to discovery requests.
+        connection_listener.enable_info_queries(move || {
+            let mut payload = Vec::new();
+            Bincode.encode(&server_info(), &mut payload).expect("Failed to encode server info");
+            payload
+        });
+
+        let connection_listener = connection_listener
             .map(move |connection| Connection::new(connection, &handle))
             .for_each(move |connection| {
                 connection_sender
@@ -176,14 +330,17 @@ fn main() -> ::amethyst::Result<()> {
             .expect("Error waiting for connections");
     });
 
-    let game_data = GameDataBuilder::default().with(PlayerSystem, "player_system", &[]);
+    let game_data = GameDataBuilder::default().with(player_system, "player_system", &[]);
 
     let server = Server {
         new_connections,
-        frame_count: 0,
+        clock: TickClock::default(),
+        player_count,
     };
 
     Application::build("./", server)?
+        .with_resource(level)
+        .with_resource(weapons)
         .with_frame_limit(
             FrameRateLimitStrategy::SleepAndYield(Duration::from_millis(2)),
             60,
@@ -200,13 +357,25 @@ struct Client {
     connection: Connection<ServerMessage, ClientMessage>,
 
     id: u64,
-    input: InputFrame,
+    inputs: InputBuffer,
 
-    /// The most recent frame of input that the client sent.
+    /// The most recent tick of input that has been applied to the player.
     ///
     /// This isn't used directly by the server. It is sent back to the client so that the client
     /// can determine how much input history needs to be replayed locally.
-    latest_frame: usize,
+    latest_tick: Tick,
+
+    /// The world snapshots recently sent to the client, used as baselines for delta compression.
+    snapshots: SnapshotHistory,
+
+    /// The server tick of the most recent snapshot that the client acknowledged receiving.
+    acknowledged_snapshot: Option<Tick>,
+
+    /// How many ticks to rewind other players when the client's next shot is fired.
+    ///
+    /// Determined when the client pulls the trigger, based on what the client was seeing at the
+    /// time.
+    rewind: f32,
 
     player: Player,
 }
@@ -215,20 +384,41 @@ impl Component for Client {
     type Storage = VecStorage<Self>;
 }
 
-struct PlayerSystem;
+struct PlayerSystem {
+    /// Where players were on recent ticks, used to rewind them when checking for hits.
+    history: PlayerHistory,
+
+    /// The projectiles currently in flight, along with how far to rewind the other players when
+    /// checking them for hits.
+    projectiles: Vec<(Projectile, f32)>,
+
+    /// The ID to assign to the next projectile fired.
+    next_projectile_id: u64,
+}
 
 impl<'a> System<'a> for PlayerSystem {
     type SystemData = (
         WriteStorage<'a, Client>,
         Entities<'a>,
         Write<'a, Broadcasts>,
-        Read<'a, Time>,
+        Read<'a, Tick>,
+        ReadExpect<'a, Level>,
+        ReadExpect<'a, Weapons>,
     );
 
     fn run(&mut self, data: Self::SystemData) {
-        let (mut clients, entities, mut broadcasts, time) = data;
+        let (mut clients, entities, mut broadcasts, tick, level, weapons) = data;
+        let tick = *tick;
+
+        // Everyone carries the same weapon for now.
+        let weapon = weapons.default_weapon();
+        let mut rng = rand::weak_rng();
 
-        let delta = time.delta_seconds();
+        // The system runs once per simulation tick, so always step by a full tick.
+        let delta = TICK_SECONDS;
+
+        // Projectiles fired this tick, along with how far to rewind the other players.
+        let mut fired = Vec::new();
 
         // For each connected client, process any incoming messages from the client, step the
         // player based on the current input state, and then send the player's current state back
@@ -249,45 +439,198 @@ impl<'a> System<'a> for PlayerSystem {
                 continue;
             }
 
-            // Poll the client's stream of incoming messages and handle each one we receive.
+            // Poll the client's stream of incoming messages and handle each one we receive. Input
+            // is buffered so that it can be applied one tick at a time.
             for message in client.connection.try_iter() {
                 trace!("Got message for client {:#x}: {:?}", client.id, message);
 
-                // If we receive the message out of order, straight up ignore it.
-                // TODO: Handle out of order messages within the protocol.
-                if message.frame < client.latest_frame {
-                    continue;
-                }
-
-                // Update our local info on the latest client frame we've received.
-                client.latest_frame = message.frame;
-
-                // Handle the actual contents of the message.
-                let player = &mut client.player;
                 match message.body {
-                    ClientMessageBody::Input(input) => {
-                        client.input = input;
+                    ClientMessageBody::Input(inputs) => {
+                        // Clients never send more than this, so a larger batch can only come from
+                        // a misbehaving client.
+                        if inputs.len() > MAX_REDUNDANT_INPUTS {
+                            warn!(
+                                "Client {:#x} sent {} ticks of input, discarding",
+                                client.id,
+                                inputs.len(),
+                            );
+                            continue;
+                        }
+
+                        // Input for ticks that were already processed is discarded by the buffer.
+                        for input in inputs {
+                            client.inputs.push(input);
+                        }
                     }
-                    ClientMessageBody::RevolverAction(action) => {
-                        player.handle_revolver_action(action);
+                    ClientMessageBody::AcknowledgeSnapshot(tick) => {
+                        // Acknowledgements can arrive out of order, so only keep the latest one.
+                        let is_latest = client
+                            .acknowledged_snapshot
+                            .map(|latest| tick > latest)
+                            .unwrap_or(true);
+                        if is_latest {
+                            client.acknowledged_snapshot = Some(tick);
+                            client.snapshots.discard_before(tick);
+                        }
                     }
                 }
             }
 
+            // Take the next tick of input from the buffer. Once the input has been applied, the
+            // client no longer needs to replay it.
+            let (consumed, input, actions) = client.inputs.consume();
+            if let Some(tick) = consumed {
+                client.latest_tick = tick;
+            }
+
             // Tick the player.
             let player = &mut client.player;
-            player.step(&client.input, delta);
+            for (action, view) in actions {
+                // Remember what the client was seeing when they pulled the trigger, so that the
+                // shot can be checked against what they saw once the hammer falls. Automatic
+                // weapons keep using it for follow-up shots, since the client's view advances
+                // along with the server and so stays the same distance in the past.
+                if action == RevolverAction::PullTrigger {
+                    client.rewind = self.history.rewind_ticks(tick, view);
+                }
+
+                player.handle_revolver_action(action, weapon);
+            }
+            player.step(&input, &level.collision, delta);
+
+            // Tick the player's revolver, firing a shot and animating the recoil if it fired. Dead
+            // players can't shoot.
+            if player.gun.step(delta, weapon) && player.is_alive() {
+                // Fire the projectiles along the player's aim before recoil is applied. They start
+                // moving once all players have moved for the tick.
+                let aim = player.aim_direction();
+                for _ in 0..weapon.pellets {
+                    let projectile = Projectile::new(
+                        self.next_projectile_id,
+                        player.id,
+                        player.eye_position(),
+                        weapon.spread_direction(aim, rng.gen(), rng.gen()),
+                        weapon.muzzle_velocity,
+                    );
+                    self.next_projectile_id += 1;
+                    fired.push((projectile, client.rewind));
+                }
+
+                player.apply_recoil(weapon);
+            }
+
+            // Round the player's state to the precision that gets sent to clients, so that the
+            // server's state is exactly what the clients receive.
+            player.quantize();
+        }
+
+        // Record where everyone is so that later shots can be checked against this tick.
+        let players = (&clients).join().map(|client| &client.player);
+        self.history.record(tick, players);
+
+        for &(projectile, _) in &fired {
+            broadcasts.push(ServerMessageBody::ProjectileSpawned(projectile));
+        }
+        self.projectiles.extend(fired);
+
+        // Step the projectiles in flight and check them against the hitboxes of all players,
+        // rewinding the other players to where the shooter saw them.
+        let mut hits = Vec::new();
+        let projectiles = ::std::mem::replace(&mut self.projectiles, Vec::new());
+
+        // Rewinding has to copy every player, so only rewind once for each distinct amount and
+        // share the result between projectiles. Projectiles fired by the same shot (e.g. pellets)
+        // always share the same rewind.
+        let mut rewound: Vec<(f32, Vec<Player>)> = Vec::new();
+        for (mut projectile, rewind) in projectiles {
+            let index = match rewound.iter().position(|&(cached, _)| cached == rewind) {
+                Some(index) => index,
+                None => {
+                    let players = (&clients).join().map(|client| &client.player);
+                    rewound.push((rewind, self.history.rewind(tick, rewind, players)));
+                    rewound.len() - 1
+                }
+            };
+
+            match projectile.step_and_collide(delta, &level.collision, &rewound[index].1) {
+                Some(impact) => {
+                    broadcasts.push(ServerMessageBody::ProjectileImpact {
+                        id: projectile.id,
+                        location: impact.location,
+                    });
+
+                    if let Some(hit) = impact.hit {
+                        hits.push((projectile.shooter, hit));
+                    }
+                }
+
+                None => {
+                    if !projectile.is_expired() {
+                        self.projectiles.push((projectile, rewind));
+                    }
+                }
+            }
+        }
+
+        for (shooter, hit) in hits {
+            debug!(
+                "Player {:#x} hit player {:#x} in the {:?}",
+                shooter,
+                hit.victim,
+                hit.hitbox,
+            );
+
+            // Only apply the damage if the victim is still around and alive. Another shot earlier
+            // in the tick may have already killed them, in which case this one doesn't count.
+            let victim = (&mut clients)
+                .join()
+                .map(|client| &mut client.player)
+                .find(|player| player.id == hit.victim && player.is_alive());
+            let victim = match victim {
+                Some(victim) => victim,
+                None => continue,
+            };
 
-            // Tick the player's revolver, spawning a bullet and animating the recoil if it fired.
-            if player.gun.step(delta) {
-                // TODO: Spawn a bullet at the current trajectory of the gun.
+            let damage = weapon.damage.for_hitbox(hit.hitbox);
+            broadcasts.push(ServerMessageBody::PlayerHit {
+                shooter,
+                victim: hit.victim,
+                hitbox: hit.hitbox,
+                location: hit.location,
+                damage,
+            });
 
-                // Apply recoil to the player's current aim.
-                let pitch_delta = rand::weak_rng().gen_range::<f32>(0.0, PI / 2.0 * 0.05);
-                let yaw_delta = rand::weak_rng().gen_range::<f32>(-PI * 0.001, PI * 0.001);
-                player.pitch = (player.pitch + pitch_delta).clamp(-PI, PI);
-                player.yaw = (player.yaw + yaw_delta) % TAU;
+            if victim.apply_damage(damage) {
+                info!("Player {:#x} killed player {:#x}", shooter, victim.id);
+                broadcasts.push(ServerMessageBody::PlayerDied {
+                    id: victim.id,
+                    killer: shooter,
+                });
             }
+
+            victim.quantize();
+        }
+
+        // Respawn any dead players that have waited long enough.
+        let respawning = (&clients)
+            .join()
+            .filter(|client| client.player.can_respawn())
+            .map(|client| client.id)
+            .collect::<Vec<_>>();
+        for id in respawning {
+            let position =
+                level.choose_spawn_point((&clients).join().map(|client| &client.player));
+            let client = (&mut clients)
+                .join()
+                .find(|client| client.id == id)
+                .expect("Respawning client disappeared");
+            client.player.respawn(position);
+            client.player.quantize();
+
+            broadcasts.push(ServerMessageBody::PlayerRespawned {
+                id,
+                position: client.player.position,
+            });
         }
     }
 }
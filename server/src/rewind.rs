@@ -0,0 +1,130 @@
+use core::{player::Player, tick::*, ViewTime};
+use std::collections::VecDeque;
+
+/// A record of where every player was on recent ticks, used to rewind players for lag
+/// compensation.
+///
+/// Clients render other players slightly in the past, and their shots take time to reach the
+/// server. To judge a shot fairly, the server rewinds the other players to where the shooter saw
+/// them before checking for hits. The history only goes back as far as the configured maximum
+/// rewind, so that players with very high latency can't hit targets that have long since moved
+/// behind cover.
+#[derive(Debug)]
+pub struct PlayerHistory {
+    ticks: VecDeque<(Tick, Vec<Player>)>,
+
+    /// The maximum number of ticks that players can be rewound.
+    max_rewind: f32,
+}
+
+impl PlayerHistory {
+    pub fn new(max_rewind_millis: u64) -> PlayerHistory {
+        PlayerHistory {
+            ticks: VecDeque::new(),
+            max_rewind: max_rewind_millis as f32 / 1000.0 * TICKS_PER_SECOND as f32,
+        }
+    }
+
+    /// Records the state of the players at the end of `tick`.
+    pub fn record<'a, I>(&mut self, tick: Tick, players: I)
+    where
+        I: IntoIterator<Item = &'a Player>,
+    {
+        self.ticks.push_back((tick, players.into_iter().cloned().collect()));
+
+        // Keep one extra tick so that we can always interpolate up to the maximum rewind.
+        let capacity = self.max_rewind.ceil() as usize + 2;
+        while self.ticks.len() > capacity {
+            self.ticks.pop_front();
+        }
+    }
+
+    /// Returns how many ticks to rewind for a shot fired on `current` by a player whose view
+    /// was `view`, clamped to the maximum rewind.
+    pub fn rewind_ticks(&self, current: Tick, view: ViewTime) -> f32 {
+        let delay = view.interpolation_delay_millis as f32 / 1000.0 * TICKS_PER_SECOND as f32;
+        (ticks_before(current, view.server_tick) + delay).max(0.0).min(self.max_rewind)
+    }
+
+    /// Returns the players as they were `rewind` ticks before `current`.
+    ///
+    /// Players are interpolated between the recorded ticks on either side, the same as the
+    /// client does when rendering them. Players that weren't recorded at that time (e.g.
+    /// because they joined since) are returned in their current state.
+    pub fn rewind<'a, I>(&self, current: Tick, rewind: f32, players: I) -> Vec<Player>
+    where
+        I: IntoIterator<Item = &'a Player>,
+    {
+        let from = self
+            .ticks
+            .iter()
+            .rev()
+            .find(|&&(tick, _)| ticks_before(current, tick) >= rewind);
+        let to = self.ticks.iter().find(|&&(tick, _)| ticks_before(current, tick) <= rewind);
+
+        players
+            .into_iter()
+            .map(|player| {
+                let find = |entry: Option<&(Tick, Vec<Player>)>| {
+                    entry.and_then(|&(tick, ref players)| {
+                        players
+                            .iter()
+                            .find(|past| past.id == player.id)
+                            .map(|past| (tick, past))
+                    })
+                };
+
+                match (find(from), find(to)) {
+                    (Some((from_tick, from)), Some((to_tick, to))) if to_tick > from_tick => {
+                        let t = (ticks_before(current, from_tick) - rewind)
+                            / (to_tick.0 - from_tick.0) as f32;
+                        from.interpolate(to, t)
+                    }
+
+                    (Some((_, past)), _) | (None, Some((_, past))) => past.clone(),
+
+                    (None, None) => player.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns how many ticks `tick` is before `current`, which is negative if it's after `current`.
+///
+/// The difference is taken in integers before converting to `f32`, since ticks themselves grow
+/// too large to be represented exactly as an `f32` once the server has been running for a while.
+fn ticks_before(current: Tick, tick: Tick) -> f32 {
+    if tick <= current {
+        (current.0 - tick.0) as f32
+    } else {
+        -((tick.0 - current.0) as f32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::math::*;
+    use super::*;
+
+    #[test]
+    fn rewind_after_long_uptime() {
+        // Far enough along that neighbouring ticks are no longer distinct as `f32`s.
+        let start = 1 << 25;
+        let current = Tick(start + 4);
+
+        let mut history = PlayerHistory::new(250);
+        for offset in 0 .. 5 {
+            let player = Player::new(1, Point3::new(offset as f32, 0.0, 0.0));
+            history.record(Tick(start + offset), &[player]);
+        }
+
+        let view = ViewTime { server_tick: Tick(start + 3), interpolation_delay_millis: 0 };
+        let rewind = history.rewind_ticks(current, view);
+        assert_eq!(1.0, rewind);
+
+        let present = Player::new(1, Point3::new(4.0, 0.0, 0.0));
+        let rewound = history.rewind(current, rewind + 0.5, &[present]);
+        assert!((rewound[0].position.x - 2.5).abs() < 1e-4, "Rewound to {}", rewound[0].position);
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate sumi;
+
+fuzz_target!(|data: &[u8]| {
+    sumi::fuzzing::reassemble(data);
+});
@@ -0,0 +1,146 @@
+//! Command line tool for inspecting captures of sumi traffic.
+//!
+//! ```text
+//! sumi-capture print <capture>
+//! sumi-capture replay <capture> [connection-id]
+//! ```
+//!
+//! `print` decodes every datagram in the capture and prints a timeline of the traffic. `replay`
+//! feeds the inbound traffic for a single connection back into a new `Connection` and prints
+//! each message that the connection receives. If no connection ID is given, the first
+//! connection that received a message in the capture is replayed.
+
+extern crate futures;
+extern crate sumi;
+extern crate tokio_core;
+
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use std::env;
+use std::process;
+use sumi::{CapturedDatagram, CaptureReader, Direction, Error, PacketKind};
+use tokio_core::reactor::Core;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match (args.get(0).map(String::as_str), args.get(1)) {
+        (Some("print"), Some(path)) if args.len() == 2 => { print(path) }
+
+        (Some("replay"), Some(path)) if args.len() <= 3 => {
+            let connection_id = match args.get(2) {
+                Some(id) => match parse_connection_id(id) {
+                    Some(id) => { Some(id) }
+                    None => { usage(); }
+                },
+
+                None => { None }
+            };
+
+            replay(path, connection_id)
+        }
+
+        _ => { usage(); }
+    };
+
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    sumi-capture print <capture>");
+    eprintln!("    sumi-capture replay <capture> [connection-id]");
+    process::exit(2);
+}
+
+// Connection IDs are printed in hex, so accept them either with or without the `0x` prefix.
+fn parse_connection_id(id: &str) -> Option<u64> {
+    let id = id.trim_left_matches("0x");
+    u64::from_str_radix(id, 16).ok()
+}
+
+fn print(path: &str) -> Result<(), Error> {
+    for datagram in CaptureReader::open(path)? {
+        let datagram = datagram?;
+        println!("{}", format_datagram(&datagram));
+    }
+
+    Ok(())
+}
+
+fn format_datagram(datagram: &CapturedDatagram) -> String {
+    let millis = datagram.timestamp.as_secs() as f64 * 1000.0
+        + datagram.timestamp.subsec_nanos() as f64 / 1_000_000.0;
+    let direction = match datagram.direction {
+        Direction::Inbound => "<-",
+        Direction::Outbound => "->",
+    };
+
+    format!(
+        "{:>12.3}ms {} {:<24} {:#018x} {}",
+        millis,
+        direction,
+        datagram.address,
+        datagram.connection_id,
+        datagram.describe(),
+    )
+}
+
+fn replay(path: &str, connection_id: Option<u64>) -> Result<(), Error> {
+    // Default to the first connection that received a message.
+    let connection_id = match connection_id {
+        Some(id) => { id }
+
+        None => {
+            let mut first_message = None;
+            for datagram in CaptureReader::open(path)? {
+                let datagram = datagram?;
+                if datagram.direction == Direction::Inbound
+                    && datagram.packet_kind() == Some(PacketKind::Message)
+                {
+                    first_message = Some(datagram.connection_id);
+                    break;
+                }
+            }
+
+            match first_message {
+                Some(id) => { id }
+
+                None => {
+                    println!("Capture doesn't contain any inbound messages");
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    println!("Replaying connection {:#018x}", connection_id);
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let (connection, replay) = sumi::replay(CaptureReader::open(path)?, connection_id, &handle)?;
+    handle.spawn(replay.map_err(|error| eprintln!("Error replaying capture: {}", error)));
+
+    // Receive messages until the connection times out, which happens once the replay has sent
+    // all of the captured datagrams.
+    let receive_all = future::loop_fn(connection, |connection| {
+        connection
+            .recv(vec![0; 256 * 1024])
+            .then(|result| match result {
+                Ok((connection, buffer, len)) => {
+                    println!("Received {} byte message: {:?}", len, &buffer[.. len]);
+                    Ok(Loop::Continue(connection))
+                }
+
+                Err(Error::PeerDisconnected) => { Ok(Loop::Break(())) }
+
+                Err(error) => { Err(error) }
+            })
+    });
+    core.run(receive_all)?;
+
+    println!("Replay finished");
+    Ok(())
+}
@@ -0,0 +1,731 @@
+// Recording and replaying the datagrams sent and received by sumi sockets.
+//
+// Captures are written in a simple binary format: An 8 byte magic number and a format version,
+// followed by each datagram in the order it was sent or received. See `write_datagram` for the
+// layout of each datagram.
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use futures::prelude::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::{
+    decode,
+    Connection,
+    Error,
+    MAX_PACKET_LEN,
+    Packet,
+    PacketData,
+    Reassembler,
+    SESSION_KEY_LEN,
+};
+use super::compression::Compression;
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+
+// Written at the start of every capture file, so that we can detect files that aren't captures
+// or were written by an incompatible version of sumi.
+const CAPTURE_MAGIC: &'static [u8; 8] = b"SUMICAP\0";
+const CAPTURE_VERSION: u32 = 1;
+
+const DIRECTION_INBOUND: u8 = 0;
+const DIRECTION_OUTBOUND: u8 = 1;
+
+const ADDRESS_V4: u8 = 4;
+const ADDRESS_V6: u8 = 6;
+
+/// Whether a captured datagram was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The datagram was received from `address`.
+    Inbound,
+
+    /// The datagram was sent to `address`.
+    Outbound,
+}
+
+/// The type of packet contained in a captured datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketKind {
+    /// A client's request to open a connection.
+    ConnectionRequest,
+
+    /// The server's challenge in response to a connection request.
+    Challenge,
+
+    /// The client's response to the server's challenge.
+    ChallengeResponse,
+
+    /// The server accepting a connection.
+    ConnectionAccepted,
+
+    /// The server refusing a connection.
+    ConnectionDenied,
+
+    /// A fragment of a message sent over a connection.
+    Message,
+
+    /// An acknowledgement of a reliably sent message.
+    Ack,
+
+    /// The server's challenge to a client whose address has changed.
+    MigrationChallenge,
+
+    /// The client's response to a migration challenge.
+    MigrationResponse,
+
+    /// A broadcast searching for servers on the local network.
+    DiscoveryRequest,
+
+    /// A server's response to a discovery request.
+    DiscoveryResponse,
+
+    /// A request for a server's info, sent outside of a connection.
+    InfoRequest,
+
+    /// A server's response to an info request.
+    InfoResponse,
+}
+
+/// A single datagram read from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedDatagram {
+    /// Whether the datagram was sent or received.
+    pub direction: Direction,
+
+    /// The time at which the datagram was sent or received, relative to when the capture was
+    /// created.
+    pub timestamp: Duration,
+
+    /// The address of the other side, i.e. where the datagram was sent to or received from.
+    pub address: SocketAddr,
+
+    /// The connection ID in the packet header, or 0 if the datagram is too short to have one.
+    pub connection_id: u64,
+
+    /// The raw bytes of the datagram.
+    pub data: Vec<u8>,
+}
+
+impl CapturedDatagram {
+    /// Returns the type of packet contained in the datagram, or `None` if the datagram doesn't
+    /// contain a valid packet.
+    pub fn packet_kind(&self) -> Option<PacketKind> {
+        let kind = match decode(&self.data)?.data {
+            PacketData::ConnectionRequest { .. } => PacketKind::ConnectionRequest,
+            PacketData::Challenge(..) => PacketKind::Challenge,
+            PacketData::ChallengeResponse { .. } => PacketKind::ChallengeResponse,
+            PacketData::ConnectionAccepted { .. } => PacketKind::ConnectionAccepted,
+            PacketData::ConnectionDenied(..) => PacketKind::ConnectionDenied,
+            PacketData::Message { .. } => PacketKind::Message,
+            PacketData::Ack(..) => PacketKind::Ack,
+            PacketData::MigrationChallenge(..) => PacketKind::MigrationChallenge,
+            PacketData::MigrationResponse { .. } => PacketKind::MigrationResponse,
+            PacketData::DiscoveryRequest => PacketKind::DiscoveryRequest,
+            PacketData::DiscoveryResponse { .. } => PacketKind::DiscoveryResponse,
+            PacketData::InfoRequest => PacketKind::InfoRequest,
+            PacketData::InfoResponse { .. } => PacketKind::InfoResponse,
+        };
+        Some(kind)
+    }
+
+    /// Returns a human-readable description of the packet contained in the datagram.
+    ///
+    /// Datagrams that don't contain a valid packet are described as invalid.
+    pub fn describe(&self) -> String {
+        match decode(&self.data) {
+            // Message fragments can be large, so only show their length.
+            Some(Packet {
+                data: PacketData::Message {
+                    sequence_number,
+                    fragment,
+                    num_fragments,
+                    fragment_number,
+                    compressed,
+                },
+                ..
+            }) => {
+                format!(
+                    "Message {{ sequence_number: {}, fragment: {}/{}, {} bytes, compressed: {} }}",
+                    sequence_number,
+                    fragment_number + 1,
+                    num_fragments,
+                    fragment.len(),
+                    compressed,
+                )
+            }
+
+            Some(Packet { data, .. }) => { format!("{:?}", data) }
+
+            None => { format!("<invalid packet, {} bytes>", self.data.len()) }
+        }
+    }
+}
+
+/// Destination for captured datagrams.
+///
+/// `Capture` is cheap to clone, and all clones write to the same destination, so a single
+/// capture can be shared between multiple sockets.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate sumi;
+/// # extern crate tokio_core;
+/// # fn main() {
+/// use sumi::{Capture, Connection};
+/// use tokio_core::reactor::Core;
+///
+/// let mut core = Core::new().unwrap();
+/// let address = "127.0.0.1:1234".parse().unwrap();
+/// let mut wait_for_connection = Connection::connect(address, &core.handle()).unwrap();
+/// wait_for_connection.set_capture(Capture::create("client.sumicap").unwrap());
+/// let connection = core.run(wait_for_connection).unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Capture {
+    inner: Arc<Mutex<CaptureWriter>>,
+}
+
+impl Capture {
+    /// Creates a capture that writes to a new file at `path`, replacing the file if it
+    /// already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Capture, Error> {
+        let file = File::create(path)?;
+        Capture::new(BufWriter::new(file))
+    }
+
+    /// Creates a capture that writes to `writer`.
+    pub fn new<W>(mut writer: W) -> Result<Capture, Error>
+    where
+        W: Write + ::std::marker::Send + 'static,
+    {
+        writer.write_all(CAPTURE_MAGIC)?;
+        writer.write_u32::<NetworkEndian>(CAPTURE_VERSION)?;
+
+        Ok(Capture {
+            inner: Arc::new(Mutex::new(CaptureWriter {
+                writer: Box::new(writer),
+                start_time: Instant::now(),
+                failed: false,
+            })),
+        })
+    }
+
+    /// Writes any buffered datagrams to the underlying writer.
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("Capture mutex was poisoned");
+        inner.writer.flush()?;
+        Ok(())
+    }
+
+    fn record(&self, direction: Direction, address: SocketAddr, data: &[u8]) {
+        let mut inner = self.inner.lock().expect("Capture mutex was poisoned");
+
+        // Only report the first failure, otherwise we'd print a warning for every datagram.
+        if inner.failed { return; }
+
+        let timestamp = inner.start_time.elapsed();
+        if let Err(error) = write_datagram(&mut inner.writer, direction, timestamp, address, data) {
+            println!("WARNING: Failed to write datagram to capture, stopping capture: {:?}", error);
+            inner.failed = true;
+        }
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "Capture {{ .. }}")
+    }
+}
+
+struct CaptureWriter {
+    writer: Box<Write + ::std::marker::Send>,
+    start_time: Instant,
+
+    // Set once writing to the capture fails, after which no more datagrams are written.
+    failed: bool,
+}
+
+impl Drop for CaptureWriter {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+// Each datagram is written as:
+//
+// - Direction (u8)
+// - Timestamp in microseconds (u64)
+// - Address family (u8), followed by the 4 or 16 byte IP address and the port (u16)
+// - Connection ID (u64)
+// - Datagram length (u16), followed by the datagram
+fn write_datagram<W: Write>(
+    writer: &mut W,
+    direction: Direction,
+    timestamp: Duration,
+    address: SocketAddr,
+    data: &[u8],
+) -> Result<(), io::Error> {
+    writer.write_u8(match direction {
+        Direction::Inbound => DIRECTION_INBOUND,
+        Direction::Outbound => DIRECTION_OUTBOUND,
+    })?;
+
+    let micros = timestamp.as_secs() * 1_000_000 + timestamp.subsec_nanos() as u64 / 1_000;
+    writer.write_u64::<NetworkEndian>(micros)?;
+
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            writer.write_u8(ADDRESS_V4)?;
+            writer.write_all(&ip.octets())?;
+        }
+
+        IpAddr::V6(ip) => {
+            writer.write_u8(ADDRESS_V6)?;
+            writer.write_all(&ip.octets())?;
+        }
+    }
+    writer.write_u16::<NetworkEndian>(address.port())?;
+
+    writer.write_u64::<NetworkEndian>(header_connection_id(data))?;
+
+    writer.write_u16::<NetworkEndian>(data.len() as u16)?;
+    writer.write_all(data)?;
+
+    Ok(())
+}
+
+// Reads the connection ID from the packet header without validating the rest of the packet, so
+// that invalid packets are still attributed to a connection where possible.
+fn header_connection_id(data: &[u8]) -> u64 {
+    if data.len() < 12 { return 0; }
+    (&data[4 .. 12]).read_u64::<NetworkEndian>().unwrap_or(0)
+}
+
+/// Reads the datagrams written to a capture.
+///
+/// `CaptureReader` is an iterator over the datagrams in the capture, in the order they were
+/// recorded.
+///
+/// # Examples
+///
+/// ```no_run
+/// use sumi::CaptureReader;
+///
+/// for datagram in CaptureReader::open("client.sumicap").unwrap() {
+///     let datagram = datagram.unwrap();
+///     println!("{:?} {} {}", datagram.timestamp, datagram.address, datagram.describe());
+/// }
+/// ```
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    /// Opens the capture file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader<BufReader<File>>, Error> {
+        let file = File::open(path)?;
+        CaptureReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Creates a reader for the capture in `reader`.
+    ///
+    /// Returns an error if `reader` doesn't contain a capture, or if the capture was written
+    /// by an incompatible version of sumi.
+    pub fn new(mut reader: R) -> Result<CaptureReader<R>, Error> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(Error::Decode("Not a sumi capture".into()));
+        }
+
+        let version = reader.read_u32::<NetworkEndian>()?;
+        if version != CAPTURE_VERSION {
+            return Err(Error::Decode(format!("Unsupported capture version {}", version)));
+        }
+
+        Ok(CaptureReader { reader })
+    }
+
+    fn read_datagram(&mut self) -> Result<Option<CapturedDatagram>, Error> {
+        // Reaching the end of the capture between datagrams is the only expected way for the
+        // capture to end, anything else means the capture was truncated.
+        let direction = match self.reader.read_u8() {
+            Ok(DIRECTION_INBOUND) => { Direction::Inbound }
+            Ok(DIRECTION_OUTBOUND) => { Direction::Outbound }
+            Ok(direction) => {
+                return Err(Error::Decode(format!("Invalid datagram direction {}", direction)));
+            }
+
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => { return Ok(None); }
+            Err(error) => { return Err(error.into()); }
+        };
+
+        let micros = self.reader.read_u64::<NetworkEndian>()?;
+        let timestamp = Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000);
+
+        let ip = match self.reader.read_u8()? {
+            ADDRESS_V4 => {
+                let mut octets = [0; 4];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+
+            ADDRESS_V6 => {
+                let mut octets = [0; 16];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+
+            family => {
+                return Err(Error::Decode(format!("Invalid address family {}", family)));
+            }
+        };
+        let port = self.reader.read_u16::<NetworkEndian>()?;
+
+        let connection_id = self.reader.read_u64::<NetworkEndian>()?;
+
+        let len = self.reader.read_u16::<NetworkEndian>()? as usize;
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data)?;
+
+        Ok(Some(CapturedDatagram {
+            direction,
+            timestamp,
+            address: SocketAddr::new(ip, port),
+            connection_id,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CapturedDatagram, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_datagram() {
+            Ok(Some(datagram)) => { Some(Ok(datagram)) }
+            Ok(None) => { None }
+            Err(error) => { Some(Err(error)) }
+        }
+    }
+}
+
+/// Replays the inbound traffic for a captured connection into a new [`Connection`].
+///
+/// Returns the new connection along with a [`Replay`] future, which must be run alongside the
+/// connection to feed it the captured datagrams. Datagrams are sent with the same timing they
+/// were originally received with.
+///
+/// Only inbound datagrams with a connection ID of `connection_id` are replayed, and only those
+/// from the first address they were received from, so traffic from after the peer migrated to
+/// a new address isn't replayed.
+///
+/// The replayed connection can be used like any other `Connection`, e.g. with [`serialized`] to
+/// receive the same messages that the original connection received. Anything sent on the
+/// replayed connection is discarded.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate futures;
+/// # extern crate sumi;
+/// # extern crate tokio_core;
+/// # fn main() {
+/// use futures::{Future, Stream};
+/// use sumi::CaptureReader;
+/// use tokio_core::reactor::Core;
+///
+/// let mut core = Core::new().unwrap();
+/// let reader = CaptureReader::open("client.sumicap").unwrap();
+/// let (connection, replay) = sumi::replay(reader, 1234, &core.handle()).unwrap();
+/// core.handle().spawn(replay.map_err(|error| panic!("{:?}", error)));
+///
+/// let messages = connection
+///     .serialized::<(), String>()
+///     .for_each(|message| {
+///         println!("Received {:?}", message);
+///         Ok(())
+///     });
+/// core.run(messages).unwrap();
+/// # }
+/// ```
+///
+/// [`Connection`]: ./struct.Connection.html
+/// [`Replay`]: ./struct.Replay.html
+/// [`serialized`]: ./struct.Connection.html#method.serialized
+pub fn replay<R: Read>(
+    reader: CaptureReader<R>,
+    connection_id: u64,
+    handle: &Handle,
+) -> Result<(Connection, Replay), Error> {
+    // Only replay datagrams from the first address that sent us a datagram for the connection.
+    // For captures made by a `ConnectionListener` this excludes the datagrams forwarded from the
+    // local end of the connection, which would otherwise look like inbound traffic.
+    let mut datagrams = VecDeque::new();
+    let mut peer_address = None;
+    for datagram in reader {
+        let datagram = datagram?;
+        if datagram.direction != Direction::Inbound || datagram.connection_id != connection_id {
+            continue;
+        }
+
+        if *peer_address.get_or_insert(datagram.address) == datagram.address {
+            datagrams.push_back(datagram);
+        }
+    }
+
+    // Replay the datagrams relative to the first one, so that we don't wait for however long
+    // the capture ran before the connection was opened.
+    let first_timestamp = datagrams
+        .front()
+        .map(|datagram| datagram.timestamp)
+        .unwrap_or(Duration::from_secs(0));
+
+    let bind_address = ([127, 0, 0, 1], 0).into();
+    let replay_socket = UdpSocket::bind(&bind_address, handle)?;
+    let connection_socket = UdpSocket::bind(&bind_address, handle)?;
+    let target_address = connection_socket.local_addr()?;
+
+    // TODO: Make disconnect timeout configurable.
+    let disconnect_timeout = Timeout::new(Duration::from_secs(1), handle)?;
+    let connection = Connection {
+        socket: Socket::new(connection_socket),
+        peer_address: replay_socket.local_addr()?,
+        connection_id,
+        sequence_number: 0,
+        compression: Compression::None,
+
+        // The replayed traffic never includes migration challenges that we could answer, so the
+        // session key is never used.
+        session_key: [0; SESSION_KEY_LEN],
+
+        send_buffer: Vec::with_capacity(MAX_PACKET_LEN),
+        recv_buffer: vec![0; MAX_PACKET_LEN],
+        fragments: Reassembler::new(),
+
+        handle: handle.clone(),
+
+        disconnect_timeout,
+    };
+
+    let replay = Replay {
+        socket: replay_socket,
+        target_address,
+        datagrams,
+        first_timestamp,
+        start_time: Instant::now(),
+        timeout: Timeout::new(Duration::from_secs(0), handle)?,
+    };
+
+    Ok((connection, replay))
+}
+
+/// Future returned by [`replay`] that sends the captured datagrams to the replayed connection.
+///
+/// Resolves once all of the datagrams have been sent.
+///
+/// [`replay`]: ./fn.replay.html
+pub struct Replay {
+    socket: UdpSocket,
+    target_address: SocketAddr,
+    datagrams: VecDeque<CapturedDatagram>,
+    first_timestamp: Duration,
+    start_time: Instant,
+
+    // Fires when the next datagram is due to be sent.
+    timeout: Timeout,
+}
+
+impl Future for Replay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let due = match self.datagrams.front() {
+                Some(datagram) => {
+                    self.start_time + (datagram.timestamp - self.first_timestamp)
+                }
+
+                None => { return Ok(Async::Ready(())); }
+            };
+
+            if due > Instant::now() {
+                self.timeout.reset(due);
+                match self.timeout.poll()? {
+                    Async::Ready(()) => {}
+                    Async::NotReady => { return Ok(Async::NotReady); }
+                }
+            }
+
+            {
+                let datagram = self.datagrams.front().unwrap();
+                try_nb!(self.socket.send_to(&datagram.data, &self.target_address));
+            }
+            self.datagrams.pop_front();
+        }
+    }
+}
+
+/// A UDP socket that records the datagrams it sends and receives to a [`Capture`], if one
+/// has been set.
+///
+/// Has the same interface as the subset of `UdpSocket` that sumi uses, so it can be used as a
+/// drop-in replacement.
+///
+/// [`Capture`]: ./struct.Capture.html
+#[derive(Debug)]
+pub(crate) struct Socket {
+    inner: UdpSocket,
+    capture: Option<Capture>,
+}
+
+impl Socket {
+    pub fn new(inner: UdpSocket) -> Socket {
+        Socket { inner, capture: None }
+    }
+
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// Replaces the underlying socket, keeping the capture (if any).
+    pub fn set_inner(&mut self, inner: UdpSocket) {
+        self.inner = inner;
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+
+    pub fn poll_read(&self) -> Async<()> {
+        self.inner.poll_read()
+    }
+
+    pub fn send_to(&self, buffer: &[u8], address: &SocketAddr) -> Result<usize, io::Error> {
+        let bytes_written = self.inner.send_to(buffer, address)?;
+        if let Some(ref capture) = self.capture {
+            capture.record(Direction::Outbound, *address, &buffer[.. bytes_written]);
+        }
+        Ok(bytes_written)
+    }
+
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        let (bytes_read, address) = self.inner.recv_from(buffer)?;
+        if let Some(ref capture) = self.capture {
+            capture.record(Direction::Inbound, address, &buffer[.. bytes_read]);
+        }
+        Ok((bytes_read, address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use encode;
+    use super::*;
+
+    #[test]
+    fn capture_roundtrip() {
+        let v4_address = "127.0.0.1:1234".parse().unwrap();
+        let v6_address = "[::1]:5678".parse().unwrap();
+
+        // Use a packet header with a known connection ID, followed by some arbitrary data.
+        let mut packet = vec![0; 4];
+        packet.extend(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        packet.extend(&[0xAB; 20]);
+
+        let mut buffer = Vec::new();
+        buffer.extend(CAPTURE_MAGIC);
+        buffer.write_u32::<NetworkEndian>(CAPTURE_VERSION).unwrap();
+        write_datagram(
+            &mut buffer,
+            Direction::Outbound,
+            Duration::from_millis(5),
+            v4_address,
+            &packet,
+        ).unwrap();
+        write_datagram(
+            &mut buffer,
+            Direction::Inbound,
+            Duration::from_millis(10),
+            v6_address,
+            &[1, 2, 3],
+        ).unwrap();
+
+        let datagrams = CaptureReader::new(Cursor::new(buffer))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                CapturedDatagram {
+                    direction: Direction::Outbound,
+                    timestamp: Duration::from_millis(5),
+                    address: v4_address,
+                    connection_id: 7,
+                    data: packet,
+                },
+                CapturedDatagram {
+                    direction: Direction::Inbound,
+                    timestamp: Duration::from_millis(10),
+                    address: v6_address,
+                    connection_id: 0,
+                    data: vec![1, 2, 3],
+                },
+            ],
+            datagrams,
+        );
+    }
+
+    #[test]
+    fn packet_kind() {
+        let mut data = Vec::new();
+        encode(Packet { connection_id: 7, data: PacketData::Ack(3) }, &mut data).unwrap();
+        let datagram = CapturedDatagram {
+            direction: Direction::Inbound,
+            timestamp: Duration::from_millis(0),
+            address: "127.0.0.1:1234".parse().unwrap(),
+            connection_id: 7,
+            data,
+        };
+        assert_eq!(Some(PacketKind::Ack), datagram.packet_kind());
+
+        let invalid = CapturedDatagram { data: vec![1, 2, 3], ..datagram };
+        assert_eq!(None, invalid.packet_kind());
+    }
+
+    #[test]
+    fn truncated_capture_is_an_error() {
+        let mut buffer = Vec::new();
+        buffer.extend(CAPTURE_MAGIC);
+        buffer.write_u32::<NetworkEndian>(CAPTURE_VERSION).unwrap();
+        write_datagram(
+            &mut buffer,
+            Direction::Inbound,
+            Duration::from_millis(5),
+            "127.0.0.1:1234".parse().unwrap(),
+            &[0xAB; 32],
+        ).unwrap();
+        buffer.truncate(buffer.len() - 1);
+
+        let mut reader = CaptureReader::new(Cursor::new(buffer)).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn reject_non_captures() {
+        assert!(CaptureReader::new(Cursor::new(b"not a capture".to_vec())).is_err());
+    }
+}
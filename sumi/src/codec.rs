@@ -0,0 +1,118 @@
+use bincode;
+use rmp_serde;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use super::Error;
+
+/// A strategy for converting messages to and from raw bytes.
+///
+/// Codecs are used by [`Serialized`] to convert outgoing messages into bytes before sending
+/// them, and to convert incoming bytes back into messages. The codec is chosen when calling
+/// [`Connection::serialized_with`], so each connection can use a different codec.
+///
+/// [`Serialized`]: ./struct.Serialized.html
+/// [`Connection::serialized_with`]: ./struct.Connection.html#method.serialized_with
+pub trait Codec {
+    /// Serializes `value`, appending the resulting bytes to `buffer`.
+    fn encode<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), Error>;
+
+    /// Deserializes a value from `bytes`.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error>;
+}
+
+/// Codec using [bincode], the default serialization strategy.
+///
+/// Bincode is fast and simple, but it uses fixed-size encodings for integers and it is not
+/// widely supported outside of Rust.
+///
+/// [bincode]: https://crates.io/crates/bincode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        bincode::serialize_into(buffer, value, bincode::Infinite)
+            .map_err(|error| Error::Encode(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        bincode::deserialize(bytes)
+            .map_err(|error| Error::Decode(error.to_string()))
+    }
+}
+
+/// Codec using [MessagePack].
+///
+/// MessagePack uses variable-length encodings for integers and lengths, so messages made up of
+/// small values are often considerably smaller than with [`Bincode`], at the cost of some extra
+/// CPU time. Structs are encoded as arrays, not maps, so field names aren't sent over the wire.
+/// MessagePack libraries exist for most languages, which makes it a good choice when
+/// interoperating with tools not written in Rust.
+///
+/// [MessagePack]: https://msgpack.org/
+/// [`Bincode`]: ./struct.Bincode.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        rmp_serde::encode::write(buffer, value)
+            .map_err(|error| Error::Encode(error.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|error| Error::Decode(error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        id: u64,
+        name: String,
+        position: (f32, f32, f32),
+        flags: Vec<bool>,
+    }
+
+    fn message() -> Message {
+        Message {
+            id: 7,
+            name: "cool message".into(),
+            position: (1.0, -2.5, 1000.0),
+            flags: vec![true, false, true],
+        }
+    }
+
+    #[test]
+    fn bincode_roundtrip() {
+        let mut buffer = Vec::new();
+        Bincode.encode(&message(), &mut buffer).expect("Failed to encode message");
+
+        let decoded: Message = Bincode.decode(&buffer).expect("Failed to decode message");
+        assert_eq!(message(), decoded);
+    }
+
+    #[test]
+    fn message_pack_roundtrip() {
+        let mut buffer = Vec::new();
+        MessagePack.encode(&message(), &mut buffer).expect("Failed to encode message");
+
+        let decoded: Message = MessagePack.decode(&buffer).expect("Failed to decode message");
+        assert_eq!(message(), decoded);
+    }
+
+    #[test]
+    fn message_pack_is_compact() {
+        let mut bincode = Vec::new();
+        Bincode.encode(&message(), &mut bincode).unwrap();
+
+        let mut message_pack = Vec::new();
+        MessagePack.encode(&message(), &mut message_pack).unwrap();
+
+        assert!(message_pack.len() < bincode.len());
+    }
+}
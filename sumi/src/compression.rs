@@ -0,0 +1,216 @@
+use lz4_flex::block as lz4;
+use std::cmp;
+use super::MAX_MESSAGE_LEN;
+
+// Messages smaller than this many bytes are never compressed. Small messages rarely compress
+// well, so it's not worth spending the CPU time trying.
+const COMPRESSION_THRESHOLD: usize = 64;
+
+// The most that a single byte of LZ4 input can expand to when decompressed, which happens when
+// the byte extends the length of a match by 255.
+const MAX_LZ4_EXPANSION: usize = 255;
+
+// The values used to identify each compression algorithm on the wire.
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_LZ4: u8 = 1;
+
+/// The compression algorithm used for messages sent over a connection.
+///
+/// Compression is negotiated as part of the connection handshake: The client requests an
+/// algorithm when connecting, and the server accepts it if it has enabled the same algorithm.
+/// Otherwise both sides fall back to `Compression::None`. The negotiated algorithm can be
+/// queried with [`Connection::compression`].
+///
+/// Even when compression is enabled, it is applied per-message. Messages that are too small to
+/// benefit, or that don't get any smaller when compressed, are sent uncompressed.
+///
+/// [`Connection::compression`]: ./struct.Connection.html#method.compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Messages are sent as-is.
+    None,
+
+    /// Messages are compressed with [LZ4], which is fast enough to use on every snapshot while
+    /// still doing well on highly redundant data.
+    ///
+    /// [LZ4]: https://lz4.github.io/lz4/
+    Lz4,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Returns the compression algorithm to use given the one requested by the peer.
+    pub(crate) fn negotiate(self, requested: Compression) -> Compression {
+        if self == requested { self } else { Compression::None }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Compression::None => COMPRESSION_NONE,
+            Compression::Lz4 => COMPRESSION_LZ4,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Compression> {
+        match value {
+            COMPRESSION_NONE => Some(Compression::None),
+            COMPRESSION_LZ4 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Attempts to compress `message` into `buffer`.
+    ///
+    /// Returns `true` if the compressed message was written to `buffer`. Returns `false` if the
+    /// message should be sent uncompressed, in which case the contents of `buffer` are
+    /// unspecified.
+    pub(crate) fn compress(self, message: &[u8], buffer: &mut Vec<u8>) -> bool {
+        if message.len() < COMPRESSION_THRESHOLD { return false; }
+
+        match self {
+            Compression::None => { false }
+
+            Compression::Lz4 => {
+                // Resizing keeps the buffer's allocation, so only the first message needs to
+                // allocate.
+                buffer.clear();
+                buffer.resize(lz4::get_maximum_output_size(message.len()), 0);
+                match lz4::compress_into(message, buffer) {
+                    Ok(len) => {
+                        buffer.truncate(len);
+                        len < message.len()
+                    }
+
+                    Err(_) => { false }
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses a message that was sent with the compressed flag set.
+///
+/// The flag doesn't specify which algorithm was used, since there's only ever one algorithm
+/// in use for a connection. The decompressed message is written to `buffer`, replacing its
+/// contents. Returns `false` if the message is malformed or would decompress to more than the
+/// maximum message size, in which case the contents of `buffer` are unspecified.
+///
+/// The output is limited to the most that `message` could possibly decompress to, so a small
+/// packet can't force a large allocation.
+pub(crate) fn decompress(message: &[u8], buffer: &mut Vec<u8>) -> bool {
+    let max_len = cmp::min(MAX_MESSAGE_LEN, message.len().saturating_mul(MAX_LZ4_EXPANSION));
+    buffer.clear();
+    buffer.resize(max_len, 0);
+    match lz4::decompress_into(message, buffer) {
+        Ok(len) => {
+            buffer.truncate(len);
+            true
+        }
+
+        Err(_) => { false }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lz4_roundtrip() {
+        let message = b"position: (1.0, 2.0, 3.0), position: (1.0, 2.0, 3.0), position: (1.0, 2.0, 3.0)";
+
+        let mut buffer = Vec::new();
+        assert!(Compression::Lz4.compress(&message[..], &mut buffer), "Message wasn't compressed");
+        assert!(buffer.len() < message.len());
+
+        let mut decompressed = Vec::new();
+        assert!(decompress(&buffer, &mut decompressed), "Failed to decompress message");
+        assert_eq!(&message[..], &decompressed[..]);
+    }
+
+    #[test]
+    fn compress_reuses_buffer() {
+        let message = [7; 1000];
+
+        let mut buffer = Vec::with_capacity(MAX_MESSAGE_LEN);
+        let capacity = buffer.capacity();
+        assert!(Compression::Lz4.compress(&message[..], &mut buffer));
+        assert!(Compression::Lz4.compress(&message[..], &mut buffer));
+        assert_eq!(capacity, buffer.capacity());
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        // A message that decompresses to just over the maximum message size.
+        let message = vec![0; MAX_MESSAGE_LEN + 1];
+
+        let mut buffer = Vec::new();
+        assert!(Compression::Lz4.compress(&message[..], &mut buffer));
+
+        let mut decompressed = Vec::new();
+        assert!(!decompress(&buffer, &mut decompressed));
+        assert!(decompressed.len() <= MAX_MESSAGE_LEN);
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let message = [0; COMPRESSION_THRESHOLD - 1];
+
+        let mut buffer = Vec::new();
+        assert!(!Compression::Lz4.compress(&message[..], &mut buffer));
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let mut buffer = Vec::new();
+
+        // Empty input, which is missing the token.
+        assert!(!decompress(&[], &mut buffer));
+
+        // Literals that run past the end of the input.
+        assert!(!decompress(&[0x50, 1, 2], &mut buffer));
+
+        // A match that refers to data before the start of the output.
+        assert!(!decompress(&[0x10, 1, 2, 0], &mut buffer));
+
+        // A zero offset.
+        assert!(!decompress(&[0x10, 1, 0, 0], &mut buffer));
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Compression::Lz4, Compression::Lz4.negotiate(Compression::Lz4));
+        assert_eq!(Compression::None, Compression::Lz4.negotiate(Compression::None));
+        assert_eq!(Compression::None, Compression::None.negotiate(Compression::Lz4));
+    }
+
+    mod proptests {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use super::super::*;
+
+        proptest! {
+            #[test]
+            fn roundtrip(message in vec(0 .. 4u8, 0 .. 4096)) {
+                let mut buffer = Vec::new();
+                if Compression::Lz4.compress(&message, &mut buffer) {
+                    let mut decompressed = Vec::new();
+                    prop_assert!(decompress(&buffer, &mut decompressed));
+                    prop_assert_eq!(&message, &decompressed);
+                }
+            }
+
+            #[test]
+            fn decompress_arbitrary_bytes(message in vec(any::<u8>(), 0 .. 1024)) {
+                let mut buffer = Vec::new();
+                let _ = decompress(&message, &mut buffer);
+                prop_assert!(buffer.len() <= MAX_MESSAGE_LEN);
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use bincode;
+use futures::prelude::*;
+use rand;
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use super::{decode, encode, Error, MAX_PACKET_LEN, Packet, PacketData, PROTOCOL_VERSION};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+
+// How often the discovery request is re-broadcast while waiting for responses, in case the
+// request or a response was dropped.
+const REBROADCAST_INTERVAL_MILLIS: u64 = 250;
+
+/// Information about a server, sent in response to discovery requests.
+///
+/// Provided to the [`ConnectionListener`] with [`enable_discovery`].
+///
+/// [`ConnectionListener`]: ./struct.ConnectionListener.html
+/// [`enable_discovery`]: ./struct.ConnectionListener.html#method.enable_discovery
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The display name of the server.
+    pub name: String,
+
+    /// The name of the map currently being played.
+    pub map: String,
+
+    /// The number of players currently connected to the server.
+    pub player_count: u32,
+}
+
+/// A server found with [`discover`].
+///
+/// [`discover`]: ./fn.discover.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    /// The address to use to connect to the server.
+    pub address: SocketAddr,
+
+    /// The version of the protocol used by the server. Connecting to the server will fail if
+    /// it doesn't match the local protocol version.
+    pub protocol_version: u32,
+
+    /// The information provided by the server.
+    pub info: ServerInfo,
+}
+
+impl DiscoveredServer {
+    /// Returns `true` if the server uses the same protocol version as the local one.
+    ///
+    /// Connecting to a server that isn't compatible will fail with
+    /// [`Error::VersionMismatch`], so incompatible servers should usually be skipped.
+    ///
+    /// [`Error::VersionMismatch`]: ./enum.Error.html#variant.VersionMismatch
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// Searches the local network for servers listening on `port`.
+///
+/// Broadcasts a discovery request and returns a stream that yields each server that responds.
+/// Each server is only yielded once, even if it responds multiple times. The stream ends once
+/// `timeout` has elapsed.
+///
+/// Only servers that have enabled discovery with [`ConnectionListener::enable_discovery`] will
+/// respond.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate futures;
+/// # extern crate sumi;
+/// # extern crate tokio_core;
+/// # fn main() {
+/// use futures::Stream;
+/// use std::time::Duration;
+/// use tokio_core::reactor::Core;
+///
+/// let mut core = Core::new().unwrap();
+/// let discover = sumi::discover(1234, Duration::from_secs(1), &core.handle())
+///     .unwrap()
+///     .for_each(|server| {
+///         println!("Found {} at {}", server.info.name, server.address);
+///         Ok(())
+///     });
+/// core.run(discover).unwrap();
+/// # }
+/// ```
+///
+/// [`ConnectionListener::enable_discovery`]: ./struct.ConnectionListener.html#method.enable_discovery
+pub fn discover(port: u16, timeout: Duration, handle: &Handle) -> Result<Discover, Error> {
+    let bind_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+    let socket = UdpSocket::bind(&bind_address, handle)?;
+    socket.set_broadcast(true)?;
+
+    let mut discover = Discover {
+        socket,
+        broadcast_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)), port),
+        request_id: rand::random(),
+        timeout: Timeout::new(timeout, handle)?,
+        interval: Interval::new(Duration::from_millis(REBROADCAST_INTERVAL_MILLIS), handle)?,
+        discovered: HashSet::new(),
+
+        read_buffer: vec![0; MAX_PACKET_LEN],
+        write_buffer: Vec::with_capacity(MAX_PACKET_LEN),
+    };
+
+    // Send the first request immediately, rather than waiting for the first interval.
+    discover.broadcast_request()?;
+
+    Ok(discover)
+}
+
+/// Stream returned by [`discover`] that yields each server found on the local network.
+///
+/// [`discover`]: ./fn.discover.html
+pub struct Discover {
+    socket: UdpSocket,
+    broadcast_address: SocketAddr,
+
+    // Random ID sent with the request and echoed in the responses, used to discard responses
+    // to other clients' requests.
+    request_id: u64,
+
+    timeout: Timeout,
+    interval: Interval,
+
+    // The addresses of the servers that have already been yielded.
+    discovered: HashSet<SocketAddr>,
+
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+}
+
+impl Discover {
+    fn broadcast_request(&mut self) -> Result<(), Error> {
+        encode(
+            Packet {
+                connection_id: self.request_id,
+                data: PacketData::DiscoveryRequest,
+            },
+            &mut self.write_buffer,
+        )?;
+
+        // NOTE: We don't do anything when we get a `WouldBlock` error because we'll resend the
+        // request at a regular interval.
+        if let Err(error) = self.socket.send_to(&self.write_buffer, &self.broadcast_address) {
+            if error.kind() != io::ErrorKind::WouldBlock {
+                return Err(error.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Stream for Discover {
+    type Item = DiscoveredServer;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Async::Ready(()) = self.timeout.poll()? {
+            return Ok(Async::Ready(None));
+        }
+
+        while let Async::Ready(_) = self.interval.poll()? {
+            self.broadcast_request()?;
+        }
+
+        loop {
+            let (bytes_read, address) = try_nb!(self.socket.recv_from(&mut self.read_buffer));
+
+            let (protocol_version, info) = match decode(&self.read_buffer[.. bytes_read]) {
+                Some(Packet {
+                    connection_id,
+                    data: PacketData::DiscoveryResponse { version, info },
+                }) => {
+                    // Discard responses to other requests.
+                    if connection_id != self.request_id { continue; }
+
+                    (version, info)
+                }
+
+                // Discard any other packets.
+                _ => { continue; }
+            };
+
+            // Don't yield the same server more than once.
+            if self.discovered.contains(&address) { continue; }
+
+            // Discard responses with info we can't decode, since they're likely from a server
+            // using a different version of the protocol.
+            let info = match bincode::deserialize(info) {
+                Ok(info) => { info }
+                Err(_) => { continue; }
+            };
+
+            self.discovered.insert(address);
+            return Ok(Async::Ready(Some(DiscoveredServer { address, protocol_version, info })));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+
+/// The error type for operations on sumi connections.
+///
+/// Protocol-level failures (e.g. the server rejecting the connection) get their own variants so
+/// that applications can react to them appropriately. Errors from the underlying UDP socket are
+/// wrapped in [`Error::Socket`].
+///
+/// [`Error::Socket`]: #variant.Socket
+#[derive(Debug, Fail)]
+pub enum Error {
+    /// The remote host didn't complete the connection handshake in time.
+    #[fail(display = "Timed out waiting for the connection handshake to complete")]
+    HandshakeTimeout,
+
+    /// The server rejected the challenge cookie sent during the connection handshake.
+    ///
+    /// This usually means that the response to the challenge took too long to reach the server,
+    /// or that the client's address changed partway through the handshake.
+    #[fail(display = "The server rejected the connection challenge")]
+    CookieRejected,
+
+    /// The local and remote hosts are using incompatible versions of the protocol.
+    #[fail(
+        display = "Protocol version mismatch, local version: {}, remote version: {}",
+        local,
+        remote
+    )]
+    VersionMismatch {
+        /// The protocol version used by the local host.
+        local: u32,
+
+        /// The protocol version used by the remote host.
+        remote: u32,
+    },
+
+    /// The server didn't respond to an info query in time.
+    #[fail(display = "Timed out waiting for a response to the info query")]
+    QueryTimeout,
+
+    /// The peer stopped responding.
+    ///
+    /// This is returned when no packets are received from the peer before the disconnect
+    /// timeout elapses, or when a reliable message isn't acknowledged in time.
+    #[fail(display = "The peer disconnected")]
+    PeerDisconnected,
+
+    /// A message was larger than the maximum supported size.
+    ///
+    /// This is returned when attempting to send a message that's too large. Received messages
+    /// that don't fit in the buffer passed to [`Connection::recv`] are discarded instead.
+    ///
+    /// [`Connection::recv`]: ./struct.Connection.html#method.recv
+    #[fail(display = "Message of {} bytes is larger than the maximum of {} bytes", len, max)]
+    MessageTooLarge {
+        /// The size of the message in bytes.
+        len: usize,
+
+        /// The maximum size of a message in bytes.
+        max: usize,
+    },
+
+    /// A message couldn't be serialized.
+    #[fail(display = "Failed to encode message: {}", _0)]
+    Encode(String),
+
+    /// A received message couldn't be deserialized.
+    #[fail(display = "Failed to decode message: {}", _0)]
+    Decode(String),
+
+    /// An error occurred on the underlying socket.
+    #[fail(display = "Socket error: {}", _0)]
+    Socket(#[cause] io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Socket(error)
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use super::{MAX_FRAGMENT_LEN, MAX_FRAGMENTS_PER_MESSAGE};
+
+// The maximum number of partially-received messages that we track for a single connection. If a
+// fragment for a new message arrives while we're already at the limit, the oldest partial message
+// is discarded to make room for it.
+pub(crate) const MAX_IN_FLIGHT_MESSAGES: usize = 8;
+
+// How long we wait for the remaining fragments of a message after receiving its first fragment
+// before discarding the partial message.
+const REASSEMBLY_TIMEOUT_MILLIS: u64 = 1000;
+
+/// Statistics about incoming messages that were discarded before they were fully received.
+///
+/// Returned by [`Connection::reassembly_stats`].
+///
+/// [`Connection::reassembly_stats`]: ./struct.Connection.html#method.reassembly_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// The number of partial messages discarded because the rest of their fragments didn't
+    /// arrive in time.
+    pub timed_out: u64,
+
+    /// The number of partial messages discarded to make room for newer messages, because too
+    /// many messages were being reassembled at once.
+    pub evicted: u64,
+
+    /// The number of fragments discarded because they were malformed or didn't match the other
+    /// fragments received for the same message.
+    pub invalid_fragments: u64,
+}
+
+/// Tracks the partially-received messages for a connection.
+///
+/// Memory use is bounded: Completed messages are removed as soon as they're returned, partial
+/// messages are discarded if they take too long to complete, and only a limited number of
+/// partial messages are tracked at once.
+#[derive(Debug, Default)]
+pub(crate) struct Reassembler {
+    messages: HashMap<u32, MessageFragments>,
+    stats: ReassemblyStats,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Default::default()
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Returns the number of partial messages currently being tracked.
+    #[cfg(feature = "fuzzing")]
+    pub fn in_flight(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Adds a received fragment to its message, returning the message once all of its fragments
+    /// have been received.
+    pub fn insert(
+        &mut self,
+        sequence_number: u32,
+        fragment: &[u8],
+        num_fragments: u8,
+        fragment_number: u8,
+        compressed: bool,
+        now: Instant,
+    ) -> Option<MessageFragments> {
+        self.evict_expired(now);
+
+        // All fragments except the last one must be the maximum size, otherwise we can't tell
+        // where each fragment goes in the final message.
+        let is_valid = num_fragments > 0
+            && fragment_number < num_fragments
+            && fragment.len() <= MAX_FRAGMENT_LEN
+            && (fragment_number == num_fragments - 1 || fragment.len() == MAX_FRAGMENT_LEN);
+        if !is_valid {
+            self.stats.invalid_fragments += 1;
+            return None;
+        }
+
+        // Make room for the new message if we're already tracking too many.
+        if !self.messages.contains_key(&sequence_number)
+            && self.messages.len() >= MAX_IN_FLIGHT_MESSAGES
+        {
+            self.evict_oldest();
+        }
+
+        let is_complete = {
+            let message = self.messages
+                .entry(sequence_number)
+                .or_insert_with(|| MessageFragments::new(num_fragments, compressed, now));
+
+            // If the packet disagrees with the first packet we received for this message, then
+            // discard it.
+            if num_fragments != message.num_fragments || compressed != message.compressed {
+                self.stats.invalid_fragments += 1;
+                return None;
+            }
+
+            message.insert(fragment_number, fragment);
+            message.received == message.num_fragments
+        };
+
+        if is_complete {
+            self.messages.remove(&sequence_number)
+        } else {
+            None
+        }
+    }
+
+    /// Discards any partial messages that have been waiting longer than the reassembly timeout.
+    fn evict_expired(&mut self, now: Instant) {
+        let timeout = Duration::from_millis(REASSEMBLY_TIMEOUT_MILLIS);
+        let before = self.messages.len();
+        self.messages.retain(|_, message| now.duration_since(message.started) < timeout);
+        self.stats.timed_out += (before - self.messages.len()) as u64;
+    }
+
+    /// Discards the partial message that started being received the longest time ago.
+    fn evict_oldest(&mut self) {
+        let oldest = self.messages
+            .iter()
+            .min_by_key(|&(_, message)| message.started)
+            .map(|(&sequence_number, _)| sequence_number);
+
+        if let Some(sequence_number) = oldest {
+            self.messages.remove(&sequence_number);
+            self.stats.evicted += 1;
+        }
+    }
+}
+
+/// A collection of fragments for a partially-received message.
+///
+/// This tracks how many fragments are expected as part of the message, which fragments have
+/// been received so far, and the data received so far.
+pub(crate) struct MessageFragments {
+    // The total number of fragments expected for the message.
+    num_fragments: u8,
+
+    // Whether the message was compressed by the sender.
+    compressed: bool,
+
+    // The time at which the first fragment of the message was received.
+    started: Instant,
+
+    // The number of fragments we've received so far.
+    received: u8,
+
+    // Bitset tracking which of the fragments have been received.
+    fragments: [u64; MAX_FRAGMENTS_PER_MESSAGE / 64],
+
+    // The message data received so far. Each fragment is written at its offset in the message,
+    // and the buffer grows as needed, so once all fragments have been received this contains
+    // exactly the full message.
+    data: Vec<u8>,
+}
+
+impl MessageFragments {
+    fn new(num_fragments: u8, compressed: bool, started: Instant) -> MessageFragments {
+        MessageFragments {
+            num_fragments,
+            compressed,
+            started,
+            received: 0,
+            fragments: [0; MAX_FRAGMENTS_PER_MESSAGE / 64],
+            data: Vec::new(),
+        }
+    }
+
+    /// Returns the data for the message.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns whether the message was compressed by the sender.
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+
+    fn insert(&mut self, fragment_number: u8, fragment: &[u8]) {
+        let index = fragment_number as usize / 64;
+        let bit = 1 << (fragment_number as usize % 64);
+
+        // Ignore duplicate fragments.
+        if self.fragments[index] & bit != 0 { return; }
+
+        let fragment_start = fragment_number as usize * MAX_FRAGMENT_LEN;
+        let fragment_end = fragment_start + fragment.len();
+        if self.data.len() < fragment_end {
+            self.data.resize(fragment_end, 0);
+        }
+        self.data[fragment_start .. fragment_end].copy_from_slice(fragment);
+
+        self.fragments[index] |= bit;
+        self.received += 1;
+    }
+}
+
+impl ::std::fmt::Debug for MessageFragments {
+    fn fmt(&self, formatter: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        write!(formatter, "MessageFragments {{ .. }}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static FRAGMENT: &'static [u8] = &[0xAB; MAX_FRAGMENT_LEN];
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        assert!(reassembler.insert(1, &[0xCD; 10], 3, 2, false, now).is_none());
+        assert!(reassembler.insert(1, FRAGMENT, 3, 0, false, now).is_none());
+
+        // Duplicate fragments are ignored.
+        assert!(reassembler.insert(1, FRAGMENT, 3, 0, false, now).is_none());
+
+        let message = reassembler.insert(1, FRAGMENT, 3, 1, false, now)
+            .expect("Message wasn't completed");
+        assert_eq!(MAX_FRAGMENT_LEN * 2 + 10, message.data().len());
+        assert_eq!(&[0xCD; 10], &message.data()[MAX_FRAGMENT_LEN * 2 ..]);
+
+        // The completed message is no longer tracked.
+        assert!(reassembler.messages.is_empty());
+    }
+
+    #[test]
+    fn reassemble_across_wraparound() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        // Interleave fragments from the messages on either side of the wraparound.
+        assert!(reassembler.insert(::std::u32::MAX, FRAGMENT, 2, 0, false, now).is_none());
+        assert!(reassembler.insert(0, FRAGMENT, 2, 0, false, now).is_none());
+
+        let message = reassembler.insert(0, &[0xCD; 10], 2, 1, false, now)
+            .expect("Message 0 wasn't completed");
+        assert_eq!(&[0xCD; 10], &message.data()[MAX_FRAGMENT_LEN ..]);
+
+        let message = reassembler.insert(::std::u32::MAX, &[0xEF; 10], 2, 1, false, now)
+            .expect("Message u32::MAX wasn't completed");
+        assert_eq!(&[0xEF; 10], &message.data()[MAX_FRAGMENT_LEN ..]);
+    }
+
+    #[test]
+    fn partial_messages_time_out() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        reassembler.insert(1, FRAGMENT, 2, 0, false, now);
+        reassembler.insert(
+            2,
+            FRAGMENT,
+            2,
+            0,
+            false,
+            now + Duration::from_millis(REASSEMBLY_TIMEOUT_MILLIS),
+        );
+
+        assert_eq!(1, reassembler.messages.len());
+        assert_eq!(1, reassembler.stats().timed_out);
+    }
+
+    #[test]
+    fn in_flight_messages_are_capped() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        for sequence_number in 0 .. MAX_IN_FLIGHT_MESSAGES as u32 + 2 {
+            let received = now + Duration::from_millis(sequence_number as u64);
+            reassembler.insert(sequence_number, FRAGMENT, 2, 0, false, received);
+        }
+
+        assert_eq!(MAX_IN_FLIGHT_MESSAGES, reassembler.messages.len());
+        assert_eq!(2, reassembler.stats().evicted);
+
+        // The oldest messages are the ones that get evicted.
+        assert!(!reassembler.messages.contains_key(&0));
+        assert!(!reassembler.messages.contains_key(&1));
+    }
+
+    #[test]
+    fn invalid_fragments_are_discarded() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+
+        // Only the last fragment may be smaller than the maximum fragment size.
+        assert!(reassembler.insert(1, &[0xCD; 10], 2, 0, false, now).is_none());
+
+        // Fragments must agree on the number of fragments in the message.
+        reassembler.insert(2, FRAGMENT, 2, 0, false, now);
+        assert!(reassembler.insert(2, &[0xCD; 10], 3, 2, false, now).is_none());
+
+        assert_eq!(2, reassembler.stats().invalid_fragments);
+    }
+}
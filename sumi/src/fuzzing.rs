@@ -0,0 +1,158 @@
+//! Entry points for the fuzz targets in `fuzz/`.
+//!
+//! This module is only available with the `fuzzing` feature, and exposes just enough of sumi's
+//! internals for the fuzz targets to drive them. Each function panics if it finds a bug, so that
+//! the fuzzer can report it.
+
+use byteorder::{NetworkEndian, ByteOrder};
+use futures::future;
+use futures::prelude::*;
+use std::cell::RefCell;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use super::{
+    decode as decode_packet,
+    encode,
+    packet_checksum,
+    ConnectionListener,
+    MAX_FRAGMENT_LEN,
+    MAX_PACKET_LEN,
+};
+use super::fragments::Reassembler;
+use tokio_core::reactor::Core;
+
+/// Prepends the correct checksum to `body`, producing a packet that passes validation.
+///
+/// Random bytes almost never have a valid checksum, so fuzz targets use this to reach the code
+/// after the checksum is verified.
+pub fn packet_with_checksum(body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 4];
+    NetworkEndian::write_u32(&mut packet[..], packet_checksum(body));
+    packet.extend(body);
+    packet
+}
+
+/// Decodes `data` both as-is and as the body of a packet with a valid checksum.
+///
+/// Any packet that decodes successfully must decode to the same packet after being re-encoded.
+pub fn decode(data: &[u8]) {
+    decode_packet(data);
+
+    // We never read more than `MAX_PACKET_LEN` bytes from a socket, so larger packets can't
+    // happen in practice.
+    if data.len() + 4 > MAX_PACKET_LEN { return; }
+
+    let buffer = packet_with_checksum(data);
+    if let Some(packet) = decode_packet(&buffer) {
+        let mut encoded = Vec::with_capacity(MAX_PACKET_LEN);
+        encode(packet, &mut encoded).expect("Failed to re-encode decoded packet");
+        assert_eq!(Some(packet), decode_packet(&encoded), "Packet changed after re-encoding");
+    }
+}
+
+/// Feeds a sequence of fragments described by `data` into a `Reassembler`.
+///
+/// Each fragment is described by 6 bytes: The sequence number, the number of fragments, the
+/// fragment number, flags (bit 0 for compressed, bit 1 for a full-size fragment), the number of
+/// milliseconds (times 10) since the previous fragment, and the length of the fragment if it's
+/// not full-size.
+pub fn reassemble(data: &[u8]) {
+    let mut reassembler = Reassembler::new();
+    let mut now = Instant::now();
+
+    for op in data.chunks(6) {
+        if op.len() < 6 { break; }
+
+        // Center the sequence numbers on 0 so that wraparound is exercised.
+        let sequence_number = (op[0] as u32).wrapping_sub(128);
+        let num_fragments = op[1];
+        let fragment_number = op[2];
+        let compressed = op[3] & 0x01 != 0;
+        now += Duration::from_millis(op[4] as u64 * 10);
+        let fragment = if op[3] & 0x02 != 0 {
+            vec![op[0]; MAX_FRAGMENT_LEN]
+        } else {
+            vec![op[0]; op[5] as usize]
+        };
+
+        let message = reassembler.insert(
+            sequence_number,
+            &fragment,
+            num_fragments,
+            fragment_number,
+            compressed,
+            now,
+        );
+
+        if let Some(message) = message {
+            // Every fragment but the last is full-size, and the last is at most full-size.
+            let len = message.data().len();
+            assert!(len <= num_fragments as usize * MAX_FRAGMENT_LEN, "Message is too long");
+            assert!(len >= (num_fragments as usize - 1) * MAX_FRAGMENT_LEN, "Message is too short");
+            assert_eq!(compressed, message.compressed());
+        }
+
+        assert!(reassembler.in_flight() <= super::fragments::MAX_IN_FLIGHT_MESSAGES);
+    }
+}
+
+struct ListenerHarness {
+    core: Core,
+    listener: ConnectionListener,
+    socket: UdpSocket,
+}
+
+thread_local! {
+    // Creating the listener is expensive, so one is reused for every input. This also lets
+    // state build up in the listener across inputs, the same as it would in a real server.
+    static HARNESS: RefCell<Option<ListenerHarness>> = RefCell::new(None);
+}
+
+/// Sends the datagrams described by `data` to a `ConnectionListener`.
+///
+/// `data` is split into datagrams, each prefixed by its length as a `u16`. Each datagram is
+/// sent with a valid checksum. The listener must never return an error or panic, no matter
+/// what it's sent.
+pub fn listener(data: &[u8]) {
+    HARNESS.with(|harness| {
+        let mut harness = harness.borrow_mut();
+        let harness = harness.get_or_insert_with(|| {
+            let core = Core::new().expect("Failed to create reactor");
+            let listener = ConnectionListener::bind("127.0.0.1:0", &core.handle())
+                .expect("Failed to bind listener");
+            let socket = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind socket");
+            ListenerHarness { core, listener, socket }
+        });
+
+        let listener_address = harness.listener.local_addr().expect("Failed to get address");
+
+        let mut remaining = data;
+        while remaining.len() >= 2 {
+            let len = NetworkEndian::read_u16(remaining) as usize;
+            let body = &remaining[2 ..];
+            let (datagram, rest) = body.split_at(::std::cmp::min(len, body.len()));
+            remaining = rest;
+
+            if datagram.len() + 4 > MAX_PACKET_LEN { continue; }
+            let packet = packet_with_checksum(datagram);
+            harness.socket.send_to(&packet, listener_address).expect("Failed to send datagram");
+        }
+
+        // Give the reactor a chance to notice the incoming datagrams, then let the listener
+        // process them.
+        harness.core.turn(Some(Duration::from_millis(1)));
+        let listener = &mut harness.listener;
+        harness.core.run(future::poll_fn(|| -> Poll<(), ()> {
+            loop {
+                match listener.poll() {
+                    // Drop any connections that get accepted, we only care about the listener.
+                    Ok(Async::Ready(Some(_))) => {}
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => { break; }
+                    Err(error) => { panic!("Listener returned an error: {:?}", error); }
+                }
+            }
+
+            Ok(Async::Ready(()))
+        })).unwrap();
+    });
+}
@@ -0,0 +1,149 @@
+use futures::prelude::*;
+use rand;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use super::{decode, encode, Error, MAX_PACKET_LEN, Packet, PacketData};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+
+/// The response to an info query, returned by [`query_info`].
+///
+/// [`query_info`]: ./fn.query_info.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InfoResponse {
+    /// The version of the protocol used by the server.
+    pub protocol_version: u32,
+
+    /// The round trip time between sending the query and receiving the response.
+    pub ping: Duration,
+
+    /// The payload provided by the server's info callback.
+    pub payload: Vec<u8>,
+}
+
+/// Queries a server for information without connecting to it.
+///
+/// Returns a future that resolves once the server responds, or fails with
+/// [`Error::QueryTimeout`] if the server doesn't respond before `timeout` elapses. Only servers
+/// that have enabled info queries with [`ConnectionListener::enable_info_queries`] will respond.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate sumi;
+/// # extern crate tokio_core;
+/// # fn main() {
+/// use std::time::Duration;
+/// use tokio_core::reactor::Core;
+///
+/// let mut core = Core::new().unwrap();
+/// let address = "127.0.0.1:1234".parse().unwrap();
+/// let query = sumi::query_info(address, Duration::from_secs(1), &core.handle()).unwrap();
+/// let response = core.run(query).unwrap();
+/// println!("Ping: {:?}", response.ping);
+/// # }
+/// ```
+///
+/// [`Error::QueryTimeout`]: ./enum.Error.html#variant.QueryTimeout
+/// [`ConnectionListener::enable_info_queries`]: ./struct.ConnectionListener.html#method.enable_info_queries
+pub fn query_info(
+    address: SocketAddr,
+    timeout: Duration,
+    handle: &Handle,
+) -> Result<QueryInfo, Error> {
+    // Bind to the unspecified address of the same family as the server's address.
+    let bind_address = match address {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0),
+    };
+    let socket = UdpSocket::bind(&bind_address, handle)?;
+
+    // Send the request immediately, so that the measured ping isn't affected by when the future
+    // is first polled.
+    let request_id = rand::random();
+    let mut write_buffer = Vec::with_capacity(MAX_PACKET_LEN);
+    encode(
+        Packet {
+            connection_id: request_id,
+            data: PacketData::InfoRequest,
+        },
+        &mut write_buffer,
+    )?;
+    let sent_at = Instant::now();
+    socket.send_to(&write_buffer, &address)?;
+
+    Ok(QueryInfo {
+        socket,
+        peer_address: address,
+        request_id,
+        sent_at,
+        timeout: Timeout::new(timeout, handle)?,
+        read_buffer: vec![0; MAX_PACKET_LEN],
+    })
+}
+
+/// Future returned by [`query_info`], resolving to the server's response.
+///
+/// [`query_info`]: ./fn.query_info.html
+pub struct QueryInfo {
+    socket: UdpSocket,
+    peer_address: SocketAddr,
+
+    // Random ID sent with the request and echoed in the response, used to discard stray
+    // responses.
+    request_id: u64,
+    sent_at: Instant,
+
+    timeout: Timeout,
+    read_buffer: Vec<u8>,
+}
+
+impl Future for QueryInfo {
+    type Item = InfoResponse;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let (bytes_read, address) = match self.socket.recv_from(&mut self.read_buffer) {
+                Ok(result) => { result }
+
+                Err(error) => {
+                    if error.kind() != io::ErrorKind::WouldBlock {
+                        return Err(error.into());
+                    }
+
+                    break;
+                }
+            };
+
+            // Discard the packet if it didn't come from the server we're querying.
+            if address != self.peer_address { continue; }
+
+            match decode(&self.read_buffer[.. bytes_read]) {
+                Some(Packet {
+                    connection_id,
+                    data: PacketData::InfoResponse { version, payload },
+                }) => {
+                    // Discard responses to other requests.
+                    if connection_id != self.request_id { continue; }
+
+                    return Ok(Async::Ready(InfoResponse {
+                        protocol_version: version,
+                        ping: self.sent_at.elapsed(),
+                        payload: payload.to_vec(),
+                    }));
+                }
+
+                // Discard any other packets.
+                _ => { continue; }
+            }
+        }
+
+        if let Async::Ready(()) = self.timeout.poll()? {
+            return Err(Error::QueryTimeout);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
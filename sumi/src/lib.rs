@@ -9,9 +9,13 @@ extern crate bincode;
 extern crate byteorder;
 extern crate crc;
 extern crate failure;
+#[macro_use]
+extern crate failure_derive;
 extern crate futures;
+extern crate lz4_flex;
 extern crate rand;
 extern crate ring;
+extern crate rmp_serde;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -20,6 +24,11 @@ extern crate state_machine_future;
 extern crate subslice_index;
 #[macro_use]
 extern crate tokio_core;
+extern crate untrusted;
+
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32::{self, Digest, Hasher32};
@@ -27,8 +36,12 @@ use futures::prelude::*;
 use rand::Rng;
 use rand::os::OsRng;
 use ring::aead::{self, Algorithm, CHACHA20_POLY1305, OpeningKey, SealingKey};
-use ring::digest::SHA512;
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::digest::{SHA256, SHA512};
+use ring::hkdf;
+use ring::hmac;
 use ring::pbkdf2;
+use ring::rand::SystemRandom;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::cmp;
@@ -37,19 +50,50 @@ use std::collections::hash_map::Entry;
 use std::hash::Hasher;
 use std::io::{self, Cursor};
 use std::mem;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::str;
 use std::time::{Duration, Instant};
 use tokio_core::net::UdpSocket;
 use tokio_core::reactor::{Handle, Interval, Timeout};
 
+pub use self::capture::{
+    replay,
+    Capture,
+    CaptureReader,
+    CapturedDatagram,
+    Direction,
+    PacketKind,
+    Replay,
+};
+pub use self::codec::{Bincode, Codec, MessagePack};
+pub use self::compression::Compression;
+pub use self::discovery::{discover, Discover, DiscoveredServer, ServerInfo};
+pub use self::error::Error;
+pub use self::fragments::ReassemblyStats;
+pub use self::info::{query_info, InfoResponse, QueryInfo};
 pub use self::send::Send;
 pub use self::send_reliable::SendReliable;
 pub use self::recv::Receive;
 
+use self::capture::Socket;
+use self::fragments::Reassembler;
+use self::rate_limit::AddressRateLimiter;
+use self::sequence::SequenceTracker;
+
+mod capture;
+mod codec;
+mod compression;
+mod discovery;
+mod error;
+mod fragments;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod info;
+mod rate_limit;
 mod recv;
 mod send;
 mod send_reliable;
+mod sequence;
 
 // The base password used to generate the encryption keys for the connection listener.
 //
@@ -73,6 +117,27 @@ const NONCE_LEN: usize = 12;
 // size of a cookie.
 const MAX_CIPHERTEXT_LEN: usize = MAX_COOKIE_LEN - NONCE_LEN;
 
+// The additional data authenticated along with each kind of cookie. This ensures that a cookie
+// issued for one purpose can't be used for another.
+static CHALLENGE_COOKIE_AD: &'static [u8] = b"challenge";
+static MIGRATION_COOKIE_AD: &'static [u8] = b"migration";
+
+// The length of the session key used to authenticate connection migration, and of the HMAC tag
+// produced with it.
+const SESSION_KEY_LEN: usize = 32;
+const MIGRATION_TAG_LEN: usize = 32;
+
+// The length of the X25519 public keys exchanged during the handshake to agree on the session
+// key.
+const PUBLIC_KEY_LEN: usize = 32;
+
+// The info used when deriving the session key from the key exchange, so that the shared secret
+// can't be confused with keys derived for any other purpose.
+static SESSION_KEY_INFO: &'static [u8] = b"sumi session key";
+
+// The minimum time between migration challenges sent for a single connection.
+const MIGRATION_CHALLENGE_INTERVAL_MILLIS: u64 = 100;
+
 // The size of the packet header in bytes.
 //
 // The packet heaer is the 4 byte CRC32 checksum (that includes the implicit protocol ID), the
@@ -83,9 +148,9 @@ const HEADER_LEN: usize = 4 + 8 + 1;
 //
 // This amount is determined by the maximum size of a single packet, minus the size of the packet
 // header, minus the 4 byte sequence number, minus 1 byte specifying the number of packets for
-// this message, minus 1 byte for the packet's chunk sequence number, minus 2 bytes for the
-// length of the fragment in bytes.
-const MAX_FRAGMENT_LEN: usize = MAX_PACKET_LEN - HEADER_LEN - 4 - 1 - 1 - 2;
+// this message, minus 1 byte for the packet's chunk sequence number, minus 1 byte for the
+// message flags, minus 2 bytes for the length of the fragment in bytes.
+const MAX_FRAGMENT_LEN: usize = MAX_PACKET_LEN - HEADER_LEN - 4 - 1 - 1 - 1 - 2;
 
 const MAX_FRAGMENTS_PER_MESSAGE: usize = 256;
 
@@ -95,15 +160,49 @@ const MAX_FRAGMENTS_PER_MESSAGE: usize = 256;
 // size a single fragment can be times 256.
 const MAX_MESSAGE_LEN: usize = MAX_FRAGMENT_LEN * MAX_FRAGMENTS_PER_MESSAGE;
 
+// The largest payload that can be sent in response to a discovery or info request.
+//
+// This is the maximum size of a packet, minus the size of the packet header, minus the 4 byte
+// protocol version, minus 2 bytes for the length of the payload.
+const MAX_RESPONSE_PAYLOAD_LEN: usize = MAX_PACKET_LEN - HEADER_LEN - 4 - 2;
+
 // The protocol ID is the first 64 bits of the MD5 hash of "sumi".
 const PROTOCOL_ID: u64 = 0x41008F06B7698109;
 
+// The version of the protocol, sent as part of the connection request. Must be incremented any
+// time a change is made to the protocol that would prevent older peers from communicating with
+// newer ones.
+const PROTOCOL_VERSION: u32 = 4;
+
 const CONNECTION_REQUEST: u8 = 1;
 const CHALLENGE: u8 = 2;
 const CHALLENGE_RESPONSE: u8 = 3;
 const CONNECTION_ACCEPTED: u8 = 4;
 const MESSAGE: u8 = 5;
 const ACK: u8 = 6;
+const CONNECTION_DENIED: u8 = 7;
+const MIGRATION_CHALLENGE: u8 = 8;
+const MIGRATION_RESPONSE: u8 = 9;
+const DISCOVERY_REQUEST: u8 = 10;
+const DISCOVERY_RESPONSE: u8 = 11;
+const INFO_REQUEST: u8 = 12;
+const INFO_RESPONSE: u8 = 13;
+
+// Limits on how many responses the listener sends to connectionless requests (i.e. discovery and
+// info requests) from each address, so that it can't be used to flood a spoofed address.
+const MAX_RESPONSE_BURST: u32 = 8;
+const MAX_RESPONSES_PER_SECOND: u32 = 4;
+
+// The maximum number of addresses that connectionless requests are rate limited for at once.
+// Requests from new addresses are ignored while this many addresses are being limited.
+const MAX_RATE_LIMITED_ADDRESSES: usize = 4096;
+
+// Reasons that a server can give for denying a connection.
+const DENIED_VERSION_MISMATCH: u8 = 1;
+const DENIED_COOKIE_REJECTED: u8 = 2;
+
+// Bit flags for the flags byte in the header of a message packet.
+const MESSAGE_FLAG_COMPRESSED: u8 = 0x01;
 
 static ALGORITHM: &'static Algorithm = &CHACHA20_POLY1305;
 
@@ -115,6 +214,11 @@ static ALGORITHM: &'static Algorithm = &CHACHA20_POLY1305;
 ///
 /// The socket will be closed when the value is dropped.
 ///
+/// If a client's address changes while it's connected (e.g. because of NAT rebinding or the
+/// client switching networks), the listener sends a challenge to the new address. Once the
+/// client proves that it owns the connection by signing the challenge with the secret key it
+/// was given during the handshake, its packets are accepted from the new address.
+///
 /// # Examples
 ///
 /// ```no_run
@@ -140,9 +244,14 @@ static ALGORITHM: &'static Algorithm = &CHACHA20_POLY1305;
 /// [`bind`]: #method.bind
 /// [`Connection`]: ./struct.Connection.html
 pub struct ConnectionListener {
-    socket: UdpSocket,
+    socket: Socket,
     local_address: SocketAddr,
 
+    // The address that the per-connection sockets forward packets to. This is the same as
+    // `local_address`, except when the listener is bound to an unspecified address (e.g.
+    // `0.0.0.0`), in which case we have to send to the loopback address instead.
+    forward_address: SocketAddr,
+
     // Map containing all the currently open connections.
     open_connections: HashMap<u64, OpenConnection>,
 
@@ -153,6 +262,20 @@ pub struct ConnectionListener {
     opening_key: OpeningKey,
     sealing_key: SealingKey,
 
+    // The compression algorithm that the listener will agree to if a client requests it.
+    compression: Compression,
+
+    // Callback providing the server info sent in response to discovery requests. Discovery
+    // requests are ignored if this isn't set.
+    discovery: Option<Box<FnMut() -> ServerInfo>>,
+
+    // Callback providing the payload sent in response to info requests. Info requests are
+    // ignored if this isn't set.
+    info: Option<Box<FnMut() -> Vec<u8>>>,
+
+    // Limits the rate at which we respond to discovery and info requests from each address.
+    response_limiter: AddressRateLimiter,
+
     // Track the time at which the `ConnectionListener` was created. This is used to send
     // timestamps as `Duration`s relative to `start_time`. This is needed since `Instant` can't
     // be serialized, but `Duration` can.
@@ -222,7 +345,7 @@ impl ConnectionListener {
     pub fn bind<A: ToSocketAddrs>(
         addresses: A,
         handle: &Handle,
-    ) -> Result<ConnectionListener, io::Error> {
+    ) -> Result<ConnectionListener, Error> {
         // Iterate over the specified addresses, trying to bind the UDP socket to each one in
         // turn. We use the first one that binds successfully, returning an error if none work.
         let socket = addresses.to_socket_addrs()?
@@ -240,6 +363,22 @@ impl ConnectionListener {
             .unwrap_or(Err(io::ErrorKind::AddrNotAvailable.into()))?;
         let local_address = socket.local_addr()?;
 
+        // If the listener is bound to an unspecified address, packets can't be sent to its local
+        // address on every platform. Instead, the per-connection sockets send to the loopback
+        // address on the same port.
+        let forward_address = match local_address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), local_address.port())
+            }
+
+            IpAddr::V6(ip) if ip.is_unspecified() => {
+                let loopback = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
+                SocketAddr::new(IpAddr::V6(loopback), local_address.port())
+            }
+
+            _ => { local_address }
+        };
+
         // Create the AEAD keys used for encrypting information in a connection challenge.
         let mut rng = OsRng::new()?;
 
@@ -261,12 +400,21 @@ impl ConnectionListener {
         let sealing_key = SealingKey::new(ALGORITHM, &key[..]).expect("Failed to create sealing key");
 
         Ok(ConnectionListener {
-            socket,
+            socket: Socket::new(socket),
             local_address,
+            forward_address,
 
             rng,
             opening_key,
             sealing_key,
+            compression: Compression::None,
+            discovery: None,
+            info: None,
+            response_limiter: AddressRateLimiter::new(
+                MAX_RESPONSE_BURST,
+                MAX_RESPONSES_PER_SECOND,
+                MAX_RATE_LIMITED_ADDRESSES,
+            ),
             start_time: Instant::now(),
             open_connections: HashMap::new(),
             read_buffer: vec![0; MAX_PACKET_LEN],
@@ -295,14 +443,107 @@ impl ConnectionListener {
     /// );
     /// # }
     /// ```
-    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
-        self.socket.local_addr()
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Sets the compression algorithm that the listener supports.
+    ///
+    /// Clients that request the same algorithm when connecting will have their messages
+    /// compressed. All other clients fall back to [`Compression::None`]. Only affects
+    /// connections established after the call. Defaults to [`Compression::None`].
+    ///
+    /// [`Compression::None`]: ./enum.Compression.html#variant.None
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Returns the compression algorithm that the listener supports.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Enables responding to discovery requests sent by [`discover`].
+    ///
+    /// `callback` is called each time a discovery request is received, and the returned
+    /// [`ServerInfo`] is sent back in the response. The encoded info must fit in a single
+    /// packet, responses with info that is too large are not sent.
+    ///
+    /// To be discoverable by clients on the local network, the listener needs to be bound to
+    /// an address that can receive broadcast packets, e.g. `0.0.0.0`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate sumi;
+    /// # extern crate tokio_core;
+    /// use sumi::{ConnectionListener, ServerInfo};
+    /// use tokio_core::reactor::Core;
+    ///
+    /// # fn main() {
+    /// let reactor = Core::new().unwrap();
+    /// let mut listener = ConnectionListener::bind("0.0.0.0:1234", &reactor.handle()).unwrap();
+    /// listener.enable_discovery(|| ServerInfo {
+    ///     name: "My Cool Server".into(),
+    ///     map: "arena".into(),
+    ///     player_count: 0,
+    /// });
+    /// # }
+    /// ```
+    ///
+    /// [`discover`]: ./fn.discover.html
+    /// [`ServerInfo`]: ./struct.ServerInfo.html
+    pub fn enable_discovery<F>(&mut self, callback: F) where F: FnMut() -> ServerInfo + 'static {
+        self.discovery = Some(Box::new(callback));
+    }
+
+    /// Enables responding to info queries sent by [`query_info`].
+    ///
+    /// Info queries let clients such as server browsers check on a server without connecting
+    /// to it. `callback` is called each time an info request is received, and the returned
+    /// bytes are sent back in the response. The payload must be no larger than 1005 bytes,
+    /// larger responses are not sent.
+    ///
+    /// Responses to info and discovery requests are rate limited for each address, so clients
+    /// may not receive a response if they send a large number of requests.
+    ///
+    /// [`query_info`]: ./fn.query_info.html
+    pub fn enable_info_queries<F>(&mut self, callback: F) where F: FnMut() -> Vec<u8> + 'static {
+        self.info = Some(Box::new(callback));
+    }
+
+    /// Records every datagram sent and received by the listener to `capture`.
+    ///
+    /// This includes the traffic for every connection accepted by the listener, as well as
+    /// discovery and info requests. Captures can be inspected with [`CaptureReader`].
+    ///
+    /// [`CaptureReader`]: ./struct.CaptureReader.html
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.socket.set_capture(capture);
+    }
+
+    /// Sends the contents of the write buffer to `address`.
+    ///
+    /// Everything the listener sends directly is a response to a packet from `address`, so if
+    /// the socket isn't ready we drop the response and rely on the peer resending its packet.
+    fn send_write_buffer(&self, address: SocketAddr) -> Result<(), Error> {
+        match self.socket.send_to(&self.write_buffer[..], &address) {
+            Ok(..) => { Ok(()) }
+            Err(error) => {
+                if error.kind() != io::ErrorKind::WouldBlock {
+                    return Err(error.into());
+                }
+
+                println!("WARNING: Dropping response to {} because sending would block", address);
+                Ok(())
+            }
+        }
     }
 }
 
 impl Stream for ConnectionListener {
     type Item = Connection;
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         // Poll the socket for incoming packets, forwarding valid packets to the correct endpoint.
@@ -324,68 +565,53 @@ impl Stream for ConnectionListener {
 
                         // All other error kinds are legit errors, and are returned as such.
                         _ => {
-                            return Err(error);
+                            return Err(error.into());
                         }
                     }
                 }
             };
 
             // Decode the packet, discarding any packets that fail basic verification.
-            let Packet { connection_id, data, .. } = match decode(&self.read_buffer[.. bytes_read])? {
+            let Packet { connection_id, data, .. } = match decode(&self.read_buffer[.. bytes_read]) {
                 Some(packet) => { packet }
                 None => { continue; }
             };
 
             match data {
-                PacketData::ConnectionRequest => {
+                PacketData::ConnectionRequest { version, compression } => {
+                    // If the client is using a different version of the protocol, let them know
+                    // that we can't accept the connection.
+                    if version != PROTOCOL_VERSION {
+                        encode(
+                            Packet {
+                                connection_id,
+                                data: PacketData::ConnectionDenied(
+                                    DenyReason::VersionMismatch(PROTOCOL_VERSION),
+                                ),
+                            },
+                            &mut self.write_buffer,
+                        )?;
+                        self.send_write_buffer(address)?;
+                        continue;
+                    }
+
                     let cookie = ChallengeCookie {
                         request_time: self.start_time.elapsed(),
                         source_addres: address,
                         connection_id,
+                        compression: self.compression.negotiate(compression),
                     };
 
                     // Construct the final cookie by combining the nonce and the ciphertext of
                     // the serialized `ChallengeCookie`.
-                    let cookie_bytes = &mut [0; MAX_COOKIE_LEN][..];
-                    let cookie_len = {
-                        // Split the cookie bytes into two buffers: The front for the nonce, and
-                        // the back for the ciphertext.
-                        let (nonce, ciphertext) = cookie_bytes.split_at_mut(NONCE_LEN);
-
-                        // Generate the nonce in the front part of the buffer.
-                        self.rng.fill_bytes(nonce);
-
-                        bincode::serialize_into(
-                            &mut Cursor::new(&mut ciphertext[..]),
-                            &cookie,
-                            bincode::Infinite,
-                        ).expect("Failed to serialize challenge cookie");
-
-                        // Shrink the size of the ciphertext buffer to be exactly the serialized
-                        // size of the cookie + the tag size of the ecryption algorithm.
-                        let serialized_len = bincode::internal::serialized_size(&cookie) as usize;
-                        let ciphertext_len = serialized_len + ALGORITHM.tag_len();
-                        debug_assert!(
-                            ciphertext.len() > ciphertext_len,
-                            "Serialized cookie is too big"
-                        );
-                        let ciphertext = &mut ciphertext[.. ciphertext_len];
-
-                        // Encrypt the cookie bytes in-place within the ciphertext buffer.
-                        let sealed_len = aead::seal_in_place(
-                            &self.sealing_key,
-                            &nonce[..],
-                            &[],
-                            ciphertext,
-                            ALGORITHM.tag_len(),
-                        ).expect("Failed to seal the challenge cookie");
-                        assert_eq!(
-                            sealed_len, ciphertext.len(),
-                            "Sealed length is different than ciphertext length"
-                        );
-
-                        nonce.len() + ciphertext_len
-                    };
+                    let cookie_bytes = &mut [0; MAX_COOKIE_LEN];
+                    let cookie_len = seal_cookie(
+                        &cookie,
+                        CHALLENGE_COOKIE_AD,
+                        &mut self.rng,
+                        &self.sealing_key,
+                        cookie_bytes,
+                    );
 
                     // Write the challenge packet into a buffer.
                     let cookie = &cookie_bytes[.. cookie_len];
@@ -398,78 +624,48 @@ impl Stream for ConnectionListener {
                     )?;
 
                     // Send that junk to junk town.
-                    match self.socket.send_to(&self.write_buffer[..], &address) {
-                        Ok(..) => {}
-                        Err(error) => {
-                            if error.kind() != io::ErrorKind::WouldBlock {
-                                return Err(error);
-                            }
-
-                            // TODO: How to do we handle a `WouldBlock` error?
-                            unimplemented!("What do we do if we get a `WouldBlock` error?");
-                        }
-                    }
+                    self.send_write_buffer(address)?;
                 }
 
-                PacketData::ChallengeResponse(cookie) => {
-                    // Split the cookie into the nonce and ciphertext.
-                    let (nonce, ciphertext) = cookie.split_at(NONCE_LEN);
-
-                    // Copy the ciphertext into another buffer where we can decrypt it in-place.
-                    let cookie_buffer = &mut [0; MAX_CIPHERTEXT_LEN][.. ciphertext.len()];
-                    io::copy(
-                        &mut Cursor::new(ciphertext),
-                        &mut Cursor::new(&mut cookie_buffer[..]),
-                    )?;
-
-                    // Try to open the cookie.
-                    let open_result = aead::open_in_place(
+                PacketData::ChallengeResponse { cookie, public_key: client_public_key } => {
+                    // Decrypt and deserialize the raw bytes of the `ChallengeCookie` back into
+                    // a struct, then verify that the cookie is valid for this request. The
+                    // cookie is only valid if it comes from the same address that the connection
+                    // request came from, if the connection ID matches the one in the cookie, and
+                    // if not too much time has passed since the original connection request was
+                    // received.
+                    let start_time = self.start_time;
+                    let cookie = open_cookie::<ChallengeCookie>(
+                        cookie,
+                        CHALLENGE_COOKIE_AD,
                         &self.opening_key,
-                        nonce,
-                        &[],
-                        0,
-                        cookie_buffer,
-                    );
-
-                    // If we fail to decrypt the cookie, simply discard the packet.
-                    let cookie = match open_result {
-                        Ok(cookie) => cookie,
-                        Err(_) => {
-                            continue;
-                        }
-                    };
-
-                    // Deserialize the raw bytes of the `ChallengeCookie` back into a struct.
-                    // If it fails to deserialize, just discard the packet.
-                    let cookie = match bincode::deserialize::<ChallengeCookie>(cookie) {
-                        Ok(cookie) => cookie,
-                        Err(_) => {
+                    ).filter(|cookie| {
+                        cookie.source_addres == address
+                            && cookie.connection_id == connection_id
+                            && (start_time + cookie.request_time).elapsed()
+                                <= Duration::from_secs(1)
+                    });
+
+                    // If the cookie is invalid, let the client know that we rejected it so that
+                    // it doesn't keep waiting for the connection to be accepted.
+                    let cookie = match cookie {
+                        Some(cookie) => { cookie }
+
+                        None => {
+                            encode(
+                                Packet {
+                                    connection_id,
+                                    data: PacketData::ConnectionDenied(DenyReason::CookieRejected),
+                                },
+                                &mut self.write_buffer,
+                            )?;
+                            self.send_write_buffer(address)?;
                             continue;
                         }
                     };
 
-                    // Discard the packet if it didn't come from the same address that
-                    // the connection request came from.
-                    if cookie.source_addres != address {
-                        continue;
-                    }
-
-                    // Discard the packet if the connection ID doesn't match the
-                    // connection ID in the cookie.
-                    if cookie.connection_id != connection_id {
-                        continue;
-                    }
-
-                    // Discard the packet if too much time has passed since the original
-                    // connection request was received.
-                    if (self.start_time + cookie.request_time).elapsed() > Duration::from_secs(1) {
-                        continue;
-                    }
-
                     // The cookie has passed validation, which means we can accept the connection!
                     // Add it to the set of open connections.
-
-
                     let (connection, client) = match self.open_connections.entry(connection_id) {
                         Entry::Occupied(entry) => { (entry.into_mut(), None) }
 
@@ -480,19 +676,36 @@ impl Stream for ConnectionListener {
                             let socket = UdpSocket::bind(&bind_address, &self.handle)?;
                             let local_address = socket.local_addr()?;
 
+                            // Agree on the session key that the client uses to authenticate
+                            // itself if its address changes. Only the public keys are sent, so
+                            // the session key itself never goes over the wire. Discard the
+                            // response if the client's public key is invalid.
+                            let mut public_key = [0; PUBLIC_KEY_LEN];
+                            let private_key = generate_key_pair(&mut public_key);
+                            let session_key = match derive_session_key(
+                                private_key,
+                                client_public_key,
+                                connection_id,
+                            ) {
+                                Some(session_key) => { session_key }
+                                None => { continue; }
+                            };
+
                             // Create a client that sends messages to the connection listener.
                             // TODO: Make disconnect timeout configurable.
                             let disconnect_timeout =
                                 Timeout::new(Duration::from_secs(1), &self.handle)?;
                             let client = Connection {
-                                socket,
-                                peer_address: self.local_address,
+                                socket: Socket::new(socket),
+                                peer_address: self.forward_address,
                                 connection_id,
                                 sequence_number: 0,
+                                compression: cookie.compression,
+                                session_key,
 
                                 send_buffer: Vec::with_capacity(MAX_PACKET_LEN),
                                 recv_buffer: vec![0; 1024],
-                                fragments: HashMap::new(),
+                                fragments: Reassembler::new(),
 
                                 handle: self.handle.clone(),
 
@@ -505,6 +718,9 @@ impl Stream for ConnectionListener {
                             let connection = OpenConnection {
                                 local_address,
                                 remote_address: address,
+                                session_key,
+                                public_key,
+                                last_migration_challenge: None,
                                 disconnect_timeout,
                             };
                             (entry.insert(connection), Some(client))
@@ -519,22 +735,18 @@ impl Stream for ConnectionListener {
 
                     // Encode the connection accepted message.
                     encode(
-                        Packet { connection_id, data: PacketData::ConnectionAccepted },
+                        Packet {
+                            connection_id,
+                            data: PacketData::ConnectionAccepted {
+                                compression: cookie.compression,
+                                public_key: &connection.public_key,
+                            },
+                        },
                         &mut self.write_buffer,
                     )?;
 
                     // Send the connection accepted message.
-                    match self.socket.send_to(&self.write_buffer[..], &address) {
-                        Ok(..) => {}
-                        Err(error) => {
-                            if error.kind() != io::ErrorKind::WouldBlock {
-                                return Err(error);
-                            }
-
-                            // TODO: How to do we handle a `WouldBlock` error?
-                            unimplemented!("What do we do if we get a `WouldBlock` error?");
-                        }
-                    }
+                    self.send_write_buffer(address)?;
 
                     // Yield the new connection.
                     if let Some(client) = client {
@@ -542,23 +754,173 @@ impl Stream for ConnectionListener {
                     }
                 }
 
+                PacketData::DiscoveryRequest => {
+                    // Check the rate limit before calling the callback, so that a flood of
+                    // requests can't make us do the work of building a response.
+                    if self.discovery.is_none() { continue; }
+                    if !self.response_limiter.try_acquire(address.ip(), Instant::now()) {
+                        continue;
+                    }
+
+                    let info = match self.discovery {
+                        Some(ref mut callback) => { callback() }
+                        None => { continue; }
+                    };
+
+                    let info = bincode::serialize(&info, bincode::Infinite)
+                        .expect("Failed to serialize server info");
+                    if info.len() > MAX_RESPONSE_PAYLOAD_LEN {
+                        println!(
+                            "WARNING: Server info is {} bytes, but the max size is {} bytes",
+                            info.len(),
+                            MAX_RESPONSE_PAYLOAD_LEN,
+                        );
+                        continue;
+                    }
+
+                    encode(
+                        Packet {
+                            connection_id,
+                            data: PacketData::DiscoveryResponse {
+                                version: PROTOCOL_VERSION,
+                                info: &info,
+                            },
+                        },
+                        &mut self.write_buffer,
+                    )?;
+                    self.send_write_buffer(address)?;
+                }
+
+                PacketData::InfoRequest => {
+                    // Check the rate limit before calling the callback, so that a flood of
+                    // requests can't make us do the work of building a response.
+                    if self.info.is_none() { continue; }
+                    if !self.response_limiter.try_acquire(address.ip(), Instant::now()) {
+                        continue;
+                    }
+
+                    let payload = match self.info {
+                        Some(ref mut callback) => { callback() }
+                        None => { continue; }
+                    };
+
+                    // Never send a response larger than the request, so that the listener
+                    // can't be used to amplify a DDOS attack.
+                    if payload.len() > MAX_RESPONSE_PAYLOAD_LEN {
+                        println!(
+                            "WARNING: Info payload is {} bytes, but the max size is {} bytes",
+                            payload.len(),
+                            MAX_RESPONSE_PAYLOAD_LEN,
+                        );
+                        continue;
+                    }
+
+                    encode(
+                        Packet {
+                            connection_id,
+                            data: PacketData::InfoResponse {
+                                version: PROTOCOL_VERSION,
+                                payload: &payload,
+                            },
+                        },
+                        &mut self.write_buffer,
+                    )?;
+                    self.send_write_buffer(address)?;
+                }
+
+                PacketData::MigrationResponse { cookie: cookie_bytes, tag } => {
+                    // Verify that the cookie is one we issued for this connection, to this
+                    // address, and that it hasn't expired.
+                    let start_time = self.start_time;
+                    let cookie = open_cookie::<MigrationCookie>(
+                        cookie_bytes,
+                        MIGRATION_COOKIE_AD,
+                        &self.opening_key,
+                    ).filter(|cookie| {
+                        cookie.source_address == address
+                            && cookie.connection_id == connection_id
+                            && (start_time + cookie.request_time).elapsed()
+                                <= Duration::from_secs(1)
+                    });
+                    if cookie.is_none() { continue; }
+
+                    let connection = match self.open_connections.get_mut(&connection_id) {
+                        Some(connection) => { connection }
+                        None => { continue; }
+                    };
+
+                    // Verify that the response was signed with the connection's session key,
+                    // which proves that it came from the client that originally connected.
+                    let key = hmac::SigningKey::new(&SHA256, &connection.session_key);
+                    if hmac::verify_with_own_key(&key, cookie_bytes, tag).is_err() { continue; }
+
+                    // The client has proven that it owns the new address, so start forwarding
+                    // its packets from there.
+                    connection.remote_address = address;
+                    connection.disconnect_timeout.reset(Instant::now() + Duration::from_secs(1));
+                }
+
                 // For all other packet types, we try to forward it to the correct socket; Either
                 // the local socket if it came from the client, or the client socket if it came
                 // from the local socket.
-                _ => if let Some(connection) = self.open_connections.get_mut(&connection_id) {
-                    let to_address = if address == connection.local_address {
-                        // Forward to the remote address.
-                        connection.remote_address
-                    } else if address == connection.remote_address {
-                        // We've received in incoming packet, so reset the disconnect timeout.
-                        connection.disconnect_timeout
-                            .reset(Instant::now() + Duration::from_secs(1));
-
-                        // Forward to the local address.
-                        connection.local_address
-                    } else {
-                        // The packet came from an unknown address, simply discard it.
-                        continue;
+                _ => {
+                    let to_address = match self.open_connections.get_mut(&connection_id) {
+                        Some(connection) => {
+                            if address == connection.local_address {
+                                // Forward to the remote address.
+                                connection.remote_address
+                            } else if address == connection.remote_address {
+                                // We've received in incoming packet, so reset the disconnect
+                                // timeout.
+                                connection.disconnect_timeout
+                                    .reset(Instant::now() + Duration::from_secs(1));
+
+                                // Forward to the local address.
+                                connection.local_address
+                            } else {
+                                // The packet came from an unknown address. This may be the
+                                // client's address changing (e.g. because of NAT rebinding),
+                                // so challenge the new address to prove that it belongs to the
+                                // client. We limit how often we send challenges so that we
+                                // can't be used to flood a spoofed address.
+                                let now = Instant::now();
+                                let interval =
+                                    Duration::from_millis(MIGRATION_CHALLENGE_INTERVAL_MILLIS);
+                                let can_challenge = connection.last_migration_challenge
+                                    .map(|last| now.duration_since(last) >= interval)
+                                    .unwrap_or(true);
+                                if !can_challenge { continue; }
+                                connection.last_migration_challenge = Some(now);
+
+                                let cookie = MigrationCookie {
+                                    request_time: self.start_time.elapsed(),
+                                    source_address: address,
+                                    connection_id,
+                                };
+                                let cookie_bytes = &mut [0; MAX_COOKIE_LEN];
+                                let cookie_len = seal_cookie(
+                                    &cookie,
+                                    MIGRATION_COOKIE_AD,
+                                    &mut self.rng,
+                                    &self.sealing_key,
+                                    cookie_bytes,
+                                );
+
+                                encode(
+                                    Packet {
+                                        connection_id,
+                                        data: PacketData::MigrationChallenge(
+                                            &cookie_bytes[.. cookie_len],
+                                        ),
+                                    },
+                                    &mut self.write_buffer,
+                                )?;
+                                self.send_write_buffer(address)?;
+                                continue;
+                            }
+                        }
+
+                        None => { continue; }
                     };
 
                     // Send the packet to its real destination.
@@ -566,11 +928,17 @@ impl Stream for ConnectionListener {
                         Ok(_) => {}
 
                         Err(error) => {
+                            // Drop the packet if the socket isn't ready, the same as if it had
+                            // been lost in transit.
                             if error.kind() == io::ErrorKind::WouldBlock {
-                                panic!("Failed to send a critical message: {:?}", data);
+                                println!(
+                                    "WARNING: Dropping packet for {} because sending would block",
+                                    to_address,
+                                );
+                                continue;
                             }
 
-                            return Err(error);
+                            return Err(error.into());
                         }
                     }
                 }
@@ -622,17 +990,25 @@ impl Stream for ConnectionListener {
 /// ```
 #[derive(Debug)]
 pub struct Connection {
-    socket: UdpSocket,
+    socket: Socket,
     peer_address: SocketAddr,
     connection_id: u64,
     sequence_number: u32,
 
+    // The compression algorithm negotiated during the handshake. Only used when sending; incoming
+    // messages are flagged individually if they were compressed.
+    compression: Compression,
+
+    // Secret shared with the server during the handshake, used to prove that we own the
+    // connection if our address changes.
+    session_key: [u8; SESSION_KEY_LEN],
+
     // Intermediate buffers used in sending and receiving messages. Unlike a raw UDP socket, we
     // need to use intermediate buffers because we have extra packet structure to handle in
     // addition to the raw bytes of the message.
     send_buffer: Vec<u8>,
     recv_buffer: Vec<u8>,
-    fragments: HashMap<u32, MessageFragments>,
+    fragments: Reassembler,
 
     // A handle to the tokio reactor so that we can spawn things like timeouts.
     handle: Handle,
@@ -667,20 +1043,63 @@ impl Connection {
     pub fn connect(
         address: SocketAddr,
         handle: &Handle,
-    ) -> Result<ConnectionNew, io::Error> {
+    ) -> Result<ConnectionNew, Error> {
+        Connection::connect_with_compression(address, Compression::None, handle)
+    }
+
+    /// Opens a new connection to a remote host, requesting that messages be compressed.
+    ///
+    /// This behaves the same as [`connect`], except that `compression` is requested as part of
+    /// the handshake. If the server doesn't support the requested algorithm, the connection
+    /// is still established but messages are sent uncompressed. Use [`compression`] to check
+    /// which algorithm was agreed on.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate sumi;
+    /// # extern crate tokio_core;
+    /// # fn main() {
+    /// use sumi::{Compression, Connection};
+    /// use tokio_core::reactor::Core;
+    ///
+    /// let mut core = Core::new().unwrap();
+    /// let address = "127.0.0.1:1234".parse().unwrap();
+    /// let wait_for_connection = Connection::connect_with_compression(
+    ///     address,
+    ///     Compression::Lz4,
+    ///     &core.handle(),
+    /// ).unwrap();
+    /// let connection = core.run(wait_for_connection).unwrap();
+    /// # }
+    /// ```
+    ///
+    /// [`connect`]: #method.connect
+    /// [`compression`]: #method.compression
+    pub fn connect_with_compression(
+        address: SocketAddr,
+        compression: Compression,
+        handle: &Handle,
+    ) -> Result<ConnectionNew, Error> {
         let address = address.into();
 
         // What's the right address to bind the local socket to?
         let bind_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
         let socket = UdpSocket::bind(&bind_address, handle)?;
 
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        let private_key = generate_key_pair(&mut public_key);
+
         Ok(ConnectionNew {
-            socket: Some(socket),
+            socket: Some(Socket::new(socket)),
             peer_address: address,
             start_time: Instant::now(),
             connection_id: rand::random(),
+            compression,
             state: ConnectionState::AwaitingChallenge,
             interval: Interval::new(Duration::from_millis(40), handle)?,
+            private_key: Some(private_key),
+            public_key,
 
             read_buffer: vec![0; MAX_PACKET_LEN],
             write_buffer: Vec::with_capacity(MAX_PACKET_LEN),
@@ -689,41 +1108,57 @@ impl Connection {
         })
     }
 
-    /// Begins sending a message, returning a futures that resolves when the messages
-    /// has been fully sent.
-    pub fn send<T>(mut self, buffer: T) -> Send<T> where T: AsRef<[u8]> {
-        let num_fragments = {
-            let buffer = buffer.as_ref();
-
-            assert!(
-                buffer.len() <= MAX_MESSAGE_LEN,
-                "Message is longer than max len of {} bytes, message len: {}",
-                MAX_MESSAGE_LEN,
-                buffer.len()
-            );
+    /// Returns the compression algorithm negotiated for the connection.
+    ///
+    /// Only messages sent through [`Serialized`] are compressed. Messages sent with [`send`]
+    /// and [`send_reliable`] are always sent as-is.
+    ///
+    /// [`Serialized`]: ./struct.Serialized.html
+    /// [`send`]: #method.send
+    /// [`send_reliable`]: #method.send_reliable
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
 
-            // Increment the sequence number.
-            self.sequence_number.wrapping_add(1);
+    /// Moves the connection to a new local socket, as if the client's address had changed.
+    ///
+    /// This can be used to recover a connection after switching networks. The next time the
+    /// listener receives a packet from the new address it challenges the client to prove that
+    /// it owns the connection, which is answered automatically the next time the connection
+    /// sends or receives. Until then, packets sent from the new address are dropped.
+    pub fn rebind(&mut self) -> Result<(), Error> {
+        let bind_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+        let socket = UdpSocket::bind(&bind_address, &self.handle)?;
+        self.socket.set_inner(socket);
+        Ok(())
+    }
 
-            // Write the first fragment of the message into the connection's write buffer.
-            let num_fragments = (buffer.len() as f32 / MAX_FRAGMENT_LEN as f32).ceil() as u8;
-            let fragment_len = cmp::min(buffer.len(), MAX_FRAGMENT_LEN);
-            encode(
-                Packet {
-                    connection_id: self.connection_id,
-                    data: PacketData::Message {
-                        sequence_number: self.sequence_number,
-                        fragment: &buffer[.. fragment_len],
-                        num_fragments,
-                        fragment_number: 0,
-                    },
-                },
-                &mut self.send_buffer,
-            ).expect("Error encoding packet");
+    /// Returns statistics about incoming messages that were discarded before they were
+    /// fully received.
+    pub fn reassembly_stats(&self) -> ReassemblyStats {
+        self.fragments.stats()
+    }
 
-            num_fragments
-        };
+    /// Records every datagram sent and received by the connection to `capture`.
+    ///
+    /// To also capture the handshake, set the capture on the [`ConnectionNew`] future instead.
+    /// Captures can be inspected with [`CaptureReader`].
+    ///
+    /// [`ConnectionNew`]: ./struct.ConnectionNew.html
+    /// [`CaptureReader`]: ./struct.CaptureReader.html
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.socket.set_capture(capture);
+    }
 
+    /// Begins sending a message, returning a futures that resolves when the messages
+    /// has been fully sent.
+    ///
+    /// The returned future fails with [`Error::MessageTooLarge`] if `buffer` is larger than
+    /// the maximum supported message size.
+    ///
+    /// [`Error::MessageTooLarge`]: ./enum.Error.html#variant.MessageTooLarge
+    pub fn send<T>(mut self, buffer: T) -> Send<T> where T: AsRef<[u8]> {
+        let num_fragments = self.write_first_fragment(buffer.as_ref());
         let sequence_number = self.sequence_number;
         Send {
             state: send::State::start(
@@ -738,39 +1173,15 @@ impl Connection {
 
     /// Begins sending a message, returning a futures that resolves when the messages
     /// has been fully sent.
+    ///
+    /// The returned future fails with [`Error::MessageTooLarge`] if `buffer` is larger than
+    /// the maximum supported message size, and with [`Error::PeerDisconnected`] if the peer
+    /// doesn't acknowledge the message in time.
+    ///
+    /// [`Error::MessageTooLarge`]: ./enum.Error.html#variant.MessageTooLarge
+    /// [`Error::PeerDisconnected`]: ./enum.Error.html#variant.PeerDisconnected
     pub fn send_reliable<T>(mut self, buffer: T) -> SendReliable<T> where T: AsRef<[u8]> {
-        let num_fragments = {
-            let buffer = buffer.as_ref();
-
-            assert!(
-                buffer.len() <= MAX_MESSAGE_LEN,
-                "Message is longer than max len of {} bytes, message len: {}",
-                MAX_MESSAGE_LEN,
-                buffer.len()
-            );
-
-            // Increment the sequence number.
-            self.sequence_number.wrapping_add(1);
-
-            // Write the first fragment of the message into the connection's write buffer.
-            let num_fragments = (buffer.len() as f32 / MAX_FRAGMENT_LEN as f32).ceil() as u8;
-            let fragment_len = cmp::min(buffer.len(), MAX_FRAGMENT_LEN);
-            encode(
-                Packet {
-                    connection_id: self.connection_id,
-                    data: PacketData::Message {
-                        sequence_number: self.sequence_number,
-                        fragment: &buffer[.. fragment_len],
-                        num_fragments,
-                        fragment_number: 0,
-                    },
-                },
-                &mut self.send_buffer,
-            ).expect("Error encoding packet");
-
-            num_fragments
-        };
-
+        let num_fragments = self.write_first_fragment(buffer.as_ref());
         let sequence_number = self.sequence_number;
         SendReliable {
             state: send_reliable::State::start(
@@ -785,9 +1196,42 @@ impl Connection {
         }
     }
 
+    /// Writes the first fragment of a new message into the send buffer, returning the total
+    /// number of fragments in the message.
+    ///
+    /// If the message is too large to send, nothing is written and 0 is returned. The send
+    /// futures check the size of the message the first time they're polled, so the error is
+    /// reported from there.
+    fn write_first_fragment(&mut self, buffer: &[u8]) -> u8 {
+        if buffer.len() > MAX_MESSAGE_LEN { return 0; }
+
+        // Increment the sequence number.
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        // Write the first fragment of the message into the connection's write buffer.
+        let num_fragments = (buffer.len() as f32 / MAX_FRAGMENT_LEN as f32).ceil() as u8;
+        let fragment_len = cmp::min(buffer.len(), MAX_FRAGMENT_LEN);
+        encode(
+            Packet {
+                connection_id: self.connection_id,
+                data: PacketData::Message {
+                    sequence_number: self.sequence_number,
+                    fragment: &buffer[.. fragment_len],
+                    num_fragments,
+                    fragment_number: 0,
+                    compressed: false,
+                },
+            },
+            &mut self.send_buffer,
+        ).expect("Error encoding packet");
+
+        num_fragments
+    }
+
     /// Creates a future that receives a message to be written to the buffer provided.
     ///
-    /// The returned future will resolve after a message has been received in full.
+    /// The returned future will resolve after a message has been received in full. Messages
+    /// that are malformed, fail to decompress, or don't fit in `buffer` are discarded.
     pub fn recv<T>(self, buffer: T) -> Receive<T> where T: AsMut<[u8]> {
         Receive {
             state: recv::State::start(
@@ -804,8 +1248,8 @@ impl Connection {
     /// deserialization of messages, making communication easier.
     ///
     /// Serialization is done using [bincode], which provides a reasonable default serialization
-    /// strategy for most purposes. For more specialized serialization strategies, just... I
-    /// dunno... do it yourself.
+    /// strategy for most purposes. To use a different serialization strategy, use
+    /// [`serialized_with`] instead.
     ///
     /// This function returns a *single* object that is both [`Stream`] and [`Sink`]; grouping
     /// this into a single object is often useful for layering things which require both read
@@ -816,15 +1260,49 @@ impl Connection {
     /// objects, allowing them to interact more easily.
     ///
     /// [bincode]: https://crates.io/crates/bincode
+    /// [`serialized_with`]: #method.serialized_with
     /// [`Stream`]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html
     /// [`Sink`]: https://docs.rs/futures/0.1/futures/sink/trait.Sink.html
     /// [`split`]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html#method.split
     /// [`Serialized`]: ./struct.Serialized.html
     pub fn serialized<T, U>(self) -> Serialized<T, U> {
+        self.serialized_with(Bincode)
+    }
+
+    /// Provides a Stream and Sink interface using the specified [`Codec`] for serialization.
+    ///
+    /// This behaves the same as [`serialized`], except that messages are converted to and from
+    /// bytes using `codec`. Both ends of the connection must use the same codec.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # extern crate sumi;
+    /// # extern crate tokio_core;
+    /// # fn main() {
+    /// use sumi::{Connection, MessagePack};
+    /// use tokio_core::reactor::Core;
+    ///
+    /// let mut core = Core::new().unwrap();
+    /// let address = "127.0.0.1:1234".parse().unwrap();
+    /// let wait_for_connection = Connection::connect(address, &core.handle()).unwrap();
+    /// let connection = core.run(wait_for_connection).unwrap();
+    /// let serialized = connection.serialized_with::<String, String, _>(MessagePack);
+    /// # }
+    /// ```
+    ///
+    /// [`Codec`]: ./trait.Codec.html
+    /// [`serialized`]: #method.serialized
+    pub fn serialized_with<T, U, C: Codec>(self, codec: C) -> Serialized<T, U, C> {
         Serialized {
             connection: self,
+            codec,
 
             flushed: true,
+            encode_buffer: Vec::with_capacity(MAX_FRAGMENT_LEN),
+            compress_buffer: Vec::with_capacity(MAX_FRAGMENT_LEN),
+            decompress_buffer: Vec::with_capacity(MAX_FRAGMENT_LEN),
+            received: SequenceTracker::default(),
 
             _send: Default::default(),
             _recv: Default::default(),
@@ -834,32 +1312,56 @@ impl Connection {
 
 /// A wrapper around a [`Connection`] that automatically handles serialization.
 ///
-/// This is created by the [`serialized`] method on [`Connection`]. See its documentation for
-/// more information.
+/// Incoming messages are always yielded in the order they were sent. Any message that arrives
+/// after a more recently sent message has already been received is discarded, as are
+/// duplicate messages.
+///
+/// This is created by the [`serialized`] and [`serialized_with`] methods on [`Connection`]. See
+/// their documentation for more information.
 ///
 /// [`Connection`]: ./struct.Connection.html
 /// [`serialized`]: ./struct.Connection.html#method.serialized
+/// [`serialized_with`]: ./struct.Connection.html#method.serialized_with
 #[derive(Debug)]
-pub struct Serialized<T, U> {
+pub struct Serialized<T, U, C = Bincode> {
     connection: Connection,
+    codec: C,
     flushed: bool,
 
-    _send: ::std::marker::PhantomData<T>,
-    _recv: ::std::marker::PhantomData<U>,
-}
+    // Intermediate buffer that outgoing messages are serialized into before being encoded into
+    // a packet. Reused for each message so that we don't allocate every time we send.
+    encode_buffer: Vec<u8>,
 
-impl<T, U> Serialized<T, U> {
+    // Buffer that outgoing messages are compressed into, if compression is enabled.
+    compress_buffer: Vec<u8>,
+
+    // Buffer that incoming compressed messages are decompressed into.
+    decompress_buffer: Vec<u8>,
+
+    // Tracks the most recent message received so that older messages can be discarded.
+    received: SequenceTracker,
+
+    _send: ::std::marker::PhantomData<T>,
+    _recv: ::std::marker::PhantomData<U>,
+}
+
+impl<T, U, C> Serialized<T, U, C> {
     /// Consumes the `Serialized` returning the underlying [`Connection`].
     ///
     /// [`Connection`]: ./struct.Connection.html
     pub fn into_inner(self) -> Connection {
         self.connection
     }
+
+    /// Returns a reference to the codec used to serialize messages.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
 }
 
-impl<T, U: DeserializeOwned> Stream for Serialized<T, U> {
+impl<T, U: DeserializeOwned, C: Codec> Stream for Serialized<T, U, C> {
     type Item = U;
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         // Check to see if the connection has timed out waiting for data. If it has, we
@@ -871,6 +1373,8 @@ impl<T, U: DeserializeOwned> Stream for Serialized<T, U> {
             let packet = try_nb!(recv_packet(
                 &self.connection.socket,
                 self.connection.peer_address,
+                self.connection.connection_id,
+                &self.connection.session_key,
                 &mut self.connection.recv_buffer,
             ));
 
@@ -880,34 +1384,62 @@ impl<T, U: DeserializeOwned> Stream for Serialized<T, U> {
 
             // Handle the packet according to its type, returning the message's data if we
             // received a message packet.
-            let message_bytes = match packet.data {
-                PacketData::Message { sequence_number: _, fragment, num_fragments, fragment_number } => {
-                    if num_fragments != 1 || fragment_number != 0 {
-                        unimplemented!("Support receiving multi-fragment messages");
-                    }
-
-                    fragment
+            let (message_bytes, compressed) = match packet.data {
+                PacketData::Message {
+                    sequence_number,
+                    fragment,
+                    num_fragments,
+                    fragment_number,
+                    compressed,
+                } => {
+                    // `Serialized` only ever sends single-fragment messages, so discard any
+                    // fragmented messages as malformed.
+                    if num_fragments != 1 || fragment_number != 0 { continue; }
+
+                    // Discard any messages that arrive after a more recent message.
+                    if !self.received.accept(sequence_number) { continue; }
+
+                    (fragment, compressed)
                 }
 
                 // Discard any stray messages that are part of the handshake.
-                PacketData::ConnectionRequest
+                PacketData::ConnectionRequest { .. }
                 | PacketData::Challenge(_)
-                | PacketData::ChallengeResponse(_)
-                | PacketData::ConnectionAccepted
+                | PacketData::ChallengeResponse { .. }
+                | PacketData::ConnectionAccepted { .. }
+                | PacketData::ConnectionDenied(_)
                 | PacketData::Ack(_)
+                | PacketData::MigrationChallenge(_)
+                | PacketData::MigrationResponse { .. }
+                | PacketData::DiscoveryRequest
+                | PacketData::DiscoveryResponse { .. }
+                | PacketData::InfoRequest
+                | PacketData::InfoResponse { .. }
                 => { continue; }
             };
 
-            if let Ok(message) = bincode::deserialize(message_bytes) {
+            // Decompress the message if the sender compressed it, discarding any messages that
+            // fail to decompress.
+            let message_bytes = if compressed {
+                if !compression::decompress(message_bytes, &mut self.decompress_buffer) {
+                    continue;
+                }
+                &self.decompress_buffer[..]
+            } else {
+                message_bytes
+            };
+
+            // Discard any messages that fail to deserialize.
+            if let Ok(message) = self.codec.decode(message_bytes) {
                 return Ok(Async::Ready(Some(message)));
             }
         }
     }
 }
 
-impl<T: Serialize, U> Sink for Serialized<T, U> {
+impl<T: Serialize, U, C: Codec> Sink for Serialized<T, U, C> {
     type SinkItem = T;
-    type SinkError = io::Error;
+    type SinkError = Error;
 
     fn start_send(
         &mut self,
@@ -920,20 +1452,36 @@ impl<T: Serialize, U> Sink for Serialized<T, U> {
             }
         }
 
-        // TODO: Don't allocate each time we serialize a message.
-        let serialized = bincode::serialize(&item, bincode::Bounded(MAX_FRAGMENT_LEN as u64))
-            .expect("Serialized size was too big, need to implement message fragmenting");
+        self.encode_buffer.clear();
+        self.codec.encode(&item, &mut self.encode_buffer)?;
+
+        // Compress the message if compression was negotiated for this connection. If the
+        // message is too small or doesn't shrink, it's sent uncompressed.
+        let compressed = self.connection.compression.compress(
+            &self.encode_buffer,
+            &mut self.compress_buffer,
+        );
+        let message = if compressed { &self.compress_buffer } else { &self.encode_buffer };
+
+        // TODO: Support sending messages that span multiple fragments.
+        if message.len() > MAX_FRAGMENT_LEN {
+            return Err(Error::MessageTooLarge {
+                len: message.len(),
+                max: MAX_FRAGMENT_LEN,
+            });
+        }
 
-        self.connection.sequence_number += 1;
+        self.connection.sequence_number = self.connection.sequence_number.wrapping_add(1);
 
         encode(
             Packet {
                 connection_id: self.connection.connection_id,
                 data: PacketData::Message {
                     sequence_number: self.connection.sequence_number,
-                    fragment: &serialized,
+                    fragment: message,
                     num_fragments: 1,
                     fragment_number: 0,
+                    compressed,
                 },
             },
             &mut self.connection.send_buffer,
@@ -963,7 +1511,7 @@ impl<T: Serialize, U> Sink for Serialized<T, U> {
             Err(io::Error::new(
                 io::ErrorKind::Other,
                 "Failed to write entire datagram to socket",
-            ))
+            ).into())
         }
     }
 }
@@ -976,28 +1524,49 @@ impl<T: Serialize, U> Sink for Serialized<T, U> {
 pub struct ConnectionNew {
     // We wrap the socket in an `Option` so that we can move the socket out of the `ConnectionNew`
     // future once the connection is accepted.
-    socket: Option<UdpSocket>,
+    socket: Option<Socket>,
 
     peer_address: SocketAddr,
     start_time: Instant,
     connection_id: u64,
+    compression: Compression,
     state: ConnectionState,
     interval: Interval,
 
+    // The key pair used to agree on the session key with the server. The private key is
+    // consumed when the connection is accepted.
+    private_key: Option<EphemeralPrivateKey>,
+    public_key: [u8; PUBLIC_KEY_LEN],
+
     read_buffer: Vec<u8>,
     write_buffer: Vec<u8>,
 
     handle: Handle,
 }
 
+impl ConnectionNew {
+    /// Records every datagram sent and received by the connection to `capture`, including
+    /// the handshake.
+    ///
+    /// The capture remains set on the [`Connection`] once the connection is established.
+    ///
+    /// [`Connection`]: ./struct.Connection.html
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.socket
+            .as_mut()
+            .expect("Connection has already been established")
+            .set_capture(capture);
+    }
+}
+
 impl Future for ConnectionNew {
     type Item = Connection;
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         // If we've taken too long, return a timeout error.
         if self.start_time.elapsed() > Duration::from_secs(1) {
-            return Err(io::ErrorKind::TimedOut.into());
+            return Err(Error::HandshakeTimeout);
         }
 
         // Read any ready messages on the socket.
@@ -1018,7 +1587,7 @@ impl Future for ConnectionNew {
             if address != self.peer_address { continue; }
 
             // Decode that sweet, sweet packet.
-            let Packet { connection_id, data, .. } = match decode(&self.read_buffer[.. bytes_read])? {
+            let Packet { connection_id, data, .. } = match decode(&self.read_buffer[.. bytes_read]) {
                 Some(packet) => { packet }
 
                 // Discard any packets that fail basic verification.
@@ -1056,7 +1625,10 @@ impl Future for ConnectionNew {
                     encode(
                         Packet {
                             connection_id: self.connection_id,
-                            data: PacketData::ChallengeResponse(cookie),
+                            data: PacketData::ChallengeResponse {
+                                cookie,
+                                public_key: &self.public_key,
+                            },
                         },
                         &mut self.write_buffer,
                     )?;
@@ -1074,7 +1646,7 @@ impl Future for ConnectionNew {
                         // send the message at a regular interval.
                         Err(error) => {
                             if error.kind() != io::ErrorKind::WouldBlock {
-                                return Err(error);
+                                return Err(error.into());
                             }
                             println!(
                                 "WARNING: Sending the challenge response would block: {:?}",
@@ -1084,7 +1656,23 @@ impl Future for ConnectionNew {
                     }
                 }
 
-                PacketData::ConnectionAccepted => {
+                PacketData::ConnectionAccepted { compression, public_key: server_public_key } => {
+                    // Derive the session key from the server's public key. If the server's key
+                    // is invalid, the private key has still been used up, so we can't accept
+                    // any later response and the handshake will time out.
+                    let private_key = match self.private_key.take() {
+                        Some(private_key) => { private_key }
+                        None => { continue; }
+                    };
+                    let session_key = match derive_session_key(
+                        private_key,
+                        server_public_key,
+                        self.connection_id,
+                    ) {
+                        Some(session_key) => { session_key }
+                        None => { continue; }
+                    };
+
                     let socket = self.socket
                         .take()
                         .expect("Poll called after connection was established");
@@ -1096,10 +1684,12 @@ impl Future for ConnectionNew {
                         peer_address: self.peer_address,
                         connection_id: self.connection_id,
                         sequence_number: 0,
+                        compression,
+                        session_key,
 
                         send_buffer: mem::replace(&mut self.write_buffer, Vec::new()),
                         recv_buffer: vec![0; MAX_PACKET_LEN],
-                        fragments: HashMap::new(),
+                        fragments: Reassembler::new(),
 
                         handle: self.handle.clone(),
 
@@ -1107,6 +1697,16 @@ impl Future for ConnectionNew {
                     }));
                 }
 
+                PacketData::ConnectionDenied(reason) => {
+                    return Err(match reason {
+                        DenyReason::VersionMismatch(remote) => Error::VersionMismatch {
+                            local: PROTOCOL_VERSION,
+                            remote,
+                        },
+                        DenyReason::CookieRejected => Error::CookieRejected,
+                    });
+                }
+
                 // Discard all other packet types.
                 _ => continue,
             }
@@ -1122,7 +1722,10 @@ impl Future for ConnectionNew {
                     encode(
                         Packet {
                             connection_id: self.connection_id,
-                            data: PacketData::ConnectionRequest,
+                            data: PacketData::ConnectionRequest {
+                                version: PROTOCOL_VERSION,
+                                compression: self.compression,
+                            },
                         },
                         &mut self.write_buffer,
                     )?;
@@ -1132,7 +1735,10 @@ impl Future for ConnectionNew {
                     encode(
                         Packet {
                             connection_id: self.connection_id,
-                            data: PacketData::ChallengeResponse(&cookie[..]),
+                            data: PacketData::ChallengeResponse {
+                                cookie: &cookie[..],
+                                public_key: &self.public_key,
+                            },
                         },
                         &mut self.write_buffer,
                     )?;
@@ -1153,7 +1759,7 @@ impl Future for ConnectionNew {
                 // send the message at a regular interval.
                 Err(error) => {
                     if error.kind() != io::ErrorKind::WouldBlock {
-                        return Err(error);
+                        return Err(error.into());
                     }
                     println!(
                         "WARNING: Resending request would block: {:?}",
@@ -1167,7 +1773,17 @@ impl Future for ConnectionNew {
     }
 }
 
-fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
+/// Decodes a packet from a received datagram.
+///
+/// Returns `None` if the datagram doesn't contain a valid packet. Datagrams come from untrusted
+/// sources, so this must never panic no matter what bytes it's given.
+fn decode<'a>(buffer: &'a [u8]) -> Option<Packet<'a>> {
+    // Reading past the end of the buffer means that the packet was truncated, which we treat
+    // the same as any other malformed packet.
+    read_packet(buffer).unwrap_or(None)
+}
+
+fn read_packet<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
     // Ignore any messages that are too small to at least contain the header, connection
     // ID, and message type.
     if buffer.len() < HEADER_LEN { return Ok(None); }
@@ -1178,15 +1794,9 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
     let (checksum, body) = buffer.split_at(4);
     let checksum = NetworkEndian::read_u32(checksum);
 
-    // Calculate the checksum of the received data by digesting the implicit protocol header and
-    // the received packet data.
-    let mut digest = Digest::new(crc32::IEEE);
-    digest.write_u64(PROTOCOL_ID);
-    Hasher32::write(&mut digest, body);
-
     // If the checksum in the packet's header doesn't match the calculated checksum, discard the
     // packet.
-    if checksum != digest.sum32() { return Ok(None); }
+    if checksum != packet_checksum(body) { return Ok(None); }
 
     let mut cursor = Cursor::new(body);
 
@@ -1202,7 +1812,13 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
             // avoid our protocl being used as part of a DDOS magnification attack.
             if buffer.len() != MAX_PACKET_LEN { return Ok(None); }
 
-            PacketData::ConnectionRequest
+            let version = cursor.read_u32::<NetworkEndian>()?;
+
+            // Fall back to no compression if the client requests an algorithm we don't know.
+            let compression = Compression::from_u8(cursor.read_u8()?)
+                .unwrap_or(Compression::None);
+
+            PacketData::ConnectionRequest { version, compression }
         }
 
         CHALLENGE => {
@@ -1224,10 +1840,32 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
             // Ignore the packet if the cookie len is just too long.
             if cookie_end > body.len() { return Ok(None); }
 
-            PacketData::ChallengeResponse(&body[cookie_start .. cookie_end])
+            let key_end = cookie_end + PUBLIC_KEY_LEN;
+            if key_end > body.len() { return Ok(None); }
+
+            PacketData::ChallengeResponse {
+                cookie: &body[cookie_start .. cookie_end],
+                public_key: &body[cookie_end .. key_end],
+            }
         }
 
-        CONNECTION_ACCEPTED => { PacketData::ConnectionAccepted }
+        CONNECTION_ACCEPTED => {
+            let compression = match Compression::from_u8(cursor.read_u8()?) {
+                Some(compression) => { compression }
+
+                // Ignore the packet if the server picked an algorithm we don't know.
+                None => { return Ok(None); }
+            };
+
+            let key_start = cursor.position() as usize;
+            let key_end = key_start + PUBLIC_KEY_LEN;
+            if key_end > body.len() { return Ok(None); }
+
+            PacketData::ConnectionAccepted {
+                compression,
+                public_key: &body[key_start .. key_end],
+            }
+        }
 
         MESSAGE => {
             // Read the sequence number for the message.
@@ -1240,6 +1878,9 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
             // If the number of fragments is invalid, discard the packet.
             if num_fragments == 0 || fragment_number >= num_fragments { return Ok(None); }
 
+            // Read the message flags.
+            let flags = cursor.read_u8()?;
+            let compressed = flags & MESSAGE_FLAG_COMPRESSED != 0;
 
             let message_len = cursor.read_u16::<NetworkEndian>()? as usize;
             let message_start = cursor.position() as usize;
@@ -1252,6 +1893,7 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
                 fragment: &body[message_start .. message_end],
                 num_fragments,
                 fragment_number,
+                compressed,
             }
         }
 
@@ -1260,6 +1902,93 @@ fn decode<'a>(buffer: &'a [u8]) -> Result<Option<Packet<'a>>, io::Error> {
             PacketData::Ack(sequence_number)
         }
 
+        CONNECTION_DENIED => {
+            let reason = match cursor.read_u8()? {
+                DENIED_VERSION_MISMATCH => {
+                    DenyReason::VersionMismatch(cursor.read_u32::<NetworkEndian>()?)
+                }
+
+                DENIED_COOKIE_REJECTED => { DenyReason::CookieRejected }
+
+                // Ignore any unknown reasons.
+                _ => { return Ok(None); }
+            };
+
+            PacketData::ConnectionDenied(reason)
+        }
+
+        MIGRATION_CHALLENGE => {
+            let cookie_len = cursor.read_u8()? as usize;
+            let cookie_start = cursor.position() as usize;
+            let cookie_end = cookie_start + cookie_len;
+
+            // Ignore the packet if the cookie len is just too long.
+            if cookie_end > body.len() { return Ok(None); }
+
+            PacketData::MigrationChallenge(&body[cookie_start .. cookie_end])
+        }
+
+        MIGRATION_RESPONSE => {
+            let cookie_len = cursor.read_u8()? as usize;
+            let cookie_start = cursor.position() as usize;
+            let cookie_end = cookie_start + cookie_len;
+            let tag_end = cookie_end + MIGRATION_TAG_LEN;
+
+            // Ignore the packet if it's too short to contain the cookie and the tag.
+            if tag_end > body.len() { return Ok(None); }
+
+            PacketData::MigrationResponse {
+                cookie: &body[cookie_start .. cookie_end],
+                tag: &body[cookie_end .. tag_end],
+            }
+        }
+
+        DISCOVERY_REQUEST => {
+            // Enforce that discovery requests be the maximum allowed size, for the same reason
+            // as connection requests.
+            if buffer.len() != MAX_PACKET_LEN { return Ok(None); }
+
+            PacketData::DiscoveryRequest
+        }
+
+        DISCOVERY_RESPONSE => {
+            let version = cursor.read_u32::<NetworkEndian>()?;
+
+            let info_len = cursor.read_u16::<NetworkEndian>()? as usize;
+            let info_start = cursor.position() as usize;
+            let info_end = info_start + info_len;
+
+            if info_end > body.len() { return Ok(None); }
+
+            PacketData::DiscoveryResponse {
+                version,
+                info: &body[info_start .. info_end],
+            }
+        }
+
+        INFO_REQUEST => {
+            // Enforce that info requests be the maximum allowed size, for the same reason as
+            // connection requests.
+            if buffer.len() != MAX_PACKET_LEN { return Ok(None); }
+
+            PacketData::InfoRequest
+        }
+
+        INFO_RESPONSE => {
+            let version = cursor.read_u32::<NetworkEndian>()?;
+
+            let payload_len = cursor.read_u16::<NetworkEndian>()? as usize;
+            let payload_start = cursor.position() as usize;
+            let payload_end = payload_start + payload_len;
+
+            if payload_end > body.len() { return Ok(None); }
+
+            PacketData::InfoResponse {
+                version,
+                payload: &body[payload_start .. payload_end],
+            }
+        }
+
         // Ignore any unknown message types.
         _ => { return Ok(None); }
     };
@@ -1283,15 +2012,19 @@ fn encode<'a>(packet: Packet<'a>, buffer: &mut Vec<u8>) -> Result<(), io::Error>
 
     // Write some stuff based on the packet data.
     match packet.data {
-        PacketData::ConnectionRequest => {
+        PacketData::ConnectionRequest { version, compression } => {
+            buffer.write_u32::<NetworkEndian>(version)?;
+            buffer.write_u8(compression.to_u8())?;
+
             // Force the packet to be the maximum size.
             buffer.resize(MAX_PACKET_LEN, 0);
         }
 
-        PacketData::Challenge(cookie) | PacketData::ChallengeResponse(cookie) => {
+        PacketData::Challenge(cookie)
+        | PacketData::MigrationChallenge(cookie) => {
             // Write the length of the cookie into the buffer.
             debug_assert!(
-                cookie.len() <= MAX_COOKIE_LEN,
+                cookie.len() <= ::std::u8::MAX as usize,
                 "Cookie is too big for its length to fit in a `u8`"
             );
             buffer.write_u8(cookie.len() as u8)?;
@@ -1300,9 +2033,58 @@ fn encode<'a>(packet: Packet<'a>, buffer: &mut Vec<u8>) -> Result<(), io::Error>
             buffer.extend(cookie);
         }
 
-        PacketData::ConnectionAccepted => {}
+        PacketData::ChallengeResponse { cookie, public_key } => {
+            debug_assert!(
+                cookie.len() <= ::std::u8::MAX as usize,
+                "Cookie is too big for its length to fit in a `u8`"
+            );
+            buffer.write_u8(cookie.len() as u8)?;
+            buffer.extend(cookie);
+
+            debug_assert_eq!(PUBLIC_KEY_LEN, public_key.len(), "Public key is the wrong size");
+            buffer.extend(public_key);
+        }
+
+        PacketData::ConnectionAccepted { compression, public_key } => {
+            buffer.write_u8(compression.to_u8())?;
+
+            debug_assert_eq!(PUBLIC_KEY_LEN, public_key.len(), "Public key is the wrong size");
+            buffer.extend(public_key);
+        }
+
+        PacketData::DiscoveryRequest | PacketData::InfoRequest => {
+            // Force the packet to be the maximum size.
+            buffer.resize(MAX_PACKET_LEN, 0);
+        }
+
+        PacketData::DiscoveryResponse { version, info: payload }
+        | PacketData::InfoResponse { version, payload } => {
+            buffer.write_u32::<NetworkEndian>(version)?;
+
+            debug_assert!(payload.len() <= MAX_RESPONSE_PAYLOAD_LEN, "Payload is too big");
+            buffer.write_u16::<NetworkEndian>(payload.len() as u16)?;
+            buffer.extend(payload);
+        }
+
+        PacketData::MigrationResponse { cookie, tag } => {
+            debug_assert!(
+                cookie.len() <= ::std::u8::MAX as usize,
+                "Cookie is too big for its length to fit in a `u8`"
+            );
+            buffer.write_u8(cookie.len() as u8)?;
+            buffer.extend(cookie);
+
+            debug_assert_eq!(MIGRATION_TAG_LEN, tag.len(), "Migration tag is the wrong size");
+            buffer.extend(tag);
+        }
 
-        PacketData::Message { sequence_number, fragment, num_fragments, fragment_number } => {
+        PacketData::Message {
+            sequence_number,
+            fragment,
+            num_fragments,
+            fragment_number,
+            compressed,
+        } => {
             // Write the sequence number.
             buffer.write_u32::<NetworkEndian>(sequence_number)?;
 
@@ -1311,6 +2093,11 @@ fn encode<'a>(packet: Packet<'a>, buffer: &mut Vec<u8>) -> Result<(), io::Error>
             buffer.write_u8(num_fragments)?;
             buffer.write_u8(fragment_number)?;
 
+            // Write the message flags.
+            let mut flags = 0;
+            if compressed { flags |= MESSAGE_FLAG_COMPRESSED; }
+            buffer.write_u8(flags)?;
+
             // Write the length of the fragment into the buffer.
             debug_assert!(
                 fragment.len() <= MAX_FRAGMENT_LEN,
@@ -1325,30 +2112,53 @@ fn encode<'a>(packet: Packet<'a>, buffer: &mut Vec<u8>) -> Result<(), io::Error>
         PacketData::Ack(sequence_number) => {
             buffer.write_u32::<NetworkEndian>(sequence_number)?;
         }
+
+        PacketData::ConnectionDenied(reason) => {
+            match reason {
+                DenyReason::VersionMismatch(version) => {
+                    buffer.write_u8(DENIED_VERSION_MISMATCH)?;
+                    buffer.write_u32::<NetworkEndian>(version)?;
+                }
+
+                DenyReason::CookieRejected => {
+                    buffer.write_u8(DENIED_COOKIE_REJECTED)?;
+                }
+            }
+        }
     }
 
     // Split the buffer into the leading checksum and the remaining body of the packet.
     let (checksum, body) = buffer.split_at_mut(4);
 
-    // Create a CRC32 digest of the implicit protocol ID and the body of the packet.
-    let mut digest = Digest::new(crc32::IEEE);
-    digest.write_u64(PROTOCOL_ID);
-    Hasher32::write(&mut digest, body);
-
     // Write the checksum into the leading 4 bytes of the packet.
-    NetworkEndian::write_u32(checksum, digest.sum32());
+    NetworkEndian::write_u32(checksum, packet_checksum(body));
 
     Ok(())
 }
 
+/// Calculates the checksum for the body of a packet (i.e. everything after the checksum).
+///
+/// The checksum is a CRC32 digest of the implicit protocol ID followed by the body, so packets
+/// from other protocols (or other versions of this one) fail validation.
+fn packet_checksum(body: &[u8]) -> u32 {
+    let mut digest = Digest::new(crc32::IEEE);
+    digest.write_u64(PROTOCOL_ID);
+    Hasher32::write(&mut digest, body);
+    digest.sum32()
+}
+
 /// Returns the next valid packet received from the connected peer.
 ///
 /// `recv_packet` will automatically discard any incoming datagrams that do not come from the
 /// connected peer, or that do not pass basic validation. It will repeated polly the
 /// underlying socket until it get a valid packet or a `WouldBlock` error.
+///
+/// Migration challenges from the peer are answered automatically and are never returned.
 fn recv_packet<'b>(
-    socket: &UdpSocket,
+    socket: &Socket,
     peer_address: SocketAddr,
+    connection_id: u64,
+    session_key: &[u8; SESSION_KEY_LEN],
     buffer: &'b mut [u8],
 ) -> Result<Packet<'b>, io::Error> {
     let len;
@@ -1359,9 +2169,39 @@ fn recv_packet<'b>(
         // discard it.
         if address != peer_address { continue; }
 
-        if let Some(_) = decode(&buffer[.. bytes_read])? {
-            len = bytes_read;
-            break;
+        match decode(&buffer[.. bytes_read]) {
+            Some(Packet { connection_id: id, data: PacketData::MigrationChallenge(cookie) }) => {
+                if id != connection_id { continue; }
+
+                // Our address has changed, so the server wants us to prove that we own the
+                // connection. We do that by signing the cookie with our session key.
+                let key = hmac::SigningKey::new(&SHA256, &session_key[..]);
+                let tag = hmac::sign(&key, cookie);
+
+                let mut response = Vec::with_capacity(MAX_PACKET_LEN);
+                encode(
+                    Packet {
+                        connection_id,
+                        data: PacketData::MigrationResponse { cookie, tag: tag.as_ref() },
+                    },
+                    &mut response,
+                )?;
+
+                // NOTE: We don't do anything when we get a `WouldBlock` error because the server
+                // will send another challenge the next time it receives a packet from us.
+                if let Err(error) = socket.send_to(&response, &peer_address) {
+                    if error.kind() != io::ErrorKind::WouldBlock {
+                        return Err(error);
+                    }
+                }
+            }
+
+            Some(_) => {
+                len = bytes_read;
+                break;
+            }
+
+            None => {}
         }
     }
 
@@ -1369,7 +2209,118 @@ fn recv_packet<'b>(
     // value (i.e. the packet) from the body of a loop. So we return the length of the packet
     // from the loop (since it's not a borrowed value) and re-borrow the packet after the
     // loop.
-    Ok(decode(&buffer[.. len])?.unwrap())
+    Ok(decode(&buffer[.. len]).expect("Packet was already decoded"))
+}
+
+/// Generates a key pair for agreeing on a session key, writing the public key to `public_key`.
+///
+/// A new key pair is generated for every connection, so the private key can only be used for a
+/// single key agreement.
+fn generate_key_pair(public_key: &mut [u8; PUBLIC_KEY_LEN]) -> EphemeralPrivateKey {
+    let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+        .expect("Failed to generate private key");
+    private_key.compute_public_key(&mut public_key[..]).expect("Failed to compute public key");
+    private_key
+}
+
+/// Derives the session key for a connection from our private key and the peer's public key.
+///
+/// Both sides of the connection derive the same key, without the key ever being sent. Returns
+/// `None` if the peer's public key is invalid.
+fn derive_session_key(
+    private_key: EphemeralPrivateKey,
+    peer_public_key: &[u8],
+    connection_id: u64,
+) -> Option<[u8; SESSION_KEY_LEN]> {
+    agreement::agree_ephemeral(
+        private_key,
+        &X25519,
+        untrusted::Input::from(peer_public_key),
+        (),
+        |key_material| {
+            // Salt the key with the connection ID so that the key is bound to the connection.
+            let mut salt = [0; 8];
+            NetworkEndian::write_u64(&mut salt, connection_id);
+            let salt = hmac::SigningKey::new(&SHA256, &salt);
+
+            let mut session_key = [0; SESSION_KEY_LEN];
+            hkdf::extract_and_expand(&salt, key_material, SESSION_KEY_INFO, &mut session_key);
+            Ok(session_key)
+        },
+    ).ok()
+}
+
+/// Serializes and encrypts `cookie` into `buffer`, returning the length of the sealed cookie.
+///
+/// The sealed cookie is made up of a random nonce followed by the ciphertext. `ad` is
+/// authenticated along with the cookie, and the same value must be used to open the cookie.
+fn seal_cookie<T: Serialize>(
+    cookie: &T,
+    ad: &[u8],
+    rng: &mut OsRng,
+    sealing_key: &SealingKey,
+    buffer: &mut [u8; MAX_COOKIE_LEN],
+) -> usize {
+    // Split the cookie bytes into two buffers: The front for the nonce, and the back for the
+    // ciphertext.
+    let (nonce, ciphertext) = buffer.split_at_mut(NONCE_LEN);
+
+    // Generate the nonce in the front part of the buffer.
+    rng.fill_bytes(nonce);
+
+    bincode::serialize_into(
+        &mut Cursor::new(&mut ciphertext[..]),
+        cookie,
+        bincode::Infinite,
+    ).expect("Failed to serialize cookie");
+
+    // Shrink the size of the ciphertext buffer to be exactly the serialized size of the cookie
+    // + the tag size of the ecryption algorithm.
+    let serialized_len = bincode::internal::serialized_size(cookie) as usize;
+    let ciphertext_len = serialized_len + ALGORITHM.tag_len();
+    debug_assert!(
+        ciphertext.len() > ciphertext_len,
+        "Serialized cookie is too big"
+    );
+    let ciphertext = &mut ciphertext[.. ciphertext_len];
+
+    // Encrypt the cookie bytes in-place within the ciphertext buffer.
+    let sealed_len = aead::seal_in_place(
+        sealing_key,
+        &nonce[..],
+        ad,
+        ciphertext,
+        ALGORITHM.tag_len(),
+    ).expect("Failed to seal the cookie");
+    assert_eq!(
+        sealed_len, ciphertext.len(),
+        "Sealed length is different than ciphertext length"
+    );
+
+    nonce.len() + ciphertext_len
+}
+
+/// Decrypts and deserializes a cookie created with `seal_cookie`.
+///
+/// Returns `None` if the cookie is malformed or wasn't sealed with the matching key and `ad`.
+fn open_cookie<T: DeserializeOwned>(
+    cookie: &[u8],
+    ad: &[u8],
+    opening_key: &OpeningKey,
+) -> Option<T> {
+    // Discard cookies that are too short to contain the nonce, or too long to be one of ours.
+    if cookie.len() < NONCE_LEN || cookie.len() > MAX_COOKIE_LEN { return None; }
+
+    // Split the cookie into the nonce and ciphertext.
+    let (nonce, ciphertext) = cookie.split_at(NONCE_LEN);
+
+    // Copy the ciphertext into another buffer where we can decrypt it in-place.
+    let cookie_buffer = &mut [0; MAX_CIPHERTEXT_LEN][.. ciphertext.len()];
+    cookie_buffer.copy_from_slice(ciphertext);
+
+    aead::open_in_place(opening_key, nonce, ad, 0, cookie_buffer)
+        .ok()
+        .and_then(|cookie| bincode::deserialize(cookie).ok())
 }
 
 #[derive(Debug)]
@@ -1383,37 +2334,32 @@ struct ChallengeCookie {
     request_time: Duration,
     source_addres: SocketAddr,
     connection_id: u64,
-}
 
-/// A collection of fragments for a partially-received message.
-///
-/// This tracks how many fragments are expected as part of the message, how many fragments have
-/// been received so far, and contains the raw data for each of the fragments.
-struct MessageFragments {
-    // The total number of fragments expected for the message.
-    num_fragments: u8,
-
-    // The number of fragments we've received so far.
-    received: u8,
-
-    // The total size in bytes of all the fragments we've received so far. Once the full message
-    // has been received, this will be the full size of the message in bytes.
-    bytes_received: usize,
-
-    // Placeholders for each of the fragments.
-    fragments: [bool; MAX_FRAGMENTS_PER_MESSAGE],
+    // The compression algorithm agreed to when the connection request was received.
+    compression: Compression,
 }
 
-impl ::std::fmt::Debug for MessageFragments {
-    fn fmt(&self, formatter: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        write!(formatter, "MessageFragments {{ .. }}")
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MigrationCookie {
+    request_time: Duration,
+    source_address: SocketAddr,
+    connection_id: u64,
 }
 
 #[derive(Debug)]
 struct OpenConnection {
     local_address: SocketAddr,
     remote_address: SocketAddr,
+    session_key: [u8; SESSION_KEY_LEN],
+
+    // The listener's public key from the key exchange, kept so that it can be resent if the
+    // connection accepted packet is lost.
+    public_key: [u8; PUBLIC_KEY_LEN],
+
+    // The last time we sent a migration challenge for this connection, used to rate limit
+    // challenges.
+    last_migration_challenge: Option<Instant>,
+
     disconnect_timeout: Timeout,
 }
 
@@ -1425,30 +2371,100 @@ struct Packet<'a> {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PacketData<'a> {
-    ConnectionRequest,
+    ConnectionRequest {
+        // The protocol version used by the client.
+        version: u32,
+
+        // The compression algorithm requested by the client.
+        compression: Compression,
+    },
     Challenge(&'a [u8]),
-    ChallengeResponse(&'a [u8]),
-    ConnectionAccepted,
+    ChallengeResponse {
+        cookie: &'a [u8],
+
+        // The client's public key, used to agree on the session key.
+        public_key: &'a [u8],
+    },
+    ConnectionAccepted {
+        // The compression algorithm the server agreed to.
+        compression: Compression,
+
+        // The server's public key, used to agree on the session key.
+        public_key: &'a [u8],
+    },
+    ConnectionDenied(DenyReason),
 
     Message {
         sequence_number: u32,
         fragment: &'a [u8],
         num_fragments: u8,
         fragment_number: u8,
+
+        // Whether the message was compressed with the connection's compression algorithm.
+        compressed: bool,
     },
 
     Ack(u32),
+
+    // Sent by the server when it receives a packet for a connection from an unknown address.
+    MigrationChallenge(&'a [u8]),
+
+    // Sent by the client in response to a migration challenge. `tag` is the HMAC of the cookie
+    // using the connection's session key.
+    MigrationResponse {
+        cookie: &'a [u8],
+        tag: &'a [u8],
+    },
+
+    // Broadcast by clients looking for servers on the local network. The connection ID is used
+    // as a request ID that's echoed back in the response.
+    DiscoveryRequest,
+
+    // Sent by the server in response to a discovery request. `info` is the serialized
+    // `ServerInfo` for the server.
+    DiscoveryResponse {
+        version: u32,
+        info: &'a [u8],
+    },
+
+    // Sent by clients to query a server's info without connecting. Like with discovery
+    // requests, the connection ID is used as a request ID.
+    InfoRequest,
+
+    // Sent by the server in response to an info request. `payload` is provided by the
+    // application.
+    InfoResponse {
+        version: u32,
+        payload: &'a [u8],
+    },
+}
+
+/// The reason given by a server when it refuses a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DenyReason {
+    /// The client is using a different version of the protocol. Contains the server's version.
+    VersionMismatch(u32),
+
+    /// The challenge cookie sent by the client failed validation.
+    CookieRejected,
 }
 
 impl<'a> PacketData<'a> {
     fn packet_type(&self) -> u8 {
         match *self {
-            PacketData::ConnectionRequest => CONNECTION_REQUEST,
+            PacketData::ConnectionRequest { .. } => CONNECTION_REQUEST,
             PacketData::Challenge(..) => CHALLENGE,
-            PacketData::ChallengeResponse(..) => CHALLENGE_RESPONSE,
-            PacketData::ConnectionAccepted => CONNECTION_ACCEPTED,
+            PacketData::ChallengeResponse { .. } => CHALLENGE_RESPONSE,
+            PacketData::ConnectionAccepted { .. } => CONNECTION_ACCEPTED,
+            PacketData::ConnectionDenied(..) => CONNECTION_DENIED,
             PacketData::Message { .. } => MESSAGE,
             PacketData::Ack(..) => ACK,
+            PacketData::MigrationChallenge(..) => MIGRATION_CHALLENGE,
+            PacketData::MigrationResponse { .. } => MIGRATION_RESPONSE,
+            PacketData::DiscoveryRequest => DISCOVERY_REQUEST,
+            PacketData::DiscoveryResponse { .. } => DISCOVERY_RESPONSE,
+            PacketData::InfoRequest => INFO_REQUEST,
+            PacketData::InfoResponse { .. } => INFO_RESPONSE,
         }
     }
 }
@@ -1459,13 +2475,18 @@ mod test {
 
     const CONNECTION_ID: u64 = 0x0011223344556677;
     static COOKIE: &'static [u8] = b"super good cookie that's totally valid";
+    static PUBLIC_KEY: &'static [u8; PUBLIC_KEY_LEN] = &[0xAB; PUBLIC_KEY_LEN];
+    static TAG: &'static [u8; MIGRATION_TAG_LEN] = &[0xCD; MIGRATION_TAG_LEN];
 
     #[test]
     fn connection_request_roundtrip() {
         let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
         let packet = Packet {
             connection_id: CONNECTION_ID,
-            data: PacketData::ConnectionRequest,
+            data: PacketData::ConnectionRequest {
+                version: PROTOCOL_VERSION,
+                compression: Compression::Lz4,
+            },
         };
 
         encode(
@@ -1473,7 +2494,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1495,7 +2516,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1509,7 +2530,7 @@ mod test {
         let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
         let packet = Packet {
             connection_id: CONNECTION_ID,
-            data: PacketData::ChallengeResponse(COOKIE),
+            data: PacketData::ChallengeResponse { cookie: COOKIE, public_key: PUBLIC_KEY },
         };
 
         encode(
@@ -1517,7 +2538,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1531,7 +2552,10 @@ mod test {
         let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
         let packet = Packet {
             connection_id: CONNECTION_ID,
-            data: PacketData::ConnectionAccepted,
+            data: PacketData::ConnectionAccepted {
+                compression: Compression::Lz4,
+                public_key: PUBLIC_KEY,
+            },
         };
 
         encode(
@@ -1539,7 +2563,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1548,6 +2572,54 @@ mod test {
         }
     }
 
+    #[test]
+    fn session_key_agreement() {
+        let mut client_public_key = [0; PUBLIC_KEY_LEN];
+        let client_private_key = generate_key_pair(&mut client_public_key);
+        let mut server_public_key = [0; PUBLIC_KEY_LEN];
+        let server_private_key = generate_key_pair(&mut server_public_key);
+
+        let client_key = derive_session_key(client_private_key, &server_public_key, CONNECTION_ID)
+            .expect("Client failed to derive session key");
+        let server_key = derive_session_key(server_private_key, &client_public_key, CONNECTION_ID)
+            .expect("Server failed to derive session key");
+        assert_eq!(client_key, server_key);
+
+        // A truncated public key is rejected.
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        let private_key = generate_key_pair(&mut public_key);
+        assert_eq!(None, derive_session_key(private_key, &client_public_key[.. 16], CONNECTION_ID));
+    }
+
+    #[test]
+    fn connection_denied_roundtrip() {
+        let reasons = [
+            DenyReason::VersionMismatch(PROTOCOL_VERSION + 1),
+            DenyReason::CookieRejected,
+        ];
+
+        for &reason in &reasons {
+            let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+            let packet = Packet {
+                connection_id: CONNECTION_ID,
+                data: PacketData::ConnectionDenied(reason),
+            };
+
+            encode(
+                packet,
+                &mut buffer,
+            ).expect("Error encoding packet");
+
+            match decode(&buffer[..]) {
+                Some(decoded) => {
+                    assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+                }
+
+                None => { panic!("Packet failed verification"); }
+            }
+        }
+    }
+
     #[test]
     fn message_fragment_roundtrip() {
         let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
@@ -1558,6 +2630,7 @@ mod test {
                 fragment: COOKIE,
                 num_fragments: 123,
                 fragment_number: 12,
+                compressed: true,
             },
         };
 
@@ -1566,7 +2639,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1577,10 +2650,56 @@ mod test {
 
     #[test]
     fn ack_roundtrip() {
+        for &sequence_number in &[7, ::std::u32::MAX] {
+            let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+            let packet = Packet {
+                connection_id: CONNECTION_ID,
+                data: PacketData::Ack(sequence_number),
+            };
+
+            encode(
+                packet,
+                &mut buffer,
+            ).expect("Error encoding packet");
+
+            match decode(&buffer[..]) {
+                Some(decoded) => {
+                    assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+                }
+
+                None => { panic!("Packet failed verification"); }
+            }
+        }
+    }
+
+    #[test]
+    fn migration_challenge_roundtrip() {
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        let packet = Packet {
+            connection_id: CONNECTION_ID,
+            data: PacketData::MigrationChallenge(COOKIE),
+        };
+
+        encode(
+            packet,
+            &mut buffer,
+        ).expect("Error encoding packet");
+
+        match decode(&buffer[..]) {
+            Some(decoded) => {
+                assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+            }
+
+            None => { panic!("Packet failed verification"); }
+        }
+    }
+
+    #[test]
+    fn migration_response_roundtrip() {
         let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
         let packet = Packet {
             connection_id: CONNECTION_ID,
-            data: PacketData::Ack(7),
+            data: PacketData::MigrationResponse { cookie: COOKIE, tag: TAG },
         };
 
         encode(
@@ -1588,7 +2707,7 @@ mod test {
             &mut buffer,
         ).expect("Error encoding packet");
 
-        match decode(&buffer[..]).expect("Error decoding packet") {
+        match decode(&buffer[..]) {
             Some(decoded) => {
                 assert_eq!(packet, decoded, "Decoded packed doesn't match original");
             }
@@ -1596,4 +2715,296 @@ mod test {
             None => { panic!("Packet failed verification"); }
         }
     }
+
+    #[test]
+    fn discovery_request_roundtrip() {
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        let packet = Packet {
+            connection_id: CONNECTION_ID,
+            data: PacketData::DiscoveryRequest,
+        };
+
+        encode(
+            packet,
+            &mut buffer,
+        ).expect("Error encoding packet");
+
+        match decode(&buffer[..]) {
+            Some(decoded) => {
+                assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+            }
+
+            None => { panic!("Packet failed verification"); }
+        }
+    }
+
+    #[test]
+    fn discovery_response_roundtrip() {
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        let packet = Packet {
+            connection_id: CONNECTION_ID,
+            data: PacketData::DiscoveryResponse { version: PROTOCOL_VERSION, info: COOKIE },
+        };
+
+        encode(
+            packet,
+            &mut buffer,
+        ).expect("Error encoding packet");
+
+        match decode(&buffer[..]) {
+            Some(decoded) => {
+                assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+            }
+
+            None => { panic!("Packet failed verification"); }
+        }
+    }
+
+    #[test]
+    fn info_request_roundtrip() {
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        let packet = Packet {
+            connection_id: CONNECTION_ID,
+            data: PacketData::InfoRequest,
+        };
+
+        encode(
+            packet,
+            &mut buffer,
+        ).expect("Error encoding packet");
+
+        match decode(&buffer[..]) {
+            Some(decoded) => {
+                assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+            }
+
+            None => { panic!("Packet failed verification"); }
+        }
+    }
+
+    #[test]
+    fn info_response_roundtrip() {
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        let packet = Packet {
+            connection_id: CONNECTION_ID,
+            data: PacketData::InfoResponse { version: PROTOCOL_VERSION, payload: COOKIE },
+        };
+
+        encode(
+            packet,
+            &mut buffer,
+        ).expect("Error encoding packet");
+
+        match decode(&buffer[..]) {
+            Some(decoded) => {
+                assert_eq!(packet, decoded, "Decoded packed doesn't match original");
+            }
+
+            None => { panic!("Packet failed verification"); }
+        }
+    }
+
+    #[test]
+    fn info_response_is_not_larger_than_request() {
+        let mut request = Vec::with_capacity(MAX_PACKET_LEN);
+        encode(
+            Packet { connection_id: CONNECTION_ID, data: PacketData::InfoRequest },
+            &mut request,
+        ).expect("Error encoding packet");
+
+        let payload = [0; MAX_RESPONSE_PAYLOAD_LEN];
+        let mut response = Vec::with_capacity(MAX_PACKET_LEN);
+        encode(
+            Packet {
+                connection_id: CONNECTION_ID,
+                data: PacketData::InfoResponse { version: PROTOCOL_VERSION, payload: &payload },
+            },
+            &mut response,
+        ).expect("Error encoding packet");
+
+        assert!(response.len() <= request.len());
+    }
+
+    #[test]
+    fn truncated_packets_are_discarded() {
+        // A message packet with a valid checksum that ends right after the packet type.
+        let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+        encode(
+            Packet { connection_id: CONNECTION_ID, data: PacketData::Ack(1) },
+            &mut buffer,
+        ).expect("Error encoding packet");
+        buffer.truncate(HEADER_LEN);
+        buffer[HEADER_LEN - 1] = MESSAGE;
+        let checksum = packet_checksum(&buffer[4 ..]);
+        NetworkEndian::write_u32(&mut buffer[.. 4], checksum);
+
+        assert_eq!(None, decode(&buffer[..]));
+    }
+
+    mod proptests {
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+        use proptest::test_runner::TestCaseError;
+        use super::super::*;
+
+        fn compression() -> BoxedStrategy<Compression> {
+            prop_oneof![Just(Compression::None), Just(Compression::Lz4)].boxed()
+        }
+
+        // Cookies have their length written as a `u8`.
+        fn cookie() -> BoxedStrategy<Vec<u8>> {
+            vec(any::<u8>(), 0 .. ::std::u8::MAX as usize + 1).boxed()
+        }
+
+        fn assert_roundtrip(packet: Packet) -> Result<(), TestCaseError> {
+            let mut buffer = Vec::with_capacity(MAX_PACKET_LEN);
+            encode(packet, &mut buffer).expect("Error encoding packet");
+
+            prop_assert!(buffer.len() <= MAX_PACKET_LEN, "Packet is too big: {}", buffer.len());
+            prop_assert_eq!(Some(packet), decode(&buffer[..]));
+            Ok(())
+        }
+
+        proptest! {
+            #[test]
+            fn connection_request_roundtrip(
+                connection_id in any::<u64>(),
+                version in any::<u32>(),
+                compression in compression(),
+            ) {
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::ConnectionRequest { version, compression },
+                })?;
+            }
+
+            #[test]
+            fn cookie_roundtrip(
+                connection_id in any::<u64>(),
+                cookie in cookie(),
+                public_key in vec(any::<u8>(), PUBLIC_KEY_LEN),
+            ) {
+                assert_roundtrip(Packet { connection_id, data: PacketData::Challenge(&cookie) })?;
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::ChallengeResponse {
+                        cookie: &cookie,
+                        public_key: &public_key,
+                    },
+                })?;
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::MigrationChallenge(&cookie),
+                })?;
+            }
+
+            #[test]
+            fn connection_accepted_roundtrip(
+                connection_id in any::<u64>(),
+                compression in compression(),
+                public_key in vec(any::<u8>(), PUBLIC_KEY_LEN),
+            ) {
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::ConnectionAccepted { compression, public_key: &public_key },
+                })?;
+            }
+
+            #[test]
+            fn connection_denied_roundtrip(
+                connection_id in any::<u64>(),
+                version in any::<u32>(),
+                cookie_rejected in any::<bool>(),
+            ) {
+                let reason = if cookie_rejected {
+                    DenyReason::CookieRejected
+                } else {
+                    DenyReason::VersionMismatch(version)
+                };
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::ConnectionDenied(reason),
+                })?;
+            }
+
+            #[test]
+            fn message_roundtrip(
+                connection_id in any::<u64>(),
+                sequence_number in any::<u32>(),
+                fragment in vec(any::<u8>(), 0 .. MAX_FRAGMENT_LEN + 1),
+                num_fragments in 1u8 .. ::std::u8::MAX,
+                fragment_number in any::<u8>(),
+                compressed in any::<bool>(),
+            ) {
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::Message {
+                        sequence_number,
+                        fragment: &fragment,
+                        num_fragments,
+                        fragment_number: fragment_number % num_fragments,
+                        compressed,
+                    },
+                })?;
+            }
+
+            #[test]
+            fn ack_roundtrip(connection_id in any::<u64>(), sequence_number in any::<u32>()) {
+                assert_roundtrip(Packet { connection_id, data: PacketData::Ack(sequence_number) })?;
+            }
+
+            #[test]
+            fn migration_response_roundtrip(
+                connection_id in any::<u64>(),
+                cookie in cookie(),
+                tag in vec(any::<u8>(), MIGRATION_TAG_LEN),
+            ) {
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::MigrationResponse { cookie: &cookie, tag: &tag },
+                })?;
+            }
+
+            #[test]
+            fn request_roundtrip(connection_id in any::<u64>()) {
+                assert_roundtrip(Packet { connection_id, data: PacketData::DiscoveryRequest })?;
+                assert_roundtrip(Packet { connection_id, data: PacketData::InfoRequest })?;
+            }
+
+            #[test]
+            fn response_roundtrip(
+                connection_id in any::<u64>(),
+                version in any::<u32>(),
+                payload in vec(any::<u8>(), 0 .. MAX_RESPONSE_PAYLOAD_LEN + 1),
+            ) {
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::DiscoveryResponse { version, info: &payload },
+                })?;
+                assert_roundtrip(Packet {
+                    connection_id,
+                    data: PacketData::InfoResponse { version, payload: &payload },
+                })?;
+            }
+
+            #[test]
+            fn decode_arbitrary_bytes(buffer in vec(any::<u8>(), 0 .. MAX_PACKET_LEN + 1)) {
+                decode(&buffer[..]);
+            }
+
+            // Random bytes almost never have a valid checksum, so also try decoding random
+            // packet bodies with the correct checksum to exercise the rest of `decode`.
+            #[test]
+            fn decode_arbitrary_bodies(body in vec(any::<u8>(), 0 .. MAX_PACKET_LEN - 4 + 1)) {
+                let mut buffer = vec![0; 4];
+                NetworkEndian::write_u32(&mut buffer[..], packet_checksum(&body));
+                buffer.extend(&body);
+
+                // Anything that decodes successfully must survive being re-encoded.
+                if let Some(packet) = decode(&buffer[..]) {
+                    assert_roundtrip(packet)?;
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// A token bucket rate limiter.
+///
+/// Tokens are added to the bucket at a fixed rate, up to a maximum. Each action takes a token,
+/// and is refused if the bucket is empty. This allows short bursts of activity while limiting
+/// the sustained rate.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    // The maximum number of tokens the bucket can hold.
+    capacity: f64,
+
+    // The number of tokens added to the bucket each second.
+    per_second: f64,
+
+    tokens: f64,
+    last_update: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, per_second: u32, now: Instant) -> RateLimiter {
+        RateLimiter {
+            capacity: capacity as f64,
+            per_second: per_second as f64,
+            tokens: capacity as f64,
+            last_update: now,
+        }
+    }
+
+    /// Attempts to take a token from the bucket, returning `false` if the bucket is empty.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        // Refill the bucket based on how much time has passed since the last update.
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last_update = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns `true` if the bucket will have refilled completely by `now`.
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens + elapsed * self.per_second >= self.capacity
+    }
+}
+
+/// Rate limits actions separately for each IP address.
+///
+/// Each address gets its own token bucket, so that a flood of requests from (or spoofed as)
+/// one address doesn't use up the budget for everyone else. The number of addresses tracked at
+/// once is capped, so that requests from many different addresses can't use an unbounded
+/// amount of memory.
+#[derive(Debug, Clone)]
+pub(crate) struct AddressRateLimiter {
+    limiters: HashMap<IpAddr, RateLimiter>,
+
+    capacity: u32,
+    per_second: u32,
+    max_addresses: usize,
+}
+
+impl AddressRateLimiter {
+    pub fn new(capacity: u32, per_second: u32, max_addresses: usize) -> AddressRateLimiter {
+        AddressRateLimiter {
+            limiters: HashMap::new(),
+            capacity,
+            per_second,
+            max_addresses,
+        }
+    }
+
+    /// Attempts to take a token from the bucket for `address`, returning `false` if the bucket
+    /// is empty.
+    ///
+    /// Also returns `false` if `address` isn't already being tracked and the maximum number of
+    /// addresses are being tracked.
+    pub fn try_acquire(&mut self, address: IpAddr, now: Instant) -> bool {
+        if !self.limiters.contains_key(&address) && self.limiters.len() >= self.max_addresses {
+            // Forget any addresses whose buckets have refilled, since a new bucket would behave
+            // exactly the same.
+            self.limiters.retain(|_, limiter| !limiter.is_full(now));
+            if self.limiters.len() >= self.max_addresses { return false; }
+        }
+
+        let capacity = self.capacity;
+        let per_second = self.per_second;
+        self.limiters
+            .entry(address)
+            .or_insert_with(|| RateLimiter::new(capacity, per_second, now))
+            .try_acquire(now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_capacity() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(4, 1, now);
+
+        for _ in 0 .. 4 {
+            assert!(limiter.try_acquire(now));
+        }
+        assert!(!limiter.try_acquire(now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(2, 10, now);
+
+        assert!(limiter.try_acquire(now));
+        assert!(limiter.try_acquire(now));
+        assert!(!limiter.try_acquire(now));
+
+        // After 150ms another token and a half have been added.
+        let later = now + Duration::from_millis(150);
+        assert!(limiter.try_acquire(later));
+        assert!(!limiter.try_acquire(later));
+
+        // The bucket never holds more than its capacity.
+        let much_later = now + Duration::from_secs(60);
+        assert!(limiter.try_acquire(much_later));
+        assert!(limiter.try_acquire(much_later));
+        assert!(!limiter.try_acquire(much_later));
+    }
+
+    #[test]
+    fn addresses_are_limited_separately() {
+        let now = Instant::now();
+        let mut limiter = AddressRateLimiter::new(2, 1, 16);
+        let first = IpAddr::from([10, 0, 0, 1]);
+        let second = IpAddr::from([10, 0, 0, 2]);
+
+        assert!(limiter.try_acquire(first, now));
+        assert!(limiter.try_acquire(first, now));
+        assert!(!limiter.try_acquire(first, now));
+
+        // Using up the first address's budget doesn't affect the second address.
+        assert!(limiter.try_acquire(second, now));
+    }
+
+    #[test]
+    fn tracked_addresses_are_capped() {
+        let now = Instant::now();
+        let mut limiter = AddressRateLimiter::new(2, 1, 2);
+
+        assert!(limiter.try_acquire(IpAddr::from([10, 0, 0, 1]), now));
+        assert!(limiter.try_acquire(IpAddr::from([10, 0, 0, 2]), now));
+        assert!(!limiter.try_acquire(IpAddr::from([10, 0, 0, 3]), now));
+
+        // Once the existing buckets have refilled they're forgotten, making room for new
+        // addresses.
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.try_acquire(IpAddr::from([10, 0, 0, 3]), later));
+    }
+}
@@ -2,12 +2,12 @@ use futures::prelude::*;
 use state_machine_future::RentToOwn;
 use std::io;
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 use super::{
+    compression,
     Connection,
     encode,
-    MAX_FRAGMENT_LEN,
-    MAX_FRAGMENTS_PER_MESSAGE,
-    MessageFragments,
+    Error,
     Packet,
     PacketData,
     recv_packet,
@@ -20,7 +20,7 @@ pub struct Receive<T> where T: AsMut<[u8]> {
 
 impl<T> Future for Receive<T> where T: AsMut<[u8]> {
     type Item = (Connection, T, usize);
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.state.poll().map_err(|(error, _)| error)
@@ -48,13 +48,13 @@ pub(crate) enum State<T> where T: AsMut<[u8]> {
     Ready((Connection, T, usize)),
 
     #[state_machine_future(error)]
-    Error((io::Error, PhantomData<T>)),
+    Error((Error, PhantomData<T>)),
 }
 
 impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
     fn poll_reading<'a>(
         reading: &'a mut RentToOwn<'a, Reading<T>>,
-    ) -> Poll<AfterReading<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterReading<T>, (Error, PhantomData<T>)> {
         let message_len;
         let sequence;
         loop {
@@ -62,95 +62,108 @@ impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
             let packet = match recv_packet(
                 &reading.connection.socket,
                 reading.connection.peer_address,
+                reading.connection.connection_id,
+                &reading.connection.session_key,
                 &mut reading.connection.recv_buffer,
             ) {
                 Ok(packet) => { packet }
 
                 Err(error) => {
                     if error.kind() == io::ErrorKind::WouldBlock {
+                        // If we haven't heard from the peer in too long, assume that it has
+                        // disconnected.
+                        let timeout = reading.connection.disconnect_timeout
+                            .poll()
+                            .map_err(|error| (error.into(), PhantomData))?;
+                        if let Async::Ready(()) = timeout {
+                            return Err((Error::PeerDisconnected, PhantomData));
+                        }
+
                         return Ok(Async::NotReady);
                     }
 
                     // HACK: We should be able to use `try_nb!` here, but since we need to bundle
                     // the error with some `PhantomData` we end up having to do this manually.
-                    return Err((error, PhantomData));
+                    return Err((error.into(), PhantomData));
                 }
             };
 
+            // Reset the timeout since we received a packet.
+            // TODO: Make disconnect timeout configurable.
+            reading.connection.disconnect_timeout.reset(Instant::now() + Duration::from_secs(1));
+
             match packet.data {
                 PacketData::Message {
                     sequence_number,
                     fragment,
                     num_fragments,
                     fragment_number,
+                    compressed,
                 } => {
                     // If there's only one fragment in the message, treat it as a special
                     // case and return it directly, to avoid the overhead of stuffing it
-                    // into the fragments map.
+                    // into the reassembler.
                     if num_fragments == 1 {
-                        // Copy the fragment data into the output buffer.
-                        let dest = &mut reading.buffer.as_mut()[.. fragment.len()];
-                        dest.copy_from_slice(fragment);
-
-                        // Set the message's length to the length of the fragment.
-                        message_len = fragment.len();
-                        sequence = sequence_number;
-                        break;
-                    }
+                        // Copy the fragment data into the output buffer. Messages that can't be
+                        // decompressed or don't fit are discarded rather than failing the
+                        // receive, the same as any other malformed packet.
+                        match read_message(fragment, compressed, reading.buffer.as_mut()) {
+                            Ok(len) => {
+                                message_len = len;
+                                sequence = sequence_number;
+                                break;
+                            }
 
-                    // Retrieve the map containing the fragments that we have received
-                    let message = reading.connection.fragments
-                        .entry(sequence_number)
-                        .or_insert_with(|| MessageFragments {
-                            num_fragments,
-                            received: 0,
-                            bytes_received: 0,
-                            fragments: [false; MAX_FRAGMENTS_PER_MESSAGE],
-                        });
-
-                    // If the packet specifies a different number of fragments than the
-                    // first packet we received for this message, then discard it.
-                    if num_fragments != message.num_fragments { continue; }
-
-                    // If the fragment number is outside the valid range for this mesage,
-                    // then discard it.
-                    if fragment_number >= message.num_fragments { continue; }
-
-                    // If we haven't already received this fragment, insert it into the
-                    // message.
-                    if !message.fragments[fragment_number as usize] {
-                        // Copy the fragment into the corresponding part of the output
-                        // buffer.
-                        let fragment_start = fragment_number as usize * MAX_FRAGMENT_LEN;
-                        let fragment_end = fragment_start + fragment.len();
-                        let buffer = &mut reading.buffer.as_mut()[fragment_start .. fragment_end];
-                        buffer.copy_from_slice(fragment);
-
-                        // Update the tracking of which fragments we have received so far.
-                        message.fragments[fragment_number as usize] = true;
-                        message.bytes_received += fragment.len();
-                        message.received += 1;
+                            Err(error) => {
+                                println!("WARNING: Discarding received message: {}", error);
+                                continue;
+                            }
+                        }
                     }
 
-                    // Check if we have received all the fragments. If we have, then build
-                    // the message from the fragments.
-                    if message.received == message.num_fragments {
-                        // Verify that we have actually received all of the fragments.
-                        for received in &message.fragments[.. message.num_fragments as usize] {
-                            assert!(received, "We somehow missed a fragment of the message");
-                        }
+                    // Add the fragment to the partially-received message. Once we have received
+                    // all the fragments, copy the full message into the output buffer.
+                    let message = reading.connection.fragments.insert(
+                        sequence_number,
+                        fragment,
+                        num_fragments,
+                        fragment_number,
+                        compressed,
+                        Instant::now(),
+                    );
+                    if let Some(message) = message {
+                        let result = read_message(
+                            message.data(),
+                            message.compressed(),
+                            reading.buffer.as_mut(),
+                        );
+                        match result {
+                            Ok(len) => {
+                                message_len = len;
+                                sequence = sequence_number;
+                                break;
+                            }
 
-                        message_len = message.bytes_received;
-                        sequence = sequence_number;
-                        break;
+                            Err(error) => {
+                                println!("WARNING: Discarding received message: {}", error);
+                                continue;
+                            }
+                        }
                     }
                 }
 
-                PacketData::ConnectionRequest
+                PacketData::ConnectionRequest { .. }
                 | PacketData::Challenge(..)
-                | PacketData::ChallengeResponse(..)
-                | PacketData::ConnectionAccepted
+                | PacketData::ChallengeResponse { .. }
+                | PacketData::ConnectionAccepted { .. }
+                | PacketData::ConnectionDenied(..)
                 | PacketData::Ack(..)
+                | PacketData::MigrationChallenge(..)
+                | PacketData::MigrationResponse { .. }
+                | PacketData::DiscoveryRequest
+                | PacketData::DiscoveryResponse { .. }
+                | PacketData::InfoRequest
+                | PacketData::InfoResponse { .. }
                 => { continue; }
             }
         }
@@ -176,7 +189,7 @@ impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
 
     fn poll_acknowledging<'a>(
         ack: &'a mut RentToOwn<'a, Acknowledging<T>>,
-    ) -> Poll<AfterAcknowledging<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterAcknowledging<T>, (Error, PhantomData<T>)> {
         {
             let ack = &mut **ack;
 
@@ -193,7 +206,7 @@ impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
 
                     // HACK: We should be able to use `try_nb!` here, but since we need to bundle
                     // the error with some `PhantomData` we end up having to do this manually.
-                    return Err((error, PhantomData));
+                    return Err((error.into(), PhantomData));
                 }
             };
 
@@ -204,7 +217,7 @@ impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
                     io::Error::new(
                         io::ErrorKind::Other,
                         "Failed to send all bytes of the fragment",
-                    ),
+                    ).into(),
                     PhantomData
                 ));
             }
@@ -215,3 +228,32 @@ impl<T> PollState<T> for State<T> where T: AsMut<[u8]> {
         Ok(Async::Ready(Ready((connection, buffer, message_len)).into()))
     }
 }
+
+/// Writes a received message into `buffer`, decompressing it first if the sender compressed it.
+///
+/// Returns the length of the message written to `buffer`. Fails if the message couldn't be
+/// decompressed or doesn't fit in `buffer`, in which case the message should be discarded.
+fn read_message(message: &[u8], compressed: bool, buffer: &mut [u8]) -> Result<usize, Error> {
+    if !compressed {
+        copy_message(message, buffer)?;
+        return Ok(message.len());
+    }
+
+    let mut decompressed = Vec::new();
+    if !compression::decompress(message, &mut decompressed) {
+        return Err(Error::Decode("Failed to decompress message".into()));
+    }
+
+    copy_message(&decompressed, buffer)?;
+    Ok(decompressed.len())
+}
+
+/// Copies a received message into the output buffer, failing if the buffer is too small.
+fn copy_message(message: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+    if message.len() > buffer.len() {
+        return Err(Error::MessageTooLarge { len: message.len(), max: buffer.len() });
+    }
+
+    buffer[.. message.len()].copy_from_slice(message);
+    Ok(())
+}
@@ -3,7 +3,7 @@ use state_machine_future::RentToOwn;
 use std::cmp;
 use std::io;
 use std::marker::PhantomData;
-use super::{encode, Connection, MAX_FRAGMENT_LEN, Packet, PacketData};
+use super::{encode, Connection, Error, MAX_FRAGMENT_LEN, MAX_MESSAGE_LEN, Packet, PacketData};
 
 /// A future representing a message being sent; Resolves once the message has been fully sent.
 #[derive(Debug)]
@@ -13,7 +13,7 @@ pub struct Send<T> where T: AsRef<[u8]> {
 
 impl<T> Future for Send<T> where T: AsRef<[u8]> {
     type Item = (Connection, T);
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.state.poll().map_err(|(error, _)| error)
@@ -41,15 +41,21 @@ pub(crate) enum State<T> where T: AsRef<[u8]> {
     Ready((Connection, T)),
 
     #[state_machine_future(error)]
-    // HACK: The error type should just be `io::Error`, but state_machine_future doesn't support
+    // HACK: The error type should just be `Error`, but state_machine_future doesn't support
     // having variants that don't reference all generic parameters.
-    Error((io::Error, PhantomData<T>)),
+    Error((Error, PhantomData<T>)),
 }
 
 impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
     fn poll_sending<'a>(
         sending: &'a mut RentToOwn<'a, Sending<T>>,
-    ) -> Poll<AfterSending<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterSending<T>, (Error, PhantomData<T>)> {
+        // Make sure that the message isn't too large to send before we start sending it.
+        let len = sending.buffer.as_ref().len();
+        if len > MAX_MESSAGE_LEN {
+            return Err((Error::MessageTooLarge { len, max: MAX_MESSAGE_LEN }, PhantomData));
+        }
+
         // Keep sending fragments until we've sent them all or we would block.
         loop {
             let sending = &mut **sending;
@@ -68,7 +74,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
                     // HACK: We should be able to use `try_nb!` here, but since we need to bundle
                     // the error with some `PhantomData` we end up having to do this manually.
-                    return Err((error, PhantomData));
+                    return Err((error.into(), PhantomData));
                 }
             };
 
@@ -79,7 +85,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                     io::Error::new(
                         io::ErrorKind::Other,
                         "Failed to send all bytes of the fragment",
-                    ),
+                    ).into(),
                     PhantomData
                 ));
             }
@@ -100,10 +106,11 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                         fragment,
                         num_fragments: sending.num_fragments,
                         fragment_number: sending.fragment_number,
+                        compressed: false,
                     },
                 },
                 &mut sending.connection.send_buffer,
-            ).map_err(|error| (error, PhantomData))?;
+            ).map_err(|error| (error.into(), PhantomData))?;
 
             // Update the current sequence number.
             sending.fragment_number += 1;
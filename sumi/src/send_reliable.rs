@@ -4,7 +4,16 @@ use std::cmp;
 use std::io;
 use std::marker::PhantomData;
 use std::time::Duration;
-use super::{encode, Connection, MAX_FRAGMENT_LEN, Packet, PacketData, recv_packet};
+use super::{
+    encode,
+    Connection,
+    Error,
+    MAX_FRAGMENT_LEN,
+    MAX_MESSAGE_LEN,
+    Packet,
+    PacketData,
+    recv_packet,
+};
 use tokio_core::reactor::Timeout;
 
 pub struct SendReliable<T> where T: AsRef<[u8]> {
@@ -13,7 +22,7 @@ pub struct SendReliable<T> where T: AsRef<[u8]> {
 
 impl<T> Future for SendReliable<T> where T: AsRef<[u8]> {
     type Item = (Connection, T);
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         self.state.poll().map_err(|(error, _)| error)
@@ -77,15 +86,21 @@ pub(crate) enum State<T> where T: AsRef<[u8]> {
     Ready((Connection, T)),
 
     #[state_machine_future(error)]
-    // HACK: The error type should just be `io::Error`, but state_machine_future doesn't support
+    // HACK: The error type should just be `Error`, but state_machine_future doesn't support
     // having variants that don't reference all generic parameters.
-    Error((io::Error, PhantomData<T>)),
+    Error((Error, PhantomData<T>)),
 }
 
 impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
     fn poll_sending<'a>(
         sending: &'a mut RentToOwn<'a, Sending<T>>,
-    ) -> Poll<AfterSending<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterSending<T>, (Error, PhantomData<T>)> {
+        // Make sure that the message isn't too large to send before we start sending it.
+        let len = sending.buffer.as_ref().len();
+        if len > MAX_MESSAGE_LEN {
+            return Err((Error::MessageTooLarge { len, max: MAX_MESSAGE_LEN }, PhantomData));
+        }
+
         // Keep sending fragments until we've sent them all or we would block.
         loop {
             let sending = &mut **sending;
@@ -104,7 +119,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
                     // HACK: We should be able to use `try_nb!` here, but since we need to bundle
                     // the error with some `PhantomData` we end up having to do this manually.
-                    return Err((error, PhantomData));
+                    return Err((error.into(), PhantomData));
                 }
             };
 
@@ -115,7 +130,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                     io::Error::new(
                         io::ErrorKind::Other,
                         "Failed to send all bytes of the fragment",
-                    ),
+                    ).into(),
                     PhantomData
                 ));
             }
@@ -136,10 +151,11 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                         fragment,
                         num_fragments: sending.num_fragments,
                         fragment_number: sending.fragment_number,
+                        compressed: false,
                     },
                 },
                 &mut sending.connection.send_buffer,
-            ).map_err(|error| (error, PhantomData))?;
+            ).map_err(|error| (error.into(), PhantomData))?;
 
             // Update the current sequence number.
             sending.fragment_number += 1;
@@ -159,9 +175,9 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
         // Create the timeout for waiting for the ack.
         let timeout = Timeout::new(timeout, &connection.handle)
-            .map_err(|error| (error, PhantomData))?;
+            .map_err(|error| (error.into(), PhantomData))?;
         let retry_timeout = Timeout::new(retry_interval, &connection.handle)
-            .map_err(|error| (error, PhantomData))?;
+            .map_err(|error| (error.into(), PhantomData))?;
 
         // return the `WaitingForAck` state.
         let waiting = WaitingForAck {
@@ -178,7 +194,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
     fn poll_waiting_for_ack<'a>(
         waiting: &'a mut RentToOwn<'a, WaitingForAck<T>>,
-    ) -> Poll<AfterWaitingForAck<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterWaitingForAck<T>, (Error, PhantomData<T>)> {
         // Repeatedly poll the socket until we receive the acknowledgement packet or there are no
         // more packets to read.
         loop {
@@ -187,6 +203,8 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                 match recv_packet(
                     &waiting.connection.socket,
                     waiting.connection.peer_address,
+                    waiting.connection.connection_id,
+                    &waiting.connection.session_key,
                     &mut waiting.connection.recv_buffer,
                 ) {
                     Ok(packet) => {
@@ -197,7 +215,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                     // have the timeout futures to poll.
                     Err(error) => {
                         if error.kind() != io::ErrorKind::WouldBlock {
-                            return Err((error, PhantomData));
+                            return Err((error.into(), PhantomData));
                         }
 
                         break;
@@ -214,13 +232,14 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
             return Ok(Async::Ready(Ready((connection, buffer)).into()));
         }
 
-        // Check if we've timed out waiting for the acknowledgement.
-        if let Async::Ready(()) = waiting.timeout.poll().map_err(|error| (error, PhantomData))? {
-            return Err((io::ErrorKind::TimedOut.into(), PhantomData))
+        // Check if we've timed out waiting for the acknowledgement. If the peer hasn't
+        // acknowledged the message by now, we assume that it has disconnected.
+        if let Async::Ready(()) = waiting.timeout.poll().map_err(|error| (error.into(), PhantomData))? {
+            return Err((Error::PeerDisconnected, PhantomData))
         }
 
         // Check if it's time to retry sending the message.
-        let retry = waiting.retry_timeout.poll().map_err(|error| (error, PhantomData))?;
+        let retry = waiting.retry_timeout.poll().map_err(|error| (error.into(), PhantomData))?;
         if let Async::Ready(()) = retry {
             let WaitingForAck {
                 mut connection,
@@ -244,6 +263,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                             fragment: &buffer[.. fragment_len],
                             num_fragments,
                             fragment_number: 0,
+                            compressed: false,
                         },
                     },
                     &mut connection.send_buffer,
@@ -268,7 +288,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
     fn poll_resending<'a>(
         sending: &'a mut RentToOwn<'a, Resending<T>>,
-    ) -> Poll<AfterResending<T>, (io::Error, PhantomData<T>)> {
+    ) -> Poll<AfterResending<T>, (Error, PhantomData<T>)> {
         // Keep sending fragments until we've sent them all or we would block.
         loop {
             let sending = &mut **sending;
@@ -287,7 +307,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
                     // HACK: We should be able to use `try_nb!` here, but since we need to bundle
                     // the error with some `PhantomData` we end up having to do this manually.
-                    return Err((error, PhantomData));
+                    return Err((error.into(), PhantomData));
                 }
             };
 
@@ -298,7 +318,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                     io::Error::new(
                         io::ErrorKind::Other,
                         "Failed to send all bytes of the fragment",
-                    ),
+                    ).into(),
                     PhantomData
                 ));
             }
@@ -319,10 +339,11 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
                         fragment,
                         num_fragments: sending.num_fragments,
                         fragment_number: sending.fragment_number,
+                        compressed: false,
                     },
                 },
                 &mut sending.connection.send_buffer,
-            ).map_err(|error| (error, PhantomData))?;
+            ).map_err(|error| (error.into(), PhantomData))?;
 
             // Update the current sequence number.
             sending.fragment_number += 1;
@@ -342,7 +363,7 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
 
         // Create the retry timeout.
         let retry_timeout = Timeout::new(retry_interval, &connection.handle)
-            .map_err(|error| (error, PhantomData))?;
+            .map_err(|error| (error.into(), PhantomData))?;
 
         // return the `WaitingForAck` state.
         let waiting = WaitingForAck {
@@ -357,3 +378,46 @@ impl<T> PollState<T> for State<T> where T: AsRef<[u8]> {
         Ok(Async::Ready(waiting.into()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use std::u32;
+    use tokio_core::reactor::Core;
+    use {Connection, ConnectionListener};
+
+    #[test]
+    fn acks_across_wraparound() {
+        static MESSAGE: &'static [u8] = &[0xAB; 256];
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = ConnectionListener::bind("127.0.0.1:0", &handle).unwrap();
+        let address = listener.local_addr().unwrap();
+        let connect = Connection::connect(address, &handle).unwrap();
+        let accept = listener.into_future().map_err(|(error, _)| error);
+        let (mut client, (server, listener)) = core.run(connect.join(accept)).unwrap();
+        let mut server = server.expect("Listener closed without accepting the connection");
+
+        // Keep the listener running so that it forwards packets to the server's connection.
+        handle.spawn(listener.for_each(|_| Ok(())).map_err(|error| panic!("{:?}", error)));
+
+        // Start just short of the wraparound, so that the messages are sent with the sequence
+        // numbers `u32::MAX` and then 0. Each send only resolves once the matching ack arrives.
+        client.sequence_number = u32::MAX - 1;
+        for &expected in &[u32::MAX, 0] {
+            let send = client.send_reliable(MESSAGE);
+            let recv = server.recv(vec![0; 1024]);
+            let ((sent, _), (received, buffer, len)) = core
+                .run(send.join(recv))
+                .expect("Failed to send reliable message");
+
+            assert_eq!(expected, sent.sequence_number);
+            assert_eq!(MESSAGE, &buffer[.. len]);
+
+            client = sent;
+            server = received;
+        }
+    }
+}
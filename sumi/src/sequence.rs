@@ -0,0 +1,81 @@
+// Helpers for comparing sequence numbers that may have wrapped around.
+//
+// Sequence numbers are `u32` values that are incremented with each message sent, wrapping back
+// to 0 after `u32::MAX`. Plain integer comparisons break down once the sequence wraps, so
+// instead we treat `a` as newer than `b` if it's ahead of `b` by less than half the range of
+// the sequence, as described in [RFC 1982].
+//
+// [RFC 1982]: https://tools.ietf.org/html/rfc1982
+
+// Half the range of a sequence number. If two sequence numbers are further apart than this,
+// we assume that the sequence has wrapped around between them.
+const HALF_RANGE: u32 = 1 << 31;
+
+/// Returns `true` if `a` is a more recent sequence number than `b`.
+pub(crate) fn sequence_greater_than(a: u32, b: u32) -> bool {
+    (a > b && a - b <= HALF_RANGE) || (a < b && b - a > HALF_RANGE)
+}
+
+/// Tracks the most recent sequence number received, used to discard messages that arrive
+/// out of order.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SequenceTracker {
+    latest: Option<u32>,
+}
+
+impl SequenceTracker {
+    /// Records `sequence_number` as received, returning `false` if a newer message (or the same
+    /// message) has already been received.
+    pub fn accept(&mut self, sequence_number: u32) -> bool {
+        match self.latest {
+            Some(latest) if !sequence_greater_than(sequence_number, latest) => { false }
+
+            _ => {
+                self.latest = Some(sequence_number);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::u32;
+    use super::*;
+
+    #[test]
+    fn greater_than() {
+        assert!(sequence_greater_than(1, 0));
+        assert!(sequence_greater_than(1000, 1));
+        assert!(!sequence_greater_than(0, 1));
+        assert!(!sequence_greater_than(7, 7));
+    }
+
+    #[test]
+    fn greater_than_wraparound() {
+        assert!(sequence_greater_than(0, u32::MAX));
+        assert!(sequence_greater_than(10, u32::MAX - 10));
+        assert!(!sequence_greater_than(u32::MAX, 0));
+        assert!(!sequence_greater_than(u32::MAX - 10, 10));
+    }
+
+    #[test]
+    fn tracker_discards_old_messages() {
+        let mut tracker = SequenceTracker::default();
+        assert!(tracker.accept(5));
+        assert!(tracker.accept(7));
+        assert!(!tracker.accept(6));
+        assert!(!tracker.accept(7));
+        assert!(tracker.accept(8));
+    }
+
+    #[test]
+    fn tracker_wraparound() {
+        let mut tracker = SequenceTracker::default();
+        assert!(tracker.accept(u32::MAX - 1));
+        assert!(tracker.accept(u32::MAX));
+        assert!(tracker.accept(0));
+        assert!(!tracker.accept(u32::MAX));
+        assert!(tracker.accept(1));
+    }
+}
@@ -218,3 +218,63 @@ fn send_recv_large_reliable() {
     let wait_for_all = future::join_all(vec![send, recv]);
     core.run(wait_for_all).unwrap();
 }
+
+#[test]
+fn migrate_to_new_address() {
+    static MESSAGE: &'static [u8] = &[0xAB; 256];
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    // Once connected, move the client to a new socket before sending. The listener has to
+    // challenge the new address and accept the client's response before the message gets
+    // through.
+    let client = Connection::connect("127.0.0.1:1238".parse().unwrap(), &handle)
+        .unwrap()
+        .and_then(|mut connection| {
+            connection.rebind().map(|()| connection)
+        })
+        .and_then(|connection| {
+            connection.send_reliable(MESSAGE)
+        })
+        .map(|(_connection, buffer)| {
+            assert_eq!(buffer, MESSAGE);
+        })
+        .map_err(|error| panic!("{:?}", error));
+    let send = Box::new(client) as Box<Future<Item = (), Error = _>>;
+
+    let connection_listener = ConnectionListener::bind("127.0.0.1:1238", &handle)
+        .unwrap()
+        .into_future()
+        .and_then(|(connection, listener)| {
+            // Spawn the connection listener so that it keeps forwarding packets after the
+            // client's address changes.
+            let listen_remaining = listener
+                .for_each(|_| -> Result<(), _> {
+                    panic!("Received too many connections");
+                })
+                .map_err(|error| panic!("{:?}", error));
+            handle.spawn(listen_remaining);
+
+            let connection = connection.unwrap();
+            connection.recv(vec![0; 1024])
+                .map_err(|error| panic!("{:?}", error))
+        })
+        .and_then(|(_connection, buffer, len)| {
+            assert_eq!(MESSAGE, &buffer[.. len]);
+            Ok(())
+        })
+        .map_err(|(error, _)| panic!("{:?}", error));
+    let recv = Box::new(connection_listener) as Box<Future<Item = (), Error = _>>;
+
+    let timeout = Timeout::new(Duration::from_secs(1), &handle)
+        .expect("Failed to create timeout")
+        .and_then(|_| -> Result<(), _> {
+            panic!("Timeout occurred");
+        })
+        .map_err(|error| panic!("{:?}", error));
+    handle.spawn(timeout);
+
+    let wait_for_all = future::join_all(vec![send, recv]);
+    core.run(wait_for_all).unwrap();
+}